@@ -1,17 +1,31 @@
 // vim: tw=80
 use std::{
-    ffi::OsStr,
+    borrow::Cow,
+    cell::Cell,
+    collections::{BTreeMap, HashMap},
+    ffi::{OsStr, OsString},
     fmt,
     fs::{self, File, OpenOptions},
-    io::{self, Seek, SeekFrom, Write},
+    io::{self, BufWriter, IsTerminal, Seek, SeekFrom, Write},
+    iter,
     mem,
-    num::{NonZeroU64, NonZeroUsize},
+    num::{NonZeroU32, NonZeroU64, NonZeroUsize},
+    ops::{Deref, DerefMut, Index, Range},
     os::unix::{
-        fs::{FileExt, FileTypeExt},
-        io::{AsFd, AsRawFd, IntoRawFd, RawFd},
+        fs::{FileExt, FileTypeExt, MetadataExt},
+        io::{AsFd, AsRawFd, BorrowedFd, IntoRawFd, RawFd},
     },
-    path::PathBuf,
+    path::{Path, PathBuf},
     process,
+    ptr,
+    sync::{
+        atomic::{AtomicBool, AtomicI32, AtomicU64, AtomicUsize, Ordering},
+        Arc,
+        Mutex,
+    },
+    thread,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+    vec,
 };
 
 use cfg_if::cfg_if;
@@ -19,17 +33,46 @@ use clap::{
     builder::TypedValueParser,
     error::ErrorKind,
     Arg,
+    Args,
     Command,
+    CommandFactory,
     Error,
     Parser,
+    Subcommand,
+    ValueEnum,
 };
 use clap_verbosity_flag::{Verbosity, WarnLevel};
 use libc::c_void;
-use log::{debug, error, info, log, warn, Level};
+use log::{debug, error, info, log, warn, Level, LevelFilter};
 use nix::{
     errno,
-    sys::mman::{mmap, msync, munmap, MapFlags, MsFlags, ProtFlags},
-    unistd::{sysconf, SysconfVar},
+    fcntl::{Flock, FlockArg},
+    sys::{
+        mman::{
+            mmap,
+            mmap_anonymous,
+            msync,
+            munmap,
+            MapFlags,
+            MsFlags,
+            ProtFlags,
+        },
+        pthread::{pthread_kill, pthread_self},
+        resource::{getrlimit, setrlimit, Resource},
+        signal::{
+            kill,
+            raise,
+            sigaction,
+            signal,
+            SaFlags,
+            SigAction,
+            SigHandler,
+            SigSet,
+            Signal,
+        },
+        wait::{waitpid, WaitPidFlag, WaitStatus},
+    },
+    unistd::{dup2, fork, sysconf, ForkResult, SysconfVar},
 };
 use rand::{
     distributions::{Distribution, Standard, WeightedIndex},
@@ -38,9 +81,11 @@ use rand::{
     RngCore,
     SeedableRng,
 };
+use rand_chacha::ChaCha8Rng;
+use rand_pcg::Pcg64;
 use rand_xorshift::XorShiftRng;
 use ringbuffer::{AllocRingBuffer, RingBuffer, RingBufferExt, RingBufferWrite};
-use serde_derive::Deserialize;
+use serde_derive::{Deserialize, Serialize};
 
 cfg_if! {
     if #[cfg(any(
@@ -49,6 +94,11 @@ cfg_if! {
             target_os = "netbsd",
             ))] {
 
+        /// Whether a file of this type may be a disk
+        fn is_disk(ft: fs::FileType) -> bool {
+            ft.is_block_device() || ft.is_char_device()
+        }
+
         fn mediasize(fd: RawFd) -> io::Result<u64> {
 
             nix::ioctl_read! {
@@ -65,7 +115,40 @@ cfg_if! {
             }
             .map_err(|_| io::Error::from_raw_os_error(errno::Errno::last_raw()))
         }
+
+        /// Return the device's logical sector size, physical sector size, and
+        /// minimum efficient I/O size, in bytes
+        fn sectorsizes(fd: RawFd) -> io::Result<(u64, u64, u64)> {
+            nix::ioctl_read! {
+                /// Get the device's sector size in bytes
+                diocgsectorsize, 'd', 128, nix::libc::c_uint
+            }
+            nix::ioctl_read! {
+                /// Get the device's stripe size, usually its physical sector
+                /// size, or 0 if unknown
+                diocgstripesize, 'd', 139, nix::libc::off_t
+            }
+
+            let mut sectorsize = mem::MaybeUninit::<nix::libc::c_uint>::uninit();
+            let mut stripesize = mem::MaybeUninit::<nix::libc::off_t>::uninit();
+            // These ioctls are always safe
+            unsafe{
+                diocgsectorsize(fd, sectorsize.as_mut_ptr())?;
+                let logical = u64::from(sectorsize.assume_init());
+                let physical = match diocgstripesize(fd, stripesize.as_mut_ptr()) {
+                    Ok(_) => (stripesize.assume_init() as u64).max(logical),
+                    Err(_) => logical
+                };
+                Ok((logical, physical, physical))
+            }
+            .map_err(|_: nix::Error| io::Error::from_raw_os_error(errno::Errno::last_raw()))
+        }
     } else if #[cfg(any(target_os = "linux"))] {
+        /// Whether a file of this type may be a disk
+        fn is_disk(ft: fs::FileType) -> bool {
+            ft.is_block_device()
+        }
+
         fn mediasize(fd: RawFd) -> io::Result<u64> {
             nix::ioctl_read!{blkgetsize64, 0x12, 0x72, u64}
 
@@ -75,11 +158,55 @@ cfg_if! {
                 blkgetsize64(fd, mediasize.as_mut_ptr())
                 .map(|_| mediasize.assume_init())
             }
-            .map_err(|_| io::Error::from_raw_os_error(nix::errno::errno()))
+            .map_err(|_| io::Error::from_raw_os_error(errno::Errno::last_raw()))
+        }
+
+        /// Return the device's logical sector size, physical sector size, and
+        /// minimum efficient I/O size, in bytes
+        fn sectorsizes(fd: RawFd) -> io::Result<(u64, u64, u64)> {
+            nix::ioctl_read_bad!{
+                blksszget,
+                nix::request_code_none!(0x12, 104),
+                nix::libc::c_int
+            }
+            nix::ioctl_read_bad!{
+                blkiomin,
+                nix::request_code_none!(0x12, 120),
+                nix::libc::c_uint
+            }
+            nix::ioctl_read_bad!{
+                blkpbszget,
+                nix::request_code_none!(0x12, 123),
+                nix::libc::c_uint
+            }
+
+            let mut logical = mem::MaybeUninit::<nix::libc::c_int>::uninit();
+            let mut iomin = mem::MaybeUninit::<nix::libc::c_uint>::uninit();
+            let mut physical = mem::MaybeUninit::<nix::libc::c_uint>::uninit();
+            // These ioctls are always safe
+            unsafe{
+                blksszget(fd, logical.as_mut_ptr())?;
+                blkiomin(fd, iomin.as_mut_ptr())?;
+                blkpbszget(fd, physical.as_mut_ptr())?;
+                Ok((
+                    logical.assume_init() as u64,
+                    u64::from(physical.assume_init()),
+                    u64::from(iomin.assume_init())
+                ))
+            }
+            .map_err(|_: nix::Error| io::Error::from_raw_os_error(errno::Errno::last_raw()))
         }
     } else {
+        fn is_disk(_ft: fs::FileType) -> bool {
+            false
+        }
+
         fn mediasize(_fd: RawFd) -> io::Result<u64> {
-            unimplemented!()
+            Err(io::ErrorKind::Unsupported.into())
+        }
+
+        fn sectorsizes(_fd: RawFd) -> io::Result<(u64, u64, u64)> {
+            Err(io::ErrorKind::Unsupported.into())
         }
     }
 }
@@ -93,12 +220,11 @@ cfg_if! {
         #[derive(Copy, Clone, Debug)]
         struct PosixFadviseAdvice(nix::fcntl::PosixFadviseAdvice);
 
-        impl Distribution<PosixFadviseAdvice> for Standard {
-            fn sample<R: Rng + ?Sized>(&self, rng: &mut R) -> PosixFadviseAdvice
-            {
+        impl PosixFadviseAdvice {
+            fn from_index(i: u32) -> Self {
                 use nix::fcntl;
 
-                let inner = match rng.next_u32() % 6 {
+                let inner = match i % 6 {
                     0u32 => fcntl::PosixFadviseAdvice::POSIX_FADV_NORMAL,
                     1u32 => fcntl::PosixFadviseAdvice::POSIX_FADV_SEQUENTIAL,
                     2u32 => fcntl::PosixFadviseAdvice::POSIX_FADV_RANDOM,
@@ -109,6 +235,27 @@ cfg_if! {
                 };
                 PosixFadviseAdvice(inner)
             }
+
+            fn index(&self) -> u32 {
+                use nix::fcntl::PosixFadviseAdvice::*;
+
+                match self.0 {
+                    POSIX_FADV_NORMAL => 0,
+                    POSIX_FADV_SEQUENTIAL => 1,
+                    POSIX_FADV_RANDOM => 2,
+                    POSIX_FADV_NOREUSE => 3,
+                    POSIX_FADV_WILLNEED => 4,
+                    POSIX_FADV_DONTNEED => 5,
+                    _ => unimplemented!()
+                }
+            }
+        }
+
+        impl Distribution<PosixFadviseAdvice> for Standard {
+            fn sample<R: Rng + ?Sized>(&self, rng: &mut R) -> PosixFadviseAdvice
+            {
+                PosixFadviseAdvice::from_index(rng.next_u32())
+            }
         }
 
         impl fmt::Display for PosixFadviseAdvice {
@@ -130,6 +277,17 @@ cfg_if! {
     } else {
         #[derive(Copy, Clone, Debug)]
         struct PosixFadviseAdvice(());
+
+        impl PosixFadviseAdvice {
+            fn from_index(_i: u32) -> Self {
+                PosixFadviseAdvice(())
+            }
+
+            fn index(&self) -> u32 {
+                0
+            }
+        }
+
         impl Distribution<PosixFadviseAdvice> for Standard {
             fn sample<R: Rng + ?Sized>(&self, _: &mut R) -> PosixFadviseAdvice
             {
@@ -167,1356 +325,10331 @@ cfg_if! {
     }
 }
 
-/// Calculate the maximum field width needed to print numbers up to this size
-fn field_width(max: usize, hex: bool) -> usize {
-    if hex {
-        2 + (8 * mem::size_of_val(&max) - max.leading_zeros() as usize + 3) / 4
-    } else {
-        1 + (max as f64).log(10.0) as usize
-    }
-}
-
-#[derive(Clone)]
-struct MonitorParser {}
-impl TypedValueParser for MonitorParser {
-    type Value = (u64, u64);
+cfg_if! {
+    if #[cfg(any(target_os = "android", target_os = "linux"))] {
+        /// Create an anonymous file in the same directory as `fname`.
+        fn open_tmpfile(fname: &Path) -> io::Result<File> {
+            use std::os::unix::fs::OpenOptionsExt;
 
-    fn parse_ref(
-        &self,
-        cmd: &Command,
-        _arg: Option<&Arg>,
-        value: &OsStr,
-    ) -> Result<Self::Value, Error> {
-        let vs = value.to_str().ok_or_else(|| {
-            clap::Error::new(ErrorKind::InvalidUtf8).with_cmd(cmd)
-        })?;
-        let fields = vs.split(':').collect::<Vec<_>>();
-        if fields.len() != 2 {
-            let e = clap::Error::raw(
-                ErrorKind::InvalidValue,
-                "-m argument must contain exactly one ':'",
-            )
-            .with_cmd(cmd);
-            return Err(e);
+            let dir = match fname.parent() {
+                Some(d) if d != Path::new("") => d,
+                _ => Path::new("."),
+            };
+            OpenOptions::new()
+                .read(true)
+                .write(true)
+                .custom_flags(libc::O_TMPFILE)
+                .mode(0o644)
+                .open(dir)
         }
-        let startop = fields[0].parse::<u64>().map_err(|_| {
-            clap::Error::raw(
-                ErrorKind::InvalidValue,
-                "-m arguments must be numeric",
-            )
-        })?;
-        let endop = fields[1].parse::<u64>().map_err(|_| {
-            clap::Error::raw(
-                ErrorKind::InvalidValue,
-                "-m arguments must be numeric",
-            )
-        })?;
-        Ok((startop, endop))
-    }
-}
-
-#[derive(Debug, Parser)]
-#[command(author, version, about, long_about = None)]
-struct Cli {
-    /// Beginning operation number
-    #[arg(short = 'b', default_value_t = NonZeroU64::new(1u64).unwrap())]
-    opnum: NonZeroU64,
 
-    /// Config file path
-    #[arg(short = 'f', value_name = "PATH")]
-    config: Option<PathBuf>,
+        /// Give an anonymous file created by `open_tmpfile` a name.
+        fn link_tmpfile(file: &File, fname: &Path) -> nix::Result<()> {
+            use nix::{fcntl::AtFlags, unistd::linkat};
 
-    /// Monitor specified byte range
-    #[arg(short = 'm', value_name = "FROM:TO", value_parser = MonitorParser{})]
-    monitor: Option<(u64, u64)>,
+            // Linking via /proc doesn't require CAP_DAC_READ_SEARCH, unlike
+            // AT_EMPTY_PATH.
+            let procpath = format!("/proc/self/fd/{}", file.as_raw_fd());
+            linkat(
+                None,
+                Path::new(&procpath),
+                None,
+                fname,
+                AtFlags::AT_SYMLINK_FOLLOW
+            )
+        }
 
-    /// Total number of operations to do [default infinity]
-    #[arg(short = 'N')]
-    numops: Option<u64>,
+        /// Open a new file description for the same file as `file`, without
+        /// looking up its name.
+        fn reopen_procfd(file: &File) -> io::Result<File> {
+            let procpath = format!("/proc/self/fd/{}", file.as_raw_fd());
+            OpenOptions::new().read(true).write(true).open(procpath)
+        }
 
-    /// Save artifacts to this directory [default ./]
-    #[arg(short = 'P', value_name = "DIRPATH")]
-    artifacts_dir: Option<PathBuf>,
+        /// Reopen `fname` relative to its parent directory using openat2 with
+        /// restrictive RESOLVE flags.
+        fn reopen_openat2(fname: &Path) -> io::Result<File> {
+            use std::os::unix::{ffi::OsStrExt, io::FromRawFd};
 
-    /// Seed for RNG
-    #[arg(short = 'S')]
-    seed: Option<u64>,
+            let dir = match fname.parent() {
+                Some(d) if d != Path::new("") => d,
+                _ => Path::new("."),
+            };
+            let dirfile = File::open(dir)?;
+            let base = std::ffi::CString::new(
+                fname.file_name().unwrap().as_bytes()
+            ).unwrap();
+            // Safe because open_how is plain old data.
+            let mut how: libc::open_how = unsafe { mem::zeroed() };
+            how.flags = (libc::O_RDWR | libc::O_CLOEXEC) as u64;
+            how.resolve = libc::RESOLVE_BENEATH | libc::RESOLVE_NO_MAGICLINKS;
+            let fd = unsafe {
+                libc::syscall(
+                    libc::SYS_openat2,
+                    dirfile.as_raw_fd(),
+                    base.as_ptr(),
+                    &how as *const libc::open_how,
+                    mem::size_of::<libc::open_how>(),
+                )
+            };
+            if fd < 0 {
+                Err(io::Error::last_os_error())
+            } else {
+                // Safe because the kernel just gave us this file descriptor
+                Ok(unsafe { File::from_raw_fd(fd as RawFd) })
+            }
+        }
+    } else {
+        fn reopen_procfd(_file: &File) -> io::Result<File> {
+            eprintln!("Reopening via /proc is not supported on this platform.");
+            process::exit(1);
+        }
 
-    /// File name to operate on
-    fname: PathBuf,
+        fn reopen_openat2(_fname: &Path) -> io::Result<File> {
+            eprintln!("openat2 is not supported on this platform.");
+            process::exit(1);
+        }
 
-    /// Inject an error on step N
-    // This option mainly exists just for the sake of the integration tests.
-    #[arg(long = "inject", hide = true, value_name = "N")]
-    inject: Option<u64>,
+        fn open_tmpfile(_fname: &Path) -> io::Result<File> {
+            eprintln!("O_TMPFILE is not supported on this platform.");
+            process::exit(1);
+        }
 
-    #[command(flatten)]
-    verbose: Verbosity<WarnLevel>,
+        fn link_tmpfile(_file: &File, _fname: &Path) -> nix::Result<()> {
+            eprintln!("O_TMPFILE is not supported on this platform.");
+            process::exit(1);
+        }
+    }
 }
 
-const fn default_flen() -> u64 {
-    256 * 1024
+cfg_if! {
+    if #[cfg(any(target_os = "freebsd", target_os = "linux"))] {
+        /// copy_file_range between two files, retrying short copies
+        fn copy_file_range_all(
+            infile: &File,
+            inoff: u64,
+            outfile: &File,
+            outoff: u64,
+            mut len: usize
+        ) -> nix::Result<()> {
+            let mut inoff: i64 = inoff.try_into().unwrap();
+            let mut outoff: i64 = outoff.try_into().unwrap();
+            while len > 0 {
+                let r = eintr(|| nix::fcntl::copy_file_range(
+                    infile.as_fd(),
+                    Some(&mut inoff),
+                    outfile.as_fd(),
+                    Some(&mut outoff),
+                    len,
+                ))?;
+                if r == 0 {
+                    return Err(nix::Error::EIO);
+                }
+                len -= r;
+            }
+            Ok(())
+        }
+    } else {
+        fn copy_file_range_all(
+            _infile: &File,
+            _inoff: u64,
+            _outfile: &File,
+            _outoff: u64,
+            _len: usize
+        ) -> nix::Result<()> {
+            eprintln!("copy_file_range is not supported on this platform.");
+            process::exit(1);
+        }
+    }
 }
 
-/// Configuration file format, as toml
-#[derive(Debug, Default, Deserialize)]
-struct Config {
-    /// Maximum file size
-    // NB: could be u64, but the C-based FSX only works with 32-bit file sizes
-    #[serde(default)]
-    flen: Option<u32>,
+cfg_if! {
+    if #[cfg(any(target_os = "android", target_os = "linux"))] {
+        /// sendfile from one regular file to another, retrying short
+        /// transfers
+        fn sendfile_all(
+            infile: &File,
+            inoff: u64,
+            outfile: &mut File,
+            outoff: u64,
+            mut len: usize
+        ) -> nix::Result<()> {
+            use nix::sys::sendfile::sendfile64;
 
-    /// Disable verifications of file size
-    #[serde(default)]
-    nosizechecks: bool,
+            let mut inoff: i64 = inoff.try_into().unwrap();
+            outfile.seek(SeekFrom::Start(outoff)).unwrap();
+            while len > 0 {
+                let r = eintr(|| sendfile64(
+                    outfile.as_fd(),
+                    infile.as_fd(),
+                    Some(&mut inoff),
+                    len
+                ))?;
+                if r == 0 {
+                    return Err(nix::Error::EIO);
+                }
+                len -= r;
+            }
+            Ok(())
+        }
 
-    /// Block mode: never change the file's size.
-    #[serde(default)]
-    blockmode: bool,
+        /// Clone a range of one file into another with FICLONERANGE
+        fn clone_range(
+            infile: &File,
+            inoff: u64,
+            outfile: &File,
+            outoff: u64,
+            len: u64
+        ) -> nix::Result<()> {
+            nix::ioctl_write_ptr! {
+                /// Share part of one file's storage with another file.
+                ficlonerange, 0x94, 13, libc::file_clone_range
+            }
 
-    /// Disable msync after mapwrite
-    #[serde(default)]
-    nomsyncafterwrite: bool,
+            let fcr = libc::file_clone_range {
+                src_fd: infile.as_raw_fd().into(),
+                src_offset: inoff,
+                src_length: len,
+                dest_offset: outoff,
+            };
+            // Safe because fcr is fully initialized
+            unsafe { ficlonerange(outfile.as_raw_fd(), &fcr) }.map(drop)
+        }
 
-    /// Specifies size distribution for all operations
-    #[serde(default)]
-    opsize: Opsize,
+        /// Make `outfile` share all of `infile`'s storage with FICLONE
+        fn clone_file(infile: &File, outfile: &File) -> nix::Result<()> {
+            nix::ioctl_write_int! {
+                /// Share one file's entire storage with another file.
+                ficlone, 0x94, 9
+            }
 
-    /// Specifies relative statistical weights of all operations
-    #[serde(default)]
-    weights: Weights,
-}
+            // Safe because the argument is a file descriptor, not a pointer
+            unsafe {
+                ficlone(
+                    outfile.as_raw_fd(),
+                    infile.as_raw_fd() as nix::sys::ioctl::ioctl_param_type
+                )
+            }.map(drop)
+        }
 
-impl Config {
-    fn load(path: &PathBuf) -> Self {
-        let r = match fs::read_to_string(path) {
-            Ok(s) => toml::from_str(&s),
-            Err(e) => {
-                eprintln!("Error reading config file: {e}");
-                process::exit(1);
+        /// Count the extents in the first `len` bytes of a file with FIEMAP
+        fn fiemap_extents(f: &File, len: u64) -> nix::Result<u32> {
+            /// struct fiemap, with no room for the extents themselves
+            #[repr(C)]
+            struct Fiemap {
+                fm_start:          u64,
+                fm_length:         u64,
+                fm_flags:          u32,
+                fm_mapped_extents: u32,
+                fm_extent_count:   u32,
+                fm_reserved:       u32,
             }
-        };
-        match r {
-            Ok(c) => c,
-            Err(e) => {
-                eprintln!("Error reading config file: {e}");
-                process::exit(1);
+            nix::ioctl_readwrite! {
+                /// Map a file's logical extents to physical ones.
+                fs_ioc_fiemap, b'f', 11, Fiemap
             }
-        }
-    }
 
-    /// Validate compatibility with these CLI arguments
-    fn validate(&self, cli: &Cli) {
-        if self.flen == Some(0) {
-            eprintln!("error: file length must be greater than zero");
-            process::exit(2);
-        }
-        if self.opsize.max == 0 {
-            eprintln!(
-                "error: Maximum operation size must be greater than zero"
-            );
-            process::exit(2);
+            let mut fm = Fiemap {
+                fm_start:          0,
+                fm_length:         len,
+                fm_flags:          0,
+                fm_mapped_extents: 0,
+                fm_extent_count:   0,
+                fm_reserved:       0,
+            };
+            // Safe because with fm_extent_count = 0, the kernel only counts
+            // the extents, without storing them after fm.
+            unsafe { fs_ioc_fiemap(f.as_raw_fd(), &mut fm) }?;
+            Ok(fm.fm_mapped_extents)
         }
-        if self.opsize.min > self.opsize.max {
-            eprintln!(
-                "error: Minimum operation size must be no greater than maximum"
-            );
-            process::exit(2);
+    } else {
+        fn sendfile_all(
+            _infile: &File,
+            _inoff: u64,
+            _outfile: &mut File,
+            _outoff: u64,
+            _len: usize
+        ) -> nix::Result<()> {
+            eprintln!("sendfile between files is not supported on this \
+                      platform.");
+            process::exit(1);
         }
-        let align = self.opsize.align.map(usize::from).unwrap_or(1);
-        if align > self.opsize.max {
-            eprintln!(
-                "error: operation alignment must be no greater than maximum \
-                 operation size"
-            );
-            process::exit(2);
+
+        fn clone_range(
+            _infile: &File,
+            _inoff: u64,
+            _outfile: &File,
+            _outoff: u64,
+            _len: u64
+        ) -> nix::Result<()> {
+            eprintln!("FICLONERANGE is not supported on this platform.");
+            process::exit(1);
         }
-        if self.blockmode && self.weights.close_open > 0.0 {
-            eprintln!("error: cannot use close_open with blockmode");
-            process::exit(2);
+
+        fn clone_file(_infile: &File, _outfile: &File) -> nix::Result<()> {
+            eprintln!("FICLONE is not supported on this platform.");
+            process::exit(1);
         }
-        if self.blockmode && self.weights.truncate > 0.0 {
-            eprintln!("error: cannot use truncate with blockmode");
-            process::exit(2);
+
+        fn fiemap_extents(_f: &File, _len: u64) -> nix::Result<u32> {
+            eprintln!("FIEMAP is not supported on this platform.");
+            process::exit(1);
         }
-        if self.blockmode && self.weights.posix_fallocate > 0.0 {
-            eprintln!("error: cannot use posix_fallocate with blockmode");
-            process::exit(2);
+    }
+}
+
+cfg_if! {
+    if #[cfg(any(
+        target_os = "android",
+        target_os = "dragonfly",
+        target_os = "freebsd",
+        target_os = "linux",
+        target_os = "netbsd"
+    ))] {
+        /// Open a file for reading, bypassing the cache
+        fn open_direct(path: &Path) -> io::Result<File> {
+            use std::os::unix::fs::OpenOptionsExt;
+
+            OpenOptions::new()
+                .read(true)
+                .custom_flags(libc::O_DIRECT)
+                .open(path)
         }
-        if self.blockmode && cli.artifacts_dir.is_none() {
-            eprintln!("error: must specify -P when using blockmode");
-            process::exit(2);
+    } else {
+        fn open_direct(_path: &Path) -> io::Result<File> {
+            eprintln!("O_DIRECT is not supported on this platform.");
+            process::exit(1);
         }
     }
 }
 
-const fn default_opsize_max() -> usize {
-    65536
+cfg_if! {
+    if #[cfg(any(
+        target_os = "android",
+        target_os = "freebsd",
+        target_os = "linux",
+    ))] {
+        /// Return the offsets of the next hole and the next data at or after
+        /// `offset`.  No next data is represented as None.
+        fn seek_hole_data(f: &File, offset: u64) -> nix::Result<(u64, Option<u64>)> {
+            use nix::unistd::{lseek, Whence};
+
+            let offset = offset as i64;
+            let hole = lseek(f.as_raw_fd(), offset, Whence::SeekHole)?;
+            let data = match lseek(f.as_raw_fd(), offset, Whence::SeekData) {
+                Ok(d) => Some(d as u64),
+                Err(nix::Error::ENXIO) => None,
+                Err(e) => return Err(e)
+            };
+            Ok((hole as u64, data))
+        }
+    } else {
+        fn seek_hole_data(_f: &File, _offset: u64) -> nix::Result<(u64, Option<u64>)> {
+            eprintln!("SEEK_HOLE is not supported on this platform.");
+            process::exit(1);
+        }
+    }
 }
 
-#[derive(Clone, Copy, Debug, Deserialize)]
-struct Opsize {
-    /// Minium size for operations
-    #[serde(default)]
-    min:   usize,
-    /// Maximum size for operations
-    #[serde(default = "default_opsize_max")]
-    max:   usize,
-    /// Alignment in bytes for all operations
-    align: Option<NonZeroUsize>,
+/// File attributes that should be identical no matter how they're retrieved
+#[derive(Debug, Eq, PartialEq)]
+struct FileAttrs {
+    size:   u64,
+    blocks: u64,
+    /// mtime as (seconds, nanoseconds)
+    mtime:  (i64, i64),
+    /// ctime as (seconds, nanoseconds)
+    ctime:  (i64, i64),
 }
 
-impl Default for Opsize {
-    fn default() -> Self {
-        Opsize {
-            min:   0,
-            max:   65536,
-            align: NonZeroUsize::new(1),
+impl From<&fs::Metadata> for FileAttrs {
+    fn from(md: &fs::Metadata) -> Self {
+        FileAttrs {
+            size:   md.size(),
+            blocks: md.blocks(),
+            mtime:  (md.mtime(), md.mtime_nsec()),
+            ctime:  (md.ctime(), md.ctime_nsec()),
         }
     }
 }
 
-const fn default_weight() -> f64 {
-    10.0
-}
-
-#[derive(Debug, Deserialize)]
-struct Weights {
-    #[serde(default)]
-    close_open:      f64,
-    #[serde(default)]
-    invalidate:      f64,
-    #[serde(default = "default_weight")]
-    mapread:         f64,
-    #[serde(default = "default_weight")]
-    mapwrite:        f64,
-    #[serde(default = "default_weight")]
-    read:            f64,
-    #[serde(default = "default_weight")]
-    write:           f64,
-    #[serde(default = "default_weight")]
-    truncate:        f64,
-    #[serde(default)]
-    fsync:           f64,
-    #[serde(default)]
-    fdatasync:       f64,
-    #[serde(default)]
-    posix_fallocate: f64,
-    #[serde(default)]
-    punch_hole:      f64,
-    #[serde(default)]
-    sendfile:        f64,
-    #[serde(default)]
-    posix_fadvise:   f64,
-    #[serde(default)]
-    copy_file_range: f64,
+cfg_if! {
+    if #[cfg(all(target_os = "linux", target_env = "gnu"))] {
+        /// Retrieve a file's attributes with statx(2)
+        fn statx_attrs(f: &File) -> io::Result<Option<FileAttrs>> {
+            let mut stx = mem::MaybeUninit::<libc::statx>::uninit();
+            // Safe because the kernel initializes stx on success
+            let r = unsafe {
+                libc::statx(
+                    f.as_raw_fd(),
+                    c"".as_ptr(),
+                    libc::AT_EMPTY_PATH,
+                    libc::STATX_BASIC_STATS,
+                    stx.as_mut_ptr()
+                )
+            };
+            if r != 0 {
+                let e = io::Error::last_os_error();
+                return if e.raw_os_error() == Some(libc::ENOSYS) {
+                    Ok(None)
+                } else {
+                    Err(e)
+                };
+            }
+            let stx = unsafe { stx.assume_init() };
+            Ok(Some(FileAttrs {
+                size:   stx.stx_size,
+                blocks: stx.stx_blocks,
+                mtime:  (stx.stx_mtime.tv_sec, stx.stx_mtime.tv_nsec.into()),
+                ctime:  (stx.stx_ctime.tv_sec, stx.stx_ctime.tv_nsec.into()),
+            }))
+        }
+    } else {
+        fn statx_attrs(_f: &File) -> io::Result<Option<FileAttrs>> {
+            Ok(None)
+        }
+    }
 }
 
-impl Default for Weights {
-    fn default() -> Self {
-        Weights {
-            close_open:      0.0,
-            invalidate:      0.0,
-            mapread:         1.0,
-            mapwrite:        1.0,
-            read:            1.0,
-            write:           1.0,
-            truncate:        1.0,
-            fsync:           0.0,
-            fdatasync:       0.0,
-            posix_fallocate: 0.0,
-            punch_hole:      0.0,
-            sendfile:        0.0,
-            posix_fadvise:   0.0,
-            copy_file_range: 0.0,
+cfg_if! {
+    if #[cfg(have_fspacectl)] {
+        fn punch_hole(f: &File, offset: u64, len: u64) -> nix::Result<()> {
+            nix::fcntl::fspacectl_all(f.as_raw_fd(), offset as i64, len as i64)
+        }
+    } else if #[cfg(any(
+            target_os = "android",
+            target_os = "emscripten",
+            target_os = "fuchsia",
+            target_os = "linux",
+        ))] {
+        fn punch_hole(f: &File, offset: u64, len: u64) -> nix::Result<()> {
+            use nix::fcntl::FallocateFlags;
+
+            nix::fcntl::fallocate(
+                f.as_raw_fd(),
+                FallocateFlags::FALLOC_FL_PUNCH_HOLE |
+                    FallocateFlags::FALLOC_FL_KEEP_SIZE,
+                offset as i64,
+                len as i64
+            )
+        }
+    } else {
+        fn punch_hole(_f: &File, _offset: u64, _len: u64) -> nix::Result<()> {
+            eprintln!("hole punching is not supported on this platform.");
+            process::exit(1);
         }
     }
 }
 
-#[derive(Clone, Copy, Debug, Eq, PartialEq)]
-enum Op {
-    CloseOpen,
-    Read,
-    Write,
-    MapRead,
-    Truncate,
-    Invalidate,
-    MapWrite,
-    Fsync,
-    Fdatasync,
-    PosixFallocate,
-    PunchHole,
-    Sendfile,
-    PosixFadvise,
-    CopyFileRange,
+/// An error from a system call
+trait SysError: fmt::Display {
+    fn errno(&self) -> i32;
+    fn from_errno(errno: i32) -> Self;
 }
 
-impl Op {
-    fn make_weighted_index<I>(weights: I) -> WeightedIndex<f64>
-    where
-        I: IntoIterator<Item = f64> + ExactSizeIterator,
-    {
-        assert_eq!(weights.len(), 14);
-        WeightedIndex::new(weights).unwrap()
+impl SysError for io::Error {
+    fn errno(&self) -> i32 {
+        self.raw_os_error().unwrap_or(0)
+    }
+
+    fn from_errno(errno: i32) -> Self {
+        io::Error::from_raw_os_error(errno)
     }
 }
 
-impl fmt::Display for Op {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
-        match self {
-            Op::CloseOpen => "close/open".fmt(f),
-            Op::Read => "read".fmt(f),
-            Op::Write => "write".fmt(f),
-            Op::MapRead => "mapread".fmt(f),
-            Op::Truncate => "truncate".fmt(f),
-            Op::Invalidate => "invalidate".fmt(f),
-            Op::MapWrite => "mapwrite".fmt(f),
-            Op::Fsync => "fsync".fmt(f),
-            Op::Fdatasync => "fdatasync".fmt(f),
-            Op::PosixFallocate => "posix_fallocate".fmt(f),
-            Op::PunchHole => "punch_hole".fmt(f),
-            Op::Sendfile => "sendfile".fmt(f),
-            Op::PosixFadvise => "posix_fadvise".fmt(f),
-            Op::CopyFileRange => "copy_file_range".fmt(f),
-        }
+impl SysError for nix::Error {
+    fn errno(&self) -> i32 {
+        *self as i32
+    }
+
+    fn from_errno(errno: i32) -> Self {
+        nix::Error::from_raw(errno)
     }
 }
 
-impl Distribution<Op> for WeightedIndex<f64> {
-    fn sample<R: Rng + ?Sized>(&self, rng: &mut R) -> Op {
-        match self.sample(rng) {
-            0usize => Op::CloseOpen,
-            1 => Op::Read,
-            2 => Op::Write,
-            3 => Op::MapRead,
-            4 => Op::Truncate,
-            5 => Op::Invalidate,
-            6 => Op::MapWrite,
-            7 => Op::Fsync,
-            8 => Op::Fdatasync,
-            9 => Op::PosixFallocate,
-            10 => Op::PunchHole,
-            11 => Op::Sendfile,
-            12 => Op::PosixFadvise,
-            13 => Op::CopyFileRange,
-            _ => panic!("WeightedIndex was generated with too many keys"),
+/// Make a system call on the test file, recording it with --strace.  Used
+/// like `sys!(self, "pread"(fd, len, offset), self.file.read_at(..))`.
+macro_rules! sys {
+    ($self:ident, $name:literal($($arg:expr),*), $call:expr) => {{
+        #[cfg(feature = "strace")]
+        let args = $self
+            .strace
+            .as_ref()
+            .map(|_| [$($arg.to_string()),*].join(", "));
+        // Without strace, the arguments are never evaluated
+        #[cfg(not(feature = "strace"))]
+        let _ = || { $(let _ = &$arg;)* };
+        let r = $call;
+        #[cfg(feature = "strace")]
+        if let (Some(strace), Some(args)) = (&*$self.strace, args) {
+            strace.record($self.steps, $name, &args, &r);
+        }
+        r
+    }};
+}
+
+/// Retry an operation for as long as it fails with EINTR
+fn eintr<T, E: SysError>(mut f: impl FnMut() -> Result<T, E>) -> Result<T, E> {
+    loop {
+        match f() {
+            Err(e) if e.errno() == libc::EINTR => continue,
+            r => return r,
         }
     }
 }
 
-#[derive(Clone, Copy)]
-enum LogEntry {
-    Skip(Op),
-    CloseOpen,
-    // offset, size
-    Read(u64, usize),
-    // old file len, offset, size
-    Write(u64, u64, usize),
-    // offset, size
-    MapRead(u64, usize),
-    // old file len, new file len
-    Truncate(u64, u64),
-    Invalidate,
-    // old file len, offset, size
-    MapWrite(u64, u64, usize),
-    Fsync,
-    Fdatasync,
-    // offset, len
-    PosixFallocate(u64, u64),
-    // offset, len
-    PunchHole(u64, u64),
-    // offset, len
-    Sendfile(u64, usize),
-    // advice, offset, len
+/// Interrupt the calling thread with SIGALRM every `interval` microseconds
+fn start_signal_storm(interval: NonZeroU64) {
+    extern "C" fn noop(_: libc::c_int) {}
+
+    // Deliberately omit SA_RESTART, so system calls fail with EINTR.
+    let sa = SigAction::new(
+        SigHandler::Handler(noop),
+        SaFlags::empty(),
+        SigSet::empty(),
+    );
+    // Safe because the handler does nothing at all
+    unsafe { sigaction(Signal::SIGALRM, &sa) }.unwrap();
+    let target = pthread_self();
+    let interval = Duration::from_micros(interval.get());
+    thread::spawn(move || loop {
+        thread::sleep(interval);
+        pthread_kill(target, Signal::SIGALRM).unwrap();
+    });
+}
+
+/// Set while the run is paused by SIGUSR2
+static PAUSED: AtomicBool = AtomicBool::new(false);
+
+/// Pause the run when SIGUSR2 arrives, and resume it when another does
+fn catch_pause_signal() {
+    extern "C" fn toggle(_: libc::c_int) {
+        PAUSED.fetch_xor(true, Ordering::SeqCst);
+    }
+
+    let sa = SigAction::new(
+        SigHandler::Handler(toggle),
+        SaFlags::SA_RESTART,
+        SigSet::empty(),
+    );
+    // Safe because the handler only touches an atomic
+    unsafe { sigaction(Signal::SIGUSR2, &sa) }.unwrap();
+}
+
+/// Set by SIGUSR1 or SIGINFO, for the status thread
+static STATUS: AtomicBool = AtomicBool::new(false);
+
+/// Set by the status thread, for the main thread to print the latency
+/// histograms
+static DUMP_LATENCIES: AtomicBool = AtomicBool::new(false);
+
+/// Print the run's status whenever SIGUSR1 or SIGINFO arrives, from a thread
+/// of its own so it works even while an operation is blocked.
+fn start_status_thread(seed: u64) {
+    extern "C" fn request(_: libc::c_int) {
+        STATUS.store(true, Ordering::SeqCst);
+    }
+
+    let sa = SigAction::new(
+        SigHandler::Handler(request),
+        SaFlags::SA_RESTART,
+        SigSet::empty(),
+    );
+    // Safe because the handler only touches atomics
+    unsafe { sigaction(Signal::SIGUSR1, &sa) }.unwrap();
     #[cfg(any(
-        target_os = "linux",
-        target_os = "android",
-        target_os = "freebsd"
+        target_os = "dragonfly",
+        target_os = "freebsd",
+        target_os = "ios",
+        target_os = "macos",
+        target_os = "netbsd",
+        target_os = "openbsd"
     ))]
-    PosixFadvise(PosixFadviseAdvice, u64, u64),
-    // old file len, in_offset, out_offset, len
-    CopyFileRange(u64, u64, u64, usize),
+    unsafe { sigaction(Signal::SIGINFO, &sa) }.unwrap();
+
+    let start = Instant::now();
+    thread::spawn(move || loop {
+        thread::sleep(Duration::from_millis(100));
+        if !STATUS.swap(false, Ordering::Relaxed) {
+            continue;
+        }
+        let step = PROGRESS.load(Ordering::Relaxed);
+        let what = match Op::ALL.get(CURRENT_OP.load(Ordering::Relaxed)) {
+            Some(op) => format!("step {step} ({op})"),
+            None => format!("after step {step}"),
+        };
+        println!(
+            "status: {what}, seed {seed}, {:.1}s elapsed, file size {:#x}, \
+             {:#x} bytes read, {:#x} bytes written, {} skipped",
+            start.elapsed().as_secs_f64(),
+            STATUS_COUNTERS.file_size.load(Ordering::Relaxed),
+            STATUS_COUNTERS.bytes_read.load(Ordering::Relaxed),
+            STATUS_COUNTERS.bytes_written.load(Ordering::Relaxed),
+            STATUS_COUNTERS.skips.load(Ordering::Relaxed)
+        );
+        DUMP_LATENCIES.store(true, Ordering::Relaxed);
+    });
 }
 
-struct Exerciser {
-    align:             usize,
-    artifacts_dir:     Option<PathBuf>,
-    blockmode:         bool,
-    /// Current file size
-    file_size:         u64,
-    flen:              u64,
-    fname:             PathBuf,
-    /// Width for printing fields containing file offsets
-    fwidth:            usize,
-    /// Inject an error on this step
-    inject:            Option<u64>,
-    // What the file ought to contain
-    good_buf:          Vec<u8>,
-    /// Monitor these byte ranges in extra detail.
-    monitor:           Option<(u64, u64)>,
-    nomsyncafterwrite: bool,
-    nosizechecks:      bool,
-    numops:            Option<u64>,
-    // Records most recent operations for future dumping
-    oplog:             AllocRingBuffer<LogEntry>,
-    opsize:            Opsize,
-    seed:              u64,
-    // 0-indexed operation number to begin real transfers.
-    simulatedopcount:  u64,
-    /// Width for printing fields containing operation sizes
-    swidth:            usize,
-    /// Width for printing the step number field
-    stepwidth:         usize,
-    // File's original data
-    original_buf:      Vec<u8>,
-    // Use XorShiftRng because it's deterministic and seedable
-    rng:               XorShiftRng,
-    // Number of steps completed so far
-    steps:             u64,
-    file:              File,
-    wi:                WeightedIndex<f64>,
+/// Describes the memory mapping that fsx is currently accessing, so a fault
+/// handler can record where a fault happened.
+struct MapFault {
+    op:        Op,
+    base:      usize,
+    /// File offset of the start of the mapping
+    offset:    u64,
+    len:       usize,
+    page_size: usize,
 }
 
-impl Exerciser {
-    cfg_if! {
-        if #[cfg(any(target_os = "macos", target_os = "dragonfly", target_os = "ios"))] {
-            fn dosendfile(&mut self, buf: &mut [u8], offset: u64, size: usize) {
-                use std::{io::Read, os::fd::BorrowedFd, os::unix::net::UnixStream, thread};
-                use nix::sys::sendfile::sendfile;
+impl MapFault {
+    /// Run `f`, recording a SIGBUS or SIGSEGV within the mapping for
+    /// `Exerciser::check_map_fault` to report.
+    fn guard<T>(&self, f: impl FnOnce() -> T) -> T {
+        MAP_FAULT.with(|mf| mf.set(self));
+        let r = f();
+        MAP_FAULT.with(|mf| mf.set(ptr::null()));
+        r
+    }
+}
 
-                let (mut rd, wr) = UnixStream::pair().unwrap();
-                // Safe because we unconditionally join the thread below.
-                let (ffd, sfd) = unsafe {(
-                    BorrowedFd::borrow_raw(self.file.as_raw_fd()),
-                    BorrowedFd::borrow_raw(wr.as_raw_fd()),
-                )};
-
-                let jh = thread::spawn(move || {
-                    sendfile(
-                        ffd,
-                        sfd,
-                        offset as i64,
-                        Some(size as _),
-                        None,
-                        None,
-                    )
-                });
-                rd.read_exact(buf).unwrap();
-                let (res, bytes_written) = jh.join().unwrap();
-                if res.is_err() {
-                    error!("sendfile returned {:?}", res);
-                    self.fail();
-                }
-                if bytes_written != size as i64 {
-                    error!("Short read with sendfile: {:#x} bytes instead of {:#x}",
-                           bytes_written, size);
-                    self.fail();
-                }
-            }
-        } else if #[cfg(target_os = "freebsd")] {
-            fn dosendfile(&mut self, buf: &mut [u8], offset: u64, size: usize) {
-                use std::{io::Read, os::fd::BorrowedFd, os::unix::net::UnixStream, thread};
-                use nix::sys::sendfile::{sendfile, SfFlags};
+thread_local! {
+    // Faults are delivered to the faulting thread, so this can be thread-local
+    static MAP_FAULT: Cell<*const MapFault> = const { Cell::new(ptr::null()) };
+}
 
-                let (mut rd, wr) = UnixStream::pair().unwrap();
-                // Safe because we unconditionally join the thread below.
-                let (ffd, sfd) = unsafe {(
-                    BorrowedFd::borrow_raw(self.file.as_raw_fd()),
-                    BorrowedFd::borrow_raw(wr.as_raw_fd()),
-                )};
-
-                let jh = thread::spawn(move || {
-                    sendfile(
-                        ffd,
-                        sfd,
-                        offset as i64,
-                        Some(size),
-                        None,
-                        None,
-                        SfFlags::empty(),
-                        0
-                    )
-                });
-                rd.read_exact(buf).unwrap();
-                let (res, bytes_written) = jh.join().unwrap();
-                if res.is_err() {
-                    error!("sendfile returned {:?}", res);
-                    self.fail();
-                }
-                if bytes_written != size as i64 {
-                    error!("Short read with sendfile: {:#x} bytes instead of {:#x}",
-                           bytes_written, size);
-                    self.fail();
-                }
+/// The signal caught while accessing a mapped file, or 0 if none
+static MAP_FAULT_SIGNAL: AtomicI32 = AtomicI32::new(0);
+
+/// The operation that was accessing the mapping, as an index into `Op::ALL`
+static MAP_FAULT_OP: AtomicUsize = AtomicUsize::new(0);
+
+/// The file offset where the fault happened
+static MAP_FAULT_OFFSET: AtomicU64 = AtomicU64::new(0);
+
+/// Handle SIGBUS and SIGSEGV raised while accessing a mapped file by recording
+/// the fault, and replacing the faulting page with anonymous memory so the
+/// access can complete.  The main thread reports the failure after the
+/// operation.
+extern "C" fn map_fault(
+    signo: libc::c_int,
+    info: *mut libc::siginfo_t,
+    _: *mut c_void,
+) {
+    let mf = MAP_FAULT.with(Cell::get);
+    let addr = unsafe { (*info).si_addr() } as usize;
+    // Safe because MapFault::guard clears MAP_FAULT before the MapFault is
+    // dropped
+    let mf = unsafe { mf.as_ref() };
+    let Some(mf) = mf.filter(|mf| (mf.base..mf.base + mf.len).contains(&addr))
+    else {
+        // Not ours.  Restore the default action and let it crash.
+        let sa = SigAction::new(
+            SigHandler::SigDfl,
+            SaFlags::empty(),
+            SigSet::empty(),
+        );
+        let sig = Signal::try_from(signo).unwrap();
+        unsafe { sigaction(sig, &sa) }.unwrap();
+        return;
+    };
+    let page = addr & !(mf.page_size - 1);
+    // Safe because the page belongs to the mapping that fsx is accessing,
+    // which the operation will unmap or never use again.
+    let p = unsafe {
+        libc::mmap(
+            page as *mut c_void,
+            mf.page_size,
+            libc::PROT_READ | libc::PROT_WRITE,
+            libc::MAP_FIXED | libc::MAP_PRIVATE | libc::MAP_ANON,
+            -1,
+            0,
+        )
+    };
+    if p == libc::MAP_FAILED {
+        let sa = SigAction::new(
+            SigHandler::SigDfl,
+            SaFlags::empty(),
+            SigSet::empty(),
+        );
+        let sig = Signal::try_from(signo).unwrap();
+        unsafe { sigaction(sig, &sa) }.unwrap();
+        return;
+    }
+    // Record only the first fault of the operation
+    if MAP_FAULT_SIGNAL
+        .compare_exchange(0, signo, Ordering::Relaxed, Ordering::Relaxed)
+        .is_ok()
+    {
+        MAP_FAULT_OP.store(mf.op as usize, Ordering::Relaxed);
+        MAP_FAULT_OFFSET
+            .store(mf.offset + (addr - mf.base) as u64, Ordering::Relaxed);
+    }
+}
+
+/// Whether a fault has been caught while accessing a mapped file
+fn map_faulted() -> bool {
+    MAP_FAULT_SIGNAL.load(Ordering::Relaxed) != 0
+}
+
+/// Catch faults while accessing mapped files.
+fn catch_map_faults() {
+    let sa = SigAction::new(
+        SigHandler::SigAction(map_fault),
+        SaFlags::SA_SIGINFO,
+        SigSet::empty(),
+    );
+    // Safe because the handler only does anything when MAP_FAULT is set, and
+    // then only within the mapping it describes.
+    unsafe {
+        sigaction(Signal::SIGBUS, &sa).unwrap();
+        sigaction(Signal::SIGSEGV, &sa).unwrap();
+    }
+}
+
+/// Held by whichever thread is reporting a failure
+static FAILURE: Mutex<()> = Mutex::new(());
+
+/// The most recently started step, for the watchdog
+static PROGRESS: AtomicU64 = AtomicU64::new(0);
+
+/// The operation in progress, as an index into `Op::ALL`, or `usize::MAX`
+/// between operations
+static CURRENT_OP: AtomicUsize = AtomicUsize::new(usize::MAX);
+
+/// The main thread's counters, published after every step for the status
+/// thread
+struct StatusCounters {
+    file_size:     AtomicU64,
+    bytes_read:    AtomicU64,
+    bytes_written: AtomicU64,
+    skips:         AtomicU64,
+}
+
+static STATUS_COUNTERS: StatusCounters = StatusCounters {
+    file_size:     AtomicU64::new(0),
+    bytes_read:    AtomicU64::new(0),
+    bytes_written: AtomicU64::new(0),
+    skips:         AtomicU64::new(0),
+};
+
+cfg_if! {
+    if #[cfg(target_os = "linux")] {
+        /// Get the kernel stack of the main thread, for hang diagnostics
+        fn kernel_stack() -> io::Result<String> {
+            fs::read_to_string("/proc/self/stack")
+        }
+    } else if #[cfg(target_os = "freebsd")] {
+        /// Get the kernel stacks of all threads, for hang diagnostics
+        fn kernel_stack() -> io::Result<String> {
+            let output = process::Command::new("procstat")
+                .arg("-kk")
+                .arg(process::id().to_string())
+                .output()?;
+            Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+        }
+    } else {
+        fn kernel_stack() -> io::Result<String> {
+            Err(io::Error::from_raw_os_error(libc::ENOTSUP))
+        }
+    }
+}
+
+cfg_if! {
+    if #[cfg(any(target_os = "android", target_os = "linux"))] {
+        /// Describe the mount containing `path`, from /proc/mounts
+        fn mount_info(path: &Path, _file: &File) -> io::Result<String> {
+            // /proc/mounts escapes whitespace and backslashes in octal
+            fn unescape(field: &str) -> String {
+                field
+                    .replace("\\040", " ")
+                    .replace("\\011", "\t")
+                    .replace("\\012", "\n")
+                    .replace("\\134", "\\")
             }
-        } else if #[cfg(any(target_os = "android", target_os = "linux"))] {
-            fn dosendfile(&mut self, buf: &mut [u8], offset: u64, size: usize) {
-                use std::{io::Read, os::fd::BorrowedFd, os::unix::net::UnixStream, thread};
-                use nix::sys::sendfile::sendfile64;
 
-                let (mut rd, wr) = UnixStream::pair().unwrap();
-                let mut ioffs = offset as i64;
-                // Safe because we unconditionally join the thread below.
-                let (ffd, sfd) = unsafe {(
-                    BorrowedFd::borrow_raw(self.file.as_raw_fd()),
-                    BorrowedFd::borrow_raw(wr.as_raw_fd()),
-                )};
+            let path = fs::canonicalize(path)?;
+            let mounts = fs::read_to_string("/proc/mounts")?;
+            mounts
+                .lines()
+                .filter_map(|line| {
+                    let fields = line.split(' ').collect::<Vec<_>>();
+                    let [dev, mnt, fstype, opts, ..] = fields[..] else {
+                        return None;
+                    };
+                    let mnt = unescape(mnt);
+                    path.starts_with(&mnt).then(|| {
+                        let desc = format!(
+                            "{} on {} type {} ({})",
+                            unescape(dev), mnt, fstype, opts
+                        );
+                        (mnt.len(), desc)
+                    })
+                })
+                // The last of the longest matches is the one that's visible
+                .max_by_key(|(len, _)| *len)
+                .map(|(_, desc)| desc)
+                .ok_or_else(|| io::Error::from_raw_os_error(libc::ENOENT))
+        }
+    } else if #[cfg(any(target_os = "freebsd", target_vendor = "apple"))] {
+        /// Describe the mount containing `file`
+        fn mount_info(_path: &Path, file: &File) -> io::Result<String> {
+            let sfs = nix::sys::statfs::fstatfs(file)?;
+            Ok(format!("type {}", sfs.filesystem_type_name()))
+        }
+    } else {
+        fn mount_info(_path: &Path, _file: &File) -> io::Result<String> {
+            Err(io::Error::from_raw_os_error(libc::ENOTSUP))
+        }
+    }
+}
 
-                let jh = thread::spawn(move || {
-                    sendfile64(sfd, ffd, Some(&mut ioffs), size)
-                });
-                rd.read_exact(buf).unwrap();
-                let res = jh.join().unwrap();
-                let bytes_written = match res {
-                    Ok(b) => b,
-                    Err(e) => {
-                        error!("sendfile returned {:?}", e);
-                        self.fail();
+/// Set by the watchdog, for the main thread to dump the operation log
+static WATCHDOG_DUMP: AtomicBool = AtomicBool::new(false);
+
+/// Set by the watchdog, for the main thread to dump the operation log, save
+/// the good file, and abort
+static WATCHDOG_ABORT: AtomicBool = AtomicBool::new(false);
+
+/// The main thread's most recent oplog lines, kept only while the watchdog
+/// runs, so it can dump them itself if the main thread is stuck for good
+static WATCHDOG_LOG: Mutex<Option<AllocRingBuffer<String>>> = Mutex::new(None);
+
+/// Act if any single step takes longer than its time limit.  `limits` holds
+/// the limit for each operation, indexed like `Op::ALL`, and `default` the
+/// limit for everything else.  The watchdog only reports; it asks the main
+/// thread to dump the log or abort, and aborts by itself, dumping its own copy
+/// of the last `oplog_size` log lines, only if the main thread doesn't respond
+/// within another time limit.
+fn start_watchdog(
+    limits: Vec<Option<OpTimeout>>,
+    default: Option<OpTimeout>,
+    kstack: bool,
+    oplog_size: usize,
+) {
+    *WATCHDOG_LOG.lock().unwrap() =
+        Some(AllocRingBuffer::with_capacity(oplog_size));
+    let shortest = limits
+        .iter()
+        .chain(std::iter::once(&default))
+        .flatten()
+        .map(|t| t.limit)
+        .fold(1.0, f64::min);
+    let poll = Duration::from_secs_f64(shortest) / 4;
+    thread::spawn(move || {
+        let mut step = PROGRESS.load(Ordering::Relaxed);
+        let mut started = Instant::now();
+        let mut fired = false;
+        let mut deadline = None;
+        loop {
+            thread::sleep(poll);
+            if deadline.is_some_and(|d| Instant::now() > d) {
+                error!("watchdog: no response from the main thread; aborting");
+                let _guard = FAILURE.lock();
+                if let Some(log) = &*WATCHDOG_LOG.lock().unwrap() {
+                    error!("LOG DUMP");
+                    for line in log.iter().flat_map(|l| l.lines()) {
+                        error!("{line}");
                     }
-                };
-                if bytes_written != size {
-                    error!("Short read with sendfile: {:#x} bytes instead of {:#x}",
-                           bytes_written, size);
-                    self.fail();
                 }
+                process::abort();
             }
-        } else {
-            fn dosendfile(&mut self, _buf: &mut [u8], _offset: u64, _size: usize) {
-                eprintln!("sendfile is not supported on this platform.");
-                process::exit(1);
+            let current = PROGRESS.load(Ordering::Relaxed);
+            if current != step {
+                step = current;
+                started = Instant::now();
+                fired = false;
+                continue;
+            }
+            let op = Op::ALL.get(CURRENT_OP.load(Ordering::Relaxed));
+            let Some(timeout) =
+                op.and_then(|op| limits[*op as usize]).or(default)
+            else {
+                continue;
+            };
+            let elapsed = started.elapsed();
+            if fired || elapsed.as_secs_f64() <= timeout.limit {
+                continue;
+            }
+            fired = true;
+            let what = match op {
+                Some(op) => format!("step {step} ({op})"),
+                None => format!("step {step}"),
+            };
+            if timeout.action == TimeoutAction::Warn {
+                warn!("watchdog: {what} has not completed after {elapsed:?}");
+                continue;
+            }
+            error!("watchdog: {what} has not completed after {elapsed:?}");
+            if kstack {
+                match kernel_stack() {
+                    Ok(stack) => error!("kernel stack:\n{}", stack.trim_end()),
+                    Err(e) => warn!("Cannot get kernel stack: {e}"),
+                }
+            }
+            if timeout.action == TimeoutAction::Dump {
+                WATCHDOG_DUMP.store(true, Ordering::Relaxed);
+            } else {
+                WATCHDOG_ABORT.store(true, Ordering::Relaxed);
+                deadline = Some(
+                    Instant::now() + Duration::from_secs_f64(timeout.limit),
+                );
             }
         }
+    });
+}
+
+/// Take an exclusive lock on a test file, through a file descriptor of its
+/// own, so no other fsx process can use the file at the same time.  Reopening
+/// the file during the run won't release it.  The artifacts aren't locked,
+/// but they're named after the test file.
+fn lock_file(path: &Path, create: bool) -> Flock<File> {
+    let file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(create)
+        .open(path)
+        .expect("Cannot create file");
+    match Flock::lock(file, FlockArg::LockExclusiveNonblock) {
+        Ok(lock) => lock,
+        Err((_, errno::Errno::EWOULDBLOCK)) => {
+            eprintln!(
+                "error: {} is already in use by another fsx process",
+                path.display()
+            );
+            process::exit(1);
+        }
+        Err((_, e)) => {
+            eprintln!("Cannot lock {}: {e}", path.display());
+            process::exit(1);
+        }
     }
+}
 
-    cfg_if! {
-        if #[cfg(any(
-            target_os = "linux",
-            target_os = "android",
-            target_os = "freebsd"
-        ))] {
-            fn posix_fadvise(
-                &mut self,
-                advice: PosixFadviseAdvice,
-                offset: u64,
-                size: u64)
-            {
-                self.oplog.push(LogEntry::PosixFadvise(advice, offset, size));
+/// Append ".N" to a path
+fn numbered(path: &Path, job: usize) -> PathBuf {
+    let mut s = path.as_os_str().to_owned();
+    s.push(format!(".{job}"));
+    s.into()
+}
 
-                if self.skip() {
-                    return;
-                }
-                info!(
-                    "{:stepwidth$} posix_fadvise({:10}) {:#fwidth$x} .. \
-                    {:#fwidth$x} ({:#swidth$x} bytes)",
-                    self.steps,
-                    advice,
-                    offset,
-                    (offset + size).saturating_sub(1),
-                    size,
-                    stepwidth = self.stepwidth,
-                    fwidth = self.fwidth,
-                    swidth = self.swidth
-                );
-                let r = nix::fcntl::posix_fadvise(self.file.as_raw_fd(),
-                    offset as i64, size as i64, advice.0);
-                if let Err(e) = r {
-                    error!("posix_fadvise failed with {e}");
-                    self.fail();
-                }
+/// Results of one run, for --report
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+struct RunSummary {
+    seed:      u64,
+    /// Number of operations executed
+    ops:       u64,
+    /// Number of operations skipped because of the errors policy
+    skipped:   u64,
+    /// The run reached its last operation
+    completed: bool,
+    failure:   Option<String>,
+    artifacts: Option<PathBuf>,
+    /// Steps after which the entire file was verified, because of
+    /// verify_interval
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    verified:  Vec<u64>,
+}
+
+/// File format for --report
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, ValueEnum)]
+enum ReportFormat {
+    #[default]
+    Toml,
+    /// Test Anything Protocol, version 14
+    Tap,
+    /// JUnit XML
+    Junit,
+}
+
+/// Escape a string for use in XML
+fn xml_escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Machine-readable summary of one or more runs
+#[derive(Debug, Default, Deserialize, Serialize)]
+struct Report {
+    runs:     u64,
+    ops:      u64,
+    skipped:  u64,
+    failures: u64,
+    #[serde(default)]
+    run:      Vec<RunSummary>,
+}
+
+impl Report {
+    fn new(run: Vec<RunSummary>) -> Self {
+        Report {
+            runs: run.len() as u64,
+            ops: run.iter().map(|r| r.ops).sum(),
+            skipped: run.iter().map(|r| r.skipped).sum(),
+            failures: run.iter().filter(|r| r.failure.is_some()).count() as u64,
+            run,
+        }
+    }
+
+    /// Format as TAP, with one subtest per run.  Each periodic verification
+    /// is a test within it.
+    fn to_tap(&self) -> String {
+        let mut s = format!("TAP version 14\n1..{}\n", self.run.len());
+        for (i, r) in self.run.iter().enumerate() {
+            let ok = |passed: bool| if passed { "ok" } else { "not ok" };
+            let passed = r.failure.is_none();
+            s += &format!("# Subtest: seed {}\n", r.seed);
+            s += &format!("    1..{}\n", r.verified.len() + 1);
+            for (j, step) in r.verified.iter().enumerate() {
+                s += &format!("    ok {} - verified at step {step}\n", j + 1);
             }
-        } else {
-            fn posix_fadvise(&mut self, _: PosixFadviseAdvice, _: u64, _: u64) {
-                eprintln!("posix_fadvise is not supported on this platform.");
-                process::exit(1);
+            s += &format!(
+                "    {} {} - {} operations\n",
+                ok(passed),
+                r.verified.len() + 1,
+                r.ops
+            );
+            if let Some(failure) = &r.failure {
+                s += "      ---\n";
+                s += &format!("      message: {failure:?}\n");
+                if let Some(artifacts) = &r.artifacts {
+                    s += &format!("      artifacts: {:?}\n", artifacts);
+                }
+                s += "      ...\n";
             }
+            s += &format!("{} {} - seed {}\n", ok(passed), i + 1, r.seed);
         }
+        s
     }
 
-    fn check_buffers(&self, buf: &[u8], mut offset: u64) {
-        let mut size = buf.len();
-        if self.good_buf[offset as usize..offset as usize + size] != buf[..] {
-            error!("miscompare: offset= {:#x}, size = {:#x}", offset, size);
-            let mut i = 0;
-            let mut n = 0;
-            let mut good = 0;
-            let mut bad = 0;
-            let mut badoffset = 0;
-            let mut op = 0;
-            error!(
-                "{:fwidth$} GOOD  BAD  {:swidth$}",
-                "OFFSET",
-                "RANGE",
-                fwidth = self.fwidth,
-                swidth = self.swidth
+    /// Format as JUnit XML, with one test suite per run.  Each periodic
+    /// verification is a test case within it.
+    fn to_junit(&self) -> String {
+        let mut s = format!(
+            "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<testsuites \
+             name=\"fsx\" tests=\"{}\" failures=\"{}\">\n",
+            self.run.iter().map(|r| r.verified.len() + 1).sum::<usize>(),
+            self.failures
+        );
+        for r in self.run.iter() {
+            let name = format!("seed {}", r.seed);
+            s += &format!(
+                "  <testsuite name=\"{name}\" tests=\"{}\" failures=\"{}\">\n",
+                r.verified.len() + 1,
+                u8::from(r.failure.is_some())
             );
-            while size > 0 {
-                let c = self.good_buf[offset as usize];
-                let t = buf[i];
-                if c != t {
-                    if n == 0 {
-                        good = c;
-                        bad = t;
-                        badoffset = offset;
-                        op = buf[if offset & 1 != 0 { i + 1 } else { i }];
-                    }
-                    n += 1;
-                }
-                offset += 1;
-                i += 1;
-                size -= 1;
+            for step in r.verified.iter() {
+                s += &format!(
+                    "    <testcase classname=\"{name}\" name=\"verified at \
+                     step {step}\"/>\n"
+                );
             }
-            assert!(n > 0);
-            // XXX The reported range may be a little too small, because
-            // some bytes in the damaged range may coincidentally match.  But
-            // this is the way that the C-based FSX reported it.
-            error!(
-                "{:#fwidth$x} {:#04x} {:#04x} {:#swidth$x}",
-                badoffset,
-                good,
-                bad,
-                n,
-                fwidth = self.fwidth,
-                swidth = self.swidth
+            let case = format!(
+                "    <testcase classname=\"{name}\" name=\"{} operations\"",
+                r.ops
             );
-            if op > 0 {
-                error!("Step# (mod 256) for a misdirected write may be {}", op);
-            } else {
-                error!(
-                    "Step# for the bad data is unknown; check HOLE and EXTEND \
-                     ops"
+            if let Some(failure) = &r.failure {
+                let artifacts = r
+                    .artifacts
+                    .as_ref()
+                    .map(|a| format!("artifacts: {}", a.display()))
+                    .unwrap_or_default();
+                s += &format!(
+                    "{case}>\n      <failure message=\"{}\">{}</failure>\n    \
+                     </testcase>\n",
+                    xml_escape(failure),
+                    xml_escape(&artifacts)
                 );
+            } else {
+                s += &format!("{case}/>\n");
             }
-            self.fail();
+            s += "  </testsuite>\n";
         }
+        s += "</testsuites>\n";
+        s
     }
 
-    fn check_eofpage(&self, offset: u64, p: *const c_void, size: usize) {
-        let page_size = Self::getpagesize() as usize;
-        let page_mask = page_size as isize - 1;
-        if offset + size as u64 <= self.file_size & !(page_mask as u64) {
-            return;
+    fn save(&self, path: &Path, format: ReportFormat) {
+        let s = match format {
+            ReportFormat::Toml => toml::to_string(self).unwrap(),
+            ReportFormat::Tap => self.to_tap(),
+            ReportFormat::Junit => self.to_junit(),
+        };
+        if let Err(e) = fs::write(path, s) {
+            warn!("writing {}: {}", path.display(), e);
         }
+    }
+}
 
-        // We landed in the last page of the file.  Test to make sure the VM
-        // system provided 0's beyond the true end of the file mapping (as
-        // required by mmap def in 1996 posix 1003.1).
-        //
-        // Safety: mmap always maps to the end of a page, and we drop the slice
-        // before munmap().
-        let last_page = unsafe {
-            let last_page_p = ((p as *mut u8)
-                .offset((offset as isize & page_mask) + size as isize)
-                as isize
-                & !page_mask) as *const u8;
-            std::slice::from_raw_parts(last_page_p, page_size)
+/// Summarize a child process's run, from its exit status and its own report
+fn child_summary(
+    seed: u64,
+    dir: &Path,
+    completed: bool,
+    failure: Option<String>,
+) -> RunSummary {
+    let mut summary = fs::read_to_string(dir.join("report.toml"))
+        .ok()
+        .and_then(|s| toml::from_str::<Report>(&s).ok())
+        .and_then(|r| r.run.into_iter().next())
+        .unwrap_or_default();
+    summary.seed = seed;
+    summary.completed = completed;
+    summary.artifacts = failure.is_some().then(|| dir.to_owned());
+    summary.failure = failure;
+    summary
+}
+
+/// In a --jobs or --soak child, send stdout and stderr to `log`, and put the
+/// report, if the parent had one, and every other output file in the child's
+/// own artifacts directory, `dir`
+fn setup_child(cli: &mut Cli, dir: PathBuf, log: &File, report: bool) {
+    dup2(log.as_raw_fd(), libc::STDOUT_FILENO).unwrap();
+    dup2(log.as_raw_fd(), libc::STDERR_FILENO).unwrap();
+    cli.report = report.then(|| dir.join("report.toml"));
+    cli.csv = cli.csv.as_ref().map(|_| dir.join("ops.csv"));
+    #[cfg(feature = "strace")]
+    {
+        cli.strace = cli.strace.as_ref().map(|_| dir.join("fsx.strace"));
+    }
+    cli.oplog_file = cli.oplog_file.as_ref().map(|_| dir.join("fsx.oplog"));
+    cli.stats_file = cli.stats_file.as_ref().map(|_| dir.join("stats.log"));
+    cli.artifacts_dir = Some(dir);
+}
+
+/// Fork one child process per job, each with its own file, seed, and
+/// artifacts directory.  Each child returns its own arguments.  The parent
+/// waits for the children, and exits with the first failure.
+fn fork_jobs(mut cli: Cli, jobs: NonZeroUsize) -> Cli {
+    let seed = cli
+        .seed
+        .as_ref()
+        .map_or_else(|| thread_rng().gen(), |s| s.0);
+    let base_dir =
+        cli.artifacts_dir
+            .clone()
+            .unwrap_or_else(|| match cli.fname.parent() {
+                Some(d) if !d.as_os_str().is_empty() => d.to_owned(),
+                _ => PathBuf::from("."),
+            });
+    let report = cli.report.take();
+    // Children report in TOML, for the parent to read
+    let format = mem::take(&mut cli.report_format);
+    let mut runs = vec![RunSummary::default(); jobs.get()];
+    let mut children = HashMap::new();
+    for job in 0..jobs.get() {
+        let dir = base_dir.join(format!("job{job}"));
+        fs::create_dir_all(&dir).expect("Cannot create artifacts directory");
+        let log = File::create(dir.join("fsx.log")).expect("Cannot create log");
+        let job_seed = seed.wrapping_add(job as u64);
+        // Safe because no other threads have been started yet
+        match unsafe { fork() }.expect("fork failed") {
+            ForkResult::Child => {
+                cli.jobs = None;
+                cli.seed = Some((job_seed, None));
+                cli.fname = numbered(&cli.fname, job);
+                cli.oracle = cli.oracle.map(|p| numbered(&p, job));
+                setup_child(&mut cli, dir, &log, report.is_some());
+                return cli;
+            }
+            ForkResult::Parent { child } => {
+                println!("job {job}: seed {job_seed}");
+                children.insert(child, (job, job_seed, dir));
+            }
+        }
+    }
+    while !children.is_empty() {
+        let (pid, failure) = match waitpid(None, None).unwrap() {
+            WaitStatus::Exited(pid, 0) => (pid, None),
+            WaitStatus::Exited(pid, code) => {
+                (pid, Some(format!("exited with status {code}")))
+            }
+            WaitStatus::Signaled(pid, sig, _) => {
+                (pid, Some(format!("was killed by {sig}")))
+            }
+            _ => continue,
         };
-        for (i, b) in last_page[self.file_size as usize & page_mask as usize..]
-            .iter()
-            .enumerate()
-        {
-            if *b != 0 {
-                error!(
-                    "Mapped non-zero data past EoF ({:#x}) page offset {:#x} \
-                     is {:#x}",
-                    self.file_size - 1,
-                    (self.file_size & page_mask as u64) + i as u64,
-                    *b
-                );
-                self.fail();
+        let (job, seed, dir) = children.remove(&pid).unwrap();
+        let completed = failure.is_none();
+        runs[job] = child_summary(seed, &dir, completed, failure.clone());
+        if let Some(failure) = failure {
+            for (pid, (job, seed, dir)) in children.iter() {
+                let _ = kill(*pid, Signal::SIGTERM);
+                let _ = waitpid(*pid, None);
+                runs[*job] = child_summary(*seed, dir, false, None);
+            }
+            if let Ok(log) = fs::read_to_string(dir.join("fsx.log")) {
+                eprint!("{log}");
+            }
+            if let Some(path) = &report {
+                Report::new(runs.clone()).save(path, format);
             }
+            error!("job {job} {failure}.  Artifacts are in {}", dir.display());
+            process::exit(1);
         }
     }
+    if let Some(path) = &report {
+        Report::new(runs).save(path, format);
+    }
+    println!("All {jobs} jobs completed A-OK!");
+    process::exit(0);
+}
 
-    fn check_size(&mut self) {
-        if !self.nosizechecks {
-            let size = self.file.metadata().unwrap().len();
-            let size_by_seek = self.file.seek(SeekFrom::End(0)).unwrap();
-            if size != self.file_size || size_by_seek != self.file_size {
-                error!(
-                    "Size error: expected {:#x} but found {:#x} by stat and \
-                     {:#x} by seek",
-                    self.file_size, size, size_by_seek
-                );
-                self.fail();
+/// Fork one child process per run, forever.  Each child returns its own
+/// arguments.  The parent keeps the artifacts of failed runs, and deletes the
+/// rest.
+fn soak(mut cli: Cli) -> Cli {
+    let base_seed = cli.seed.as_ref().map(|s| s.0);
+    let base_dir =
+        cli.artifacts_dir
+            .clone()
+            .unwrap_or_else(|| match cli.fname.parent() {
+                Some(d) if !d.as_os_str().is_empty() => d.to_owned(),
+                _ => PathBuf::from("."),
+            });
+    let report = cli.report.take();
+    // Children report in TOML, for the parent to read
+    let format = mem::take(&mut cli.report_format);
+    let mut runs = Vec::new();
+    let mut failures = 0u64;
+    for run in 0u64.. {
+        // With -S, the whole campaign is reproducible.
+        let seed = match base_seed {
+            Some(s) => s.wrapping_add(run),
+            None => thread_rng().gen(),
+        };
+        let dir = base_dir.join(format!("run{run}-seed{seed}"));
+        fs::create_dir_all(&dir).expect("Cannot create artifacts directory");
+        let log = File::create(dir.join("fsx.log")).expect("Cannot create log");
+        // Safe because no other threads have been started yet
+        match unsafe { fork() }.expect("fork failed") {
+            ForkResult::Child => {
+                cli.soak = false;
+                cli.seed = Some((seed, None));
+                setup_child(&mut cli, dir, &log, report.is_some());
+                return cli;
+            }
+            ForkResult::Parent { child } => {
+                let failure = match waitpid(child, None).unwrap() {
+                    WaitStatus::Exited(_, 0) => None,
+                    WaitStatus::Exited(_, code) => {
+                        Some(format!("exited with status {code}"))
+                    }
+                    WaitStatus::Signaled(_, sig, _) => {
+                        Some(format!("was killed by {sig}"))
+                    }
+                    status => Some(format!("stopped with {status:?}")),
+                };
+                let completed = failure.is_none();
+                runs.push(child_summary(
+                    seed,
+                    &dir,
+                    completed,
+                    failure.clone(),
+                ));
+                if let Some(path) = &report {
+                    Report::new(runs.clone()).save(path, format);
+                }
+                if let Some(failure) = failure {
+                    failures += 1;
+                    error!(
+                        "run {run} with seed {seed} {failure}.  Artifacts are \
+                         in {}",
+                        dir.display()
+                    );
+                } else {
+                    let _ = fs::remove_dir_all(&dir);
+                    info!("run {run} with seed {seed} completed A-OK!");
+                }
+                println!("{} runs, {failures} failures", run + 1);
             }
         }
     }
+    unreachable!()
+}
 
-    /// Close and reopen the file
-    fn closeopen(&mut self) {
-        self.oplog.push(LogEntry::CloseOpen);
+/// Calculate the maximum field width needed to print numbers up to this size
+fn field_width(max: usize, hex: bool) -> usize {
+    if hex {
+        2 + (8 * mem::size_of_val(&max) - max.leading_zeros() as usize)
+            .div_ceil(4)
+    } else {
+        1 + (max as f64).log(10.0) as usize
+    }
+}
 
-        if self.skip() {
-            return;
-        }
-        info!("{:width$} close/open", self.steps, width = self.stepwidth);
+#[derive(Clone)]
+struct RangeParser {
+    flag: &'static str,
+}
+impl TypedValueParser for RangeParser {
+    type Value = (u64, u64);
 
-        // We must remove and drop the old File before opening it, and that
-        // requires swapping its contents.
-        // Safe because we never access the uninitialized File object.
-        unsafe {
-            let placeholder: File = mem::MaybeUninit::zeroed().assume_init();
-            drop(mem::replace(&mut self.file, placeholder));
-            let newfile = OpenOptions::new()
-                .read(true)
-                .write(true)
-                .open(&self.fname)
-                .expect("Cannot open file");
-            let placeholder = mem::replace(&mut self.file, newfile);
-            let _ = placeholder.into_raw_fd();
+    fn parse_ref(
+        &self,
+        cmd: &Command,
+        _arg: Option<&Arg>,
+        value: &OsStr,
+    ) -> Result<Self::Value, Error> {
+        let vs = value.to_str().ok_or_else(|| {
+            clap::Error::new(ErrorKind::InvalidUtf8).with_cmd(cmd)
+        })?;
+        let fields = vs.split(':').collect::<Vec<_>>();
+        if fields.len() != 2 {
+            let e = clap::Error::raw(
+                ErrorKind::InvalidValue,
+                format!("{} argument must contain exactly one ':'", self.flag),
+            )
+            .with_cmd(cmd);
+            return Err(e);
         }
+        let startop = fields[0].parse::<u64>().map_err(|_| {
+            clap::Error::raw(
+                ErrorKind::InvalidValue,
+                format!("{} arguments must be numeric", self.flag),
+            )
+        })?;
+        let endop = fields[1].parse::<u64>().map_err(|_| {
+            clap::Error::raw(
+                ErrorKind::InvalidValue,
+                format!("{} arguments must be numeric", self.flag),
+            )
+        })?;
+        Ok((startop, endop))
     }
+}
 
-    fn copy_file_range(
-        &mut self,
-        op: Op,
-        mut ioffset: u64,
-        mut ooffset: u64,
-        mut size: usize,
-    ) {
-        let cur_file_size = self.file_size;
+/// Parse a seed, along with the name it was hashed from, if any.  Anything
+/// other than a number is hashed with FNV-1a and SplitMix64.  Never change the
+/// hash, or named seeds will stop reproducing!
+fn parse_seed(s: &str) -> Result<(u64, Option<String>), String> {
+    if let Ok(seed) = s.parse::<u64>() {
+        return Ok((seed, None));
+    }
+    let fnv = s.bytes().fold(0xcbf2_9ce4_8422_2325u64, |h, b| {
+        (h ^ u64::from(b)).wrapping_mul(0x0000_0100_0000_01b3)
+    });
+    Ok((splitmix64(fnv), Some(s.to_owned())))
+}
 
-        ioffset = if self.file_size > 0 {
-            ioffset % self.file_size
-        } else {
-            0
-        };
-        ioffset -= ioffset % self.align as u64;
-        if ioffset + size as u64 > self.file_size {
-            size = usize::try_from(self.file_size - ioffset).unwrap();
-        }
+#[derive(Debug, Parser)]
+#[command(author, version, about, long_about = None)]
+struct Fsx {
+    #[command(subcommand)]
+    command: Cmd,
+}
 
-        ooffset %= self.flen;
-        ooffset -= ooffset % self.align as u64;
-        if ooffset + size as u64 > self.flen {
-            size = usize::try_from(self.flen - ooffset).unwrap();
-        }
+#[derive(Debug, Subcommand)]
+enum Cmd {
+    /// Exercise a file.  This is the default, if no subcommand is given.  To
+    /// exercise a file named like a subcommand, use "fsx -- FILENAME".
+    Run(Cli),
+    /// Verify a test file against its .fsxgood artifact
+    Verify(VerifyArgs),
+    /// Replay the operations recorded in a .fsxops trace
+    Replay(ReplayArgs),
+    /// Print the effective configuration, including defaults
+    Config(ConfigArgs),
+    /// Write a commented configuration file with every option at its default
+    GenerateConfig(GenerateConfigArgs),
+    /// Probe a file system for the operations that fsx can use
+    Doctor(DoctorArgs),
+}
 
-        size = if ooffset >= ioffset {
-            size.min((ooffset - ioffset) as usize)
-        } else {
-            size.min((ioffset - ooffset) as usize)
-        };
-        size -= size % self.align;
+#[derive(Debug, Args)]
+struct VerifyArgs {
+    /// Directory containing the .fsxgood file [default: the file's directory]
+    #[arg(short = 'P', value_name = "DIRPATH")]
+    artifacts_dir: Option<PathBuf>,
 
-        if size == 0 {
-            self.oplog.push(LogEntry::Skip(op));
-            debug!(
-                "{:width$} skipping zero size copy_file_range",
-                self.steps,
-                width = self.stepwidth
-            );
-        } else {
+    /// Path to the expected contents, instead of the .fsxgood file
+    #[arg(long = "good", value_name = "PATH")]
+    good: Option<PathBuf>,
+
+    /// File to verify
+    fname: PathBuf,
+}
+
+#[derive(Debug, Args)]
+struct ReplayArgs {
+    /// Trace to replay
+    trace: PathBuf,
+
+    #[command(flatten)]
+    cli: Cli,
+}
+
+#[derive(Debug, Args)]
+struct ConfigArgs {
+    /// Config file path
+    #[arg(short = 'f', value_name = "PATH")]
+    config: Option<PathBuf>,
+
+    /// Apply this profile from the config file
+    #[arg(long = "profile", value_name = "NAME", requires = "config")]
+    profile: Option<String>,
+}
+
+#[derive(Debug, Args)]
+struct GenerateConfigArgs {
+    /// Where to write the file [default: stdout]
+    path: Option<PathBuf>,
+}
+
+#[derive(Debug, Args)]
+struct DoctorArgs {
+    /// Directory, or file within a directory, on the file system to probe
+    path: PathBuf,
+}
+
+#[derive(Debug, Parser)]
+#[command(author, version, about, long_about = None)]
+struct Cli {
+    /// Beginning operation number
+    #[arg(short = 'b', default_value_t = NonZeroU64::new(1u64).unwrap())]
+    opnum: NonZeroU64,
+
+    /// Config file path
+    #[arg(short = 'f', value_name = "PATH")]
+    config: Option<PathBuf>,
+
+    /// Apply this profile from the config file
+    #[arg(long = "profile", value_name = "NAME", requires = "config")]
+    profile: Option<String>,
+
+    /// Monitor specified byte range.  May be given multiple times.
+    #[arg(short = 'm', value_name = "FROM:TO", value_parser = RangeParser{flag: "-m"})]
+    monitor: Vec<(u64, u64)>,
+
+    /// Place operations within the monitored byte ranges, when they fit
+    #[arg(long = "monitor-only")]
+    monitor_only: bool,
+
+    /// Total number of operations to do [default infinity]
+    #[arg(short = 'N')]
+    numops: Option<u64>,
+
+    /// Save artifacts to this directory [default ./]
+    #[arg(short = 'P', value_name = "DIRPATH")]
+    artifacts_dir: Option<PathBuf>,
+
+    /// Name artifacts after this template, which may include {name}, {seed},
+    /// {timestamp}, and {pid}
+    #[arg(
+        long = "artifact-name",
+        value_name = "TEMPLATE",
+        default_value = "{name}"
+    )]
+    artifact_name: String,
+
+    /// Seed for RNG.  Non-numeric seeds are hashed.
+    #[arg(short = 'S', value_parser = parse_seed)]
+    seed: Option<(u64, Option<String>)>,
+
+    /// Mirror every modification to this reference file, and compare the two
+    /// whenever the entire file is verified
+    #[arg(long = "oracle", value_name = "PATH")]
+    oracle: Option<PathBuf>,
+
+    /// Run N copies of fsx in parallel, each on its own file
+    #[arg(short = 'j', long = "jobs", value_name = "N")]
+    jobs: Option<NonZeroUsize>,
+
+    /// File name to operate on
+    fname: PathBuf,
+
+    /// Run forever, restarting with a new seed and a fresh file after each
+    /// run, and saving each failed run's artifacts to its own directory
+    #[arg(long = "soak")]
+    soak: bool,
+
+    /// Write a machine-readable TOML summary of the run, or of every job or
+    /// soak run, to this file
+    #[arg(long = "report", value_name = "PATH")]
+    report: Option<PathBuf>,
+
+    /// Format of the --report file
+    #[arg(
+        long = "report-format",
+        value_name = "FORMAT",
+        default_value = "toml",
+        requires = "report"
+    )]
+    report_format: ReportFormat,
+
+    /// Prefix every log line with the wall-clock time, and log how long each
+    /// operation took
+    #[arg(long = "timestamps")]
+    timestamps: bool,
+
+    /// Print a progress snapshot at this interval, like "60s"
+    #[arg(
+        long = "stats-interval",
+        value_name = "DURATION",
+        value_parser = humantime::parse_duration
+    )]
+    stats_interval: Option<Duration>,
+
+    /// Append the --stats-interval snapshots to this file, instead of
+    /// printing them
+    #[arg(
+        long = "stats-file",
+        value_name = "PATH",
+        requires = "stats_interval"
+    )]
+    stats_file: Option<PathBuf>,
+
+    /// Collect a latency histogram for each operation, and print them at exit
+    /// or on SIGUSR1
+    #[arg(long = "latency")]
+    latency: bool,
+
+    /// Serve Prometheus metrics over HTTP at this address, like
+    /// "127.0.0.1:9100"
+    #[cfg(feature = "metrics")]
+    #[arg(long = "prometheus", value_name = "ADDR")]
+    prometheus: Option<String>,
+
+    /// Push metrics to the statsd server at this address, like
+    /// "localhost:8125"
+    #[cfg(feature = "metrics")]
+    #[arg(long = "statsd", value_name = "ADDR")]
+    statsd: Option<String>,
+
+    /// Append every operation to this file, in the format of the log dump
+    #[arg(long = "oplog-file", value_name = "PATH")]
+    oplog_file: Option<PathBuf>,
+
+    /// Write one CSV row per operation, with its step, op, offset, length,
+    /// duration in microseconds, and result, to this file
+    #[arg(long = "csv", value_name = "PATH")]
+    csv: Option<PathBuf>,
+
+    /// Record every system call that operations make on the test file, with
+    /// its arguments and result, to this file
+    #[cfg(feature = "strace")]
+    #[arg(long = "strace", value_name = "PATH")]
+    strace: Option<PathBuf>,
+
+    /// Replay the operations recorded in a .fsxops trace, instead of choosing
+    /// them randomly.  Use the same config file as the recorded run.
+    #[arg(long = "replay", value_name = "PATH")]
+    replay: Option<PathBuf>,
+
+    /// Resume a run at the step saved in a .fsxstate file, instead of
+    /// starting from the beginning.  Use the same config file as the saved
+    /// run.
+    #[arg(long = "rng-state", value_name = "PATH")]
+    rng_state: Option<PathBuf>,
+
+    /// Randomize the weights of enabled operations, and the opsize, from this
+    /// meta-seed [default: the seed].  The generated config is saved to the
+    /// artifacts directory.
+    #[arg(
+        long = "fuzz-config",
+        value_name = "META_SEED",
+        num_args = 0..=1,
+        require_equals = true
+    )]
+    fuzz_config: Option<Option<u64>>,
+
+    /// Before each operation, print it and wait for a command on stdin
+    #[arg(long = "step")]
+    step: bool,
+
+    /// Stop with SIGSTOP immediately before operation N, so a debugger or
+    /// tracer can attach.  Continue with SIGCONT.
+    #[arg(long = "break-at", value_name = "N")]
+    break_at: Option<NonZeroU64>,
+
+    /// Skip the I/O of operations A through B, inclusive, while still
+    /// updating the expected contents, as if they had silently failed
+    #[arg(
+        long = "skip-ops",
+        value_name = "A:B[,C:D]",
+        value_delimiter = ',',
+        value_parser = RangeParser{flag: "--skip-ops"}
+    )]
+    skip_ops: Vec<(u64, u64)>,
+
+    /// Inject an error on step N
+    // This option mainly exists just for the sake of the integration tests.
+    #[arg(long = "inject", hide = true, value_name = "N")]
+    inject: Option<u64>,
+
+    /// Fail step N's first system call with this errno, instead of skipping
+    /// its I/O
+    #[arg(
+        long = "inject-errno",
+        hide = true,
+        requires = "inject",
+        value_name = "ERRNO",
+        value_parser = parse_errno
+    )]
+    inject_errno: Option<i32>,
+
+    #[command(flatten)]
+    verbose: Verbosity<WarnLevel>,
+
+    /// Config built from the C-based fsx's options, instead of a config file
+    #[arg(skip)]
+    legacy_config: Option<Config>,
+
+    /// The C-based fsx's options that were ignored, to warn about once logging
+    /// is set up
+    #[arg(skip)]
+    legacy_ignored: Vec<&'static str>,
+}
+
+/// The C-based fsx's command line, selected with --legacy-cli
+#[derive(Debug, Parser)]
+#[command(version, long_about = None)]
+struct LegacyCli {
+    /// Beginning operation number
+    #[arg(short = 'b', default_value_t = NonZeroU64::new(1u64).unwrap())]
+    opnum: NonZeroU64,
+
+    /// 1 in P chance of file close+open at each op [default infinity]
+    #[arg(short = 'c', value_name = "P")]
+    closeprob: Option<NonZeroU32>,
+
+    /// Debug output for all operations
+    #[arg(short = 'd')]
+    debug: bool,
+
+    /// Flush and invalidate cache after I/O (ignored)
+    #[arg(short = 'f')]
+    flush: bool,
+
+    /// 1 in P chance of calling msync(MS_INVALIDATE) [default infinity]
+    #[arg(short = 'i', value_name = "P",
+          value_parser = clap::value_parser!(u32).range(2..))]
+    invlprob: Option<u32>,
+
+    /// The upper bound on file size
+    #[arg(short = 'l', value_name = "FLEN")]
+    flen: Option<u32>,
+
+    /// Monitor specified byte range
+    #[arg(short = 'm', value_name = "FROM:TO", value_parser = RangeParser{flag: "-m"})]
+    monitor: Option<(u64, u64)>,
+
+    /// No verifications of file size
+    #[arg(short = 'n')]
+    nosizechecks: bool,
+
+    /// The upper bound on operation size
+    #[arg(short = 'o', value_name = "OPLEN")]
+    oplen: Option<usize>,
+
+    /// Debug output at specified operation interval (ignored)
+    #[arg(short = 'p', value_name = "N")]
+    progressinterval: Option<u64>,
+
+    /// Quieter operation
+    #[arg(short = 'q')]
+    quiet: bool,
+
+    /// Alignment of reads
+    #[arg(short = 'r', value_name = "READBDY")]
+    readbdy: Option<NonZeroUsize>,
+
+    /// Truncation style (ignored)
+    #[arg(short = 's', value_name = "STYLE")]
+    style: Option<u32>,
+
+    /// Alignment of truncates
+    #[arg(short = 't', value_name = "TRUNCBDY")]
+    truncbdy: Option<NonZeroUsize>,
+
+    /// Alignment of writes
+    #[arg(short = 'w', value_name = "WRITEBDY")]
+    writebdy: Option<NonZeroUsize>,
+
+    /// Write output in hex (always true)
+    #[arg(short = 'x')]
+    hex: bool,
+
+    /// Debug output starting at specified operation (ignored)
+    #[arg(short = 'D', value_name = "STARTINGOP")]
+    debugstart: Option<u64>,
+
+    /// No file creations and no file size changes
+    #[arg(short = 'L')]
+    lite: bool,
+
+    /// Total number of operations to do [default infinity]
+    #[arg(short = 'N')]
+    numops: Option<u64>,
+
+    /// Use oplen for every operation
+    #[arg(short = 'O')]
+    fixed_oplen: bool,
+
+    /// Save artifacts to this directory [default ./]
+    #[arg(short = 'P', value_name = "DIRPATH")]
+    artifacts_dir: Option<PathBuf>,
+
+    /// Disable mapped reads
+    #[arg(short = 'R')]
+    nomapread: bool,
+
+    /// Seed for RNG.  0 chooses a random seed.
+    #[arg(short = 'S', value_parser = parse_seed)]
+    seed: Option<(u64, Option<String>)>,
+
+    /// Don't msync after mapped writes
+    #[arg(short = 'U')]
+    nomsyncafterwrite: bool,
+
+    /// Disable mapped writes
+    #[arg(short = 'W')]
+    nomapwrite: bool,
+
+    /// File name to operate on
+    fname: PathBuf,
+}
+
+impl LegacyCli {
+    /// Translate the C-based fsx's options into a Cli and Config
+    fn into_cli(self) -> Cli {
+        let legacy_ignored = [
+            ("-f", self.flush),
+            ("-p", self.progressinterval.is_some()),
+            ("-s", self.style.is_some()),
+            ("-D", self.debugstart.is_some()),
+        ]
+        .into_iter()
+        .filter_map(|(flag, used)| used.then_some(flag))
+        .collect();
+        let mut config = Config {
+            flen: self.flen,
+            nosizechecks: self.nosizechecks,
+            nomsyncafterwrite: self.nomsyncafterwrite,
+            blockmode: self.lite,
+            closeprob: self.closeprob,
+            ..Default::default()
+        };
+        if let Some(oplen) = self.oplen {
+            config.opsize.max = oplen;
+        }
+        if self.fixed_oplen {
+            config.opsize.min = config.opsize.max;
+        }
+        // fsx-rs can't align each operation differently, so use one alignment
+        // that satisfies them all
+        config.opsize.align = [self.readbdy, self.truncbdy, self.writebdy]
+            .into_iter()
+            .flatten()
+            .reduce(|a, b| {
+                lcm(a, b).unwrap_or_else(|| {
+                    eprintln!(
+                        "error: the -r, -t, and -w alignments have no common \
+                         multiple"
+                    );
+                    process::exit(2);
+                })
+            });
+        if self.nomapread {
+            config.weights.mapread = 0.0;
+        }
+        if self.nomapwrite {
+            config.weights.mapwrite = 0.0;
+        }
+        // Weight invalidate so it has a 1 in P chance of being chosen for any
+        // given step.
+        let total = config.weights.to_array().iter().sum::<f64>();
+        if let Some(p) = self.invlprob {
+            config.weights.invalidate = total / f64::from(p - 1);
+        }
+        let verbose = match (self.debug, self.quiet) {
+            (true, false) => 2,
+            (true, true) => 1,
+            (false, _) => 0,
+        };
+        // Everything but the translated options takes its default value
+        let defaults =
+            [OsStr::new("fsx"), OsStr::new("--"), self.fname.as_os_str()];
+        Cli {
+            opnum: self.opnum,
+            monitor: self.monitor.into_iter().collect(),
+            numops: self.numops,
+            artifacts_dir: self.artifacts_dir,
+            seed: self.seed.filter(|&(seed, _)| seed != 0),
+            verbose: Verbosity::new(verbose, 0),
+            legacy_config: Some(config),
+            legacy_ignored,
+            ..Cli::parse_from(defaults)
+        }
+    }
+}
+
+/// Least common multiple, if it fits
+fn lcm(a: NonZeroUsize, b: NonZeroUsize) -> Option<NonZeroUsize> {
+    let (mut x, mut y) = (a.get(), b.get());
+    while y != 0 {
+        (x, y) = (y, x % y);
+    }
+    a.checked_mul(NonZeroUsize::new(b.get() / x).unwrap())
+}
+
+const fn default_flen() -> u64 {
+    256 * 1024
+}
+
+const fn default_stblocks_tolerance() -> u64 {
+    65536
+}
+
+fn default_files() -> NonZeroUsize {
+    NonZeroUsize::new(1).unwrap()
+}
+
+fn default_oplog_size() -> NonZeroUsize {
+    NonZeroUsize::new(1024).unwrap()
+}
+
+fn default_threads() -> NonZeroUsize {
+    NonZeroUsize::new(1).unwrap()
+}
+
+fn default_hook_interval() -> NonZeroU64 {
+    NonZeroU64::new(1000).unwrap()
+}
+
+const fn default_timecheck_granularity() -> f64 {
+    1.0
+}
+
+/// Configuration file format, as toml
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+struct Config {
+    /// Maximum file size
+    // NB: could be u64, but the C-based FSX only works with 32-bit file sizes
+    #[serde(default)]
+    flen: Option<u32>,
+
+    /// Disable verifications of file size
+    #[serde(default)]
+    nosizechecks: bool,
+
+    /// Block mode: never change the file's size.
+    #[serde(default)]
+    blockmode: bool,
+
+    /// In blockmode, test only the part of the file beginning here
+    #[serde(default)]
+    blockmode_offset: u64,
+
+    /// In blockmode, test only this many bytes of the file
+    #[serde(default)]
+    blockmode_length: Option<NonZeroU64>,
+
+    /// Start with this file's contents, instead of an empty or zeroed test
+    /// file.  At most flen bytes are used.
+    #[serde(default)]
+    initial_contents: Option<PathBuf>,
+
+    /// Exercise an existing regular file in place, starting from its current
+    /// contents, rather than truncating it.
+    #[serde(default)]
+    keep_contents: bool,
+
+    /// How mapread, mapwrite, and invalidate map the file: "op", "window", or
+    /// "file"
+    #[serde(default)]
+    map_mode: MapMode,
+
+    /// Disable msync after mapwrite
+    #[serde(default)]
+    nomsyncafterwrite: bool,
+
+    /// How the close_open operation reopens the file
+    #[serde(default)]
+    reopen: Reopen,
+
+    /// Close and reopen the file after each operation, with probability 1
+    /// in N, like the C-based fsx's -c option
+    #[serde(default)]
+    closeprob: Option<NonZeroU32>,
+
+    /// Logging verbosity, unless overridden by -v or -q
+    #[serde(default)]
+    log_level: Option<LevelFilter>,
+
+    /// How many recent operations to keep for the log dump
+    #[serde(default = "default_oplog_size")]
+    oplog_size: NonZeroUsize,
+
+    /// Byte ranges to monitor, like -m, as `[from, to]` pairs
+    #[serde(default)]
+    monitor: Vec<(u64, u64)>,
+
+    /// Verify the entire file's contents every N operations
+    #[serde(default)]
+    verify_interval: Option<NonZeroU64>,
+
+    /// Save the run's state every N operations, for --rng-state
+    #[serde(default)]
+    checkpoint_interval: Option<NonZeroU64>,
+
+    /// Read back every written range immediately after writing it
+    #[serde(default)]
+    verify_after_write: VerifyAfterWrite,
+
+    /// Repeat every read with O_DIRECT, bypassing the cache
+    #[serde(default)]
+    direct_read_verify: bool,
+
+    /// Verify st_blocks after punch_hole and posix_fallocate
+    #[serde(default)]
+    stblocks_check: bool,
+
+    /// Tolerance in bytes for stblocks_check
+    #[serde(default = "default_stblocks_tolerance")]
+    stblocks_tolerance: u64,
+
+    /// Verify with SEEK_HOLE that punched ranges actually became holes
+    #[serde(default)]
+    holecheck: bool,
+
+    /// Verify that mtime and ctime advance after every modification
+    #[serde(default)]
+    timecheck: bool,
+
+    /// Timestamp granularity in seconds for timecheck
+    #[serde(default = "default_timecheck_granularity")]
+    timecheck_granularity: f64,
+
+    /// Path of a secondary file to use for cross-file operations.  Relative
+    /// paths are relative to the test file's directory.
+    #[serde(default)]
+    scratch_file: Option<PathBuf>,
+
+    /// Operate on an anonymous file created with O_TMPFILE
+    #[serde(default)]
+    tmpfile: bool,
+
+    /// When using tmpfile, link the file into place at the end of the run
+    #[serde(default)]
+    tmpfile_link: bool,
+
+    /// How to handle ENOSPC from write, posix_fallocate, and copy_file_range.
+    /// Shorthand for an ENOSPC entry in `errors`.
+    #[serde(default)]
+    enospc: Enospc,
+
+    /// What to do when an operation fails, by errno
+    #[serde(default)]
+    errors: BTreeMap<String, ErrorPolicy>,
+
+    /// Interrupt fsx with a signal every this many microseconds, to verify
+    /// that interrupted system calls are correctly retried
+    #[serde(default)]
+    signal_storm: Option<NonZeroU64>,
+
+    /// Snapshot the file system every N operations by running
+    /// snapshot_command, and later verify the snapshot's contents
+    #[serde(default)]
+    snapshot_interval: Option<NonZeroU64>,
+
+    /// Shell command that takes a snapshot.  `{n}` expands to the snapshot's
+    /// number, and `{path}` to the test file's path, quoted for the shell.
+    #[serde(default)]
+    snapshot_command: Option<String>,
+
+    /// Path to the test file within each snapshot, with the same expansions as
+    /// snapshot_command
+    #[serde(default)]
+    snapshot_path: Option<String>,
+
+    /// Shell command to check the file system's integrity, like a scrub or
+    /// `fsck -n`.  It runs every check_hook_interval operations and at the end
+    /// of the run, and fsx fails if it does.  `{path}` expands to the test
+    /// file's path.
+    #[serde(default)]
+    check_hook: Option<String>,
+
+    /// Run check_hook every N operations
+    #[serde(default = "default_hook_interval")]
+    check_hook_interval: NonZeroU64,
+
+    /// Shell command that unmounts and remounts the test file system.  Every
+    /// remount_interval operations, fsx closes the file, runs this command,
+    /// then reopens the file and verifies its entire contents.  `{path}`
+    /// expands to the test file's path.
+    #[serde(default)]
+    remount_hook: Option<String>,
+
+    /// Run remount_hook every N operations
+    #[serde(default = "default_hook_interval")]
+    remount_interval: NonZeroU64,
+
+    /// Every N operations, fsync the file and then evict its data from the
+    /// cache, so later reads must hit the media
+    #[serde(default)]
+    drop_caches: Option<NonZeroU64>,
+
+    /// Shell command that drops caches, instead of
+    /// posix_fadvise(POSIX_FADV_DONTNEED) on the whole file.  `{path}`
+    /// expands to the test file's path.
+    #[serde(default)]
+    drop_caches_hook: Option<String>,
+
+    /// Act, as watchdog_action says, if any single operation takes longer
+    /// than this many seconds
+    #[serde(default)]
+    watchdog: Option<f64>,
+
+    /// What to do when the watchdog fires
+    #[serde(default)]
+    watchdog_action: TimeoutAction,
+
+    /// When the watchdog fires, also print the kernel stack of the stuck
+    /// thread
+    #[serde(default)]
+    watchdog_kstack: bool,
+
+    /// Per-operation time limits, overriding watchdog.  Keys are operation
+    /// names, as printed in the operation log.
+    #[serde(default)]
+    timeouts: BTreeMap<String, OpTimeout>,
+
+    /// Record every operation to a binary .fsxops trace in the artifacts
+    /// directory: "off", "always", or "failure"
+    #[serde(default)]
+    trace: TraceMode,
+
+    /// Save the whole expected and actual contents on failure, or just the
+    /// regions that differ: "full" or "diff"
+    #[serde(default)]
+    artifact_mode: ArtifactMode,
+
+    /// How to model the file's expected contents: "full", or "checksum" to
+    /// keep only a CRC of each 64 KiB block, for files too big for memory
+    #[serde(default)]
+    shadow: ShadowMode,
+
+    /// On failure, save this many of the kernel log's last lines to the
+    /// artifacts directory
+    #[serde(default)]
+    dmesg_lines: Option<NonZeroUsize>,
+
+    /// Shell command that prints the kernel log, instead of `dmesg`
+    #[serde(default)]
+    dmesg_command: Option<String>,
+
+    /// Stop the run successfully once write and mapwrite have written this
+    /// many bytes.  With threads, the budget applies to each thread.
+    #[serde(default)]
+    max_write_bytes: Option<NonZeroU64>,
+
+    /// How to derive operations from the seed: "legacy", or "v1", which is
+    /// guaranteed never to change
+    #[serde(default)]
+    op_stream: OpStream,
+
+    /// Pseudorandom number generator for the legacy op stream
+    #[serde(default)]
+    rng: RngKind,
+
+    /// Number of threads to exercise the file with.  Each thread operates on
+    /// its own disjoint region of the file, through a shared file descriptor.
+    #[serde(default = "default_threads")]
+    threads: NonZeroUsize,
+
+    /// Crash consistency mode: kill a child fsx process at a random step,
+    /// then verify that every byte either survived its last fsync, or holds a
+    /// value written since.  Requires -N.
+    #[serde(default)]
+    crash: bool,
+
+    /// Shell command to run after killing the child in crash mode, like a
+    /// remount.  `{path}` expands to the test file's path.
+    #[serde(default)]
+    crash_hook: Option<String>,
+
+    /// Periodically make the underlying device faulty, then verify that
+    /// data written before the fault survived
+    #[serde(default)]
+    fault: Option<FaultConfig>,
+
+    /// Number of test files.  If more than one, fsx picks a file for each
+    /// operation, and the files are named FILENAME.0, FILENAME.1, etc.
+    #[serde(default = "default_files")]
+    files: NonZeroUsize,
+
+    /// Number of background threads that continuously verify random ranges
+    /// of the file, while the main thread modifies other ranges
+    #[serde(default)]
+    verify_threads: usize,
+
+    /// Built-in settings for a file system with known limitations: "zfs",
+    /// "nfs", "fuse", or "msdosfs"
+    #[serde(default)]
+    preset: Option<Preset>,
+
+    /// Specifies size distribution for all operations
+    #[serde(default)]
+    opsize: Opsize,
+
+    /// Specifies where operations fall within the file
+    #[serde(default)]
+    offsets: Offsets,
+
+    /// What data to write
+    #[serde(default)]
+    pattern: Pattern,
+
+    /// Specifies relative statistical weights of all operations
+    #[serde(default)]
+    weights: Weights,
+
+    /// Execute each of these operations at most this many times.  Keys are
+    /// operation names, as printed in the operation log.
+    #[serde(default)]
+    max_count: BTreeMap<String, NonZeroU64>,
+
+    /// A schedule of phases, each with its own weights and opsize.  If
+    /// empty, the top-level weights and opsize apply throughout.
+    #[serde(default)]
+    phases: Vec<PhaseConfig>,
+
+    /// After the last phase: "cycle" back to the first, or "progress",
+    /// remaining in the last phase
+    #[serde(default)]
+    phase_mode: PhaseMode,
+
+    /// An explicit sequence of operations to execute
+    #[serde(default)]
+    script: Vec<ScriptOp>,
+
+    /// Run the script "before" random operations, or "instead" of them
+    #[serde(default)]
+    script_mode: ScriptMode,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        toml::from_str("").unwrap()
+    }
+}
+
+/// Merge `overlay` into `base`, recursing into tables that both contain
+fn merge_tables(base: &mut toml::Table, overlay: toml::Table) {
+    for (k, v) in overlay {
+        match (base.get_mut(&k), v) {
+            (Some(toml::Value::Table(b)), toml::Value::Table(o)) => {
+                merge_tables(b, o)
+            }
+            (_, v) => {
+                base.insert(k, v);
+            }
+        }
+    }
+}
+
+/// Expand `${VAR}` and `${VAR:-default}` in a string from the environment.
+/// `$${` is a literal `${`.
+fn interpolate(s: &str) -> Result<String, String> {
+    let mut out = String::new();
+    let mut rest = s;
+    while let Some(i) = rest.find('$') {
+        out.push_str(&rest[..i]);
+        rest = &rest[i..];
+        if let Some(r) = rest.strip_prefix("$${") {
+            out.push_str("${");
+            rest = r;
+            continue;
+        }
+        let Some(r) = rest.strip_prefix("${") else {
+            out.push('$');
+            rest = &rest[1..];
+            continue;
+        };
+        let Some(end) = r.find('}') else {
+            return Err(format!("unterminated variable in {s:?}"));
+        };
+        let (name, default) = match r[..end].split_once(":-") {
+            Some((name, default)) => (name, Some(default)),
+            None => (&r[..end], None),
+        };
+        match (std::env::var(name), default) {
+            (Ok(v), _) => out.push_str(&v),
+            (Err(_), Some(d)) => out.push_str(d),
+            (Err(_), None) => {
+                return Err(format!("environment variable {name} is not set"))
+            }
+        }
+        rest = &r[end + 1..];
+    }
+    out.push_str(rest);
+    Ok(out)
+}
+
+/// Interpolate environment variables into every string within a value
+fn interpolate_value(v: &mut toml::Value) -> Result<(), String> {
+    match v {
+        toml::Value::String(s) => *s = interpolate(s)?,
+        toml::Value::Array(a) => {
+            for v in a.iter_mut() {
+                interpolate_value(v)?;
+            }
+        }
+        toml::Value::Table(t) => {
+            for (_, v) in t.iter_mut() {
+                interpolate_value(v)?;
+            }
+        }
+        _ => (),
+    }
+    Ok(())
+}
+
+/// The number of single-character edits to turn one string into another
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b = b.chars().collect::<Vec<_>>();
+    let mut row = (0..=b.len()).collect::<Vec<_>>();
+    for (i, ca) in a.chars().enumerate() {
+        let mut prev = row[0];
+        row[0] = i + 1;
+        for (j, &cb) in b.iter().enumerate() {
+            let cur = row[j + 1];
+            row[j + 1] = if ca == cb {
+                prev
+            } else {
+                1 + prev.min(cur).min(row[j])
+            };
+            prev = cur;
+        }
+    }
+    row[b.len()]
+}
+
+/// Given serde's complaint about an unknown field, suggest the closest known
+/// one
+fn did_you_mean(msg: &str) -> Option<String> {
+    let (_, rest) = msg.split_once("unknown field `")?;
+    let (field, rest) = rest.split_once('`')?;
+    let (_, expected) = rest.split_once("expected ")?;
+    let expected = expected.lines().next()?;
+    expected
+        .split('`')
+        .skip(1)
+        .step_by(2)
+        .map(|name| (edit_distance(field, name), name))
+        .filter(|&(d, name)| d <= 2.max(name.len() / 3))
+        .min()
+        .map(|(_, name)| {
+            format!("unknown key `{field}`, did you mean `{name}`?")
+        })
+}
+
+/// Groups of related operations, whose weights may be set together
+const WEIGHT_GROUPS: &[(&str, &[&str])] = &[
+    ("reads", &["read", "mapread", "sendfile", "read_eof"]),
+    ("writes", &["write", "mapwrite"]),
+    ("syncs", &["fsync", "fdatasync"]),
+    (
+        "space",
+        &["truncate", "posix_fallocate", "punch_hole", "fsize_limit"],
+    ),
+    (
+        "copies",
+        &["copy_file_range", "copy_file_range_overlap", "reflink"],
+    ),
+    (
+        "scratch",
+        &[
+            "scratch_copy_in",
+            "scratch_copy_out",
+            "scratch_sendfile",
+            "scratch_clone",
+        ],
+    ),
+    (
+        "misc",
+        &["close_open", "invalidate", "posix_fadvise", "stat"],
+    ),
+];
+
+/// Divide the weight of each group in a weights table evenly among its
+/// members, except those whose weights are set individually.
+fn expand_groups(weights: &mut toml::Table) -> Result<(), String> {
+    let Some(groups) = weights.remove("groups") else {
+        return Ok(());
+    };
+    let toml::Value::Table(groups) = groups else {
+        return Err("weights.groups must be a table".into());
+    };
+    for (name, weight) in groups {
+        let Some((_, members)) = WEIGHT_GROUPS.iter().find(|g| g.0 == name)
+        else {
+            let names = WEIGHT_GROUPS.iter().map(|g| g.0).collect::<Vec<_>>();
+            return Err(format!(
+                "unknown weight group {name}.  Groups are: {}",
+                names.join(", ")
+            ));
+        };
+        let share = match weight {
+            toml::Value::Float(f) => {
+                toml::Value::Float(f / members.len() as f64)
+            }
+            toml::Value::Integer(i) => {
+                toml::Value::Float(i as f64 / members.len() as f64)
+            }
+            toml::Value::String(s) => match parse_percent(&s) {
+                Some(p) => toml::Value::String(format!(
+                    "{}%",
+                    p / members.len() as f64
+                )),
+                None => {
+                    return Err(format!(
+                        "weight group {name} must be a number or a percentage"
+                    ))
+                }
+            },
+            _ => return Err(format!("weight group {name} must be a number")),
+        };
+        for member in members.iter() {
+            if !weights.contains_key(*member) {
+                weights.insert(member.to_string(), share.clone());
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Parse a percentage, like "12.5%"
+fn parse_percent(s: &str) -> Option<f64> {
+    s.strip_suffix('%')
+        .and_then(|p| p.trim().parse::<f64>().ok())
+        .filter(|p| (0.0..=100.0).contains(p))
+}
+
+/// Convert percentage weights, like "25%", into ordinary weights.  Operations
+/// with ordinary weights, including default ones, share whatever percentage
+/// remains, in proportion to their weights.
+fn expand_percentages(weights: &mut toml::Table) -> Result<(), String> {
+    let mut percents = Vec::new();
+    for (name, value) in weights.iter_mut() {
+        if let toml::Value::String(s) = value {
+            let Some(p) = parse_percent(s) else {
+                return Err(format!(
+                    "weight {name} must be a number or a percentage from 0% \
+                     to 100%"
+                ));
+            };
+            percents.push((name.clone(), p));
+            *value = toml::Value::Float(0.0);
+        }
+    }
+    if percents.is_empty() {
+        return Ok(());
+    }
+    let total = percents.iter().map(|(_, p)| p).sum::<f64>();
+    if total > 100.0 {
+        return Err(format!("percentage weights total {total}%, over 100%"));
+    }
+    // Any other error will be reported when parsing the whole config
+    let Ok(others) = weights.clone().try_into::<Weights>() else {
+        return Ok(());
+    };
+    let others = others.to_array();
+    let sum = others.iter().sum::<f64>();
+    if sum > 0.0 {
+        let scaled = others.map(|w| w * (100.0 - total) / sum);
+        let scaled =
+            toml::Table::try_from(Weights::from_array(scaled)).unwrap();
+        for (name, w) in scaled {
+            if w.as_float().is_some_and(|w| w > 0.0) {
+                weights.insert(name, w);
+            }
+        }
+    }
+    for (name, p) in percents {
+        weights.insert(name, toml::Value::Float(p));
+    }
+    Ok(())
+}
+
+/// Expand the weight groups and percentages of the top-level weights and of
+/// every phase
+fn expand_all_groups(table: &mut toml::Table) -> Result<(), String> {
+    if let Some(toml::Value::Table(w)) = table.get_mut("weights") {
+        expand_groups(w)?;
+        expand_percentages(w)?;
+    }
+    if let Some(toml::Value::Array(phases)) = table.get_mut("phases") {
+        for phase in phases.iter_mut() {
+            if let Some(toml::Value::Table(w)) = phase.get_mut("weights") {
+                expand_groups(w)?;
+                expand_percentages(w)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Read a config file as a table, after applying its includes
+fn load_table(path: &Path, depth: usize) -> toml::Table {
+    if depth > 16 {
+        eprintln!("Error reading config file: too many nested includes");
+        process::exit(1);
+    }
+    let mut table = match fs::read_to_string(path) {
+        Ok(s) => match s.parse::<toml::Table>() {
+            Ok(t) => t,
+            Err(e) => {
+                eprintln!("Error reading config file {}: {e}", path.display());
+                process::exit(1);
+            }
+        },
+        Err(e) => {
+            eprintln!("Error reading config file {}: {e}", path.display());
+            process::exit(1);
+        }
+    };
+    for (_, v) in table.iter_mut() {
+        if let Err(e) = interpolate_value(v) {
+            eprintln!("Error reading config file {}: {e}", path.display());
+            process::exit(1);
+        }
+    }
+    let Some(include) = table.remove("include") else {
+        return table;
+    };
+    let Some(includes) = include.as_array() else {
+        eprintln!(
+            "Error reading config file {}: include must be an array of paths",
+            path.display()
+        );
+        process::exit(1);
+    };
+    let mut merged = toml::Table::new();
+    for inc in includes {
+        let Some(inc) = inc.as_str() else {
+            eprintln!(
+                "Error reading config file {}: include must be an array of \
+                 paths",
+                path.display()
+            );
+            process::exit(1);
+        };
+        // Relative paths are relative to the including file
+        let inc = path.parent().unwrap_or(Path::new("")).join(inc);
+        merge_tables(&mut merged, load_table(&inc, depth + 1));
+    }
+    merge_tables(&mut merged, table);
+    merged
+}
+
+impl Config {
+    /// Load a config file, applying the named profile, if any
+    fn load(path: &Path, profile: Option<&str>) -> Self {
+        let mut table = load_table(path, 0);
+        let profiles = match table.remove("profile") {
+            None => toml::Table::new(),
+            Some(toml::Value::Table(t)) => t,
+            Some(_) => {
+                eprintln!("Error reading config file: profile must be a table");
+                process::exit(1);
+            }
+        };
+        if let Some(name) = profile {
+            match profiles.get(name) {
+                Some(toml::Value::Table(t)) => {
+                    merge_tables(&mut table, t.clone())
+                }
+                _ => {
+                    let names = profiles.keys().cloned().collect::<Vec<_>>();
+                    eprintln!(
+                        "error: no profile {name} in config file.  Available \
+                         profiles: {}",
+                        names.join(", ")
+                    );
+                    process::exit(2);
+                }
+            }
+        }
+        let parse = |table: toml::Table| -> Self {
+            match table.try_into() {
+                Ok(c) => c,
+                Err(e) => {
+                    let e = e.to_string();
+                    match did_you_mean(&e) {
+                        Some(hint) => {
+                            eprintln!("Error reading config file: {hint}")
+                        }
+                        None => eprintln!("Error reading config file: {e}"),
+                    }
+                    process::exit(1);
+                }
+            }
+        };
+        if let Err(e) = expand_all_groups(&mut table) {
+            eprintln!("Error reading config file: {e}");
+            process::exit(1);
+        }
+        let mut config = parse(table.clone());
+        if let Some(preset) = config.preset {
+            // The preset's defaults yield to the file's own settings
+            let mut merged: toml::Table = preset.defaults().parse().unwrap();
+            merge_tables(&mut merged, table);
+            config = parse(merged);
+            let weights = iter::once(&mut config.weights)
+                .chain(config.phases.iter_mut().map(|p| &mut p.weights));
+            for weights in weights {
+                let mut w = weights.to_array();
+                for &op in preset.unsupported() {
+                    if w[op as usize] > 0.0 {
+                        warn!(
+                            "disabling {op}, which {preset:?} does not support"
+                        );
+                        w[op as usize] = 0.0;
+                    }
+                }
+                *weights = Weights::from_array(w);
+            }
+        }
+        config
+    }
+
+    /// If the test file is a disk device, switch to blockmode, disabling the
+    /// operations that devices don't support.
+    fn detect_device(&mut self, path: &Path) {
+        let Ok(f) = File::open(path) else { return };
+        if self.blockmode || !is_disk(f.metadata().unwrap().file_type()) {
+            return;
+        }
+        // Only disks report a media size
+        match mediasize(f.as_raw_fd()) {
+            Ok(_) => (),
+            Err(e) if e.kind() == io::ErrorKind::Unsupported => return,
+            Err(e) => {
+                debug!("{} is not a disk: {e}", path.display());
+                return;
+            }
+        }
+        info!("{} is a device; using blockmode", path.display());
+        self.blockmode = true;
+        let weights = iter::once(&mut self.weights)
+            .chain(self.phases.iter_mut().map(|p| &mut p.weights));
+        for weights in weights {
+            let mut w = weights.to_array();
+            for op in [
+                Op::CloseOpen,
+                Op::Truncate,
+                Op::PosixFallocate,
+                Op::Reflink,
+                Op::FsizeLimit,
+                Op::ReadEof,
+            ] {
+                if w[op as usize] > 0.0 {
+                    warn!("disabling {op}, which block devices do not support");
+                    w[op as usize] = 0.0;
+                }
+            }
+            *weights = Weights::from_array(w);
+        }
+        if self.closeprob.take().is_some() {
+            warn!("disabling closeprob, which block devices do not support");
+        }
+    }
+
+    /// In blockmode on a disk device, align operations to the sector size
+    /// unless otherwise configured, and warn about alignments that the
+    /// device won't accept.
+    fn align_to_device(&mut self, path: &Path) {
+        if !self.blockmode {
+            return;
+        }
+        let Ok(f) = File::open(path) else { return };
+        if !is_disk(f.metadata().unwrap().file_type()) {
+            return;
+        }
+        let (logical, physical, iomin) = match sectorsizes(f.as_raw_fd()) {
+            Ok(sizes) => sizes,
+            Err(e) if e.kind() == io::ErrorKind::Unsupported => return,
+            Err(e) => {
+                warn!("Cannot get the sector sizes of {}: {e}", path.display());
+                return;
+            }
+        };
+        info!(
+            "{}: {logical}-byte logical sectors, {physical}-byte physical \
+             sectors, {iomin}-byte minimum I/O",
+            path.display()
+        );
+        let opsizes = iter::once(&mut self.opsize)
+            .chain(self.phases.iter_mut().filter_map(|p| p.opsize.as_mut()));
+        for opsize in opsizes {
+            let (oalign, salign) = (opsize.offset_align(), opsize.size_align());
+            // Some devices report no minimum I/O size
+            let align = iomin.max(logical);
+            if oalign == 1 && salign == 1 {
+                info!(
+                    "Aligning operations to the {align}-byte minimum I/O size"
+                );
+                opsize.align = NonZeroUsize::new(align as usize);
+                opsize.min = opsize.min.max(align as usize);
+            } else if oalign as u64 % logical != 0
+                || salign as u64 % logical != 0
+            {
+                warn!(
+                    "opsize alignment is not a multiple of the {logical}-byte \
+                     sector size; I/O will likely fail with EINVAL"
+                );
+            } else if oalign as u64 % physical.max(align) != 0 {
+                warn!(
+                    "opsize alignment is not a multiple of the {}-byte \
+                     physical sector or minimum I/O size; writes will be slow",
+                    physical.max(align)
+                );
+            }
+        }
+    }
+
+    /// The greatest weight of each operation, across all phases.  Scripted
+    /// operations count as having some weight.
+    fn max_weights(&self) -> Weights {
+        let mut weights = self.weights.to_array();
+        for phase in self.phases.iter() {
+            for (w, pw) in weights.iter_mut().zip(phase.weights.to_array()) {
+                *w = w.max(pw);
+            }
+        }
+        for rec in self.script.iter().filter_map(ScriptOp::to_record) {
+            let w = &mut weights[rec.op as usize];
+            *w = w.max(1.0);
+        }
+        Weights::from_array(weights)
+    }
+
+    /// Randomize the weights of every enabled operation, and the opsize
+    fn fuzz(&mut self, meta: u64) {
+        let mut rng = XorShiftRng::seed_from_u64(meta);
+        let weights = iter::once(&mut self.weights)
+            .chain(self.phases.iter_mut().map(|p| &mut p.weights));
+        for weights in weights {
+            let mut w = weights.to_array();
+            let enabled: Vec<usize> =
+                (0..w.len()).filter(|&i| w[i] > 0.0).collect();
+            for &i in enabled.iter() {
+                // Sometimes disable an operation altogether
+                w[i] = if rng.gen_bool(0.25) {
+                    0.0
+                } else {
+                    rng.gen_range(0.1..10.0)
+                };
+            }
+            if w.iter().all(|&x| x == 0.0) {
+                w[enabled[rng.gen_range(0..enabled.len())]] = 1.0;
+            }
+            *weights = Weights::from_array(w);
+        }
+        // Never go below the configured alignment, which may be a
+        // requirement of the file system.
+        let min_align =
+            self.opsize.offset_align().max(self.opsize.size_align());
+        let align = (1usize << rng.gen_range(0..=12)).max(min_align);
+        let limit = 1usize << rng.gen_range(0..=17);
+        let max = rng.gen_range(1..=limit).max(align);
+        let min = if rng.gen() { 0 } else { rng.gen_range(0..=max) };
+        self.opsize.min = min;
+        self.opsize.max = max;
+        self.opsize.align = NonZeroUsize::new(align);
+    }
+
+    /// Validate compatibility with these CLI arguments
+    fn validate(&self, cli: &Cli) {
+        for name in self.errors.keys() {
+            if !ERRNOS.iter().any(|(n, _)| n == name) {
+                eprintln!("error: unsupported errno {name} in errors");
+                process::exit(2);
+            }
+        }
+        if self.flen == Some(0) {
+            eprintln!("error: file length must be greater than zero");
+            process::exit(2);
+        }
+        let opsizes = iter::once(&self.opsize)
+            .chain(self.phases.iter().filter_map(|p| p.opsize.as_ref()));
+        for opsize in opsizes {
+            if opsize.max == 0 {
+                eprintln!(
+                    "error: Maximum operation size must be greater than zero"
+                );
+                process::exit(2);
+            }
+            if opsize.min > opsize.max {
+                eprintln!(
+                    "error: Minimum operation size must be no greater than \
+                     maximum"
+                );
+                process::exit(2);
+            }
+            if let SizeDistribution::Fixed(sizes) = &opsize.distribution {
+                if sizes.is_empty() {
+                    eprintln!("error: fixed size distribution is empty");
+                    process::exit(2);
+                }
+                if sizes.iter().any(|&s| s < opsize.min || s > opsize.max) {
+                    eprintln!(
+                        "error: fixed operation sizes must be between the \
+                         minimum and maximum"
+                    );
+                    process::exit(2);
+                }
+            }
+            if opsize.size_align() > opsize.max {
+                eprintln!(
+                    "error: operation alignment must be no greater than \
+                     maximum operation size"
+                );
+                process::exit(2);
+            }
+        }
+        if let OffsetMode::Hotspot { ranges, bias } = &self.offsets.mode {
+            if ranges.is_empty() {
+                eprintln!("error: hotspot offsets need at least one range");
+                process::exit(2);
+            }
+            if ranges.iter().any(|(start, end)| start >= end) {
+                eprintln!("error: hotspot ranges must not be empty");
+                process::exit(2);
+            }
+            if !(0.0..=1.0).contains(bias) {
+                eprintln!("error: hotspot bias must be between 0 and 1");
+                process::exit(2);
+            }
+        }
+        if self.monitor.iter().any(|(from, to)| from > to) {
+            eprintln!("error: monitor ranges must not end before they start");
+            process::exit(2);
+        }
+        if cli.monitor_only && cli.monitor.is_empty() && self.monitor.is_empty()
+        {
+            eprintln!(
+                "error: --monitor-only requires a range from -m or the \
+                 monitor config option"
+            );
+            process::exit(2);
+        }
+        let tables = iter::once(("[weights]".to_owned(), &self.weights)).chain(
+            self.phases
+                .iter()
+                .enumerate()
+                .map(|(i, p)| (format!("phase {i}"), &p.weights)),
+        );
+        for (name, weights) in tables {
+            let w = weights.to_array();
+            for (op, &x) in Op::ALL.iter().zip(w.iter()) {
+                if !(x.is_finite() && x >= 0.0) {
+                    eprintln!(
+                        "error: {name} has an invalid weight {x} for {op}"
+                    );
+                    process::exit(2);
+                }
+            }
+            if w.iter().all(|&x| x == 0.0) {
+                eprintln!("error: {name} has no nonzero weights");
+                process::exit(2);
+            }
+            if Op::ALL
+                .iter()
+                .zip(w)
+                .all(|(op, x)| x == 0.0 || !op.supported())
+            {
+                let ops = Op::ALL
+                    .iter()
+                    .zip(w)
+                    .filter(|&(_, x)| x > 0.0)
+                    .map(|(op, _)| op.to_string())
+                    .collect::<Vec<_>>();
+                eprintln!(
+                    "error: {name} only has nonzero weights for operations \
+                     that this platform does not support: {}",
+                    ops.join(", ")
+                );
+                process::exit(2);
+            }
+        }
+        for sop in self.script.iter() {
+            if sop.to_record().is_none() {
+                eprintln!("error: unknown operation {} in script", sop.op);
+                process::exit(2);
+            }
+            if sop.file as usize >= self.files.get() {
+                eprintln!(
+                    "error: script uses file {}, but there are only {}",
+                    sop.file, self.files
+                );
+                process::exit(2);
+            }
+        }
+        if self.script_mode == ScriptMode::Instead && self.script.is_empty() {
+            eprintln!("error: script_mode = \"instead\" requires a script");
+            process::exit(2);
+        }
+        // Any operation that some phase or the script may use must be
+        // compatible with the rest of the configuration.
+        let weights = self.max_weights();
+        if self.blockmode && weights.close_open > 0.0 {
+            eprintln!("error: cannot use close_open with blockmode");
+            process::exit(2);
+        }
+        if self.blockmode && self.closeprob.is_some() {
+            eprintln!("error: cannot use closeprob with blockmode");
+            process::exit(2);
+        }
+        if self.blockmode && weights.truncate > 0.0 {
+            eprintln!("error: cannot use truncate with blockmode");
+            process::exit(2);
+        }
+        if self.blockmode && weights.posix_fallocate > 0.0 {
+            eprintln!("error: cannot use posix_fallocate with blockmode");
+            process::exit(2);
+        }
+        if self.tmpfile && self.blockmode {
+            eprintln!("error: cannot use tmpfile with blockmode");
+            process::exit(2);
+        }
+        if self.blockmode_offset > 0 || self.blockmode_length.is_some() {
+            if !self.blockmode {
+                eprintln!(
+                    "error: blockmode_offset and blockmode_length require \
+                     blockmode"
+                );
+                process::exit(2);
+            }
+            if self.flen.is_some() && self.blockmode_length.is_some() {
+                eprintln!("error: cannot use flen with blockmode_length");
+                process::exit(2);
+            }
+            for (name, used) in [
+                ("crash", self.crash),
+                ("fault", self.fault.is_some()),
+                ("holecheck", self.holecheck),
+                ("scratch_file", self.scratch_file.is_some()),
+                ("snapshot_interval", self.snapshot_interval.is_some()),
+                ("verify_threads", self.verify_threads > 0),
+            ] {
+                if used {
+                    eprintln!(
+                        "error: cannot use {name} with blockmode_offset or \
+                         blockmode_length"
+                    );
+                    process::exit(2);
+                }
+            }
+        }
+        if self.keep_contents {
+            for (name, used) in [
+                ("blockmode", self.blockmode),
+                ("tmpfile", self.tmpfile),
+                ("initial_contents", self.initial_contents.is_some()),
+            ] {
+                if used {
+                    eprintln!("error: cannot use keep_contents with {name}");
+                    process::exit(2);
+                }
+            }
+        }
+        if self.tmpfile
+            && (weights.close_open > 0.0 || self.closeprob.is_some())
+            && self.reopen != Reopen::ProcFd
+        {
+            eprintln!(
+                "error: close_open with tmpfile requires reopen = \"procfd\""
+            );
+            process::exit(2);
+        }
+        let snapshot_opts = [
+            self.snapshot_interval.is_some(),
+            self.snapshot_command.is_some(),
+            self.snapshot_path.is_some(),
+        ];
+        if snapshot_opts.contains(&true) && snapshot_opts.contains(&false) {
+            eprintln!(
+                "error: snapshot_interval, snapshot_command, and \
+                 snapshot_path must be used together"
+            );
+            process::exit(2);
+        }
+        if self.remount_hook.is_some() {
+            if self.tmpfile {
+                eprintln!("error: cannot use remount_hook with tmpfile");
+                process::exit(2);
+            }
+            if self.scratch_file.is_some() || weights.reflink > 0.0 {
+                eprintln!(
+                    "error: remount_hook cannot be used with scratch_file or \
+                     reflink"
+                );
+                process::exit(2);
+            }
+        }
+        if self.dmesg_command.is_some() && self.dmesg_lines.is_none() {
+            eprintln!("error: dmesg_command requires dmesg_lines");
+            process::exit(2);
+        }
+        if self.drop_caches_hook.is_some() && self.drop_caches.is_none() {
+            eprintln!("error: drop_caches_hook requires drop_caches");
+            process::exit(2);
+        }
+        if cfg!(not(any(
+            target_os = "linux",
+            target_os = "android",
+            target_os = "freebsd"
+        ))) && self.drop_caches.is_some()
+            && self.drop_caches_hook.is_none()
+        {
+            eprintln!(
+                "error: drop_caches requires drop_caches_hook on this platform"
+            );
+            process::exit(2);
+        }
+        if self.tmpfile_link && !self.tmpfile {
+            eprintln!("error: tmpfile_link requires tmpfile");
+            process::exit(2);
+        }
+        let scratch_weights = weights.scratch_copy_in
+            + weights.scratch_copy_out
+            + weights.scratch_sendfile
+            + weights.scratch_clone;
+        if scratch_weights > 0.0 && self.scratch_file.is_none() {
+            eprintln!("error: scratch operations require scratch_file");
+            process::exit(2);
+        }
+        if self.blockmode && weights.reflink > 0.0 {
+            eprintln!("error: cannot use reflink with blockmode");
+            process::exit(2);
+        }
+        if self.blockmode && self.timecheck {
+            eprintln!("error: cannot use timecheck with blockmode");
+            process::exit(2);
+        }
+        if self.timecheck_granularity < 0.0 {
+            eprintln!("error: timecheck_granularity must not be negative");
+            process::exit(2);
+        }
+        if self.watchdog.is_some_and(|t| t <= 0.0) {
+            eprintln!("error: watchdog must be positive");
+            process::exit(2);
+        }
+        for name in self.max_count.keys() {
+            if !Op::ALL.iter().any(|op| op.to_string() == *name) {
+                eprintln!("error: unknown operation {name} in max_count");
+                process::exit(2);
+            }
+        }
+        for (name, timeout) in self.timeouts.iter() {
+            if !Op::ALL.iter().any(|op| op.to_string() == *name) {
+                eprintln!("error: unknown operation {name} in timeouts");
+                process::exit(2);
+            }
+            if timeout.limit <= 0.0 {
+                eprintln!("error: timeout for {name} must be positive");
+                process::exit(2);
+            }
+        }
+        if self.blockmode && weights.fsize_limit > 0.0 {
+            eprintln!("error: cannot use fsize_limit with blockmode");
+            process::exit(2);
+        }
+        if self.blockmode && weights.read_eof > 0.0 {
+            eprintln!("error: cannot use read_eof with blockmode");
+            process::exit(2);
+        }
+        if self.threads.get() > 1 {
+            // Operations that can change the file's size, or touch more than
+            // one region, would interfere with the other threads.
+            for (name, weight) in [
+                ("close_open", weights.close_open),
+                ("truncate", weights.truncate),
+                ("posix_fallocate", weights.posix_fallocate),
+                ("copy_file_range", weights.copy_file_range),
+                ("copy_file_range_overlap", weights.copy_file_range_overlap),
+                ("reflink", weights.reflink),
+                ("stat", weights.stat),
+                ("read_eof", weights.read_eof),
+                ("fsize_limit", weights.fsize_limit),
+            ] {
+                if weight > 0.0 {
+                    eprintln!("error: cannot use {name} with threads");
+                    process::exit(2);
+                }
+            }
+            for (name, used) in [
+                ("closeprob", self.closeprob.is_some()),
+                ("scratch_file", self.scratch_file.is_some()),
+                ("tmpfile_link", self.tmpfile_link),
+                ("timecheck", self.timecheck),
+                ("stblocks_check", self.stblocks_check),
+                ("snapshot_interval", self.snapshot_interval.is_some()),
+                ("remount_hook", self.remount_hook.is_some()),
+                ("trace", self.trace != TraceMode::Off),
+                ("--csv", cli.csv.is_some()),
+                #[cfg(feature = "strace")]
+                ("--strace", cli.strace.is_some()),
+                ("--oplog-file", cli.oplog_file.is_some()),
+                ("script", !self.script.is_empty()),
+                ("--oracle", cli.oracle.is_some()),
+            ] {
+                if used {
+                    eprintln!("error: cannot use {name} with threads");
+                    process::exit(2);
+                }
+            }
+        }
+        if self.crash {
+            for (name, used) in [
+                ("-b", cli.opnum.get() > 1),
+                ("tmpfile", self.tmpfile),
+                ("threads", self.threads.get() > 1),
+                ("verify_threads", self.verify_threads > 0),
+                ("multiple files", self.files.get() > 1),
+                ("trace", self.trace != TraceMode::Off),
+                ("--csv", cli.csv.is_some()),
+                #[cfg(feature = "strace")]
+                ("--strace", cli.strace.is_some()),
+                ("--oplog-file", cli.oplog_file.is_some()),
+                ("--oracle", cli.oracle.is_some()),
+            ] {
+                if used {
+                    eprintln!("error: cannot use {name} with crash");
+                    process::exit(2);
+                }
+            }
+            if cli.numops.is_none() {
+                eprintln!("error: crash requires -N");
+                process::exit(2);
+            }
+        }
+        let resumable = [
+            ("--rng-state", cli.rng_state.is_some()),
+            ("checkpoint_interval", self.checkpoint_interval.is_some()),
+        ];
+        for (feature, _) in resumable.iter().filter(|(_, used)| *used) {
+            for (name, used) in [
+                ("-b", cli.opnum.get() > 1),
+                ("threads", self.threads.get() > 1),
+                ("multiple files", self.files.get() > 1),
+                ("crash", self.crash),
+                ("script", !self.script.is_empty()),
+                ("--replay", cli.replay.is_some()),
+                ("--jobs", cli.jobs.is_some()),
+                ("--soak", cli.soak),
+            ] {
+                if used {
+                    eprintln!("error: cannot use {name} with {feature}");
+                    process::exit(2);
+                }
+            }
+            if matches!(self.rng, RngKind::Pcg64 | RngKind::Legacy) {
+                eprintln!(
+                    "error: cannot use {feature} with the {} RNG",
+                    self.rng
+                );
+                process::exit(2);
+            }
+        }
+        if let Some(fault) = &self.fault {
+            for (name, used) in [
+                ("threads", self.threads.get() > 1),
+                ("verify_threads", self.verify_threads > 0),
+                ("multiple files", self.files.get() > 1),
+                ("crash", self.crash),
+                ("snapshot_interval", self.snapshot_interval.is_some()),
+                ("--oracle", cli.oracle.is_some()),
+            ] {
+                if used {
+                    eprintln!("error: cannot use {name} with fault");
+                    process::exit(2);
+                }
+            }
+            if fault.mode == FaultMode::Error
+                && weights.mapwrite > 0.0
+                && !self.nomsyncafterwrite
+            {
+                eprintln!(
+                    "error: fault mode \"error\" requires mapwrite = 0 or \
+                     nomsyncafterwrite"
+                );
+                process::exit(2);
+            }
+        }
+        if self.crash_hook.is_some() && !self.crash {
+            eprintln!("error: crash_hook requires crash");
+            process::exit(2);
+        }
+        if self.files.get() > 1 {
+            for (name, used) in [
+                ("blockmode", self.blockmode),
+                ("tmpfile", self.tmpfile),
+                ("threads", self.threads.get() > 1),
+                ("verify_threads", self.verify_threads > 0),
+                ("snapshot_interval", self.snapshot_interval.is_some()),
+                ("remount_hook", self.remount_hook.is_some()),
+                ("--oracle", cli.oracle.is_some()),
+            ] {
+                if used {
+                    eprintln!("error: cannot use {name} with multiple files");
+                    process::exit(2);
+                }
+            }
+        }
+        if self.verify_threads > 0 && self.threads.get() > 1 {
+            eprintln!("error: cannot use verify_threads with threads");
+            process::exit(2);
+        }
+        if self.verify_threads > 0 && self.remount_hook.is_some() {
+            eprintln!("error: cannot use remount_hook with verify_threads");
+            process::exit(2);
+        }
+        if self.blockmode && cli.jobs.is_some() {
+            eprintln!("error: cannot use --jobs with blockmode");
+            process::exit(2);
+        }
+        if cli.soak {
+            for (name, used) in [
+                ("--jobs", cli.jobs.is_some()),
+                ("--replay", cli.replay.is_some()),
+            ] {
+                if used {
+                    eprintln!("error: cannot use {name} with --soak");
+                    process::exit(2);
+                }
+            }
+        }
+        #[cfg(feature = "metrics")]
+        if cli.prometheus.is_some() && cli.jobs.is_some() {
+            eprintln!("error: cannot use --prometheus with --jobs");
+            process::exit(2);
+        }
+        if cli.step {
+            for (name, used) in
+                [("--jobs", cli.jobs.is_some()), ("--soak", cli.soak)]
+            {
+                if used {
+                    eprintln!("error: cannot use {name} with --step");
+                    process::exit(2);
+                }
+            }
+        }
+        if cli.replay.is_some() {
+            for (name, used) in [
+                ("--jobs", cli.jobs.is_some()),
+                ("threads", self.threads.get() > 1),
+                ("crash", self.crash),
+                ("script", !self.script.is_empty()),
+            ] {
+                if used {
+                    eprintln!("error: cannot use {name} with --replay");
+                    process::exit(2);
+                }
+            }
+        }
+        if self.blockmode && cli.artifacts_dir.is_none() {
+            eprintln!("error: must specify -P when using blockmode");
+            process::exit(2);
+        }
+        if !self.oplog_size.is_power_of_two() {
+            eprintln!("error: oplog_size must be a power of two");
+            process::exit(2);
+        }
+        if cli.artifact_name.contains('/') {
+            eprintln!("error: --artifact-name must not contain '/'");
+            process::exit(2);
+        }
+        if self.op_stream == OpStream::V1 && self.rng != RngKind::Xorshift {
+            eprintln!("error: op_stream = \"v1\" does not use rng");
+            process::exit(2);
+        }
+        if self.shadow == ShadowMode::Checksum {
+            // These either copy data that checksum mode doesn't have, or
+            // need the whole expected contents at once.
+            for (name, weight) in [
+                ("copy_file_range", weights.copy_file_range),
+                ("copy_file_range_overlap", weights.copy_file_range_overlap),
+                ("reflink", weights.reflink),
+                ("read_eof", weights.read_eof),
+                ("fsize_limit", weights.fsize_limit),
+            ] {
+                if weight > 0.0 {
+                    eprintln!(
+                        "error: cannot use {name} with shadow = \"checksum\""
+                    );
+                    process::exit(2);
+                }
+            }
+            for (name, used) in [
+                ("-b", cli.opnum.get() > 1),
+                ("threads", self.threads.get() > 1),
+                ("verify_threads", self.verify_threads > 0),
+                ("multiple files", self.files.get() > 1),
+                ("crash", self.crash),
+                ("fault", self.fault.is_some()),
+                ("scratch_file", self.scratch_file.is_some()),
+                ("snapshot_interval", self.snapshot_interval.is_some()),
+                (
+                    "artifact_mode = \"diff\"",
+                    self.artifact_mode == ArtifactMode::Diff,
+                ),
+                ("--oracle", cli.oracle.is_some()),
+                ("--rng-state", cli.rng_state.is_some()),
+                ("checkpoint_interval", self.checkpoint_interval.is_some()),
+            ] {
+                if used {
+                    eprintln!(
+                        "error: cannot use {name} with shadow = \"checksum\""
+                    );
+                    process::exit(2);
+                }
+            }
+        }
+        if self.rng == RngKind::Legacy {
+            self.validate_legacy_rng(cli);
+        }
+    }
+
+    /// rng = "legacy" only supports what the C-based fsx's test loop can draw
+    fn validate_legacy_rng(&self, cli: &Cli) {
+        let weights = self.weights.to_array();
+        let legacy_ops =
+            [Op::Read, Op::Write, Op::MapRead, Op::MapWrite, Op::Truncate];
+        if let Some(op) = Op::ALL
+            .iter()
+            .find(|op| weights[**op as usize] > 0.0 && !legacy_ops.contains(op))
+        {
+            eprintln!("error: cannot use {op} with rng = \"legacy\"");
+            process::exit(2);
+        }
+        if weights[Op::Read as usize] <= 0.0
+            || weights[Op::Write as usize] <= 0.0
+        {
+            eprintln!("error: rng = \"legacy\" requires read and write");
+            process::exit(2);
+        }
+        let opsize = &self.opsize;
+        if opsize.distribution != SizeDistribution::Uniform
+            || (opsize.min != 0 && opsize.min != opsize.max)
+            || opsize.size_align() != 1
+            || opsize.offset_align() != 1
+        {
+            eprintln!(
+                "error: rng = \"legacy\" requires unaligned operation sizes \
+                 that are either fixed or uniform from 0"
+            );
+            process::exit(2);
+        }
+        for (name, used) in [
+            ("offsets", self.offsets.mode != OffsetMode::Uniform),
+            ("phases", !self.phases.is_empty()),
+            ("script", !self.script.is_empty()),
+            ("files", self.files.get() > 1),
+            ("threads", self.threads.get() > 1),
+            ("scratch_file", self.scratch_file.is_some()),
+            ("max_count", !self.max_count.is_empty()),
+            ("--monitor-only", cli.monitor_only),
+        ] {
+            if used {
+                eprintln!("error: cannot use {name} with rng = \"legacy\"");
+                process::exit(2);
+            }
+        }
+    }
+}
+
+/// Methods that the close_open operation may use to reopen the file
+#[derive(
+    Clone, Copy, Debug, Default, Deserialize, Serialize, Eq, PartialEq,
+)]
+#[serde(rename_all = "lowercase")]
+enum Reopen {
+    /// Close the file, then open it again by name
+    #[default]
+    Path,
+    /// Open /proc/self/fd/N, then close the original file descriptor
+    ProcFd,
+    /// Close the file, then open it again with openat2 and RESOLVE flags
+    Openat2,
+}
+
+/// How to read back written data immediately after writing it
+#[derive(
+    Clone, Copy, Debug, Default, Deserialize, Serialize, Eq, PartialEq,
+)]
+#[serde(rename_all = "lowercase")]
+enum VerifyAfterWrite {
+    /// Don't read it back
+    #[default]
+    Off,
+    /// Read with pread after write, and via mmap after mapwrite
+    Same,
+    /// Read via mmap after write, and with pread after mapwrite
+    Opposite,
+}
+
+/// Snapshots taken during the run, and what they should contain
+struct Snapshots {
+    interval: NonZeroU64,
+    command:  String,
+    path:     String,
+    /// Expected size and block CRCs of each snapshot taken so far.  Only
+    /// CRCs are kept, so memory doesn't grow by a whole file per snapshot.
+    taken:    Vec<(u64, Vec<u32>)>,
+}
+
+/// Tracks, while replaying the operations that preceded a crash, which of the
+/// file's surviving bytes are legitimate.
+struct CrashCheck {
+    /// The step that was in progress when the crash happened
+    step:   u64,
+    /// The file's contents after the crash
+    actual: Vec<u8>,
+    /// Whether each byte of `actual` matches a value that the file has held
+    /// since the last sync
+    seen:   Vec<bool>,
+    /// The file's contents as of the last sync
+    synced: Vec<u8>,
+    /// Smallest and largest file sizes since the last sync
+    sizes:  (u64, u64),
+}
+
+impl CrashCheck {
+    fn new(step: u64, actual: Vec<u8>) -> Self {
+        let seen = vec![false; actual.len()];
+        CrashCheck {
+            step,
+            actual,
+            seen,
+            synced: Vec::new(),
+            sizes: (0, 0),
+        }
+    }
+
+    /// Everything up to now is durable
+    fn sync(&mut self, good_buf: &SparseBuf, file_size: u64) {
+        self.synced = good_buf.to_vec(0..file_size as usize);
+        self.seen.fill(false);
+        self.sizes = (file_size, file_size);
+        self.observe(good_buf, file_size);
+    }
+
+    /// The file might have been in this state when the crash happened
+    fn observe(&mut self, good_buf: &SparseBuf, file_size: u64) {
+        for (i, (a, seen)) in
+            self.actual.iter().zip(self.seen.iter_mut()).enumerate()
+        {
+            let good = if (i as u64) < file_size {
+                good_buf[i]
+            } else {
+                0
+            };
+            *seen |= *a == good;
+        }
+        self.sizes = (self.sizes.0.min(file_size), self.sizes.1.max(file_size));
+    }
+
+    /// Verify that every byte matches some state that the file was in
+    fn verify(&self) -> Result<(), String> {
+        let len = self.actual.len() as u64;
+        if len < self.sizes.0 || len > self.sizes.1 {
+            return Err(format!(
+                "Size error: expected {:#x} .. {:#x} but found {:#x}",
+                self.sizes.0, self.sizes.1, len
+            ));
+        }
+        if let Some(i) = self.seen.iter().position(|seen| !seen) {
+            let bad = self.seen[i..].iter().take_while(|seen| !*seen).count();
+            let synced = self.synced.get(i).copied().unwrap_or(0);
+            return Err(format!(
+                "Consistency error at offset {:#x}: found {:#04x}, which is \
+                 neither the synced value {:#04x} nor any value written \
+                 since.  {:#x} bad bytes",
+                i, self.actual[i], synced, bad
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// The exerciser's model of the file, shared with verifier threads
+#[derive(Default)]
+struct Shadow {
+    buf:       SparseBuf,
+    file_size: u64,
+    /// Byte range currently being modified, which may not be verified
+    busy:      Option<(u64, u64)>,
+    /// Description of the first miscompare found by any verifier
+    failure:   Option<String>,
+    done:      bool,
+}
+
+/// Background threads that verify the file while the exerciser modifies it
+struct Verifiers {
+    shadow:  Arc<Mutex<Shadow>>,
+    threads: Vec<thread::JoinHandle<()>>,
+}
+
+/// Continuously verify random ranges of the file that aren't being modified.
+fn verifier(file: File, shadow: Arc<Mutex<Shadow>>, max_size: usize) {
+    let mut rng = thread_rng();
+    let mut buf = vec![0u8; max_size];
+    loop {
+        thread::yield_now();
+        let mut sh = shadow.lock().unwrap();
+        if sh.done || sh.failure.is_some() {
+            break;
+        }
+        if sh.file_size == 0 {
+            continue;
+        }
+        let offset = rng.gen_range(0..sh.file_size);
+        let size = rng
+            .gen_range(1..=max_size)
+            .min(usize::try_from(sh.file_size - offset).unwrap());
+        if let Some((start, end)) = sh.busy {
+            if start < offset + size as u64 && offset < end {
+                continue;
+            }
+        }
+        let buf = &mut buf[..size];
+        if let Err(e) = file.read_exact_at(buf, offset) {
+            sh.failure = Some(format!("verifier: read failed with {e}"));
+            break;
+        }
+        let good = sh.buf.slice(offset as usize..offset as usize + size);
+        if let Some(i) = (0..size).find(|&i| buf[i] != good[i]) {
+            sh.failure = Some(format!(
+                "verifier: miscompare at offset {:#x}: expected {:#04x}, got \
+                 {:#04x}",
+                offset + i as u64,
+                good[i],
+                buf[i]
+            ));
+            break;
+        }
+    }
+}
+
+/// How a faulty device misbehaves
+#[derive(
+    Clone, Copy, Debug, Default, Deserialize, Serialize, Eq, PartialEq,
+)]
+#[serde(rename_all = "lowercase")]
+enum FaultMode {
+    /// Writes fail with EIO
+    #[default]
+    Error,
+    /// Writes appear to succeed, but are silently discarded
+    Drop,
+}
+
+/// How operations are derived from the seed
+#[derive(
+    Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq, Serialize,
+)]
+#[serde(rename_all = "lowercase")]
+enum OpStream {
+    /// Drawn from the configured `rng`, and dependent on the rand crate
+    #[default]
+    Legacy,
+    /// A fixed derivation, documented in doc/op_stream.md
+    V1,
+}
+
+/// Which pseudorandom number generator to use
+#[derive(
+    Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq, Serialize,
+)]
+#[serde(rename_all = "lowercase")]
+enum RngKind {
+    /// Fast, but statistically weak
+    #[default]
+    Xorshift,
+    /// ChaCha with 8 rounds
+    Chacha8,
+    /// PCG XSL RR 128/64
+    Pcg64,
+    /// A copy of the C library's random(3), with the C-based fsx's mapping
+    /// from its output to operations
+    Legacy,
+}
+
+impl fmt::Display for RngKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RngKind::Xorshift => f.write_str("xorshift"),
+            RngKind::Chacha8 => f.write_str("chacha8"),
+            RngKind::Pcg64 => f.write_str("pcg64"),
+            RngKind::Legacy => f.write_str("legacy"),
+        }
+    }
+}
+
+/// The additive feedback generator behind the C library's random(3), in its
+/// default configuration.  It's reimplemented here because random(3) keeps
+/// global state, which threads and verifiers would share.
+#[derive(Clone, Debug)]
+struct LibcRandom {
+    state: [u32; 31],
+    front: usize,
+    rear:  usize,
+}
+
+impl LibcRandom {
+    /// Seed it like srandom(3)
+    fn new(seed: u32) -> Self {
+        let mut state = [0u32; 31];
+        state[0] = LibcRandom::first(seed);
+        for i in 1..state.len() {
+            state[i] = LibcRandom::next_seed(state[i - 1]);
+        }
+        let mut rng = LibcRandom {
+            state,
+            front: 3,
+            rear: 0,
+        };
+        for _ in 0..10 * state.len() {
+            rng.next();
+        }
+        rng
+    }
+
+    /// glibc replaces a zero seed with 1
+    #[cfg(not(target_os = "freebsd"))]
+    fn first(seed: u32) -> u32 {
+        seed.max(1)
+    }
+
+    #[cfg(target_os = "freebsd")]
+    fn first(seed: u32) -> u32 {
+        seed
+    }
+
+    /// x * 16807 % (2^31 - 1), computed by Schrage's method with glibc's
+    /// 32-bit signed arithmetic
+    #[cfg(not(target_os = "freebsd"))]
+    fn next_seed(x: u32) -> u32 {
+        let x = i64::from(x as i32);
+        let mut word = (16807 * (x % 127773) - 2836 * (x / 127773)) as i32;
+        if word < 0 {
+            word = word.wrapping_add(0x7fffffff);
+        }
+        word as u32
+    }
+
+    /// Like glibc's, but FreeBSD first maps x into [1, 2^31 - 2] and
+    /// subtracts 1 from the result
+    #[cfg(target_os = "freebsd")]
+    fn next_seed(x: u32) -> u32 {
+        let x = i64::from(x % 0x7ffffffe + 1);
+        let mut word = 16807 * (x % 127773) - 2836 * (x / 127773);
+        if word < 0 {
+            word += 0x7fffffff;
+        }
+        (word - 1) as u32
+    }
+
+    /// Like random(3), a value in [0, 2^31)
+    fn next(&mut self) -> u32 {
+        let x = self.state[self.front].wrapping_add(self.state[self.rear]);
+        self.state[self.front] = x;
+        self.front = (self.front + 1) % self.state.len();
+        self.rear = (self.rear + 1) % self.state.len();
+        x >> 1
+    }
+}
+
+/// A deterministic, seedable RNG of any supported kind
+#[derive(Clone, Debug)]
+enum FsxRng {
+    Xorshift(XorShiftRng),
+    Chacha8(Box<ChaCha8Rng>),
+    Pcg64(Pcg64),
+    Legacy(Box<LibcRandom>),
+}
+
+impl FsxRng {
+    fn new(kind: RngKind, seed: u64) -> Self {
+        match kind {
+            RngKind::Xorshift => {
+                FsxRng::Xorshift(XorShiftRng::seed_from_u64(seed))
+            }
+            RngKind::Chacha8 => {
+                FsxRng::Chacha8(Box::new(ChaCha8Rng::seed_from_u64(seed)))
+            }
+            RngKind::Pcg64 => FsxRng::Pcg64(Pcg64::seed_from_u64(seed)),
+            RngKind::Legacy => {
+                // Like the C-based fsx, use only the low 32 bits
+                FsxRng::Legacy(Box::new(LibcRandom::new(seed as u32)))
+            }
+        }
+    }
+
+    fn kind(&self) -> RngKind {
+        match self {
+            FsxRng::Xorshift(_) => RngKind::Xorshift,
+            FsxRng::Chacha8(_) => RngKind::Chacha8,
+            FsxRng::Pcg64(_) => RngKind::Pcg64,
+            FsxRng::Legacy(_) => RngKind::Legacy,
+        }
+    }
+}
+
+impl RngCore for FsxRng {
+    fn next_u32(&mut self) -> u32 {
+        match self {
+            FsxRng::Xorshift(r) => r.next_u32(),
+            FsxRng::Chacha8(r) => r.next_u32(),
+            FsxRng::Pcg64(r) => r.next_u32(),
+            FsxRng::Legacy(r) => r.next(),
+        }
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        match self {
+            FsxRng::Xorshift(r) => r.next_u64(),
+            FsxRng::Chacha8(r) => r.next_u64(),
+            FsxRng::Pcg64(r) => r.next_u64(),
+            FsxRng::Legacy(_) => {
+                u64::from(self.next_u32()) << 32 | u64::from(self.next_u32())
+            }
+        }
+    }
+
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        match self {
+            FsxRng::Xorshift(r) => r.fill_bytes(dest),
+            FsxRng::Chacha8(r) => r.fill_bytes(dest),
+            FsxRng::Pcg64(r) => r.fill_bytes(dest),
+            // Like the C-based fsx's original_buf
+            FsxRng::Legacy(r) => {
+                for b in dest.iter_mut() {
+                    *b = (r.next() % 256) as u8;
+                }
+            }
+        }
+    }
+
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand::Error> {
+        match self {
+            FsxRng::Xorshift(r) => r.try_fill_bytes(dest),
+            FsxRng::Chacha8(r) => r.try_fill_bytes(dest),
+            FsxRng::Pcg64(r) => r.try_fill_bytes(dest),
+            FsxRng::Legacy(_) => {
+                self.fill_bytes(dest);
+                Ok(())
+            }
+        }
+    }
+}
+
+/// The serialized state of an `FsxRng`
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum RngState {
+    Xorshift(XorShiftRng),
+    Chacha8 {
+        seed:     [u8; 32],
+        stream:   u64,
+        word_pos: u64,
+    },
+}
+
+impl RngState {
+    /// Save the RNG's state, if its kind supports that
+    fn save(rng: &FsxRng) -> Option<Self> {
+        match rng {
+            FsxRng::Xorshift(r) => Some(RngState::Xorshift(r.clone())),
+            FsxRng::Chacha8(r) => Some(RngState::Chacha8 {
+                seed:     r.get_seed(),
+                stream:   r.get_stream(),
+                word_pos: r.get_word_pos().try_into().unwrap(),
+            }),
+            FsxRng::Pcg64(_) | FsxRng::Legacy(_) => None,
+        }
+    }
+
+    fn kind(&self) -> RngKind {
+        match self {
+            RngState::Xorshift(_) => RngKind::Xorshift,
+            RngState::Chacha8 { .. } => RngKind::Chacha8,
+        }
+    }
+
+    fn restore(self) -> FsxRng {
+        match self {
+            RngState::Xorshift(r) => FsxRng::Xorshift(r),
+            RngState::Chacha8 {
+                seed,
+                stream,
+                word_pos,
+            } => {
+                let mut r = ChaCha8Rng::from_seed(seed);
+                r.set_stream(stream);
+                r.set_word_pos(word_pos.into());
+                FsxRng::Chacha8(Box::new(r))
+            }
+        }
+    }
+}
+
+/// Everything that the rest of a run depends on, as of the start of a step.
+/// Saved on failure as a .fsxstate file, and restored by --rng-state.
+#[derive(Debug, Deserialize, Serialize)]
+struct RunState {
+    seed:      u64,
+    /// Steps already completed
+    step:      u64,
+    file_size: u64,
+    cursor:    u64,
+    /// How many of each operation have been executed
+    counts:    Vec<u64>,
+    /// The expected file contents, if saved, relative to the state file
+    #[serde(default)]
+    contents:  Option<PathBuf>,
+    rng:       RngState,
+}
+
+impl RunState {
+    fn load(path: &Path) -> Self {
+        let s = fs::read_to_string(path).unwrap_or_else(|e| {
+            eprintln!("Cannot read {}: {e}", path.display());
+            process::exit(1);
+        });
+        let mut state: Self = toml::from_str(&s).unwrap_or_else(|e| {
+            eprintln!("error: {}: {e}", path.display());
+            process::exit(2);
+        });
+        state.contents = state.contents.map(|c| path.with_file_name(c));
+        state
+    }
+}
+
+/// The run's state at the start of the current step, kept in case it fails
+#[derive(Clone, Debug)]
+struct StepStart {
+    rng:       FsxRng,
+    file_size: u64,
+    cursor:    u64,
+    counts:    [u64; Op::ALL.len()],
+}
+
+/// The SplitMix64 mixing function
+fn splitmix64(x: u64) -> u64 {
+    let mut z = x.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+/// One random word of op stream v1.  Never change this!
+fn v1_word(seed: u64, step: u64, field: u64) -> u64 {
+    splitmix64(splitmix64(splitmix64(seed) ^ step) ^ field)
+}
+
+/// Fill a buffer with op stream v1 words for step 0, little-endian
+fn v1_fill(seed: u64, buf: &mut [u8]) {
+    for (i, chunk) in buf.chunks_mut(8).enumerate() {
+        let word = v1_word(seed, 0, i as u64).to_le_bytes();
+        chunk.copy_from_slice(&word[..chunk.len()]);
+    }
+}
+
+/// Fill a buffer that begins at byte `start` of a stream of 8-byte words,
+/// where `word(i)` is the stream's `i`th word
+fn fill_words(buf: &mut [u8], start: usize, word: impl Fn(usize) -> [u8; 8]) {
+    let skip = start % 8;
+    let head = ((8 - skip) % 8).min(buf.len());
+    let (head, rest) = buf.split_at_mut(head);
+    if !head.is_empty() {
+        head.copy_from_slice(&word(start / 8)[skip..skip + head.len()]);
+    }
+    let first = start.div_ceil(8);
+    for (i, chunk) in rest.chunks_mut(8).enumerate() {
+        chunk.copy_from_slice(&word(first + i)[..chunk.len()]);
+    }
+}
+
+/// Size of each chunk of a `SparseBuf`
+const SPARSE_CHUNK: usize = 1 << 20;
+
+/// Largest flen for the features that still keep whole copies of the file in
+/// RAM
+const WHOLE_COPY_MAX: u64 = 1 << 30;
+
+/// What an unallocated chunk of a `SparseBuf` contains
+static ZEROS: [u8; SPARSE_CHUNK] = [0; SPARSE_CHUNK];
+
+/// A fixed-length byte buffer that allocates memory only for the chunks that
+/// have been written.  Unallocated chunks read as zeros, and zeroing a whole
+/// chunk frees it, so a huge file's untouched and punched regions cost
+/// nothing.
+#[derive(Clone, Debug, Default)]
+struct SparseBuf {
+    len:     usize,
+    chunks:  Vec<Option<Box<[u8]>>>,
+    /// Everything changed since the last `mark`, oldest first.  Only kept
+    /// once `mark` has been called.
+    journal: Option<Vec<Undo>>,
+}
+
+/// The previous contents of a range of a `SparseBuf`
+#[derive(Clone, Debug)]
+struct Undo {
+    range: Range<usize>,
+    /// `None` if the range was unallocated
+    old:   Option<Box<[u8]>>,
+}
+
+impl SparseBuf {
+    fn new(len: usize) -> Self {
+        SparseBuf {
+            len,
+            chunks: vec![None; len.div_ceil(SPARSE_CHUNK)],
+            journal: None,
+        }
+    }
+
+    /// Start journaling changes afresh, so `rewound` can recover the current
+    /// contents
+    fn mark(&mut self) {
+        self.journal.get_or_insert_with(Vec::new).clear();
+    }
+
+    /// The contents as of the last `mark`
+    fn rewound(&self) -> SparseBuf {
+        let mut buf = SparseBuf {
+            len:     self.len,
+            chunks:  self.chunks.clone(),
+            journal: None,
+        };
+        for undo in self.journal.iter().flatten().rev() {
+            match &undo.old {
+                Some(old) => buf.write(undo.range.start, old),
+                None => buf.fill(undo.range.clone(), 0),
+            }
+        }
+        buf
+    }
+
+    fn len(&self) -> usize {
+        self.len
+    }
+
+    /// The length of chunk `i`
+    fn chunk_len(&self, i: usize) -> usize {
+        SPARSE_CHUNK.min(self.len - i * SPARSE_CHUNK)
+    }
+
+    /// Split `range` at chunk boundaries, yielding each piece's range and
+    /// its contents, or `None` if its chunk isn't allocated
+    fn segments(
+        &self,
+        range: Range<usize>,
+    ) -> impl Iterator<Item = (Range<usize>, Option<&[u8]>)> {
+        assert!(range.start <= range.end && range.end <= self.len);
+        let mut pos = range.start;
+        iter::from_fn(move || {
+            if pos >= range.end {
+                return None;
+            }
+            let (i, o) = (pos / SPARSE_CHUNK, pos % SPARSE_CHUNK);
+            let n = (SPARSE_CHUNK - o).min(range.end - pos);
+            let seg = pos..pos + n;
+            pos += n;
+            Some((seg, self.chunks[i].as_deref().map(|c| &c[o..o + n])))
+        })
+    }
+
+    /// Call `f` with each piece of `range`, split at chunk boundaries,
+    /// allocating chunks as needed
+    fn segments_mut(
+        &mut self,
+        range: Range<usize>,
+        mut f: impl FnMut(usize, &mut [u8]),
+    ) {
+        assert!(range.start <= range.end && range.end <= self.len);
+        let mut pos = range.start;
+        while pos < range.end {
+            let (i, o) = (pos / SPARSE_CHUNK, pos % SPARSE_CHUNK);
+            let n = (SPARSE_CHUNK - o).min(range.end - pos);
+            let len = self.chunk_len(i);
+            if let Some(journal) = &mut self.journal {
+                let old = self.chunks[i].as_deref().map(|c| c[o..o + n].into());
+                journal.push(Undo {
+                    range: pos..pos + n,
+                    old,
+                });
+            }
+            let chunk = self.chunks[i]
+                .get_or_insert_with(|| vec![0; len].into_boxed_slice());
+            f(pos, &mut chunk[o..o + n]);
+            pos += n;
+        }
+    }
+
+    /// The contents of `range`, copied only if it spans chunks
+    fn slice(&self, range: Range<usize>) -> Cow<'_, [u8]> {
+        let mut segs = self.segments(range.clone());
+        match (segs.next(), segs.next()) {
+            (None, _) => Cow::Borrowed(&[]),
+            (Some((_, Some(s))), None) => Cow::Borrowed(s),
+            (Some((r, None)), None) => Cow::Borrowed(&ZEROS[..r.len()]),
+            _ => Cow::Owned(self.to_vec(range)),
+        }
+    }
+
+    /// Write `range` to `file` at `offset`, including any zeros
+    fn write_to(
+        &self,
+        file: &File,
+        range: Range<usize>,
+        offset: u64,
+    ) -> io::Result<()> {
+        for (r, s) in self.segments(range.clone()) {
+            let s = s.unwrap_or(&ZEROS[..r.len()]);
+            file.write_all_at(s, offset + (r.start - range.start) as u64)?;
+        }
+        Ok(())
+    }
+
+    fn to_vec(&self, range: Range<usize>) -> Vec<u8> {
+        let mut v = vec![0; range.len()];
+        self.read(range.start, &mut v);
+        v
+    }
+
+    /// Copy out the contents at `offset`
+    fn read(&self, offset: usize, buf: &mut [u8]) {
+        for (r, s) in self.segments(offset..offset + buf.len()) {
+            let dst = &mut buf[r.start - offset..r.end - offset];
+            match s {
+                Some(s) => dst.copy_from_slice(s),
+                None => dst.fill(0),
+            }
+        }
+    }
+
+    /// Copy `data` in at `offset`
+    fn write(&mut self, offset: usize, data: &[u8]) {
+        self.segments_mut(offset..offset + data.len(), |pos, dst| {
+            dst.copy_from_slice(&data[pos - offset..pos - offset + dst.len()])
+        });
+    }
+
+    fn fill(&mut self, range: Range<usize>, value: u8) {
+        if value != 0 {
+            self.segments_mut(range, |_, dst| dst.fill(value));
+            return;
+        }
+        assert!(range.start <= range.end && range.end <= self.len);
+        let mut pos = range.start;
+        while pos < range.end {
+            let (i, o) = (pos / SPARSE_CHUNK, pos % SPARSE_CHUNK);
+            let n = (SPARSE_CHUNK - o).min(range.end - pos);
+            if n == self.chunk_len(i) {
+                let old = self.chunks[i].take();
+                if let (Some(journal), Some(_)) = (&mut self.journal, &old) {
+                    journal.push(Undo {
+                        range: pos..pos + n,
+                        old,
+                    });
+                }
+            } else if let Some(c) = &mut self.chunks[i] {
+                if let Some(journal) = &mut self.journal {
+                    journal.push(Undo {
+                        range: pos..pos + n,
+                        old:   Some(c[o..o + n].into()),
+                    });
+                }
+                c[o..o + n].fill(0);
+            }
+            pos += n;
+        }
+    }
+
+    /// Copy `src` to `dst`, like `slice::copy_within`
+    fn copy_within(&mut self, src: Range<usize>, dst: usize) {
+        let len = src.len();
+        let mut tmp = vec![0; len.min(SPARSE_CHUNK)];
+        // Copy a piece at a time, in the order that doesn't clobber the
+        // source before it's read.
+        let mut pieces = (0..len).step_by(SPARSE_CHUNK).collect::<Vec<_>>();
+        if dst > src.start {
+            pieces.reverse();
+        }
+        for done in pieces {
+            let n = SPARSE_CHUNK.min(len - done);
+            self.read(src.start + done, &mut tmp[..n]);
+            self.write(dst + done, &tmp[..n]);
+        }
+    }
+}
+
+impl Index<usize> for SparseBuf {
+    type Output = u8;
+
+    fn index(&self, i: usize) -> &u8 {
+        assert!(i < self.len);
+        match &self.chunks[i / SPARSE_CHUNK] {
+            Some(c) => &c[i % SPARSE_CHUNK],
+            None => &0,
+        }
+    }
+}
+
+/// The file's original data, for the stamp pattern with op stream legacy: the
+/// RNG's first flen bytes of output.  Each chunk is generated when first
+/// needed, from the RNG's state at its start, so a huge flen costs RAM only
+/// for the chunks that get written.
+#[derive(Clone, Debug)]
+struct OriginalBuf {
+    /// The RNG's state at the start of each chunk not yet generated
+    rngs: Vec<Option<FsxRng>>,
+    data: SparseBuf,
+}
+
+impl OriginalBuf {
+    /// Advance `rng` past `len` bytes of original data
+    fn new(rng: &mut FsxRng, len: usize) -> Self {
+        let data = SparseBuf::new(len);
+        let mut scratch = vec![0; SPARSE_CHUNK.min(len)];
+        let rngs = (0..len.div_ceil(SPARSE_CHUNK))
+            .map(|i| {
+                let saved = rng.clone();
+                rng.fill_bytes(&mut scratch[..data.chunk_len(i)]);
+                Some(saved)
+            })
+            .collect();
+        OriginalBuf { rngs, data }
+    }
+
+    /// The original data in `range`
+    fn slice(&mut self, range: Range<usize>) -> Cow<'_, [u8]> {
+        for i in range.start / SPARSE_CHUNK..range.end.div_ceil(SPARSE_CHUNK) {
+            if let Some(mut rng) = self.rngs[i].take() {
+                let start = i * SPARSE_CHUNK;
+                let chunk = start..start + self.data.chunk_len(i);
+                self.data.segments_mut(chunk, |_, buf| rng.fill_bytes(buf));
+            }
+        }
+        self.data.slice(range)
+    }
+}
+
+/// How the exerciser models the test file's expected contents
+#[derive(
+    Clone, Copy, Debug, Default, Deserialize, Serialize, Eq, PartialEq,
+)]
+#[serde(rename_all = "lowercase")]
+enum ShadowMode {
+    /// Keep the whole expected contents in memory
+    #[default]
+    Full,
+    /// Keep only a checksum of each `CHECKSUM_BLOCK`
+    Checksum,
+}
+
+/// Granularity of the checksums kept with `ShadowMode::Checksum`
+const CHECKSUM_BLOCK: usize = 1 << 16;
+
+/// Lookup table for `crc32`
+const CRC32_TABLE: [u32; 256] = {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut c = i as u32;
+        let mut k = 0;
+        while k < 8 {
+            c = if c & 1 == 0 {
+                c >> 1
+            } else {
+                0xedb8_8320 ^ (c >> 1)
+            };
+            k += 1;
+        }
+        table[i] = c;
+        i += 1;
+    }
+    table
+};
+
+/// The IEEE CRC-32 of `data`, as used by zlib
+fn crc32(data: &[u8]) -> u32 {
+    !data.iter().fold(!0u32, |c, &b| {
+        CRC32_TABLE[((c ^ u32::from(b)) & 0xff) as usize] ^ (c >> 8)
+    })
+}
+
+/// The model of the file kept with `ShadowMode::Checksum`.  The shadow buffer
+/// then holds only the blocks that the current operation uses, read back from
+/// the file and verified as they're loaded.
+#[derive(Debug)]
+struct Checksums {
+    /// CRC-32 of each block, counting any bytes past EoF as zeros
+    crcs:   Vec<u32>,
+    /// The step that last changed each block, or 0 for none, reported with a
+    /// miscompare so the log dump can be searched for the last modifier.  It
+    /// isn't enough to regenerate the block's expected contents.
+    steps:  Vec<u64>,
+    /// CRC-32 of a whole block of zeros, which most blocks of a huge file
+    /// will be
+    zero:   u32,
+    /// Blocks loaded into the shadow buffer by the current operation
+    loaded: Vec<usize>,
+    /// Whether the current operation changed the loaded blocks
+    dirty:  bool,
+}
+
+impl Checksums {
+    /// Checksum every block of `buf`, a file of `file_size` bytes
+    fn new(buf: &SparseBuf, file_size: u64) -> Self {
+        let mut checksums = Checksums {
+            crcs:   Vec::new(),
+            steps:  vec![0; buf.len().div_ceil(CHECKSUM_BLOCK)],
+            zero:   crc32(&ZEROS[..CHECKSUM_BLOCK]),
+            loaded: Vec::new(),
+            dirty:  false,
+        };
+        checksums.crcs = (0..buf.len().div_ceil(CHECKSUM_BLOCK))
+            .map(|b| checksums.crc(buf, b, file_size))
+            .collect();
+        checksums
+    }
+
+    /// Record block `b`'s checksum after step `step`
+    fn set(&mut self, b: usize, crc: u32, step: u64) {
+        if self.crcs[b] != crc {
+            self.crcs[b] = crc;
+            self.steps[b] = step;
+        }
+    }
+
+    /// The range of block `b`, in a file at most `flen` bytes long
+    fn block(b: usize, flen: usize) -> Range<usize> {
+        let start = b * CHECKSUM_BLOCK;
+        start..(start + CHECKSUM_BLOCK).min(flen)
+    }
+
+    /// Checksum block `b` of `buf`, a file of `file_size` bytes
+    fn crc(&self, buf: &SparseBuf, b: usize, file_size: u64) -> u32 {
+        let range = Self::block(b, buf.len());
+        let end = range.end.min(file_size as usize).max(range.start);
+        if buf.segments(range.start..end).all(|(_, s)| s.is_none()) {
+            return self.zeros(range.len());
+        }
+        let mut data = buf.to_vec(range.start..end);
+        data.resize(range.len(), 0);
+        crc32(&data)
+    }
+
+    /// Checksum `len` bytes of zeros
+    fn zeros(&self, len: usize) -> u32 {
+        if len == CHECKSUM_BLOCK {
+            self.zero
+        } else {
+            crc32(&ZEROS[..len])
+        }
+    }
+}
+
+/// Generate `pattern`'s data for step `step` into `buf`, which belongs at
+/// `start`.  The stamp pattern needs the file's original data there, too.
+fn gendata(
+    pattern: &Pattern,
+    seed: u64,
+    step: u64,
+    start: usize,
+    buf: &mut [u8],
+    orig: Option<&[u8]>,
+) {
+    let size = buf.len();
+    // Random data that depends only on the step and the word's index
+    let word = |i: usize| v1_word(seed, step, 7 + i as u64).to_le_bytes();
+    match pattern {
+        Pattern::Stamp => {
+            // Odd offsets mix in the original data
+            let stamp = (step % 256) as u8;
+            let orig = orig.unwrap();
+            buf.fill(stamp);
+            let first = (start % 2).min(size);
+            if first > 0 {
+                buf[0] = stamp.wrapping_add(orig[0]);
+            }
+            let pairs = buf[first..].chunks_exact_mut(2);
+            for (b, o) in pairs.zip(orig[first..].chunks_exact(2)) {
+                b[1] = stamp.wrapping_add(o[1]);
+            }
+        }
+        Pattern::Stamp64 => {
+            fill_words(buf, start, |i| stamp64_encode(step, i * 8))
+        }
+        Pattern::Random => fill_words(buf, start, word),
+        Pattern::Zeros => buf.fill(0),
+        Pattern::Compressible => {
+            let mut done = 0;
+            while done < size {
+                let uoff = start + done;
+                let n = (COMPRESSIBLE_RUN - uoff % COMPRESSIBLE_RUN)
+                    .min(size - done);
+                buf[done..done + n].fill(word(uoff / COMPRESSIBLE_RUN)[0]);
+                done += n;
+            }
+        }
+        Pattern::Dedupe { block } => {
+            let block = block.get();
+            // The same for every step, so that every block is identical.  No
+            // step is numbered u64::MAX.
+            let word =
+                |i: usize| v1_word(seed, u64::MAX, 7 + i as u64).to_le_bytes();
+            // Where a whole block has already been generated, if anywhere
+            let mut whole = None;
+            let mut done = 0;
+            while done < size {
+                let i = (start + done) % block;
+                let n = (block - i).min(size - done);
+                if let Some(w) = whole {
+                    buf.copy_within(w + i..w + i + n, done);
+                } else {
+                    fill_words(&mut buf[done..done + n], i, word);
+                    if n == block {
+                        whole = Some(done);
+                    }
+                }
+                done += n;
+            }
+        }
+    }
+}
+
+/// Which artifacts to save on failure
+#[derive(
+    Clone, Copy, Debug, Default, Deserialize, Serialize, Eq, PartialEq,
+)]
+#[serde(rename_all = "lowercase")]
+enum ArtifactMode {
+    /// The whole expected and actual contents, in .fsxgood and .fsxbad
+    #[default]
+    Full,
+    /// Only the differing regions, in .fsxdiff
+    Diff,
+}
+
+/// One differing region of a .fsxdiff file
+#[derive(Debug, Serialize)]
+struct DiffRegion {
+    offset:   u64,
+    /// Hex-encoded expected contents
+    expected: String,
+    /// Hex-encoded actual contents
+    actual:   String,
+}
+
+/// Contents of a .fsxdiff file
+#[derive(Debug, Default, Serialize)]
+struct DiffArtifact {
+    expected_size: u64,
+    actual_size:   u64,
+    /// Were regions omitted, for exceeding `FSXDIFF_MAX`?
+    truncated:     bool,
+    region:        Vec<DiffRegion>,
+}
+
+/// Most bytes of differing regions to save to .fsxdiff
+const FSXDIFF_MAX: usize = 1 << 20;
+
+/// Most segments of the file's layout to log on failure, besides those that
+/// overlap the miscompare
+const LAYOUT_MAX: usize = 64;
+
+/// Differences closer together than this are saved as one region
+const FSXDIFF_GAP: usize = 16;
+
+fn hex(buf: &[u8]) -> String {
+    buf.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+/// When to save the binary operation trace
+#[derive(
+    Clone, Copy, Debug, Default, Deserialize, Serialize, Eq, PartialEq,
+)]
+#[serde(rename_all = "lowercase")]
+enum TraceMode {
+    #[default]
+    Off,
+    /// Save it after every run
+    Always,
+    /// Save it only if the run fails
+    Failure,
+}
+
+/// The first bytes of every .fsxops file
+const TRACE_MAGIC: &[u8; 6] = b"FSXOPS";
+
+/// Version of the .fsxops file format
+const TRACE_VERSION: u16 = 1;
+
+/// An operation and every random value drawn for it
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+struct OpRecord {
+    op:     Op,
+    /// Index of the target file, when using multiple files
+    target: u32,
+    size:   u64,
+    offset: u32,
+    /// Truncate's new size, copy_file_range's other offset,
+    /// copy_file_range_overlap's shift, or posix_fadvise's advice
+    extra:  u32,
+    /// fsize_limit's truncate, or copy_file_range_overlap's direction
+    flag:   bool,
+}
+
+impl OpRecord {
+    /// Size of an encoded record, in bytes
+    const LEN: usize = 22;
+
+    /// Encode as little-endian
+    fn encode(&self) -> [u8; Self::LEN] {
+        let mut buf = [0u8; Self::LEN];
+        buf[0] = self.op as u8;
+        buf[1..5].copy_from_slice(&self.target.to_le_bytes());
+        buf[5..13].copy_from_slice(&self.size.to_le_bytes());
+        buf[13..17].copy_from_slice(&self.offset.to_le_bytes());
+        buf[17..21].copy_from_slice(&self.extra.to_le_bytes());
+        buf[21] = u8::from(self.flag);
+        buf
+    }
+
+    fn decode(buf: &[u8]) -> Option<Self> {
+        Some(OpRecord {
+            op:     *Op::ALL.get(usize::from(buf[0]))?,
+            target: u32::from_le_bytes(buf[1..5].try_into().unwrap()),
+            size:   u64::from_le_bytes(buf[5..13].try_into().unwrap()),
+            offset: u32::from_le_bytes(buf[13..17].try_into().unwrap()),
+            extra:  u32::from_le_bytes(buf[17..21].try_into().unwrap()),
+            flag:   buf[21] != 0,
+        })
+    }
+}
+
+/// Read a .fsxops trace, returning its seed and records
+fn read_trace(path: &Path) -> io::Result<(u64, Vec<OpRecord>)> {
+    let invalid = |msg: String| io::Error::new(io::ErrorKind::InvalidData, msg);
+    let data = fs::read(path)?;
+    if data.len() < 16 || &data[..6] != TRACE_MAGIC {
+        return Err(invalid("not an fsx trace".into()));
+    }
+    let version = u16::from_le_bytes(data[6..8].try_into().unwrap());
+    if version != TRACE_VERSION {
+        return Err(invalid(format!("unsupported trace version {version}")));
+    }
+    let seed = u64::from_le_bytes(data[8..16].try_into().unwrap());
+    let body = &data[16..];
+    if body.len() % OpRecord::LEN != 0 {
+        return Err(invalid("truncated record".into()));
+    }
+    let records = body
+        .chunks_exact(OpRecord::LEN)
+        .enumerate()
+        .map(|(i, chunk)| {
+            OpRecord::decode(chunk).ok_or_else(|| {
+                invalid(format!("unknown operation at step {}", i + 1))
+            })
+        })
+        .collect::<io::Result<Vec<_>>>()?;
+    Ok((seed, records))
+}
+
+/// The binary operation trace being recorded
+struct Trace {
+    mode:   TraceMode,
+    path:   PathBuf,
+    writer: Mutex<BufWriter<File>>,
+}
+
+impl Trace {
+    fn create(mode: TraceMode, path: PathBuf, seed: u64) -> io::Result<Self> {
+        let mut writer = BufWriter::new(File::create(&path)?);
+        writer.write_all(TRACE_MAGIC)?;
+        writer.write_all(&TRACE_VERSION.to_le_bytes())?;
+        writer.write_all(&seed.to_le_bytes())?;
+        Ok(Trace {
+            mode,
+            path,
+            writer: Mutex::new(writer),
+        })
+    }
+
+    fn record(&self, rec: &OpRecord) -> io::Result<()> {
+        self.writer.lock().unwrap().write_all(&rec.encode())
+    }
+
+    /// Flush the trace, if nobody else is writing it
+    fn flush(&self) {
+        if let Ok(mut w) = self.writer.try_lock() {
+            if let Err(e) = w.flush() {
+                warn!("writing {}: {}", self.path.display(), e);
+            }
+        }
+    }
+}
+
+/// Sub-buckets per power of two in a `Histogram`
+const HIST_SUB: u64 = 8;
+
+/// A log-linear histogram of latencies in nanoseconds, like HdrHistogram,
+/// accurate to within 1/8
+#[derive(Clone)]
+struct Histogram {
+    counts: Vec<u64>,
+    max:    u64,
+}
+
+impl Default for Histogram {
+    fn default() -> Self {
+        Histogram {
+            counts: vec![0; Self::bucket(u64::MAX) + 1],
+            max:    0,
+        }
+    }
+}
+
+impl Histogram {
+    fn bucket(ns: u64) -> usize {
+        if ns < HIST_SUB {
+            return ns as usize;
+        }
+        let exp = 63 - u64::from(ns.leading_zeros());
+        let shift = exp - HIST_SUB.trailing_zeros() as u64;
+        ((shift + 1) * HIST_SUB + ((ns >> shift) & (HIST_SUB - 1))) as usize
+    }
+
+    /// The largest value that falls in the given bucket
+    fn upper_bound(bucket: usize) -> u64 {
+        let bucket = bucket as u64;
+        if bucket < HIST_SUB {
+            return bucket;
+        }
+        let shift = bucket / HIST_SUB - 1;
+        let lower = (HIST_SUB + bucket % HIST_SUB) << shift;
+        lower + ((1 << shift) - 1)
+    }
+
+    fn record(&mut self, latency: Duration) {
+        let ns = u64::try_from(latency.as_nanos()).unwrap_or(u64::MAX);
+        self.counts[Self::bucket(ns)] += 1;
+        self.max = self.max.max(ns);
+    }
+
+    fn merge(&mut self, other: &Histogram) {
+        for (a, b) in self.counts.iter_mut().zip(other.counts.iter()) {
+            *a += b;
+        }
+        self.max = self.max.max(other.max);
+    }
+
+    fn count(&self) -> u64 {
+        self.counts.iter().sum()
+    }
+
+    /// The latency in nanoseconds that `p` of all samples were no slower than
+    fn percentile(&self, p: f64) -> u64 {
+        let rank = ((p * self.count() as f64).ceil() as u64).max(1);
+        let mut seen = 0;
+        for (bucket, &count) in self.counts.iter().enumerate() {
+            seen += count;
+            if seen >= rank {
+                return Self::upper_bound(bucket).min(self.max);
+            }
+        }
+        self.max
+    }
+}
+
+/// Print a table of latency percentiles, in microseconds
+fn print_latencies(latencies: &[Histogram]) {
+    println!(
+        "{:16} {:>10} {:>10} {:>10} {:>10} {:>10} {:>10}",
+        "latency (us)", "count", "p50", "p90", "p99", "p99.9", "max"
+    );
+    for (op, h) in Op::ALL.iter().zip(latencies) {
+        let count = h.count();
+        if count == 0 {
+            continue;
+        }
+        let us = |ns: u64| ns as f64 / 1000.0;
+        println!(
+            "{:16} {:>10} {:>10.1} {:>10.1} {:>10.1} {:>10.1} {:>10.1}",
+            op.to_string(),
+            count,
+            us(h.percentile(0.5)),
+            us(h.percentile(0.9)),
+            us(h.percentile(0.99)),
+            us(h.percentile(0.999)),
+            us(h.max)
+        );
+    }
+}
+
+/// A progress line on the terminal, for runs with -N
+struct Meter {
+    start: Instant,
+    last:  Instant,
+}
+
+impl Meter {
+    /// How often to redraw the line
+    const INTERVAL: Duration = Duration::from_millis(250);
+
+    fn draw(&mut self, steps: u64, numops: u64) {
+        let now = Instant::now();
+        if now.duration_since(self.last) < Self::INTERVAL {
+            return;
+        }
+        self.last = now;
+        let rate = steps as f64 / now.duration_since(self.start).as_secs_f64();
+        let eta = (numops.saturating_sub(steps) as f64 / rate) as u64;
+        eprint!(
+            "\r\x1b[Kstep {steps}/{numops} ({:.1}%), {rate:.0} ops/s, ETA \
+             {}:{:02}:{:02}",
+            100.0 * steps as f64 / numops as f64,
+            eta / 3600,
+            eta / 60 % 60,
+            eta % 60
+        );
+    }
+
+    /// Erase the line, so it doesn't mingle with whatever comes next
+    fn clear() {
+        eprint!("\r\x1b[K");
+    }
+}
+
+/// Counters for --prometheus and --statsd, shared by all threads
+#[cfg(feature = "metrics")]
+struct Metrics {
+    /// Operations executed, indexed like `Op::ALL`
+    ops:           Vec<AtomicU64>,
+    /// Total latency of those operations, in nanoseconds
+    latency_ns:    Vec<AtomicU64>,
+    bytes_read:    AtomicU64,
+    bytes_written: AtomicU64,
+    skips:         AtomicU64,
+}
+
+/// A snapshot of `Metrics`
+#[cfg(feature = "metrics")]
+#[derive(Clone, Default)]
+struct MetricValues {
+    ops:           Vec<u64>,
+    latency_ns:    Vec<u64>,
+    bytes_read:    u64,
+    bytes_written: u64,
+    skips:         u64,
+}
+
+#[cfg(feature = "metrics")]
+impl Metrics {
+    fn new() -> Self {
+        let counters = || Op::ALL.iter().map(|_| AtomicU64::new(0)).collect();
+        Metrics {
+            ops:           counters(),
+            latency_ns:    counters(),
+            bytes_read:    AtomicU64::new(0),
+            bytes_written: AtomicU64::new(0),
+            skips:         AtomicU64::new(0),
+        }
+    }
+
+    /// Record one step.  `latency` is `None` if the op didn't really run.
+    fn record(
+        &self,
+        op: Op,
+        latency: Option<Duration>,
+        read: u64,
+        written: u64,
+        skips: u64,
+    ) {
+        if let Some(latency) = latency {
+            let ns = u64::try_from(latency.as_nanos()).unwrap_or(u64::MAX);
+            self.ops[op as usize].fetch_add(1, Ordering::Relaxed);
+            self.latency_ns[op as usize].fetch_add(ns, Ordering::Relaxed);
+        }
+        self.bytes_read.fetch_add(read, Ordering::Relaxed);
+        self.bytes_written.fetch_add(written, Ordering::Relaxed);
+        self.skips.fetch_add(skips, Ordering::Relaxed);
+    }
+
+    fn values(&self) -> MetricValues {
+        let load = |v: &Vec<AtomicU64>| {
+            v.iter().map(|x| x.load(Ordering::Relaxed)).collect()
+        };
+        MetricValues {
+            ops:           load(&self.ops),
+            latency_ns:    load(&self.latency_ns),
+            bytes_read:    self.bytes_read.load(Ordering::Relaxed),
+            bytes_written: self.bytes_written.load(Ordering::Relaxed),
+            skips:         self.skips.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Format the metrics in the Prometheus text exposition format
+    fn to_prometheus(&self) -> String {
+        fn family(s: &mut String, name: &str, kind: &str, help: &str) {
+            *s += &format!("# HELP {name} {help}\n# TYPE {name} {kind}\n");
+        }
+
+        let v = self.values();
+        let mut s = String::new();
+        family(&mut s, "fsx_ops_total", "counter", "Operations executed.");
+        for (op, n) in Op::ALL.iter().zip(v.ops.iter()) {
+            s += &format!("fsx_ops_total{{op=\"{op}\"}} {n}\n");
+        }
+        family(
+            &mut s,
+            "fsx_op_latency_seconds",
+            "summary",
+            "Latency of executed operations.",
+        );
+        for ((op, n), ns) in
+            Op::ALL.iter().zip(v.ops.iter()).zip(v.latency_ns.iter())
+        {
+            s += &format!(
+                "fsx_op_latency_seconds_sum{{op=\"{op}\"}} \
+                 {}\nfsx_op_latency_seconds_count{{op=\"{op}\"}} {n}\n",
+                *ns as f64 / 1e9
+            );
+        }
+        for (name, help, value) in [
+            ("fsx_bytes_read_total", "Bytes read.", v.bytes_read),
+            ("fsx_bytes_written_total", "Bytes written.", v.bytes_written),
+            (
+                "fsx_skipped_ops_total",
+                "Operations skipped because of the errors policy.",
+                v.skips,
+            ),
+        ] {
+            family(&mut s, name, "counter", help);
+            s += &format!("{name} {value}\n");
+        }
+        s
+    }
+}
+
+/// How long a Prometheus scraper may take to send its request or read the
+/// response, so a stalled client can't block the others
+#[cfg(feature = "metrics")]
+const PROMETHEUS_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Serve the metrics to Prometheus, from a thread of its own
+#[cfg(feature = "metrics")]
+fn start_prometheus(addr: &str, metrics: Arc<Metrics>) {
+    let listener = match std::net::TcpListener::bind(addr) {
+        Ok(l) => l,
+        Err(e) => {
+            eprintln!("Cannot listen on {addr}: {e}");
+            process::exit(1);
+        }
+    };
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let Ok(mut stream) = stream else {
+                continue;
+            };
+            let _ = stream.set_read_timeout(Some(PROMETHEUS_TIMEOUT));
+            let _ = stream.set_write_timeout(Some(PROMETHEUS_TIMEOUT));
+            // Every request gets the metrics, whatever its path
+            let mut buf = [0u8; 1024];
+            let _ = io::Read::read(&mut stream, &mut buf);
+            let body = metrics.to_prometheus();
+            let _ = write!(
+                stream,
+                "HTTP/1.0 200 OK\r\nContent-Type: text/plain; \
+                 version=0.0.4\r\nContent-Length: {}\r\n\r\n{body}",
+                body.len()
+            );
+        }
+    });
+}
+
+/// How often to push metrics to statsd
+#[cfg(feature = "metrics")]
+const STATSD_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Push the metrics to statsd periodically, from a thread of its own
+#[cfg(feature = "metrics")]
+fn start_statsd(addr: &str, metrics: Arc<Metrics>) {
+    let socket =
+        std::net::ToSocketAddrs::to_socket_addrs(addr).and_then(|mut addrs| {
+            let addr = addrs.next().ok_or(io::ErrorKind::NotFound)?;
+            let local = if addr.is_ipv4() {
+                "0.0.0.0:0"
+            } else {
+                "[::]:0"
+            };
+            let socket = std::net::UdpSocket::bind(local)?;
+            socket.connect(addr)?;
+            Ok(socket)
+        });
+    let socket = match socket {
+        Ok(s) => s,
+        Err(e) => {
+            eprintln!("Cannot connect to statsd at {addr}: {e}");
+            process::exit(1);
+        }
+    };
+    thread::spawn(move || {
+        let mut last = MetricValues {
+            ops: vec![0; Op::ALL.len()],
+            latency_ns: vec![0; Op::ALL.len()],
+            ..Default::default()
+        };
+        loop {
+            thread::sleep(STATSD_INTERVAL);
+            let now = metrics.values();
+            let mut lines = Vec::new();
+            for (i, op) in Op::ALL.iter().enumerate() {
+                let n = now.ops[i] - last.ops[i];
+                if n == 0 {
+                    continue;
+                }
+                let ns = now.latency_ns[i] - last.latency_ns[i];
+                lines.push(format!("fsx.ops.{op}:{n}|c"));
+                let ms = ns as f64 / n as f64 / 1e6;
+                lines.push(format!("fsx.latency.{op}:{ms:.3}|ms"));
+            }
+            lines.push(format!(
+                "fsx.bytes_read:{}|c",
+                now.bytes_read - last.bytes_read
+            ));
+            lines.push(format!(
+                "fsx.bytes_written:{}|c",
+                now.bytes_written - last.bytes_written
+            ));
+            lines.push(format!("fsx.skipped:{}|c", now.skips - last.skips));
+            for line in lines {
+                // Metrics are best-effort
+                let _ = socket.send(line.as_bytes());
+            }
+            last = now;
+        }
+    });
+}
+
+/// Append an operation's start time and duration to its log line, if known
+fn with_timing(
+    line: String,
+    timing: Option<&(SystemTime, Duration)>,
+) -> String {
+    match timing {
+        Some((time, elapsed)) => format!(
+            "{line}  at {} took {}us",
+            humantime::format_rfc3339_micros(*time),
+            elapsed.as_micros()
+        ),
+        None => line,
+    }
+}
+
+/// The complete operation log, for --oplog-file
+struct OplogFile {
+    path:   PathBuf,
+    writer: Mutex<BufWriter<File>>,
+}
+
+impl OplogFile {
+    fn create(path: PathBuf) -> io::Result<Self> {
+        let writer = BufWriter::new(File::create(&path)?);
+        Ok(OplogFile {
+            path,
+            writer: Mutex::new(writer),
+        })
+    }
+
+    fn append(&self, line: &str) -> io::Result<()> {
+        writeln!(self.writer.lock().unwrap(), "{line}")
+    }
+
+    /// Flush the file, if nobody else is writing it
+    fn flush(&self) {
+        if let Ok(mut w) = self.writer.try_lock() {
+            if let Err(e) = w.flush() {
+                warn!("writing {}: {}", self.path.display(), e);
+            }
+        }
+    }
+}
+
+/// Periodic progress snapshots, for --stats-interval
+struct Stats {
+    interval:   Duration,
+    /// Append snapshots here, instead of printing them
+    file:       Option<(PathBuf, File)>,
+    start:      Instant,
+    last:       Instant,
+    last_steps: u64,
+}
+
+/// One CSV row per operation, for --csv
+struct CsvTrace {
+    path:   PathBuf,
+    writer: Mutex<BufWriter<File>>,
+}
+
+impl CsvTrace {
+    fn create(path: PathBuf) -> io::Result<Self> {
+        let mut writer = BufWriter::new(File::create(&path)?);
+        writeln!(writer, "step,op,offset,len,duration_us,result")?;
+        Ok(CsvTrace {
+            path,
+            writer: Mutex::new(writer),
+        })
+    }
+
+    fn record(
+        &self,
+        step: u64,
+        op: Op,
+        range: Option<(u64, u64)>,
+        duration: Duration,
+        result: &str,
+    ) -> io::Result<()> {
+        let (offset, len) = match range {
+            Some((offset, len)) => (offset.to_string(), len.to_string()),
+            None => (String::new(), String::new()),
+        };
+        writeln!(
+            self.writer.lock().unwrap(),
+            "{step},{op},{offset},{len},{},{result}",
+            duration.as_micros()
+        )
+    }
+
+    /// Flush the file, if nobody else is writing it
+    fn flush(&self) {
+        if let Ok(mut w) = self.writer.try_lock() {
+            if let Err(e) = w.flush() {
+                warn!("writing {}: {}", self.path.display(), e);
+            }
+        }
+    }
+}
+
+/// The return value of a system call, as recorded by --strace
+#[cfg(feature = "strace")]
+trait SysRet {
+    fn ret(&self) -> String;
+}
+
+#[cfg(feature = "strace")]
+impl SysRet for () {
+    fn ret(&self) -> String {
+        "0".to_owned()
+    }
+}
+
+#[cfg(feature = "strace")]
+impl SysRet for usize {
+    fn ret(&self) -> String {
+        self.to_string()
+    }
+}
+
+#[cfg(feature = "strace")]
+impl SysRet for ptr::NonNull<c_void> {
+    fn ret(&self) -> String {
+        format!("{:p}", *self)
+    }
+}
+
+#[cfg(feature = "strace")]
+impl SysRet for fs::Metadata {
+    fn ret(&self) -> String {
+        "0".to_owned()
+    }
+}
+
+/// Every system call that operations make on the test file, for --strace
+#[cfg(feature = "strace")]
+struct Strace {
+    path:   PathBuf,
+    writer: Mutex<BufWriter<File>>,
+}
+
+#[cfg(feature = "strace")]
+impl Strace {
+    fn create(path: PathBuf) -> io::Result<Self> {
+        let writer = BufWriter::new(File::create(&path)?);
+        Ok(Strace {
+            path,
+            writer: Mutex::new(writer),
+        })
+    }
+
+    fn record<T: SysRet, E: SysError>(
+        &self,
+        step: u64,
+        name: &str,
+        args: &str,
+        r: &Result<T, E>,
+    ) {
+        let ret = match r {
+            Ok(v) => v.ret(),
+            Err(e) => {
+                let errno = nix::Error::from_raw(e.errno());
+                format!("-1 {:?} ({})", errno, errno.desc())
+            }
+        };
+        let mut w = self.writer.lock().unwrap();
+        if let Err(e) = writeln!(w, "{step} {name}({args}) = {ret}") {
+            warn!("writing {}: {}", self.path.display(), e);
+        }
+    }
+
+    /// Flush the file, if nobody else is writing it
+    fn flush(&self) {
+        if let Ok(mut w) = self.writer.try_lock() {
+            if let Err(e) = w.flush() {
+                warn!("writing {}: {}", self.path.display(), e);
+            }
+        }
+    }
+}
+
+/// Everything needed to reproduce a run, except for its length
+#[derive(Clone, Debug)]
+struct Repro {
+    /// Name of the fsx executable
+    argv0:  String,
+    /// Command line options, other than -f and -N
+    args:   Vec<String>,
+    /// The resolved config, saved upon failure
+    config: String,
+    /// File name to operate on
+    fname:  PathBuf,
+}
+
+impl Repro {
+    fn new(cli: &Cli, fname: &Path, config: &Config) -> Self {
+        let argv0 = std::env::args().next().unwrap_or_else(|| "fsx".into());
+        let mut args = Vec::new();
+        let mut arg = |flag: &str, value: String| {
+            args.push(flag.to_owned());
+            args.push(value);
+        };
+        if cli.opnum.get() > 1 {
+            arg("-b", cli.opnum.to_string());
+        }
+        for (from, to) in &cli.monitor {
+            arg("-m", format!("{from}:{to}"));
+        }
+        if let Some((seed, _)) = &cli.seed {
+            arg("-S", seed.to_string());
+        }
+        if let Some(dir) = &cli.artifacts_dir {
+            arg("-P", dir.display().to_string());
+        }
+        if let Some(oracle) = &cli.oracle {
+            arg("--oracle", oracle.display().to_string());
+        }
+        if let Some(trace) = &cli.replay {
+            arg("--replay", trace.display().to_string());
+        }
+        for (a, b) in cli.skip_ops.iter() {
+            arg("--skip-ops", format!("{a}:{b}"));
+        }
+        if let Some(n) = cli.inject {
+            arg("--inject", n.to_string());
+        }
+        if let Some(errno) = cli.inject_errno {
+            let name = ERRNOS.iter().find(|(_, e)| *e == errno).unwrap().0;
+            arg("--inject-errno", name.to_owned());
+        }
+        if cli.monitor_only {
+            args.push("--monitor-only".to_owned());
+        }
+        Repro {
+            argv0,
+            args,
+            config: toml::to_string(config).unwrap(),
+            fname: fname.to_owned(),
+        }
+    }
+
+    /// A copy-pastable command line that reproduces the first `steps`
+    /// operations, using a config file saved to `config_path`.
+    fn command(&self, steps: u64, config_path: &Path) -> String {
+        let mut words = vec![self.argv0.clone()];
+        words.extend(self.args.iter().cloned());
+        words.push("-N".to_owned());
+        words.push(steps.to_string());
+        words.push("-f".to_owned());
+        words.push(config_path.display().to_string());
+        words.push(self.fname.display().to_string());
+        words
+            .iter()
+            .map(|w| shell_quote(w))
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+}
+
+/// Quote a word for a POSIX shell, if it needs it
+fn shell_quote(word: &str) -> String {
+    let safe = |c: char| c.is_ascii_alphanumeric() || "-_./:,=+@%".contains(c);
+    if !word.is_empty() && word.chars().all(safe) {
+        word.to_owned()
+    } else {
+        format!("'{}'", word.replace('\'', "'\\''"))
+    }
+}
+
+/// Fill in the run-specific parts of an --artifact-name template, leaving
+/// {name} for `artifact_path`
+fn resolve_artifact_name(template: &str, seed: u64) -> String {
+    let timestamp = humantime::format_rfc3339_seconds(SystemTime::now())
+        .to_string()
+        .replace(['-', ':'], "");
+    template
+        .replace("{seed}", &seed.to_string())
+        .replace("{timestamp}", &timestamp)
+        .replace("{pid}", &process::id().to_string())
+}
+
+/// Path for an artifact named after the test file by the `name` template,
+/// with the given suffix
+fn artifact_path(
+    artifacts_dir: Option<&Path>,
+    fname: &Path,
+    name: &str,
+    suffix: &str,
+) -> PathBuf {
+    let mut final_component = OsString::new();
+    for (i, part) in name.split("{name}").enumerate() {
+        if i > 0 {
+            final_component.push(fname.file_name().unwrap());
+        }
+        final_component.push(part);
+    }
+    final_component.push(suffix);
+    let mut path = if let Some(d) = artifacts_dir {
+        d.to_owned()
+    } else {
+        let mut fname = fname.to_owned();
+        fname.pop();
+        fname
+    };
+    path.push(final_component);
+    path
+}
+
+/// Save `buf` to `path`, leaving holes where it's unallocated
+fn save_sparse(buf: &SparseBuf, path: &Path) -> io::Result<()> {
+    let f = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(path)?;
+    f.set_len(buf.len() as u64)?;
+    buf.segments(0..buf.len())
+        .filter_map(|(r, s)| s.map(|s| (r.start, s)))
+        .try_for_each(|(o, s)| f.write_all_at(s, o as u64))
+}
+
+/// What to do when an operation exceeds its time limit
+#[derive(
+    Clone, Copy, Debug, Default, Deserialize, Serialize, Eq, PartialEq,
+)]
+#[serde(rename_all = "lowercase")]
+enum TimeoutAction {
+    /// Log a warning, and keep going
+    Warn,
+    /// Dump the operation log, and keep going
+    Dump,
+    /// Dump the operation log, save the good file, and abort
+    #[default]
+    Abort,
+}
+
+/// Time limit for one kind of operation
+#[derive(Clone, Copy, Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+struct OpTimeout {
+    /// In seconds
+    limit:  f64,
+    #[serde(default)]
+    action: TimeoutAction,
+}
+
+/// Periodically inject faults into the underlying device
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+struct FaultConfig {
+    /// dm-flakey device name (Linux) or gnop provider name (FreeBSD)
+    device:   String,
+    #[serde(default)]
+    mode:     FaultMode,
+    /// Begin a fault window every N operations
+    interval: NonZeroU64,
+    /// Each fault window lasts this many operations
+    duration: NonZeroU64,
+}
+
+/// A fault window in progress
+struct FaultWindow {
+    start:       u64,
+    /// The file's contents when the window began
+    synced:      SparseBuf,
+    synced_size: u64,
+    /// The file's contents after each operation during the window
+    states:      Vec<(SparseBuf, u64)>,
+    /// The EIO error policy before the window began
+    eio:         Option<ErrorPolicy>,
+}
+
+/// Fault injection state
+struct Fault {
+    conf:   FaultConfig,
+    /// Shell commands to begin and end a fault window
+    on:     String,
+    off:    String,
+    window: Option<FaultWindow>,
+}
+
+cfg_if! {
+    if #[cfg(target_os = "linux")] {
+        /// Commands to switch a dm-flakey device between faulty and healthy
+        fn fault_commands(conf: &FaultConfig) -> Result<(String, String), String> {
+            let output = process::Command::new("dmsetup")
+                .arg("table")
+                .arg(&conf.device)
+                .output()
+                .map_err(|e| format!("Cannot run dmsetup: {e}"))?;
+            let table = String::from_utf8_lossy(&output.stdout);
+            let fields = table.split_whitespace().collect::<Vec<_>>();
+            if !output.status.success()
+                || fields.len() < 5
+                || fields[2] != "flakey"
+            {
+                return Err(format!("{} is not a dm-flakey device", conf.device));
+            }
+            // start, length, "flakey", underlying device, offset
+            let prefix = fields[..5].join(" ");
+            let feature = match conf.mode {
+                FaultMode::Error => "error_writes",
+                FaultMode::Drop => "drop_writes",
+            };
+            let dev = shell_quote(&conf.device);
+            let load = |table: String| {
+                format!(
+                    "dmsetup suspend {dev} && \
+                     dmsetup load {dev} --table {} && \
+                     dmsetup resume {dev}",
+                    shell_quote(&table)
+                )
+            };
+            Ok((
+                load(format!("{prefix} 0 3600 1 {feature}")),
+                load(format!("{prefix} 3600 0")),
+            ))
+        }
+    } else if #[cfg(target_os = "freebsd")] {
+        /// Commands to switch a gnop provider between faulty and healthy
+        fn fault_commands(conf: &FaultConfig) -> Result<(String, String), String> {
+            if conf.mode == FaultMode::Drop {
+                return Err("gnop cannot drop writes".to_string());
+            }
+            let dev = shell_quote(&conf.device);
+            Ok((
+                format!("gnop configure -e 5 -w 100 {dev}"),
+                format!("gnop configure -w 0 {dev}"),
+            ))
+        }
+    } else {
+        fn fault_commands(_conf: &FaultConfig) -> Result<(String, String), String> {
+            Err("fault injection is not supported on this platform".to_string())
+        }
+    }
+}
+
+/// How to handle running out of space
+#[derive(
+    Clone, Copy, Debug, Default, Deserialize, Serialize, Eq, PartialEq,
+)]
+#[serde(rename_all = "lowercase")]
+enum Enospc {
+    /// Treat ENOSPC as a test failure
+    #[default]
+    Fail,
+    /// Log it, and roll back whatever part of the operation didn't complete
+    Skip,
+}
+
+/// What to do when an operation fails with a particular errno
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, Eq, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum ErrorPolicy {
+    /// Treat it as a test failure
+    Fail,
+    /// Log it, and roll back whatever part of the operation didn't complete
+    Skip,
+    /// Retry the system call up to this many times, then fail
+    Retry(NonZeroU32),
+}
+
+/// The errnos that may be used in the `errors` table
+const ERRNOS: &[(&str, i32)] = &[
+    ("EAGAIN", libc::EAGAIN),
+    ("EBUSY", libc::EBUSY),
+    ("EINVAL", libc::EINVAL),
+    ("EIO", libc::EIO),
+    ("ENOSPC", libc::ENOSPC),
+    ("ENOTSUP", libc::ENOTSUP),
+    ("EOPNOTSUPP", libc::EOPNOTSUPP),
+    ("ETIMEDOUT", libc::ETIMEDOUT),
+];
+
+/// Parse one of the `ERRNOS` by name
+fn parse_errno(s: &str) -> Result<i32, String> {
+    ERRNOS
+        .iter()
+        .find(|(n, _)| *n == s)
+        .map(|(_, e)| *e)
+        .ok_or_else(|| format!("unknown errno {s}"))
+}
+
+const fn default_opsize_max() -> usize {
+    65536
+}
+
+/// How to distribute operation sizes between min and max
+#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum SizeDistribution {
+    /// Every size is equally likely
+    #[default]
+    Uniform,
+    /// Every power of two range is equally likely, so small sizes dominate
+    Geometric,
+    /// Mostly sizes near max / 64, with a heavy tail of huge ones
+    Pareto,
+    /// Choose one of these sizes
+    Fixed(Vec<usize>),
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+struct Opsize {
+    /// Minium size for operations
+    #[serde(default)]
+    min:          usize,
+    /// Maximum size for operations
+    #[serde(default = "default_opsize_max")]
+    max:          usize,
+    /// Alignment in bytes for all operations' offsets and sizes
+    align:        Option<NonZeroUsize>,
+    /// Alignment in bytes for operation offsets, overriding `align`
+    align_offset: Option<NonZeroUsize>,
+    /// Alignment in bytes for operation sizes, overriding `align`
+    align_size:   Option<NonZeroUsize>,
+    /// How to distribute sizes between min and max
+    #[serde(default)]
+    distribution: SizeDistribution,
+}
+
+impl Opsize {
+    /// Alignment for operation offsets
+    fn offset_align(&self) -> usize {
+        self.align_offset
+            .or(self.align)
+            .map(usize::from)
+            .unwrap_or(1)
+    }
+
+    /// Alignment for operation sizes
+    fn size_align(&self) -> usize {
+        self.align_size.or(self.align).map(usize::from).unwrap_or(1)
+    }
+
+    /// Draw an operation size
+    fn sample(&self, rng: &mut impl Rng) -> usize {
+        match self.distribution {
+            SizeDistribution::Uniform => rng.gen_range(self.min..=self.max),
+            _ => self.quantile(rng.gen()),
+        }
+    }
+
+    /// Map a number in [0, 1) to a size, for non-uniform distributions
+    fn quantile(&self, x: f64) -> usize {
+        let (min, max) = (self.min as f64, self.max as f64);
+        let size = match &self.distribution {
+            SizeDistribution::Uniform => min + x * (max + 1.0 - min),
+            SizeDistribution::Geometric => {
+                let lo = min.max(1.0);
+                lo * ((max + 1.0) / lo).powf(x)
+            }
+            SizeDistribution::Pareto => {
+                // The shape that gives the 80-20 rule, scaled so that about
+                // one percent of operations reach the maximum
+                let lo = min.max(max / 64.0).max(1.0);
+                lo / (1.0 - x).powf(1.0 / 1.16)
+            }
+            SizeDistribution::Fixed(sizes) => {
+                return sizes[(x * sizes.len() as f64) as usize];
+            }
+        };
+        (size as usize).clamp(self.min, self.max)
+    }
+}
+
+impl Default for Opsize {
+    fn default() -> Self {
+        Opsize {
+            min:          0,
+            max:          65536,
+            align:        NonZeroUsize::new(1),
+            align_offset: None,
+            align_size:   None,
+            distribution: SizeDistribution::Uniform,
+        }
+    }
+}
+
+/// Where to place operations within the file
+#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum OffsetMode {
+    /// Every offset is equally likely
+    #[default]
+    Uniform,
+    /// Each operation begins where the previous one ended, wrapping around
+    Sequential,
+    /// Operations cluster at the end of the file
+    Append,
+    /// Most operations fall within a few hot byte ranges
+    Hotspot {
+        /// Half-open byte ranges
+        ranges: Vec<(u64, u64)>,
+        /// Fraction of operations that fall within the ranges
+        #[serde(default = "default_hotspot_bias")]
+        bias:   f64,
+    },
+}
+
+const fn default_hotspot_bias() -> f64 {
+    0.8
+}
+
+/// What data write-like operations generate
+#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum Pattern {
+    /// The step number, mixed with the original data in every other byte
+    #[default]
+    Stamp,
+    /// Each aligned 8-byte word holds the step number and the word's offset
+    Stamp64,
+    /// Incompressible random data
+    Random,
+    /// All zeros
+    Zeros,
+    /// Runs of identical bytes
+    Compressible,
+    /// Identical random blocks, at every block-aligned offset, for the whole
+    /// run
+    Dedupe {
+        #[serde(default = "default_dedupe_block")]
+        block: NonZeroUsize,
+    },
+}
+
+fn default_dedupe_block() -> NonZeroUsize {
+    NonZeroUsize::new(4096).unwrap()
+}
+
+/// Length of each run of identical bytes in the compressible pattern
+const COMPRESSIBLE_RUN: usize = 64;
+
+/// Largest file that gets saved in full to .fsxbad
+const FSXBAD_MAX: u64 = 64 << 20;
+
+/// Encode the stamp64 pattern's word for an 8-byte aligned offset
+fn stamp64_encode(step: u64, offset: usize) -> [u8; 8] {
+    (step << 32 | (offset as u64 / 8) & 0xffff_ffff).to_le_bytes()
+}
+
+/// Decode a stamp64 word into the step that wrote it and the offset it was
+/// meant for, modulo 32 GB
+fn stamp64_decode(word: [u8; 8]) -> (u64, u64) {
+    let w = u64::from_le_bytes(word);
+    (w >> 32, (w & 0xffff_ffff) * 8)
+}
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+struct Offsets {
+    #[serde(default)]
+    mode: OffsetMode,
+}
+
+const fn default_weight() -> f64 {
+    10.0
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+struct Weights {
+    #[serde(default)]
+    close_open: f64,
+    #[serde(default)]
+    invalidate: f64,
+    #[serde(default = "default_weight")]
+    mapread: f64,
+    #[serde(default = "default_weight")]
+    mapwrite: f64,
+    #[serde(default = "default_weight")]
+    read: f64,
+    #[serde(default = "default_weight")]
+    write: f64,
+    #[serde(default = "default_weight")]
+    truncate: f64,
+    #[serde(default)]
+    fsync: f64,
+    #[serde(default)]
+    fdatasync: f64,
+    #[serde(default)]
+    posix_fallocate: f64,
+    #[serde(default)]
+    punch_hole: f64,
+    #[serde(default)]
+    sendfile: f64,
+    #[serde(default)]
+    posix_fadvise: f64,
+    #[serde(default)]
+    copy_file_range: f64,
+    #[serde(default)]
+    copy_file_range_overlap: f64,
+    #[serde(default)]
+    scratch_copy_in: f64,
+    #[serde(default)]
+    scratch_copy_out: f64,
+    #[serde(default)]
+    scratch_sendfile: f64,
+    #[serde(default)]
+    scratch_clone: f64,
+    #[serde(default)]
+    reflink: f64,
+    #[serde(default)]
+    stat: f64,
+    #[serde(default)]
+    read_eof: f64,
+    #[serde(default)]
+    fsize_limit: f64,
+}
+
+impl Default for Weights {
+    fn default() -> Self {
+        Weights {
+            close_open: 0.0,
+            invalidate: 0.0,
+            mapread: 1.0,
+            mapwrite: 1.0,
+            read: 1.0,
+            write: 1.0,
+            truncate: 1.0,
+            fsync: 0.0,
+            fdatasync: 0.0,
+            posix_fallocate: 0.0,
+            punch_hole: 0.0,
+            sendfile: 0.0,
+            posix_fadvise: 0.0,
+            copy_file_range: 0.0,
+            copy_file_range_overlap: 0.0,
+            scratch_copy_in: 0.0,
+            scratch_copy_out: 0.0,
+            scratch_sendfile: 0.0,
+            scratch_clone: 0.0,
+            reflink: 0.0,
+            stat: 0.0,
+            read_eof: 0.0,
+            fsize_limit: 0.0,
+        }
+    }
+}
+
+impl Weights {
+    /// The weights, indexed like `Op::ALL`
+    fn to_array(&self) -> [f64; Op::ALL.len()] {
+        [
+            self.close_open,
+            self.read,
+            self.write,
+            self.mapread,
+            self.truncate,
+            self.invalidate,
+            self.mapwrite,
+            self.fsync,
+            self.fdatasync,
+            self.posix_fallocate,
+            self.punch_hole,
+            self.sendfile,
+            self.posix_fadvise,
+            self.copy_file_range,
+            self.copy_file_range_overlap,
+            self.scratch_copy_in,
+            self.scratch_copy_out,
+            self.scratch_sendfile,
+            self.scratch_clone,
+            self.reflink,
+            self.stat,
+            self.read_eof,
+            self.fsize_limit,
+        ]
+    }
+
+    fn from_array(w: [f64; Op::ALL.len()]) -> Self {
+        Weights {
+            close_open: w[0],
+            read: w[1],
+            write: w[2],
+            mapread: w[3],
+            truncate: w[4],
+            invalidate: w[5],
+            mapwrite: w[6],
+            fsync: w[7],
+            fdatasync: w[8],
+            posix_fallocate: w[9],
+            punch_hole: w[10],
+            sendfile: w[11],
+            posix_fadvise: w[12],
+            copy_file_range: w[13],
+            copy_file_range_overlap: w[14],
+            scratch_copy_in: w[15],
+            scratch_copy_out: w[16],
+            scratch_sendfile: w[17],
+            scratch_clone: w[18],
+            reflink: w[19],
+            stat: w[20],
+            read_eof: w[21],
+            fsize_limit: w[22],
+        }
+    }
+}
+
+/// Built-in settings for file systems with known limitations
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, Eq, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum Preset {
+    Zfs,
+    Nfs,
+    Fuse,
+    Msdosfs,
+}
+
+impl Preset {
+    /// Operations that the file system doesn't support.  They're disabled
+    /// regardless of the weights.
+    fn unsupported(&self) -> &'static [Op] {
+        match self {
+            Preset::Zfs => &[Op::PosixFallocate],
+            Preset::Nfs | Preset::Fuse | Preset::Msdosfs => &[
+                Op::PosixFallocate,
+                Op::PunchHole,
+                Op::Reflink,
+                Op::ScratchClone,
+            ],
+        }
+    }
+
+    /// Defaults for other settings, as TOML.  Each aligns operations to the
+    /// file system's usual block size.
+    fn defaults(&self) -> &'static str {
+        match self {
+            // FAT stores modification times with 2 second granularity, in
+            // 512 byte sectors
+            Preset::Msdosfs => {
+                "timecheck_granularity = 2.0\n[opsize]\nalign = 512"
+            }
+            // Most pools use ashift=12
+            Preset::Zfs => "[opsize]\nalign = 4096",
+            // Both transfer whole pages through the page cache
+            Preset::Nfs | Preset::Fuse => "[opsize]\nalign = 4096",
+        }
+    }
+}
+
+/// One phase of a phased weight schedule
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+struct PhaseConfig {
+    /// Length of the phase, in operations
+    ops:     NonZeroU64,
+    #[serde(default)]
+    weights: Weights,
+    /// If unset, use the top-level opsize
+    #[serde(default)]
+    opsize:  Option<Opsize>,
+}
+
+/// What to do after the last phase
+#[derive(
+    Clone, Copy, Debug, Default, Deserialize, Serialize, Eq, PartialEq,
+)]
+#[serde(rename_all = "lowercase")]
+enum PhaseMode {
+    /// Start over with the first phase
+    #[default]
+    Cycle,
+    /// Remain in the last phase for the rest of the run
+    Progress,
+}
+
+/// One operation of a script.  Fields have the same meanings as in a trace.
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+struct ScriptOp {
+    /// Operation name, as printed in the operation log
+    op:     String,
+    /// Index of the target file, when using multiple files
+    #[serde(default)]
+    file:   u32,
+    #[serde(default)]
+    offset: u32,
+    #[serde(default)]
+    size:   u64,
+    /// Truncate's new size, copy_file_range's other offset,
+    /// copy_file_range_overlap's shift, or posix_fadvise's advice
+    #[serde(default)]
+    extra:  u32,
+    /// fsize_limit's truncate, or copy_file_range_overlap's direction
+    #[serde(default)]
+    flag:   bool,
+}
+
+impl ScriptOp {
+    fn to_record(&self) -> Option<OpRecord> {
+        let op = *Op::ALL.iter().find(|op| op.to_string() == self.op)?;
+        Some(OpRecord {
+            op,
+            target: self.file,
+            size: self.size,
+            offset: self.offset,
+            extra: self.extra,
+            flag: self.flag,
+        })
+    }
+}
+
+/// When to run the script
+#[derive(
+    Clone, Copy, Debug, Default, Deserialize, Serialize, Eq, PartialEq,
+)]
+#[serde(rename_all = "lowercase")]
+enum ScriptMode {
+    /// Run the script, then continue with random operations
+    #[default]
+    Before,
+    /// Run only the script
+    Instead,
+}
+
+/// A phase, ready to draw operations from
+#[derive(Clone, Debug)]
+struct Phase {
+    ops:     u64,
+    wi:      WeightedIndex<f64>,
+    weights: [f64; Op::ALL.len()],
+    opsize:  Opsize,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum Op {
+    CloseOpen,
+    Read,
+    Write,
+    MapRead,
+    Truncate,
+    Invalidate,
+    MapWrite,
+    Fsync,
+    Fdatasync,
+    PosixFallocate,
+    PunchHole,
+    Sendfile,
+    PosixFadvise,
+    CopyFileRange,
+    CopyFileRangeOverlap,
+    ScratchCopyIn,
+    ScratchCopyOut,
+    ScratchSendfile,
+    ScratchClone,
+    Reflink,
+    Stat,
+    ReadEof,
+    FsizeLimit,
+}
+
+impl Op {
+    /// Every operation, in the same order as the weights
+    const ALL: [Op; 23] = [
+        Op::CloseOpen,
+        Op::Read,
+        Op::Write,
+        Op::MapRead,
+        Op::Truncate,
+        Op::Invalidate,
+        Op::MapWrite,
+        Op::Fsync,
+        Op::Fdatasync,
+        Op::PosixFallocate,
+        Op::PunchHole,
+        Op::Sendfile,
+        Op::PosixFadvise,
+        Op::CopyFileRange,
+        Op::CopyFileRangeOverlap,
+        Op::ScratchCopyIn,
+        Op::ScratchCopyOut,
+        Op::ScratchSendfile,
+        Op::ScratchClone,
+        Op::Reflink,
+        Op::Stat,
+        Op::ReadEof,
+        Op::FsizeLimit,
+    ];
+
+    /// Can this operation run on the current platform at all?
+    fn supported(&self) -> bool {
+        match self {
+            Op::PosixFallocate => cfg!(any(
+                target_os = "android",
+                target_os = "dragonfly",
+                target_os = "emscripten",
+                target_os = "freebsd",
+                target_os = "fuchsia",
+                target_os = "linux"
+            )),
+            Op::PunchHole => cfg!(any(
+                have_fspacectl,
+                target_os = "android",
+                target_os = "emscripten",
+                target_os = "fuchsia",
+                target_os = "linux"
+            )),
+            Op::Sendfile => cfg!(any(
+                target_os = "android",
+                target_os = "dragonfly",
+                target_os = "freebsd",
+                target_os = "ios",
+                target_os = "linux",
+                target_os = "macos"
+            )),
+            Op::PosixFadvise => cfg!(any(
+                target_os = "android",
+                target_os = "freebsd",
+                target_os = "linux"
+            )),
+            Op::CopyFileRange | Op::ScratchCopyIn | Op::ScratchCopyOut => {
+                cfg!(any(target_os = "freebsd", target_os = "linux"))
+            }
+            // Linux rejects every overlapping copy within a file with EINVAL
+            Op::CopyFileRangeOverlap => cfg!(target_os = "freebsd"),
+            Op::ScratchSendfile | Op::ScratchClone | Op::Reflink => {
+                cfg!(any(target_os = "android", target_os = "linux"))
+            }
+            _ => true,
+        }
+    }
+
+    fn make_weighted_index<I>(weights: I) -> WeightedIndex<f64>
+    where
+        I: IntoIterator<Item = f64> + ExactSizeIterator,
+    {
+        assert_eq!(weights.len(), Op::ALL.len());
+        WeightedIndex::new(weights).unwrap()
+    }
+}
+
+impl fmt::Display for Op {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+        match self {
+            Op::CloseOpen => "close/open".fmt(f),
+            Op::Read => "read".fmt(f),
+            Op::Write => "write".fmt(f),
+            Op::MapRead => "mapread".fmt(f),
+            Op::Truncate => "truncate".fmt(f),
+            Op::Invalidate => "invalidate".fmt(f),
+            Op::MapWrite => "mapwrite".fmt(f),
+            Op::Fsync => "fsync".fmt(f),
+            Op::Fdatasync => "fdatasync".fmt(f),
+            Op::PosixFallocate => "posix_fallocate".fmt(f),
+            Op::PunchHole => "punch_hole".fmt(f),
+            Op::Sendfile => "sendfile".fmt(f),
+            Op::PosixFadvise => "posix_fadvise".fmt(f),
+            Op::CopyFileRange => "copy_file_range".fmt(f),
+            Op::CopyFileRangeOverlap => "copy_file_range_overlap".fmt(f),
+            Op::ScratchCopyIn => "scratch_copy_in".fmt(f),
+            Op::ScratchCopyOut => "scratch_copy_out".fmt(f),
+            Op::ScratchSendfile => "scratch_sendfile".fmt(f),
+            Op::ScratchClone => "scratch_clone".fmt(f),
+            Op::Reflink => "reflink".fmt(f),
+            Op::Stat => "stat".fmt(f),
+            Op::ReadEof => "read_eof".fmt(f),
+            Op::FsizeLimit => "fsize_limit".fmt(f),
+        }
+    }
+}
+
+impl Distribution<Op> for WeightedIndex<f64> {
+    fn sample<R: Rng + ?Sized>(&self, rng: &mut R) -> Op {
+        let i: usize = self.sample(rng);
+        *Op::ALL
+            .get(i)
+            .expect("WeightedIndex was generated with too many keys")
+    }
+}
+
+#[derive(Clone, Copy)]
+enum LogEntry {
+    Skip(Op),
+    CloseOpen,
+    // offset, size
+    Read(u64, usize),
+    // old file len, offset, size
+    Write(u64, u64, usize),
+    // offset, size
+    MapRead(u64, usize),
+    // old file len, new file len
+    Truncate(u64, u64),
+    Invalidate,
+    // old file len, offset, size
+    MapWrite(u64, u64, usize),
+    Fsync,
+    Fdatasync,
+    // offset, len
+    PosixFallocate(u64, u64),
+    // offset, len
+    PunchHole(u64, u64),
+    // offset, len
+    Sendfile(u64, usize),
+    // advice, offset, len
+    #[cfg(any(
+        target_os = "linux",
+        target_os = "android",
+        target_os = "freebsd"
+    ))]
+    PosixFadvise(PosixFadviseAdvice, u64, u64),
+    // old file len, in_offset, out_offset, len
+    CopyFileRange(u64, u64, u64, usize),
+    // op, old destination file len, in_offset, out_offset, len
+    Scratch(Op, u64, u64, u64, usize),
+    // file len
+    Reflink(u64),
+    Stat,
+    // file len, offset, size
+    ReadEof(u64, u64, usize),
+    // file len, limit, offset, size, truncate
+    FsizeLimit(u64, u64, u64, usize, bool),
+}
+
+impl LogEntry {
+    /// The byte range that the operation modified or examined, as offset and
+    /// length, if it has one
+    fn range(&self) -> Option<(u64, u64)> {
+        match *self {
+            LogEntry::Read(offset, size)
+            | LogEntry::MapRead(offset, size)
+            | LogEntry::Sendfile(offset, size)
+            | LogEntry::Write(_, offset, size)
+            | LogEntry::MapWrite(_, offset, size)
+            | LogEntry::ReadEof(_, offset, size)
+            | LogEntry::FsizeLimit(_, _, offset, size, _)
+            | LogEntry::CopyFileRange(_, _, offset, size)
+            | LogEntry::Scratch(_, _, _, offset, size) => {
+                Some((offset, size as u64))
+            }
+            LogEntry::PosixFallocate(offset, len)
+            | LogEntry::PunchHole(offset, len) => Some((offset, len)),
+            #[cfg(any(
+                target_os = "linux",
+                target_os = "android",
+                target_os = "freebsd"
+            ))]
+            LogEntry::PosixFadvise(_, offset, len) => Some((offset, len)),
+            LogEntry::Truncate(old, new) => {
+                Some((old.min(new), old.abs_diff(new)))
+            }
+            LogEntry::Skip(_)
+            | LogEntry::CloseOpen
+            | LogEntry::Invalidate
+            | LogEntry::Fsync
+            | LogEntry::Fdatasync
+            | LogEntry::Reflink(_)
+            | LogEntry::Stat => None,
+        }
+    }
+
+    /// Does the operation change the file's contents within its range?
+    fn modifies(&self) -> bool {
+        match self {
+            LogEntry::Read(..)
+            | LogEntry::MapRead(..)
+            | LogEntry::Sendfile(..)
+            | LogEntry::ReadEof(..) => false,
+            #[cfg(any(
+                target_os = "linux",
+                target_os = "android",
+                target_os = "freebsd"
+            ))]
+            LogEntry::PosixFadvise(..) => false,
+            _ => true,
+        }
+    }
+}
+
+/// The state of one test file, when using multiple files
+struct Target {
+    fname:       PathBuf,
+    file:        SharedFile,
+    direct_file: Option<SharedFile>,
+    good_buf:    SparseBuf,
+    file_size:   u64,
+}
+
+/// A secondary file used as the source or destination of cross-file
+/// operations.
+struct Scratch {
+    file:     File,
+    path:     PathBuf,
+    /// Current scratch file size
+    size:     u64,
+    /// What the scratch file ought to contain
+    good_buf: Vec<u8>,
+}
+
+/// How the map operations map the file
+#[derive(
+    Clone, Copy, Debug, Default, Deserialize, Serialize, Eq, PartialEq,
+)]
+#[serde(rename_all = "lowercase")]
+enum MapMode {
+    /// mmap and munmap around every operation
+    #[default]
+    Op,
+    /// Keep a mapping between operations, remapping only when an operation
+    /// falls outside of it or the file's size changes
+    Window,
+    /// Map the first `flen` bytes of the file once, and operate through that
+    /// mapping for the rest of the run
+    File,
+}
+
+/// Granularity of the mappings kept with `MapMode::Window`
+const MAP_WINDOW: u64 = 1 << 20;
+
+/// A mapping of the test file kept between operations, with
+/// `MapMode::Window` or `MapMode::File`
+struct MapWindow {
+    p:         ptr::NonNull<c_void>,
+    /// File offset of the mapping's start, relative to the exercised region
+    offset:    u64,
+    len:       usize,
+    /// The file's size when it was mapped, if the mapping is only valid for
+    /// that size
+    file_size: Option<u64>,
+}
+
+impl MapWindow {
+    /// A pointer to the mapped page at `pg_start`, if the mapping covers
+    /// `len` bytes from there
+    fn get(
+        &self,
+        pg_start: u64,
+        len: usize,
+        file_size: u64,
+    ) -> Option<ptr::NonNull<c_void>> {
+        if self.file_size.map_or(true, |s| s == file_size)
+            && self.offset <= pg_start
+            && pg_start + len as u64 <= self.offset + self.len as u64
+        {
+            let delta = (pg_start - self.offset) as usize;
+            // Safe because the result lies within the mapping
+            let p = unsafe { self.p.as_ptr().cast::<u8>().add(delta) };
+            ptr::NonNull::new(p.cast())
+        } else {
+            None
+        }
+    }
+}
+
+// Safe because the mapping is only accessed by the Exerciser that owns it
+unsafe impl Send for MapWindow {}
+
+impl Drop for MapWindow {
+    fn drop(&mut self) {
+        // Safe because nothing borrows from the mapping between operations
+        unsafe { munmap(self.p, self.len) }.unwrap();
+    }
+}
+
+/// Something that belongs to the main thread alone.  Worker threads'
+/// exercisers, cloned from the main one, get the default instead.
+#[derive(Default)]
+struct Unshared<T: Default>(T);
+
+impl<T: Default> Clone for Unshared<T> {
+    fn clone(&self) -> Self {
+        Unshared(T::default())
+    }
+}
+
+impl<T: Default> Deref for Unshared<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
+
+impl<T: Default> DerefMut for Unshared<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.0
+    }
+}
+
+/// A file descriptor shared between threads.  Cloning it dups the
+/// descriptor.
+struct SharedFile(File);
+
+impl Clone for SharedFile {
+    fn clone(&self) -> Self {
+        SharedFile(self.0.try_clone().unwrap())
+    }
+}
+
+impl Deref for SharedFile {
+    type Target = File;
+
+    fn deref(&self) -> &File {
+        &self.0
+    }
+}
+
+impl DerefMut for SharedFile {
+    fn deref_mut(&mut self) -> &mut File {
+        &mut self.0
+    }
+}
+
+impl AsFd for SharedFile {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.0.as_fd()
+    }
+}
+
+#[derive(Clone)]
+struct Exerciser {
+    /// Alignment for operation offsets
+    align_offset: usize,
+    /// Alignment for operation sizes
+    align_size: usize,
+    artifacts_dir: Option<PathBuf>,
+    /// --artifact-name template, with everything but {name} filled in
+    artifact_name: String,
+    artifact_mode: ArtifactMode,
+    blockmode: bool,
+    /// File offset where the exercised part of the file begins
+    base: u64,
+    /// The test file's preferred I/O block size
+    blksize: u64,
+    /// Current file size
+    file_size: u64,
+    flen: u64,
+    fname: PathBuf,
+    /// Width for printing fields containing file offsets
+    fwidth: usize,
+    /// Inject an error on this step
+    inject: Option<u64>,
+    /// Fail the injected step's first system call with this errno
+    inject_errno: Option<i32>,
+    /// Whether `inject_errno` has been used
+    errno_injected: Cell<bool>,
+    /// Ranges of steps whose I/O to skip, inclusive
+    skip_ops: Vec<(u64, u64)>,
+    // What the file ought to contain
+    good_buf: SparseBuf,
+    /// With `ShadowMode::Checksum`, the model of the file in place of
+    /// good_buf
+    checksums: Unshared<Option<Checksums>>,
+    /// Scratch buffer for reads, reused between operations
+    read_buf: Vec<u8>,
+    /// Monitor these byte ranges in extra detail.
+    monitor: Vec<(u64, u64)>,
+    /// Place operations within the monitored ranges
+    monitor_only: bool,
+    map_mode: MapMode,
+    /// The mapping kept between operations, with `MapMode::Window` or
+    /// `MapMode::File`
+    map_window: Unshared<Option<MapWindow>>,
+    nomsyncafterwrite: bool,
+    nosizechecks: bool,
+    numops: Option<u64>,
+    /// How to reopen the file during close_open
+    reopen: Reopen,
+    /// Close and reopen the file after each operation, with probability 1
+    /// in N
+    closeprob: Option<NonZeroU32>,
+    /// Steps that were followed by a close and reopen, because of closeprob
+    reopens: AllocRingBuffer<u64>,
+    /// The most recent whole-file clone, and what it ought to contain
+    reflink: Unshared<Option<(File, Vec<u8>)>>,
+    /// Secondary file for cross-file operations
+    scratch: Unshared<Option<Scratch>>,
+    // Records most recent operations for future dumping
+    oplog: AllocRingBuffer<LogEntry>,
+    opsize: Opsize,
+    /// Where to place operations within the file
+    offsets: OffsetMode,
+    /// What data to write
+    pattern: Pattern,
+    /// Where the next operation begins, for sequential offsets
+    cursor: u64,
+    seed: u64,
+    // 0-indexed operation number to begin real transfers.
+    simulatedopcount: u64,
+    /// Width for printing fields containing operation sizes
+    swidth: usize,
+    /// Width for printing the step number field
+    stepwidth: usize,
+    /// The file is anonymous, created with O_TMPFILE
+    tmpfile: bool,
+    /// Link the anonymous file into place at the end of the run
+    tmpfile_link: bool,
+    /// File's original data, for the stamp pattern.  Op stream v1 recomputes
+    /// it as needed instead.
+    original_buf: Option<OriginalBuf>,
+    /// Verify the entire file's contents every N operations
+    verify_interval: Option<NonZeroU64>,
+    /// Save the run's state every N operations
+    checkpoint_interval: Option<NonZeroU64>,
+    /// Read back every written range immediately after writing it
+    verify_after_write: VerifyAfterWrite,
+    /// A second file descriptor, opened with O_DIRECT
+    direct_file: Option<SharedFile>,
+    /// Verify st_blocks after punch_hole and posix_fallocate, with this
+    /// tolerance in bytes
+    stblocks_check: Option<u64>,
+    /// Verify with SEEK_HOLE that punched ranges actually became holes
+    holecheck: bool,
+    /// Verify that timestamps advance after modifications, with this
+    /// granularity
+    timecheck: Option<Duration>,
+    /// What to do when an operation fails, by errno
+    errors: HashMap<i32, ErrorPolicy>,
+    /// A reference copy of the file, kept on a trusted file system
+    oracle: Unshared<Option<File>>,
+    /// File system snapshots to take and verify
+    snapshots: Unshared<Option<Snapshots>>,
+    /// Shell command that checks the file system's integrity, and how often
+    /// to run it
+    check_hook: Option<(String, NonZeroU64)>,
+    /// Shell command that remounts the file system, and how often to run it
+    remount_hook: Option<(String, NonZeroU64)>,
+    /// How often to drop the file's cached data, and the hook that does it
+    drop_caches: Option<(Option<String>, NonZeroU64)>,
+    /// How many lines of the kernel log to save on failure, and the command
+    /// that prints it
+    dmesg: Option<(NonZeroUsize, String)>,
+    /// Locks on the test files, held for the whole run
+    locks: Unshared<Vec<Flock<File>>>,
+    /// A signal storm is running, so expect partial transfers
+    signal_storm: bool,
+    /// This thread's number, when running multiple threads
+    thread: Option<usize>,
+    /// The byte range that this exerciser may operate on
+    region: (u64, u64),
+    /// Background verifier threads
+    verifiers: Unshared<Option<Verifiers>>,
+    /// All test files but the current one, when using multiple files
+    files: Unshared<Vec<Option<Target>>>,
+    /// Index of the current test file within `files`
+    cur: usize,
+    /// Which file each recent operation used, in step with `oplog`
+    targets: AllocRingBuffer<usize>,
+    /// When each recent operation started and how long it took, in step with
+    /// `oplog`.  Only recorded with --timestamps.
+    timings: Option<AllocRingBuffer<(SystemTime, Duration)>>,
+    /// Publish the current step here, for a crash test's parent process
+    progress: Unshared<Option<&'static AtomicU64>>,
+    /// Replaying operations to verify a crash
+    crash: Unshared<Option<CrashCheck>>,
+    /// Periodic fault injection
+    fault: Unshared<Option<Fault>>,
+    /// Binary record of every operation
+    trace: Unshared<Option<Trace>>,
+    /// CSV record of every operation
+    csv: Unshared<Option<CsvTrace>>,
+    /// Record of every system call made by operations
+    #[cfg(feature = "strace")]
+    strace: Unshared<Option<Strace>>,
+    /// Complete operation log
+    oplog_file: Unshared<Option<OplogFile>>,
+    /// Recorded operations to replay, instead of drawing random ones
+    replay: Unshared<Option<vec::IntoIter<OpRecord>>>,
+    /// Scripted operations yet to execute
+    script: Unshared<vec::IntoIter<OpRecord>>,
+    /// Where to write a summary of the run
+    report: Unshared<Option<PathBuf>>,
+    report_format: ReportFormat,
+    /// Steps after which the entire file was verified, because of
+    /// verify_interval
+    verified: Vec<u64>,
+    /// How to reproduce the run, if it fails
+    repro: Option<Repro>,
+    /// Number of operations skipped because of the errors policy
+    skips: Cell<u64>,
+    /// Total bytes written by write and mapwrite
+    bytes_written: u64,
+    /// Total bytes read by read, mapread, and sendfile
+    bytes_read: u64,
+    /// Periodic progress snapshots
+    stats: Unshared<Option<Stats>>,
+    /// Progress line on the terminal
+    meter: Unshared<Option<Meter>>,
+    /// First and last bad offsets of a miscompare, for the log dump
+    badrange: Cell<Option<(u64, u64)>>,
+    /// Counters for the metrics exporters
+    #[cfg(feature = "metrics")]
+    metrics: Option<Arc<Metrics>>,
+    /// Latency histograms, indexed like `Op::ALL`
+    latencies: Option<Vec<Histogram>>,
+    /// Stop after writing this many bytes
+    max_write_bytes: Option<NonZeroU64>,
+    // Deterministic and seedable
+    rng: FsxRng,
+    // Number of steps completed so far
+    steps: u64,
+    file: SharedFile,
+    wi: WeightedIndex<f64>,
+    /// Weights of each operation, indexed like `Op::ALL`
+    weights: [f64; Op::ALL.len()],
+    /// The most times to execute each operation, indexed like `Op::ALL`
+    max_count: Vec<Option<NonZeroU64>>,
+    /// How many times each operation has executed, indexed like `Op::ALL`
+    counts: [u64; Op::ALL.len()],
+    /// The run's state at the start of this step, for the .fsxstate file
+    step_start: Unshared<Option<StepStart>>,
+    op_stream: OpStream,
+    /// Phased weight schedule, if any
+    phases: Vec<Phase>,
+    /// Index of the current phase within `phases`
+    phase: usize,
+    /// Cycle through the phases, rather than stopping at the last one
+    phase_cycle: bool,
+    /// Wait for a command before each operation
+    interactive: bool,
+    /// Stop the process before this step
+    break_at: Option<NonZeroU64>,
+}
+
+impl Exerciser {
+    cfg_if! {
+        if #[cfg(any(target_os = "macos", target_os = "dragonfly", target_os = "ios"))] {
+            fn dosendfile(&mut self, buf: &mut [u8], offset: u64, size: usize) -> bool {
+                use std::{io::Read, os::unix::net::UnixStream, thread};
+                let offset = offset + self.base;
+                use nix::sys::sendfile::sendfile;
+
+                // Safe because we unconditionally join the thread below.
+                let ffd = unsafe { BorrowedFd::borrow_raw(self.file.as_raw_fd()) };
+                let res = self.retry(|| {
+                    let (mut rd, wr) = UnixStream::pair().unwrap();
+                    let wfd = wr.as_raw_fd();
+                    // The thread owns the socket's write end, so the read
+                    // below ends early if sendfile fails.
+                    let jh = thread::spawn(move || {
+                        sendfile(
+                            ffd,
+                            wr.as_fd(),
+                            offset as i64,
+                            Some(size as _),
+                            None,
+                            None,
+                        )
+                    });
+                    // A short transfer is reported below
+                    let _ = rd.read_exact(buf);
+                    let (res, bytes_written) = jh.join().unwrap();
+                    sys!(
+                        self,
+                        "sendfile"(ffd.as_raw_fd(), wfd, offset, size),
+                        res.map(|()| bytes_written as usize)
+                    )
+                });
+                let bytes_written = match res {
+                    Ok(b) => b,
+                    Err(e) if self.skip_error(Op::Sendfile, &e) => return false,
+                    Err(e) => {
+                        error!("sendfile failed with {e}");
+                        self.fail();
+                    }
+                };
+                if bytes_written != size {
+                    error!("Short read with sendfile: {:#x} bytes instead of {:#x}",
+                           bytes_written, size);
+                    self.fail();
+                }
+                true
+            }
+        } else if #[cfg(target_os = "freebsd")] {
+            fn dosendfile(&mut self, buf: &mut [u8], offset: u64, size: usize) -> bool {
+                use std::{io::Read, os::fd::BorrowedFd, os::unix::net::UnixStream, thread};
+                let offset = offset + self.base;
+                use nix::sys::sendfile::{sendfile, SfFlags};
+
+                // Safe because we unconditionally join the thread below.
+                let ffd = unsafe { BorrowedFd::borrow_raw(self.file.as_raw_fd()) };
+                let res = self.retry(|| {
+                    let (mut rd, wr) = UnixStream::pair().unwrap();
+                    let wfd = wr.as_raw_fd();
+                    // The thread owns the socket's write end, so the read
+                    // below ends early if sendfile fails.
+                    let jh = thread::spawn(move || {
+                        sendfile(
+                            ffd,
+                            wr.as_fd(),
+                            offset as i64,
+                            Some(size),
+                            None,
+                            None,
+                            SfFlags::empty(),
+                            0
+                        )
+                    });
+                    // A short transfer is reported below
+                    let _ = rd.read_exact(buf);
+                    let (res, bytes_written) = jh.join().unwrap();
+                    sys!(
+                        self,
+                        "sendfile"(ffd.as_raw_fd(), wfd, offset, size),
+                        res.map(|()| bytes_written as usize)
+                    )
+                });
+                let bytes_written = match res {
+                    Ok(b) => b,
+                    Err(e) if self.skip_error(Op::Sendfile, &e) => return false,
+                    Err(e) => {
+                        error!("sendfile failed with {e}");
+                        self.fail();
+                    }
+                };
+                if bytes_written != size {
+                    error!("Short read with sendfile: {:#x} bytes instead of {:#x}",
+                           bytes_written, size);
+                    self.fail();
+                }
+                true
+            }
+        } else if #[cfg(any(target_os = "android", target_os = "linux"))] {
+            fn dosendfile(&mut self, buf: &mut [u8], offset: u64, size: usize) -> bool {
+                use std::{io::Read, os::fd::BorrowedFd, os::unix::net::UnixStream, thread};
+                let offset = offset + self.base;
+                use nix::sys::sendfile::sendfile64;
+
+                // Safe because we unconditionally join the thread below.
+                let ffd = unsafe { BorrowedFd::borrow_raw(self.file.as_raw_fd()) };
+                let res = self.retry(|| {
+                    let (mut rd, wr) = UnixStream::pair().unwrap();
+                    let wfd = wr.as_raw_fd();
+                    let mut ioffs = offset as i64;
+                    // The thread owns the socket's write end, so the read
+                    // below ends early if sendfile fails.
+                    let jh = thread::spawn(move || {
+                        let mut sent = 0;
+                        while sent < size {
+                            match sendfile64(wr.as_fd(), ffd, Some(&mut ioffs), size - sent) {
+                                Ok(0) => break,
+                                Ok(n) => sent += n,
+                                Err(nix::Error::EINTR) => (),
+                                Err(e) => return Err(e),
+                            }
+                        }
+                        Ok(sent)
+                    });
+                    // A short transfer is reported below
+                    let _ = rd.read_exact(buf);
+                    sys!(
+                        self,
+                        "sendfile"(wfd, ffd.as_raw_fd(), offset, size),
+                        jh.join().unwrap()
+                    )
+                });
+                let bytes_written = match res {
+                    Ok(b) => b,
+                    Err(e) if self.skip_error(Op::Sendfile, &e) => return false,
+                    Err(e) => {
+                        error!("sendfile failed with {e}");
+                        self.fail();
+                    }
+                };
+                if bytes_written != size {
+                    error!("Short read with sendfile: {:#x} bytes instead of {:#x}",
+                           bytes_written, size);
+                    self.fail();
+                }
+                true
+            }
+        } else {
+            fn dosendfile(&mut self, _buf: &mut [u8], _offset: u64, _size: usize) -> bool {
+                eprintln!("sendfile is not supported on this platform.");
+                process::exit(1);
+            }
+        }
+    }
+
+    cfg_if! {
+        if #[cfg(any(
+            target_os = "linux",
+            target_os = "android",
+            target_os = "freebsd"
+        ))] {
+            fn posix_fadvise(
+                &mut self,
+                advice: PosixFadviseAdvice,
+                offset: u64,
+                size: u64)
+            {
+                self.oplog.push(LogEntry::PosixFadvise(advice, offset, size));
+
+                if self.skip() {
+                    return;
+                }
+                info!(
+                    "{:stepwidth$} posix_fadvise({:10}) {:#fwidth$x} .. \
+                    {:#fwidth$x} ({:#swidth$x} bytes)",
+                    self.steps,
+                    advice,
+                    offset,
+                    (offset + size).saturating_sub(1),
+                    size,
+                    stepwidth = self.stepwidth,
+                    fwidth = self.fwidth,
+                    swidth = self.swidth
+                );
+                let r = sys!(
+                    self,
+                    "posix_fadvise"(
+                        self.file.as_raw_fd(),
+                        offset + self.base,
+                        size,
+                        advice
+                    ),
+                    nix::fcntl::posix_fadvise(self.file.as_raw_fd(),
+                        (offset + self.base) as i64, size as i64, advice.0)
+                );
+                if let Err(e) = r {
+                    error!("posix_fadvise failed with {e}");
+                    self.fail();
+                }
+            }
+        } else {
+            fn posix_fadvise(&mut self, _: PosixFadviseAdvice, _: u64, _: u64) {
+                eprintln!("posix_fadvise is not supported on this platform.");
+                process::exit(1);
+            }
+        }
+    }
+
+    fn check_buffers(&self, buf: &[u8], mut offset: u64) {
+        let start = offset;
+        let mut size = buf.len();
+        let good = self.good_buf.slice(offset as usize..offset as usize + size);
+        if *good != buf[..] {
+            error!("miscompare: offset= {:#x}, size = {:#x}", offset, size);
+            let mut i = 0;
+            let mut n = 0;
+            let mut good = 0;
+            let mut bad = 0;
+            let mut badoffset = 0;
+            let mut lastbad = 0;
+            let mut op = 0;
+            error!(
+                "{:fwidth$} GOOD  BAD  {:swidth$}",
+                "OFFSET",
+                "RANGE",
+                fwidth = self.fwidth,
+                swidth = self.swidth
+            );
+            while size > 0 {
+                let c = self.good_buf[offset as usize];
+                let t = buf[i];
+                if c != t {
+                    if n == 0 {
+                        good = c;
+                        bad = t;
+                        badoffset = offset;
+                        op = buf[if offset & 1 != 0 { i + 1 } else { i }];
+                    }
+                    lastbad = offset;
+                    n += 1;
+                }
+                offset += 1;
+                i += 1;
+                size -= 1;
+            }
+            assert!(n > 0);
+            // XXX The reported range may be a little too small, because
+            // some bytes in the damaged range may coincidentally match.  But
+            // this is the way that the C-based FSX reported it.
+            error!(
+                "{:#fwidth$x} {:#04x} {:#04x} {:#swidth$x}",
+                badoffset,
+                good,
+                bad,
+                n,
+                fwidth = self.fwidth,
+                swidth = self.swidth
+            );
+            if let Some((step, intended)) =
+                self.stamp64_hint(buf, start, badoffset)
+            {
+                // The encoding keeps only 32 bits of each
+                let step_mod = if self.steps > u64::from(u32::MAX) {
+                    " (mod 2^32)"
+                } else {
+                    ""
+                };
+                let offset_mod = if self.flen > 8 << 32 {
+                    " (mod 32 GiB)"
+                } else {
+                    ""
+                };
+                error!(
+                    "Step#{step_mod} for the bad data is {step}, which wrote \
+                     it for offset {intended:#x}{offset_mod}",
+                );
+            } else if op > 0 && self.pattern == Pattern::Stamp {
+                error!("Step# (mod 256) for a misdirected write may be {}", op);
+            } else {
+                error!(
+                    "Step# for the bad data is unknown; check HOLE and EXTEND \
+                     ops"
+                );
+            }
+            self.badrange.set(Some((badoffset, lastbad)));
+            self.fail();
+        }
+    }
+
+    /// With the stamp64 pattern, decode which step wrote the bad byte at
+    /// `badoffset`, and for what offset
+    fn stamp64_hint(
+        &self,
+        buf: &[u8],
+        start: u64,
+        badoffset: u64,
+    ) -> Option<(u64, u64)> {
+        if self.pattern != Pattern::Stamp64 {
+            return None;
+        }
+        let wstart = badoffset - badoffset % 8;
+        if wstart < start || wstart + 8 > start + buf.len() as u64 {
+            return None;
+        }
+        let i = (wstart - start) as usize;
+        let (step, woffset) = stamp64_decode(buf[i..i + 8].try_into().unwrap());
+        // Step 0 is never written; it must be a hole or the original data
+        (step > 0).then_some((step, woffset + badoffset % 8))
+    }
+
+    fn check_eofpage(&self, offset: u64, p: *const c_void, size: usize) {
+        let page_size = Self::getpagesize() as usize;
+        let page_mask = page_size as isize - 1;
+        // In blockmode, the tested range need not end at EoF
+        if self.blockmode
+            || offset + size as u64 <= self.file_size & !(page_mask as u64)
+        {
+            return;
+        }
+
+        // We landed in the last page of the file.  Test to make sure the VM
+        // system provided 0's beyond the true end of the file mapping (as
+        // required by mmap def in 1996 posix 1003.1).
+        //
+        // Safety: mmap always maps to the end of a page, and we drop the slice
+        // before munmap().
+        let last_page = unsafe {
+            let last_page_p = ((p as *mut u8)
+                .offset((offset as isize & page_mask) + size as isize)
+                as isize
+                & !page_mask) as *const u8;
+            std::slice::from_raw_parts(last_page_p, page_size)
+        };
+        for (i, b) in last_page[self.file_size as usize & page_mask as usize..]
+            .iter()
+            .enumerate()
+        {
+            if *b != 0 {
+                error!(
+                    "Mapped non-zero data past EoF ({:#x}) page offset {:#x} \
+                     is {:#x}",
+                    self.file_size - 1,
+                    (self.file_size & page_mask as u64) + i as u64,
+                    *b
+                );
+                self.fail();
+            }
+        }
+    }
+
+    fn check_size(&mut self) {
+        if !self.nosizechecks {
+            let size = self.file.metadata().unwrap().len();
+            let size_by_seek = self.file.seek(SeekFrom::End(0)).unwrap();
+            if size != self.file_size || size_by_seek != self.file_size {
+                error!(
+                    "Size error: expected {:#x} but found {:#x} by stat and \
+                     {:#x} by seek",
+                    self.file_size, size, size_by_seek
+                );
+                self.fail();
+            }
+        }
+    }
+
+    /// Close and reopen the file
+    fn closeopen(&mut self) {
+        self.oplog.push(LogEntry::CloseOpen);
+
+        if self.skip() {
+            return;
+        }
+        self.reopen_file();
+    }
+
+    /// Close and reopen the file, following an operation if `closeprob` says
+    /// so.  Like the other random choices, it's derived from the seed.
+    fn maybe_closeopen(&mut self) {
+        let Some(closeprob) = self.closeprob else {
+            return;
+        };
+        let word = v1_word(self.seed, self.steps, 6);
+        if word % u64::from(closeprob.get()) != 0 {
+            return;
+        }
+        self.reopens.push(self.steps);
+        if !self.skip() {
+            self.reopen_file();
+        }
+    }
+
+    fn reopen_file(&mut self) {
+        info!("{:width$} close/open", self.steps, width = self.stepwidth);
+
+        if self.reopen == Reopen::ProcFd {
+            // The old file descriptor must remain open until the new one is.
+            let newfile = match reopen_procfd(&self.file) {
+                Ok(f) => f,
+                Err(e) => {
+                    error!("Cannot reopen file via /proc: {e}");
+                    self.fail();
+                }
+            };
+            drop(mem::replace(&mut self.file, SharedFile(newfile)));
+            self.check_file();
+            return;
+        }
+
+        // We must remove and drop the old File before opening it, and that
+        // requires swapping its contents.
+        // Safe because we never access the uninitialized File object.
+        unsafe {
+            let placeholder = mem::MaybeUninit::zeroed().assume_init();
+            drop(mem::replace(&mut self.file, SharedFile(placeholder)));
+            let newfile = if self.reopen == Reopen::Openat2 {
+                eintr(|| reopen_openat2(&self.fname)).expect("Cannot open file")
+            } else {
+                eintr(|| {
+                    OpenOptions::new().read(true).write(true).open(&self.fname)
+                })
+                .expect("Cannot open file")
+            };
+            let placeholder = mem::replace(&mut self.file, SharedFile(newfile));
+            let _ = placeholder.0.into_raw_fd();
+        }
+        if self.reopen == Reopen::Openat2 || self.verify_interval.is_some() {
+            self.check_file();
+        }
+    }
+
+    /// Read back the entire file and compare it to the shadow buffer
+    fn check_file(&self) {
+        if let Some(oracle) = &*self.oracle {
+            self.check_oracle(oracle);
+        }
+        let (start, end) = (self.region.0, self.region.1.min(self.file_size));
+        if self.checksums.is_some() {
+            let mut buf = Vec::new();
+            let blocks = start as usize / CHECKSUM_BLOCK
+                ..(end as usize).div_ceil(CHECKSUM_BLOCK);
+            for b in blocks {
+                self.ck_read(b, &mut buf);
+            }
+            return;
+        }
+        // Read a chunk at a time, to bound memory use for huge files
+        let len = end.saturating_sub(start) as usize;
+        let mut buf = vec![0u8; len.min(SPARSE_CHUNK)];
+        let mut offset = start;
+        while offset < end {
+            let n = (end - offset).min(buf.len() as u64) as usize;
+            let buf = &mut buf[..n];
+            if let Err(e) = self.file.read_exact_at(buf, self.base + offset) {
+                error!("reading back file: {e}");
+                self.fail();
+            }
+            self.check_buffers(buf, offset);
+            offset += n as u64;
+        }
+    }
+
+    /// With `ShadowMode::Checksum`, read block `b` back from the file into
+    /// `buf`, with zeros past EoF, and verify it against its checksum
+    fn ck_read(&self, b: usize, buf: &mut Vec<u8>) {
+        let checksums = self.checksums.as_ref().unwrap();
+        let range = Checksums::block(b, self.good_buf.len());
+        let end = range.end.min(self.file_size as usize).max(range.start);
+        buf.clear();
+        buf.resize(range.len(), 0);
+        if let Err(e) = self.file.read_exact_at(
+            &mut buf[..end - range.start],
+            self.base + range.start as u64,
+        ) {
+            error!("reading back file: {e}");
+            self.fail();
+        }
+        let (expected, actual) = (checksums.crcs[b], crc32(buf));
+        if actual != expected {
+            error!(
+                "checksum mismatch: offset= {:#x}, size = {:#x}",
+                range.start,
+                range.len()
+            );
+            error!(
+                "expected CRC-32 {:#010x}, found {:#010x}",
+                expected, actual
+            );
+            match checksums.steps[b] {
+                0 => error!("No step has changed the block"),
+                step => error!("Step {step} last changed the block"),
+            }
+            error!(
+                "shadow = \"checksum\" keeps no expected data, so it can't \
+                 tell which bytes of the block are bad"
+            );
+            error!(
+                "The seed and config regenerate the expected data, so rerun \
+                 with shadow = \"full\" to find them, if the file fits in RAM"
+            );
+            self.badrange
+                .set(Some((range.start as u64, range.end as u64 - 1)));
+            self.fail();
+        }
+    }
+
+    /// With `ShadowMode::Checksum`, load the blocks overlapping `range` into
+    /// the shadow buffer, verifying each as it's read.  `modify` means that
+    /// the current operation will change them.
+    fn ck_load(&mut self, range: Range<u64>, modify: bool) {
+        let Some(checksums) = &*self.checksums else {
+            return;
+        };
+        if range.is_empty() {
+            return;
+        }
+        let first = range.start as usize / CHECKSUM_BLOCK;
+        let last = (range.end as usize - 1) / CHECKSUM_BLOCK;
+        let blocks = (first..=last)
+            .filter(|b| !checksums.loaded.contains(b))
+            .collect::<Vec<_>>();
+        let mut buf = Vec::new();
+        for b in blocks {
+            self.ck_read(b, &mut buf);
+            self.good_buf.write(b * CHECKSUM_BLOCK, &buf);
+            self.checksums.as_mut().unwrap().loaded.push(b);
+        }
+        self.checksums.as_mut().unwrap().dirty |= modify;
+    }
+
+    /// With `ShadowMode::Checksum`, update the checksums after an operation
+    /// that began with the file `old_file_size` bytes long, and drop the
+    /// loaded blocks from the shadow buffer
+    fn ck_store(&mut self, old_file_size: u64) {
+        let Some(checksums) = &mut *self.checksums else {
+            return;
+        };
+        let loaded = mem::take(&mut checksums.loaded);
+        if mem::take(&mut checksums.dirty) {
+            for &b in loaded.iter() {
+                let crc = checksums.crc(&self.good_buf, b, self.file_size);
+                checksums.set(b, crc, self.steps);
+            }
+        }
+        // Blocks entirely past EoF are all zeros
+        let flen = self.good_buf.len();
+        let past_eof = self.file_size.div_ceil(CHECKSUM_BLOCK as u64) as usize
+            ..old_file_size.div_ceil(CHECKSUM_BLOCK as u64) as usize;
+        for b in past_eof {
+            let crc = checksums.zeros(Checksums::block(b, flen).len());
+            checksums.set(b, crc, self.steps);
+        }
+        // Free the chunks that held the loaded blocks.  Nothing else in them
+        // is needed.
+        for b in loaded {
+            let range = Checksums::block(b, flen);
+            let start = range.start - range.start % SPARSE_CHUNK;
+            let end = range.end.next_multiple_of(SPARSE_CHUNK).min(flen);
+            self.good_buf.fill(start..end, 0);
+        }
+    }
+
+    /// Compare the file to the oracle file, independently of fsx's model.
+    fn check_oracle(&self, oracle: &File) {
+        let len = if self.blockmode {
+            self.file_size
+        } else {
+            self.file.metadata().unwrap().len()
+        };
+        let olen = oracle.metadata().unwrap().len();
+        if len != olen {
+            error!(
+                "file and oracle file sizes differ: FILE {:#x} ORACLE {:#x}",
+                len, olen
+            );
+            self.fail();
+        }
+        // Compare a chunk at a time, so a huge file needn't fit in RAM
+        let mut buf = vec![0u8; SPARSE_CHUNK.min(len as usize)];
+        let mut obuf = buf.clone();
+        let mut unmodeled = len != self.file_size;
+        for start in (0..len as usize).step_by(SPARSE_CHUNK) {
+            let n = SPARSE_CHUNK.min(len as usize - start);
+            let (buf, obuf) = (&mut buf[..n], &mut obuf[..n]);
+            if let Err(e) =
+                self.file.read_exact_at(buf, self.base + start as u64)
+            {
+                error!("reading back file: {e}");
+                self.fail();
+            }
+            if let Err(e) = oracle.read_exact_at(obuf, start as u64) {
+                error!("reading back oracle file: {e}");
+                self.fail();
+            }
+            if let Some(i) =
+                buf.iter().zip(obuf.iter()).position(|(b, o)| b != o)
+            {
+                error!(
+                    "file and oracle file differ: offset= {:#x}, FILE {:#04x} \
+                     ORACLE {:#04x}",
+                    start + i,
+                    buf[i],
+                    obuf[i]
+                );
+                self.fail();
+            }
+            unmodeled =
+                unmodeled || buf[..] != *self.good_buf.slice(start..start + n);
+        }
+        if unmodeled {
+            error!(
+                "file agrees with the oracle file, but not with fsx's own \
+                 model"
+            );
+            if len != self.file_size {
+                self.fail();
+            }
+        }
+    }
+
+    /// Apply a modification to the oracle file, if any
+    fn mirror<F>(&self, f: F)
+    where
+        F: FnOnce(&File) -> io::Result<()>,
+    {
+        if let Some(oracle) = &*self.oracle {
+            if let Err(e) = f(oracle) {
+                error!("updating oracle file: {e}");
+                self.fail();
+            }
+        }
+    }
+
+    /// Mirror a copy within the file to the oracle file
+    fn mirror_copy(&self, ioffset: u64, ooffset: u64, size: usize) {
+        self.mirror(|o| {
+            let mut buf = vec![0u8; size];
+            o.read_exact_at(&mut buf, ioffset)?;
+            o.write_all_at(&buf, ooffset)
+        })
+    }
+
+    fn copy_file_range(
+        &mut self,
+        op: Op,
+        mut ioffset: u64,
+        mut ooffset: u64,
+        mut size: usize,
+    ) {
+        let cur_file_size = self.file_size;
+
+        ioffset = if self.file_size > 0 {
+            ioffset % self.file_size
+        } else {
+            0
+        };
+        ioffset -= ioffset % self.align_offset as u64;
+        if ioffset + size as u64 > self.file_size {
+            size = usize::try_from(self.file_size - ioffset).unwrap();
+        }
+
+        ooffset %= self.flen;
+        ooffset -= ooffset % self.align_offset as u64;
+        if ooffset + size as u64 > self.flen {
+            size = usize::try_from(self.flen - ooffset).unwrap();
+        }
+
+        size = if ooffset >= ioffset {
+            size.min((ooffset - ioffset) as usize)
+        } else {
+            size.min((ioffset - ooffset) as usize)
+        };
+        size -= size % self.align_size;
+
+        if size == 0 {
+            self.oplog.push(LogEntry::Skip(op));
+            debug!(
+                "{:width$} skipping zero size copy_file_range",
+                self.steps,
+                width = self.stepwidth
+            );
+        } else {
             if self.file_size < ooffset + size as u64 {
                 if self.file_size < ooffset {
-                    self.good_buf[self.file_size as usize..ooffset as usize]
-                        .fill(0);
+                    self.good_buf
+                        .fill(self.file_size as usize..ooffset as usize, 0);
+                }
+                self.file_size = ooffset + size as u64;
+            }
+            let i = ioffset as usize;
+            let j = ooffset as usize;
+            let saved =
+                self.may_skip().then(|| self.good_buf.to_vec(j..j + size));
+            self.good_buf.copy_within(i..i + size, j);
+
+            self.oplog.push(LogEntry::CopyFileRange(
+                cur_file_size,
+                ioffset,
+                ooffset,
+                size,
+            ));
+            let loglevel = self.loglevel(ioffset, Some(ooffset), size);
+            log!(
+                loglevel,
+                "{:stepwidth$} copy_file_range [{:#fwidth$x}:{:#fwidth$x}] => \
+                 [{:#fwidth$x}:{:#fwidth$x}] ({:#swidth$x} bytes)",
+                self.steps,
+                ioffset,
+                ioffset + size as u64 - 1,
+                ooffset,
+                ooffset + size as u64 - 1,
+                size,
+                stepwidth = self.stepwidth,
+                fwidth = self.fwidth,
+                swidth = self.swidth
+            );
+            let copied = self.do_copy_file_range(ioffset, ooffset, size);
+            self.mirror_copy(ioffset, ooffset, copied);
+            if copied < size {
+                self.rollback(ooffset, copied, saved.unwrap(), cur_file_size);
+            }
+        }
+    }
+
+    /// Like copy_file_range, but deliberately choose overlapping source and
+    /// destination ranges.
+    fn copy_file_range_overlap(
+        &mut self,
+        mut ioffset: u64,
+        mut shift: u64,
+        mut forward: bool,
+        mut size: usize,
+    ) {
+        let cur_file_size = self.file_size;
+        let align = self.align_offset as u64;
+
+        ioffset = if self.file_size > 0 {
+            ioffset % self.file_size
+        } else {
+            0
+        };
+        ioffset -= ioffset % align;
+        if ioffset + size as u64 > self.file_size {
+            size = usize::try_from(self.file_size - ioffset).unwrap();
+        }
+        size -= size % self.align_size;
+
+        // The ranges overlap iff 0 < shift < size, and shift must be aligned.
+        let nshifts = (size as u64 / align).saturating_sub(1);
+        let mut ooffset = ioffset;
+        if nshifts > 0 {
+            shift = align * (1 + shift % nshifts);
+            if ioffset < shift {
+                forward = true;
+            }
+            ooffset = if forward {
+                ioffset + shift
+            } else {
+                ioffset - shift
+            };
+            if ooffset + size as u64 > self.flen {
+                size = usize::try_from(self.flen - ooffset).unwrap();
+                size -= size % self.align_size;
+            }
+        }
+        if nshifts == 0 || size as u64 <= shift {
+            self.oplog.push(LogEntry::Skip(Op::CopyFileRangeOverlap));
+            debug!(
+                "{:width$} skipping too small copy_file_range_overlap",
+                self.steps,
+                width = self.stepwidth
+            );
+            return;
+        }
+
+        // Save the destination's previous contents, in case the file system
+        // refuses to copy.
+        let i = ioffset as usize;
+        let j = ooffset as usize;
+        let saved = self.good_buf.to_vec(j..j + size);
+        self.file_size = self.file_size.max(ooffset + size as u64);
+        self.good_buf.copy_within(i..i + size, j);
+
+        self.oplog.push(LogEntry::CopyFileRange(
+            cur_file_size,
+            ioffset,
+            ooffset,
+            size,
+        ));
+        if self.skip() {
+            return;
+        }
+        let loglevel = self.loglevel(ioffset, Some(ooffset), size);
+        log!(
+            loglevel,
+            "{:stepwidth$} copy_file_range [{:#fwidth$x}:{:#fwidth$x}] => \
+             [{:#fwidth$x}:{:#fwidth$x}] ({:#swidth$x} bytes) overlapping",
+            self.steps,
+            ioffset,
+            ioffset + size as u64 - 1,
+            ooffset,
+            ooffset + size as u64 - 1,
+            size,
+            stepwidth = self.stepwidth,
+            fwidth = self.fwidth,
+            swidth = self.swidth
+        );
+        match self.do_copy_file_range_once(ioffset, ooffset, size) {
+            Ok(r) if r == size => self.mirror_copy(ioffset, ooffset, size),
+            Ok(r) => {
+                error!(
+                    "Short overlapping copy_file_range: {:#x} bytes instead \
+                     of {:#x}",
+                    r, size
+                );
+                self.fail();
+            }
+            Err(nix::Error::EINVAL) => {
+                // POSIX leaves overlapping copies unspecified, and most
+                // implementations reject them.  That's fine, as long as the
+                // file is left unchanged.
+                info!(
+                    "{:width$} copy_file_range rejected overlapping ranges",
+                    self.steps,
+                    width = self.stepwidth
+                );
+                self.good_buf.write(j, &saved);
+                self.file_size = cur_file_size;
+            }
+            Err(e) => {
+                error!("copy_file_range failed with {e}");
+                self.fail();
+            }
+        }
+    }
+
+    /// Perform a single copy_file_range call within the file, returning the
+    /// number of bytes copied.
+    #[cfg(any(target_os = "freebsd", target_os = "linux"))]
+    fn do_copy_file_range_once(
+        &mut self,
+        inoff: u64,
+        outoff: u64,
+        len: usize,
+    ) -> nix::Result<usize> {
+        let mut inoff: i64 = (inoff + self.base).try_into().unwrap();
+        let mut outoff: i64 = (outoff + self.base).try_into().unwrap();
+        eintr(|| {
+            sys!(
+                self,
+                "copy_file_range"(
+                    self.file.as_raw_fd(),
+                    inoff,
+                    self.file.as_raw_fd(),
+                    outoff,
+                    len
+                ),
+                nix::fcntl::copy_file_range(
+                    self.file.as_fd(),
+                    Some(&mut inoff),
+                    self.file.as_fd(),
+                    Some(&mut outoff),
+                    len,
+                )
+            )
+        })
+    }
+
+    #[cfg(not(any(target_os = "freebsd", target_os = "linux")))]
+    fn do_copy_file_range_once(
+        &mut self,
+        _inoff: u64,
+        _outoff: u64,
+        _len: usize,
+    ) -> nix::Result<usize> {
+        eprintln!("copy_file_range is not supported on this platform.");
+        process::exit(1);
+    }
+
+    /// Actually perform the copy_file_range, including retrying short writes
+    #[cfg(any(target_os = "freebsd", target_os = "linux"))]
+    fn do_copy_file_range(
+        &mut self,
+        inoff: u64,
+        outoff: u64,
+        len: usize,
+    ) -> usize {
+        let mut inoff: i64 = (inoff + self.base).try_into().unwrap();
+        let mut outoff: i64 = (outoff + self.base).try_into().unwrap();
+        let mut copied = 0;
+        while copied < len {
+            let r = match self.retry(|| {
+                sys!(
+                    self,
+                    "copy_file_range"(
+                        self.file.as_raw_fd(),
+                        inoff,
+                        self.file.as_raw_fd(),
+                        outoff,
+                        len - copied
+                    ),
+                    nix::fcntl::copy_file_range(
+                        self.file.as_fd(),
+                        Some(&mut inoff),
+                        self.file.as_fd(),
+                        Some(&mut outoff),
+                        len - copied,
+                    )
+                )
+            }) {
+                Ok(r) => r,
+                Err(e) if self.skip_error(Op::CopyFileRange, &e) => break,
+                Err(e) => {
+                    error!("copy_file_range failed with {e}");
+                    self.fail();
+                }
+            };
+            assert!(r > 0, "0-length copy_file_range");
+            copied += r;
+        }
+        copied
+    }
+
+    #[cfg(not(any(target_os = "freebsd", target_os = "linux")))]
+    fn do_copy_file_range(
+        &mut self,
+        _inoff: u64,
+        _outoff: u64,
+        _len: usize,
+    ) -> usize {
+        eprintln!("copy_file_range is not supported on this platform.");
+        process::exit(1);
+    }
+
+    /// Read part of the file.  Returns false if the error policy skipped the
+    /// read, leaving `buf` incomplete.
+    fn doread(&mut self, buf: &mut [u8], offset: u64, size: usize) -> bool {
+        // A signal may interrupt the read after a partial transfer.
+        let mut read = 0;
+        while read < size {
+            let off = self.base + offset + read as u64;
+            let r = self.retry(|| {
+                sys!(
+                    self,
+                    "pread"(self.file.as_raw_fd(), size - read, off),
+                    self.file.read_at(&mut buf[read..size], off)
+                )
+            });
+            match r {
+                Ok(0) => break,
+                Ok(n) => read += n,
+                Err(e) if self.skip_error(Op::Read, &e) => return false,
+                Err(e) => {
+                    error!("read failed with {e}");
+                    self.fail();
+                }
+            }
+        }
+        if read < size {
+            error!("short read: {:#x} bytes instead of {:#x}", read, size);
+            self.fail();
+        }
+        true
+    }
+
+    /// Read part of the file through a mapping.  Returns false if the error
+    /// policy skipped the read.
+    fn domapread(&mut self, buf: &mut [u8], offset: u64, size: usize) -> bool {
+        let page_mask = Self::getpagesize() as usize - 1;
+        let pg_offset = offset as usize & page_mask;
+        let map_size = pg_offset + size;
+        unsafe {
+            let Some(p) = self.map_or_skip(
+                Op::MapRead,
+                offset - pg_offset as u64,
+                map_size,
+            ) else {
+                return false;
+            };
+            self.map_fault(Op::MapRead, p.as_ptr(), offset, map_size)
+                .guard(|| {
+                    p.as_ptr()
+                        .cast::<u8>()
+                        .add(pg_offset)
+                        .copy_to(buf.as_mut_ptr(), size);
+                    self.check_eofpage(offset, p.as_ptr(), size);
+                });
+            self.unmap(Op::MapRead, p, map_size);
+        }
+        // After a fault, buf holds garbage.  step will report the fault.
+        !map_faulted()
+    }
+
+    fn domapwrite(
+        &mut self,
+        cur_file_size: u64,
+        size: usize,
+        offset: u64,
+    ) -> usize {
+        let page_mask = Self::getpagesize() as usize - 1;
+        let pg_offset = offset as usize & page_mask;
+        let map_size = pg_offset + size;
+        // Safety: good luck proving it's safe.
+        unsafe {
+            // Map before extending the file, so a failed mmap leaves the file
+            // as it was.
+            let Some(p) = self.map_or_skip(
+                Op::MapWrite,
+                offset - pg_offset as u64,
+                map_size,
+            ) else {
+                return 0;
+            };
+            if self.file_size > cur_file_size {
+                let r = self.retry(|| {
+                    sys!(
+                        self,
+                        "ftruncate"(self.file.as_raw_fd(), self.file_size),
+                        self.file.set_len(self.file_size)
+                    )
+                });
+                match r {
+                    Ok(()) => (),
+                    Err(e) if self.skip_error(Op::MapWrite, &e) => {
+                        self.unmap(Op::MapWrite, p, map_size);
+                        return 0;
+                    }
+                    Err(e) => {
+                        error!("ftruncate failed with {e}");
+                        self.fail();
+                    }
+                }
+            }
+            let buf =
+                &self.good_buf.slice(offset as usize..offset as usize + size);
+            self.map_fault(Op::MapWrite, p.as_ptr(), offset, map_size)
+                .guard(|| {
+                    p.as_ptr()
+                        .cast::<u8>()
+                        .add(pg_offset)
+                        .copy_from(buf.as_ptr(), size);
+                    if !self.nomsyncafterwrite && !map_faulted() {
+                        sys!(
+                            self,
+                            "msync"(format!("{:p}", p), map_size, "MS_SYNC"),
+                            msync(p, map_size, MsFlags::MS_SYNC)
+                        )
+                        .unwrap();
+                    }
+                    self.check_eofpage(offset, p.as_ptr(), size);
+                });
+            self.unmap(Op::MapWrite, p, map_size);
+        }
+        size
+    }
+
+    fn dowrite(
+        &mut self,
+        _cur_file_size: u64,
+        size: usize,
+        offset: u64,
+    ) -> usize {
+        let buf = &self.good_buf.slice(offset as usize..offset as usize + size);
+        let mut written = 0;
+        while written < size {
+            let off = self.base + offset + written as u64;
+            let r = self.retry(|| {
+                sys!(
+                    self,
+                    "pwrite"(self.file.as_raw_fd(), size - written, off),
+                    self.file.write_at(&buf[written..], off)
+                )
+            });
+            match r {
+                // A short write is legitimate only if the next one fails, or
+                // if a signal interrupted it.
+                Ok(n) if n > 0 && (written == 0 || self.signal_storm) => {
+                    written += n
+                }
+                Ok(_) => {
+                    error!(
+                        "short write: {:#x} bytes instead of {:#x}",
+                        written, size
+                    );
+                    self.fail();
+                }
+                Err(e) if self.skip_error(Op::Write, &e) => return written,
+                Err(e) => {
+                    error!("write failed with {e}");
+                    self.fail();
+                }
+            }
+        }
+        size
+    }
+
+    /// Undo the shadow update for the part of an operation beyond the first
+    /// `done` bytes of the range at `offset`, whose prior contents are in
+    /// `saved`.
+    fn rollback(
+        &mut self,
+        offset: u64,
+        done: usize,
+        saved: Vec<u8>,
+        cur_file_size: u64,
+    ) {
+        let o = offset as usize;
+        self.good_buf.write(o + done, &saved[done..]);
+        if done > 0 {
+            self.file_size = cur_file_size.max(offset + done as u64);
+        } else {
+            self.file_size = cur_file_size;
+        }
+    }
+
+    /// The configured policy for an errno
+    fn policy(&self, errno: i32) -> ErrorPolicy {
+        self.errors
+            .get(&errno)
+            .copied()
+            .unwrap_or(ErrorPolicy::Fail)
+    }
+
+    /// Whether the error policy might skip any operation
+    fn may_skip(&self) -> bool {
+        self.errors.values().any(|p| *p == ErrorPolicy::Skip)
+    }
+
+    /// Perform a system call, retrying it as the error policy directs
+    fn retry<T, E: SysError>(
+        &self,
+        mut f: impl FnMut() -> Result<T, E>,
+    ) -> Result<T, E> {
+        let mut tries = 0;
+        loop {
+            let r = match self.inject_errno {
+                Some(errno)
+                    if Some(self.steps) == self.inject
+                        && !self.errno_injected.replace(true) =>
+                {
+                    Err(E::from_errno(errno))
+                }
+                _ => eintr(&mut f),
+            };
+            match r {
+                Err(e) => match self.policy(e.errno()) {
+                    ErrorPolicy::Retry(n) if tries < n.get() => {
+                        tries += 1;
+                        info!(
+                            "{:width$} retrying after {}",
+                            self.steps,
+                            e,
+                            width = self.stepwidth
+                        );
+                    }
+                    _ => return Err(e),
+                },
+                r => return r,
+            }
+        }
+    }
+
+    /// Whether the error policy says to skip the rest of an operation that
+    /// failed with this error
+    fn skip_error<E: SysError>(&self, op: Op, e: &E) -> bool {
+        let skip = self.policy(e.errno()) == ErrorPolicy::Skip;
+        if skip {
+            self.skips.set(self.skips.get() + 1);
+            warn!(
+                "{:width$} {} failed with {}, skipping",
+                self.steps,
+                op,
+                e,
+                width = self.stepwidth
+            );
+        }
+        skip
+    }
+
+    /// Describe one oplog entry, for the log dump
+    fn describe(&self, i: u64, le: &LogEntry) -> String {
+        match le {
+            LogEntry::Skip(op) => format!(
+                "{:stepwidth$} SKIPPED  ({})",
+                i,
+                op,
+                stepwidth = self.stepwidth
+            ),
+            LogEntry::CloseOpen => format!(
+                "{:stepwidth$} CLOSE/OPEN",
+                i,
+                stepwidth = self.stepwidth
+            ),
+            LogEntry::Read(offset, size) => format!(
+                "{:stepwidth$} READ     {:#fwidth$x} => {:#fwidth$x} \
+                 ({:#swidth$x} bytes)",
+                i,
+                offset,
+                offset + *size as u64,
+                size,
+                stepwidth = self.stepwidth,
+                fwidth = self.fwidth,
+                swidth = self.swidth
+            ),
+            LogEntry::MapRead(offset, size) => format!(
+                "{:stepwidth$} MAPREAD  {:#fwidth$x} => {:#fwidth$x} \
+                 ({:#swidth$x} bytes)",
+                i,
+                offset,
+                offset + *size as u64,
+                size,
+                stepwidth = self.stepwidth,
+                fwidth = self.fwidth,
+                swidth = self.swidth
+            ),
+            LogEntry::Write(old_len, offset, size) => {
+                let sym = if offset > old_len {
+                    " HOLE"
+                } else if offset + *size as u64 > *old_len {
+                    " EXTEND"
+                } else {
+                    ""
+                };
+                format!(
+                    "{:stepwidth$} WRITE    {:#fwidth$x} => {:#fwidth$x} \
+                     ({:#swidth$x} bytes){}",
+                    i,
+                    offset,
+                    offset + *size as u64,
+                    size,
+                    sym,
+                    stepwidth = self.stepwidth,
+                    fwidth = self.fwidth,
+                    swidth = self.swidth
+                )
+            }
+            LogEntry::MapWrite(old_len, offset, size) => {
+                let sym = if offset > old_len {
+                    " HOLE"
+                } else if offset + *size as u64 > *old_len {
+                    " EXTEND"
+                } else {
+                    ""
+                };
+                format!(
+                    "{:stepwidth$} MAPWRITE {:#fwidth$x} => {:#fwidth$x} \
+                     ({:#swidth$x} bytes){}",
+                    i,
+                    offset,
+                    offset + *size as u64,
+                    size,
+                    sym,
+                    stepwidth = self.stepwidth,
+                    fwidth = self.fwidth,
+                    swidth = self.swidth
+                )
+            }
+            LogEntry::Truncate(old_len, new_len) => {
+                let dir = if new_len > old_len { "UP" } else { "DOWN" };
+                format!(
+                    "{:stepwidth$} TRUNCATE  {:4} from {:#fwidth$x} to \
+                     {:#fwidth$x}",
+                    i,
+                    dir,
+                    old_len,
+                    new_len,
+                    stepwidth = self.stepwidth,
+                    fwidth = self.fwidth
+                )
+            }
+            LogEntry::Invalidate => format!(
+                "{:stepwidth$} INVALIDATE",
+                i,
+                stepwidth = self.stepwidth
+            ),
+            LogEntry::Fsync => {
+                format!("{:stepwidth$} FSYNC", i, stepwidth = self.stepwidth)
+            }
+            LogEntry::Fdatasync => format!(
+                "{:stepwidth$} FDATASYNC",
+                i,
+                stepwidth = self.stepwidth
+            ),
+            LogEntry::PosixFallocate(offset, len) => {
+                format!(
+                    "{:stepwidth$} POSIX_FALLOCATE {:#fwidth$x} => \
+                     {:#fwidth$x} ({:#swidth$x} bytes)",
+                    i,
+                    offset,
+                    offset + len - 1,
+                    len,
+                    stepwidth = self.stepwidth,
+                    swidth = self.swidth,
+                    fwidth = self.fwidth
+                )
+            }
+            LogEntry::PunchHole(offset, len) => {
+                format!(
+                    "{:stepwidth$} PUNCH_HOLE {:#fwidth$x} => {:#fwidth$x} \
+                     ({:#swidth$x} bytes)",
+                    i,
+                    offset,
+                    offset + len - 1,
+                    len,
+                    stepwidth = self.stepwidth,
+                    swidth = self.swidth,
+                    fwidth = self.fwidth
+                )
+            }
+            LogEntry::Sendfile(offset, size) => format!(
+                "{:stepwidth$} SENDFILE {:#fwidth$x} => {:#fwidth$x} \
+                 ({:#swidth$x} bytes)",
+                i,
+                offset,
+                offset + *size as u64,
+                size,
+                stepwidth = self.stepwidth,
+                fwidth = self.fwidth,
+                swidth = self.swidth
+            ),
+            #[cfg(any(
+                target_os = "linux",
+                target_os = "android",
+                target_os = "freebsd"
+            ))]
+            LogEntry::PosixFadvise(advice, offset, len) => format!(
+                "{:stepwidth$} POSIX_FADVISE({:10}) {:#fwidth$x} => \
+                 {:#fwidth$x} ({:#swidth$x} bytes)",
+                i,
+                advice,
+                offset,
+                offset + len - 1,
+                len,
+                stepwidth = self.stepwidth,
+                swidth = self.swidth,
+                fwidth = self.fwidth
+            ),
+            LogEntry::ReadEof(len, offset, size) => format!(
+                "{:stepwidth$} READ_EOF {:#fwidth$x} => {:#fwidth$x} \
+                 ({:#swidth$x} bytes) EOF at {:#fwidth$x}",
+                i,
+                offset,
+                offset + *size as u64,
+                size,
+                len,
+                stepwidth = self.stepwidth,
+                fwidth = self.fwidth,
+                swidth = self.swidth
+            ),
+            LogEntry::FsizeLimit(len, limit, offset, size, truncate) => {
+                if *truncate {
+                    format!(
+                        "{:stepwidth$} FSIZE_LIMIT {:#fwidth$x} TRUNCATE \
+                         {:#fwidth$x} => {:#fwidth$x}",
+                        i,
+                        limit,
+                        len,
+                        offset + *size as u64,
+                        stepwidth = self.stepwidth,
+                        fwidth = self.fwidth,
+                    )
+                } else {
+                    format!(
+                        "{:stepwidth$} FSIZE_LIMIT {:#fwidth$x} WRITE \
+                         {:#fwidth$x} => {:#fwidth$x} ({:#swidth$x} bytes)",
+                        i,
+                        limit,
+                        offset,
+                        offset + *size as u64,
+                        size,
+                        stepwidth = self.stepwidth,
+                        fwidth = self.fwidth,
+                        swidth = self.swidth
+                    )
+                }
+            }
+            LogEntry::Stat => {
+                format!("{:stepwidth$} STAT", i, stepwidth = self.stepwidth)
+            }
+            LogEntry::Reflink(len) => format!(
+                "{:stepwidth$} REFLINK  {:#fwidth$x}",
+                i,
+                len,
+                stepwidth = self.stepwidth,
+                fwidth = self.fwidth
+            ),
+            LogEntry::Scratch(op, old_len, ioffset, ooffset, size) => {
+                let sym = if ooffset > old_len {
+                    " HOLE"
+                } else if ooffset + *size as u64 > *old_len {
+                    " EXTEND"
+                } else {
+                    ""
+                };
+                format!(
+                    "{:stepwidth$} {} [{:#fwidth$x},{:#fwidth$x}] => \
+                     [{:#fwidth$x},{:#fwidth$x}] ({:#swidth$x} bytes){}",
+                    i,
+                    op.to_string().to_uppercase(),
+                    ioffset,
+                    ioffset + *size as u64,
+                    ooffset,
+                    ooffset + *size as u64,
+                    size,
+                    sym,
+                    stepwidth = self.stepwidth,
+                    fwidth = self.fwidth,
+                    swidth = self.swidth
+                )
+            }
+            LogEntry::CopyFileRange(old_len, ioffset, ooffset, size) => {
+                let sym = if ooffset > old_len {
+                    " HOLE"
+                } else if ooffset + *size as u64 > *old_len {
+                    " EXTEND"
+                } else {
+                    ""
+                };
+                format!(
+                    "{:stepwidth$} COPY_FILE_RANGE \
+                     [{:#fwidth$x},{:#fwidth$x}] => \
+                     [{:#fwidth$x},{:#fwidth$x}] ({:#swidth$x} bytes){}",
+                    i,
+                    ioffset,
+                    ioffset + *size as u64,
+                    ooffset,
+                    ooffset + *size as u64,
+                    size,
+                    sym,
+                    stepwidth = self.stepwidth,
+                    fwidth = self.fwidth,
+                    swidth = self.swidth
+                )
+            }
+        }
+    }
+
+    /// Dump the contents of the oplog
+    fn dump_logfile(&self) {
+        let first = self.steps + 1 - self.oplog.len() as u64;
+        error!("Using seed {} and RNG {}", self.seed, self.rng.kind());
+        if let Some(thread) = self.thread {
+            error!(
+                "Thread {} operating on {:#x} .. {:#x}",
+                thread,
+                self.region.0,
+                self.region.1 - 1
+            );
+        }
+        error!("LOG DUMP");
+        let mut targets = self.targets.iter();
+        let mut timings = self.timings.iter().flat_map(|t| t.iter());
+        let mut last_target = None;
+        let mut overlapping = Vec::new();
+        for (i, le) in (first..).zip(self.oplog.iter()) {
+            if let Some(&t) = targets.next() {
+                if last_target != Some(t) {
+                    error!(
+                        "{:stepwidth$} FILE     {}",
+                        i,
+                        self.target_name(t).display(),
+                        stepwidth = self.stepwidth
+                    );
+                    last_target = Some(t);
+                }
+            }
+            let line = self.describe(i, le);
+            // Mark operations that overlap a miscompare, like the C-based FSX
+            let mark = match (self.badrange.get(), le.range()) {
+                (Some((first, last)), Some((offset, len)))
+                    if last_target.map_or(true, |t| t == self.cur)
+                        && offset <= last
+                        && first < offset + len =>
+                {
+                    overlapping.push(i);
+                    if le.modifies() {
+                        "\t******WWWW"
+                    } else {
+                        "\t***RRRR***"
+                    }
+                }
+                _ => "",
+            };
+            let line = format!("{line}{mark}");
+            error!("{}", with_timing(line, timings.next()));
+            if self.reopens.iter().any(|&step| step == i) {
+                error!(
+                    "{:stepwidth$} CLOSE/OPEN",
+                    i,
+                    stepwidth = self.stepwidth
+                );
+            }
+        }
+        if let Some((first, last)) = self.badrange.get() {
+            if overlapping.is_empty() {
+                error!(
+                    "No logged operation overlaps the bad range {:#x} .. {:#x}",
+                    first, last
+                );
+            } else {
+                let steps = overlapping
+                    .iter()
+                    .map(u64::to_string)
+                    .collect::<Vec<_>>()
+                    .join(", ");
+                error!(
+                    "Steps that overlap the bad range {:#x} .. {:#x}: {}",
+                    first, last, steps
+                );
+            }
+        }
+    }
+
+    /// Log the file's data and hole layout, as found by SEEK_HOLE and
+    /// SEEK_DATA, marking the segments that overlap a miscompare
+    fn dump_layout(&self) {
+        if self.blockmode
+            || cfg!(not(any(
+                target_os = "android",
+                target_os = "freebsd",
+                target_os = "linux",
+            )))
+        {
+            return;
+        }
+        let Some(len) = self.actual_len() else {
+            return;
+        };
+        let mut segments = Vec::new();
+        let mut pos = 0;
+        while pos < len {
+            let (hole, data) = match seek_hole_data(&self.file, self.base + pos)
+            {
+                Ok(r) => r,
+                Err(e) => {
+                    warn!("Cannot read the file layout: lseek: {e}");
+                    return;
+                }
+            };
+            let (hole, data) = (hole - self.base, data.map(|d| d - self.base));
+            let (end, kind) = if data == Some(pos) {
+                (hole.min(len), "data")
+            } else {
+                (data.unwrap_or(len).min(len), "hole")
+            };
+            segments.push((pos, end, kind));
+            pos = end;
+        }
+        error!("FILE LAYOUT");
+        let mut omitted = 0;
+        for (i, &(start, end, kind)) in segments.iter().enumerate() {
+            let bad = self
+                .badrange
+                .get()
+                .is_some_and(|(first, last)| start <= last && first < end);
+            if i >= LAYOUT_MAX && !bad {
+                omitted += 1;
+                continue;
+            }
+            let mark = if bad { "\t***BAD***" } else { "" };
+            error!("{:#x} .. {:#x} {}{}", start, end - 1, kind, mark);
+        }
+        if omitted > 0 {
+            error!("... and {} more segments", omitted);
+        }
+    }
+
+    /// Log the kernel version and the file system's type, mount options, and
+    /// statvfs, for triage
+    fn dump_fs_info(&self) {
+        error!("FILE SYSTEM");
+        match nix::sys::utsname::uname() {
+            Ok(u) => error!(
+                "kernel: {} {} {} {}",
+                u.sysname().to_string_lossy(),
+                u.release().to_string_lossy(),
+                u.version().to_string_lossy(),
+                u.machine().to_string_lossy()
+            ),
+            Err(e) => warn!("uname: {e}"),
+        }
+        if self.blockmode {
+            return;
+        }
+        match mount_info(&self.fname, &self.file) {
+            Ok(desc) => error!("mount: {desc}"),
+            Err(e) => {
+                warn!("Cannot find the mount of {}: {e}", self.fname.display())
+            }
+        }
+        match nix::sys::statvfs::fstatvfs(&self.file) {
+            Ok(sv) => error!(
+                "statvfs: bsize {}, frsize {}, blocks {} ({} free, {} \
+                 available), files {} ({} free), namemax {}, flags {:?}",
+                sv.block_size(),
+                sv.fragment_size(),
+                sv.blocks(),
+                sv.blocks_free(),
+                sv.blocks_available(),
+                sv.files(),
+                sv.files_free(),
+                sv.name_max(),
+                sv.flags()
+            ),
+            Err(e) => warn!("fstatvfs: {e}"),
+        }
+    }
+
+    /// Report a failure and exit.
+    fn fail(&self) -> ! {
+        // Never release the lock, so only one thread reports its failure.
+        let _guard = FAILURE.lock();
+        if self.meter.is_some() {
+            Meter::clear();
+        }
+        self.dump_logfile();
+        self.dump_layout();
+        self.dump_fs_info();
+        if self.checksums.is_some() {
+            self.save_bad_block();
+        } else {
+            match self.artifact_mode {
+                ArtifactMode::Full => {
+                    self.save_goodfile();
+                    self.save_badfile();
+                }
+                ArtifactMode::Diff => self.save_difffile(),
+            }
+        }
+        if let Some(trace) = &*self.trace {
+            trace.flush();
+        }
+        if let Some(csv) = &*self.csv {
+            csv.flush();
+        }
+        #[cfg(feature = "strace")]
+        if let Some(strace) = &*self.strace {
+            strace.flush();
+        }
+        if let Some(oplog_file) = &*self.oplog_file {
+            oplog_file.flush();
+        }
+        self.save_dmesg();
+        self.save_report(Some("failed"));
+        self.save_state();
+        self.save_repro();
+        if let Some(latencies) = &self.latencies {
+            print_latencies(latencies);
+        }
+        process::exit(1);
+    }
+
+    /// Wrapper around read-like operations
+    fn read_like<F>(&mut self, op: Op, offset: u64, size: usize, f: F)
+    where
+        F: Fn(&mut Exerciser, &mut [u8], u64, usize) -> bool,
+    {
+        if size == 0 {
+            self.oplog.push(LogEntry::Skip(op));
+            debug!(
+                "{:width$} skipping zero size read",
+                self.steps,
+                width = self.stepwidth
+            );
+            return;
+        }
+        if size as u64 + offset > self.file_size {
+            self.oplog.push(LogEntry::Skip(op));
+            debug!(
+                "{:width$} skipping seek/read past EoF",
+                self.steps,
+                width = self.stepwidth
+            );
+            return;
+        }
+        match op {
+            Op::Read => self.oplog.push(LogEntry::Read(offset, size)),
+            Op::MapRead => self.oplog.push(LogEntry::MapRead(offset, size)),
+            Op::Sendfile => self.oplog.push(LogEntry::Sendfile(offset, size)),
+            _ => unimplemented!(),
+        }
+        if self.skip() {
+            return;
+        }
+        let loglevel = self.loglevel(offset, None, size);
+        log!(
+            loglevel,
+            "{:stepwidth$} {:8} {:#fwidth$x} .. {:#fwidth$x} ({:#swidth$x} \
+             bytes)",
+            self.steps,
+            op,
+            offset,
+            offset + size as u64 - 1,
+            size,
+            stepwidth = self.stepwidth,
+            fwidth = self.fwidth,
+            swidth = self.swidth
+        );
+        self.ck_load(offset..offset + size as u64, false);
+        let mut temp_buf = self.take_read_buf(size);
+        if f(self, &mut temp_buf[..size], offset, size) {
+            self.bytes_read += size as u64;
+            self.check_buffers(&temp_buf[..size], offset);
+        }
+        self.read_buf = temp_buf;
+        if self.direct_file.is_some() {
+            let direct_buf = self.doread_direct(offset, size);
+            self.check_buffers(&direct_buf, offset);
+        }
+    }
+
+    /// Take the read scratch buffer, grown to at least `size` bytes.  The
+    /// caller should put it back when done.
+    fn take_read_buf(&mut self, size: usize) -> Vec<u8> {
+        let mut buf = mem::take(&mut self.read_buf);
+        if buf.len() < size {
+            buf.resize(size, 0);
+        }
+        buf
+    }
+
+    /// Read a range of the file with O_DIRECT.  The actual I/O will be
+    /// expanded to satisfy alignment requirements.
+    fn doread_direct(&self, offset: u64, size: usize) -> Vec<u8> {
+        let f = self.direct_file.as_ref().unwrap();
+        let align = self.blksize.max(512);
+        let start = offset - offset % align;
+        let end = (offset + size as u64).div_ceil(align) * align;
+        let len = (end - start) as usize;
+        // The buffer must be aligned, too.
+        let mut storage = vec![0u8; len + align as usize];
+        let skip = storage.as_ptr().align_offset(align as usize);
+        let buf = &mut storage[skip..skip + len];
+        let mut nread = 0;
+        while start + (nread as u64) < offset + size as u64 {
+            match f.read_at(&mut buf[nread..], self.base + start + nread as u64)
+            {
+                Ok(0) => break,
+                Ok(n) => nread += n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => (),
+                Err(e) => {
+                    error!("O_DIRECT read failed with {e}");
+                    self.fail();
+                }
+            }
+        }
+        let first = (offset - start) as usize;
+        if nread < first + size {
+            error!(
+                "short O_DIRECT read: {:#x} bytes instead of {:#x}",
+                nread.saturating_sub(first),
+                size
+            );
+            self.fail();
+        }
+        buf[first..first + size].to_vec()
+    }
+
+    /// Move data between the test file and the scratch file
+    fn scratch_op(
+        &mut self,
+        op: Op,
+        mut ioffset: u64,
+        mut ooffset: u64,
+        mut size: usize,
+    ) {
+        let scratch = self.scratch.as_mut().unwrap();
+        // Which direction does the data flow?
+        let into_scratch =
+            matches!(op, Op::ScratchCopyOut | Op::ScratchSendfile);
+        let (src_size, dst_size) = if into_scratch {
+            (self.file_size, scratch.size)
+        } else {
+            (scratch.size, self.file_size)
+        };
+        // FICLONERANGE requires block-aligned ranges
+        let (oalign, salign) = if op == Op::ScratchClone {
+            let bs = self.blksize as usize;
+            (self.align_offset.max(bs), self.align_size.max(bs))
+        } else {
+            (self.align_offset, self.align_size)
+        };
+
+        ioffset = if src_size > 0 { ioffset % src_size } else { 0 };
+        ioffset -= ioffset % oalign as u64;
+        if ioffset + size as u64 > src_size {
+            size = usize::try_from(src_size - ioffset).unwrap();
+        }
+        ooffset %= self.flen;
+        ooffset -= ooffset % oalign as u64;
+        if ooffset + size as u64 > self.flen {
+            size = usize::try_from(self.flen - ooffset).unwrap();
+        }
+        size -= size % salign;
+
+        if size == 0 {
+            self.oplog.push(LogEntry::Skip(op));
+            debug!(
+                "{:width$} skipping zero size {}",
+                self.steps,
+                op,
+                width = self.stepwidth
+            );
+            return;
+        }
+
+        let i = ioffset as usize;
+        let j = ooffset as usize;
+        let new_size = dst_size.max(ooffset + size as u64);
+        if into_scratch {
+            if dst_size < ooffset {
+                scratch.good_buf[dst_size as usize..j].fill(0);
+            }
+            scratch.good_buf[j..j + size]
+                .copy_from_slice(&self.good_buf.slice(i..i + size));
+            scratch.size = new_size;
+        } else {
+            if dst_size < ooffset {
+                self.good_buf.fill(dst_size as usize..j, 0);
+            }
+            self.good_buf.write(j, &scratch.good_buf[i..i + size]);
+            self.file_size = new_size;
+        }
+        self.oplog
+            .push(LogEntry::Scratch(op, dst_size, ioffset, ooffset, size));
+
+        if self.skip() {
+            return;
+        }
+        let loglevel = if into_scratch {
+            self.loglevel(ioffset, None, size)
+        } else {
+            self.loglevel(ooffset, None, size)
+        };
+        log!(
+            loglevel,
+            "{:stepwidth$} {} [{:#fwidth$x}:{:#fwidth$x}] => \
+             [{:#fwidth$x}:{:#fwidth$x}] ({:#swidth$x} bytes)",
+            self.steps,
+            op,
+            ioffset,
+            ioffset + size as u64 - 1,
+            ooffset,
+            ooffset + size as u64 - 1,
+            size,
+            stepwidth = self.stepwidth,
+            fwidth = self.fwidth,
+            swidth = self.swidth
+        );
+
+        let scratch = self.scratch.as_mut().unwrap();
+        let r = match op {
+            Op::ScratchCopyIn => copy_file_range_all(
+                &scratch.file,
+                ioffset,
+                &self.file,
+                ooffset,
+                size,
+            ),
+            Op::ScratchCopyOut => copy_file_range_all(
+                &self.file,
+                ioffset,
+                &scratch.file,
+                ooffset,
+                size,
+            ),
+            Op::ScratchSendfile => sendfile_all(
+                &self.file,
+                ioffset,
+                &mut scratch.file,
+                ooffset,
+                size,
+            ),
+            Op::ScratchClone => clone_range(
+                &scratch.file,
+                ioffset,
+                &self.file,
+                ooffset,
+                size as u64,
+            ),
+            _ => unreachable!(),
+        };
+        match r {
+            Ok(()) => (),
+            Err(
+                nix::Error::EOPNOTSUPP | nix::Error::EINVAL | nix::Error::EXDEV,
+            ) if op == Op::ScratchClone => {
+                error!("Test file system does not support FICLONERANGE.");
+                self.fail();
+            }
+            Err(e) => {
+                error!("{op} failed with {e}");
+                self.fail();
+            }
+        }
+        if into_scratch {
+            self.check_scratch(ooffset, size);
+        } else {
+            let scratch = self.scratch.as_ref().unwrap();
+            self.mirror(|o| {
+                let mut buf = vec![0u8; size];
+                scratch.file.read_exact_at(&mut buf, ioffset)?;
+                o.write_all_at(&buf, ooffset)
+            });
+        }
+    }
+
+    /// Read back part of the scratch file and compare it to its shadow
+    /// buffer
+    fn check_scratch(&self, offset: u64, size: usize) {
+        let scratch = self.scratch.as_ref().unwrap();
+        let mut buf = vec![0u8; size];
+        if let Err(e) = scratch.file.read_exact_at(&mut buf, offset) {
+            error!("reading back {}: {e}", scratch.path.display());
+            self.fail();
+        }
+        let good = &scratch.good_buf[offset as usize..offset as usize + size];
+        if let Some(i) = good.iter().zip(buf.iter()).position(|(g, b)| g != b) {
+            error!(
+                "scratch file miscompare: offset= {:#x}, GOOD {:#04x} BAD \
+                 {:#04x}",
+                offset + i as u64,
+                good[i],
+                buf[i]
+            );
+            self.fail();
+        }
+    }
+
+    /// Clone the entire file, and remember what the clone should contain.
+    fn reflink(&mut self) {
+        self.oplog.push(LogEntry::Reflink(self.file_size));
+
+        if self.skip() {
+            return;
+        }
+        info!(
+            "{:width$} reflink {:#x} bytes",
+            self.steps,
+            self.file_size,
+            width = self.stepwidth
+        );
+
+        // Before replacing the previous clone, verify that it never changed.
+        self.check_reflink();
+
+        let mut final_component =
+            self.fname.as_path().file_name().unwrap().to_owned();
+        final_component.push(".fsxclone");
+        let mut clonefname = self.fname.clone();
+        clonefname.set_file_name(final_component);
+        let clonefile = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&clonefname)
+            .expect("Cannot create clone file");
+        match clone_file(&self.file, &clonefile) {
+            Ok(()) => (),
+            Err(
+                nix::Error::EOPNOTSUPP | nix::Error::EINVAL | nix::Error::EXDEV,
+            ) => {
+                error!("Test file system does not support FICLONE.");
+                self.fail();
+            }
+            Err(e) => {
+                error!("FICLONE failed with {e}");
+                self.fail();
+            }
+        }
+        let snapshot = self.good_buf.to_vec(0..self.file_size as usize);
+        *self.reflink = Some((clonefile, snapshot));
+    }
+
+    /// Verify that the most recent whole-file clone still has the contents
+    /// that the original file had when it was cloned.
+    fn check_reflink(&self) {
+        let Some((clonefile, snapshot)) = &*self.reflink else {
+            return;
+        };
+        let len = clonefile.metadata().unwrap().len();
+        if len != snapshot.len() as u64 {
+            error!(
+                "Clone size error: expected {:#x} but found {:#x}",
+                snapshot.len(),
+                len
+            );
+            self.fail();
+        }
+        let mut buf = vec![0u8; snapshot.len()];
+        if let Err(e) = clonefile.read_exact_at(&mut buf, 0) {
+            error!("reading back clone: {e}");
+            self.fail();
+        }
+        if let Some(i) =
+            snapshot.iter().zip(buf.iter()).position(|(g, b)| g != b)
+        {
+            error!(
+                "clone miscompare: offset= {:#x}, GOOD {:#04x} BAD {:#04x}",
+                i, snapshot[i], buf[i]
+            );
+            self.fail();
+        }
+    }
+
+    /// Read a range that reaches or straddles EOF, and verify that exactly the
+    /// bytes before EOF are returned.
+    fn read_eof(&mut self, offset: u64, size: usize) {
+        if size == 0 {
+            self.oplog.push(LogEntry::Skip(Op::ReadEof));
+            debug!(
+                "{:width$} skipping zero size read",
+                self.steps,
+                width = self.stepwidth
+            );
+            return;
+        }
+        self.oplog
+            .push(LogEntry::ReadEof(self.file_size, offset, size));
+        if self.skip() {
+            return;
+        }
+        let loglevel = self.loglevel(offset, None, size);
+        log!(
+            loglevel,
+            "{:stepwidth$} {:8} {:#fwidth$x} .. {:#fwidth$x} ({:#swidth$x} \
+             bytes) EOF at {:#fwidth$x}",
+            self.steps,
+            Op::ReadEof,
+            offset,
+            offset + size as u64 - 1,
+            size,
+            self.file_size,
+            stepwidth = self.stepwidth,
+            fwidth = self.fwidth,
+            swidth = self.swidth
+        );
+        let mut temp_buf = vec![0u8; size];
+        let mut nread = 0;
+        loop {
+            let off = offset + nread as u64;
+            let r = self.retry(|| {
+                sys!(
+                    self,
+                    "pread"(self.file.as_raw_fd(), size - nread, off),
+                    self.file.read_at(&mut temp_buf[nread..], off)
+                )
+            });
+            match r {
+                Ok(0) => break,
+                Ok(n) => {
+                    nread += n;
+                    if nread == size {
+                        break;
+                    }
+                }
+                Err(e) if self.skip_error(Op::ReadEof, &e) => return,
+                Err(e) => {
+                    error!("read failed with {e}");
+                    self.fail();
+                }
+            }
+        }
+        let expected = self.file_size.saturating_sub(offset).min(size as u64);
+        if nread as u64 != expected {
+            error!(
+                "read at EOF returned {:#x} bytes instead of {:#x}",
+                nread, expected
+            );
+            self.fail();
+        }
+        self.check_buffers(&temp_buf[..nread], offset)
+    }
+
+    /// Lower RLIMIT_FSIZE to the middle of the range and try to write or
+    /// extend across it.  Everything beyond the limit must fail with EFBIG.
+    fn fsize_limit(&mut self, offset: u64, size: usize, truncate: bool) {
+        let (soft, hard) = getrlimit(Resource::RLIMIT_FSIZE).unwrap();
+        // An unprivileged process can't raise the limit past the hard limit
+        let limit = (offset + (size / 2) as u64).min(hard);
+        let end = offset + size as u64;
+        if limit <= offset || (truncate && end <= self.file_size) {
+            self.oplog.push(LogEntry::Skip(Op::FsizeLimit));
+            debug!(
+                "{:width$} skipping fsize_limit that can't exceed the limit",
+                self.steps,
+                width = self.stepwidth
+            );
+            return;
+        }
+        let cur_file_size = self.file_size;
+        let uoff = offset as usize;
+        let ulimit = limit as usize;
+        let saved = self.good_buf.to_vec(uoff..uoff + size);
+        if !truncate {
+            // Linux allows the portion below the limit to be written.
+            self.gendata(offset, ulimit - uoff);
+            if self.file_size < offset {
+                self.good_buf.fill(self.file_size as usize..uoff, 0);
+            }
+            self.file_size = self.file_size.max(limit);
+        }
+        self.oplog.push(LogEntry::FsizeLimit(
+            cur_file_size,
+            limit,
+            offset,
+            size,
+            truncate,
+        ));
+        if self.skip() {
+            return;
+        }
+        let loglevel = self.loglevel(offset, None, size);
+        log!(
+            loglevel,
+            "{:stepwidth$} {:8} {:#fwidth$x} {} {:#fwidth$x} .. {:#fwidth$x}",
+            self.steps,
+            Op::FsizeLimit,
+            limit,
+            if truncate { "truncate" } else { "write" },
+            offset,
+            end - 1,
+            stepwidth = self.stepwidth,
+            fwidth = self.fwidth,
+        );
+
+        setrlimit(Resource::RLIMIT_FSIZE, limit, hard).unwrap();
+        let mut partial = false;
+        let r = if truncate {
+            self.file.set_len(end).map(|_| 0)
+        } else {
+            let mut buf = saved.clone();
+            buf[..ulimit - uoff]
+                .copy_from_slice(&self.good_buf.slice(uoff..ulimit));
+            match self.file.write_at(&buf, offset) {
+                Ok(n) if n == ulimit - uoff => {
+                    // The remainder must be rejected outright.
+                    partial = true;
+                    self.file.write_at(&buf[n..], limit).map(|_| n)
+                }
+                r => r,
+            }
+        };
+        setrlimit(Resource::RLIMIT_FSIZE, soft, hard).unwrap();
+        match r {
+            Err(e) if e.raw_os_error() == Some(libc::EFBIG) => {
+                if partial {
+                    let range = uoff..ulimit;
+                    self.mirror(|o| {
+                        o.write_all_at(&self.good_buf.slice(range), offset)
+                    });
+                } else if !truncate {
+                    // Some implementations reject the entire write.
+                    self.good_buf.write(uoff, &saved);
+                    self.file_size = cur_file_size;
+                }
+            }
+            Ok(n) => {
+                error!(
+                    "{} beyond RLIMIT_FSIZE succeeded ({:#x} bytes)",
+                    if truncate { "truncate" } else { "write" },
+                    n
+                );
+                self.fail();
+            }
+            Err(e) => {
+                error!("expected EFBIG but got {e}");
+                self.fail();
+            }
+        }
+    }
+
+    /// Expand the placeholders in a hook's template
+    fn expand(&self, template: &str, n: usize) -> String {
+        let path = shell_quote(&self.fname.to_string_lossy());
+        template
+            .replace("{n}", &n.to_string())
+            .replace("{path}", &path)
+    }
+
+    /// Like `expand`, but for a path rather than a shell command
+    fn expand_path(&self, template: &str, n: usize) -> String {
+        template
+            .replace("{n}", &n.to_string())
+            .replace("{path}", &self.fname.to_string_lossy())
+    }
+
+    /// Run a user-supplied shell command, failing if it does
+    fn run_hook(&self, what: &str, cmd: &str) {
+        debug!(
+            "{:width$} running {what}: {cmd}",
+            self.steps,
+            width = self.stepwidth
+        );
+        let mut child =
+            match process::Command::new("sh").arg("-c").arg(cmd).spawn() {
+                Ok(child) => child,
+                Err(e) => {
+                    error!("Cannot run {what}: {e}");
+                    self.fail();
+                }
+            };
+        // Poll, so the watchdog can interrupt a hung hook
+        let mut delay = Duration::from_millis(1);
+        let status = loop {
+            match child.try_wait() {
+                Ok(Some(status)) => break status,
+                Ok(None) => {
+                    self.check_watchdog();
+                    thread::sleep(delay);
+                    delay = (delay * 2).min(Duration::from_millis(50));
+                }
+                Err(e) => {
+                    error!("Cannot wait for {what}: {e}");
+                    self.fail();
+                }
+            }
+        };
+        if !status.success() {
+            error!("{what} failed with {status}");
+            self.fail();
+        }
+    }
+
+    /// Dump the log or abort, if the watchdog asked the main thread to
+    fn check_watchdog(&self) {
+        if self.thread.is_some() {
+            return;
+        }
+        if WATCHDOG_ABORT.load(Ordering::Relaxed) {
+            let _guard = FAILURE.lock();
+            self.dump_logfile();
+            self.save_goodfile();
+            process::abort();
+        }
+        if WATCHDOG_DUMP.swap(false, Ordering::Relaxed) {
+            self.dump_logfile();
+        }
+    }
+
+    /// Flush the file and run the integrity check hook
+    fn check_hook(&self) {
+        let (cmd, _) = self.check_hook.as_ref().unwrap();
+        let cmd = self.expand(cmd, self.steps as usize);
+        info!("{:width$} check_hook", self.steps, width = self.stepwidth);
+        if let Err(e) = eintr(|| self.file.sync_all()) {
+            error!("fsync failed with {e}");
+            self.fail();
+        }
+        self.run_hook("check_hook", &cmd);
+    }
+
+    /// Flush the file, then evict its data from the cache
+    fn drop_caches(&self) {
+        info!("{:width$} drop_caches", self.steps, width = self.stepwidth);
+        if let Err(e) = eintr(|| self.file.sync_all()) {
+            error!("fsync failed with {e}");
+            self.fail();
+        }
+        let (hook, _) = self.drop_caches.as_ref().unwrap();
+        if let Some(cmd) = hook {
+            let cmd = self.expand(cmd, self.steps as usize);
+            self.run_hook("drop_caches_hook", &cmd);
+            return;
+        }
+        #[cfg(any(
+            target_os = "linux",
+            target_os = "android",
+            target_os = "freebsd"
+        ))]
+        if let Err(e) = nix::fcntl::posix_fadvise(
+            self.file.as_raw_fd(),
+            0,
+            0,
+            nix::fcntl::PosixFadviseAdvice::POSIX_FADV_DONTNEED,
+        ) {
+            error!("posix_fadvise failed with {e}");
+            self.fail();
+        }
+    }
+
+    /// Close the file, run the remount hook, then reopen the file and verify
+    /// its entire contents.
+    fn remount(&mut self) {
+        let (cmd, _) = self.remount_hook.as_ref().unwrap();
+        let cmd = self.expand(cmd, self.steps as usize);
+        info!("{:width$} remount", self.steps, width = self.stepwidth);
+        if let Err(e) = eintr(|| self.file.sync_all()) {
+            error!("fsync failed with {e}");
+            self.fail();
+        }
+        self.cycle_mount(&cmd);
+        self.check_size();
+        self.check_file();
+    }
+
+    /// Close the file, run the remount hook, then reopen the file
+    fn cycle_mount(&mut self, cmd: &str) {
+        // Every file descriptor on the file system must be closed.  Use
+        // /dev/null as a placeholder, since the hook may fail.
+        let direct = self.direct_file.take().is_some();
+        self.drop_map_window();
+        let placeholder = File::open("/dev/null").unwrap();
+        drop(mem::replace(&mut self.file, SharedFile(placeholder)));
+        self.run_hook("remount_hook", cmd);
+        let r = eintr(|| {
+            OpenOptions::new().read(true).write(true).open(&self.fname)
+        });
+        self.file = match r {
+            Ok(f) => SharedFile(f),
+            Err(e) => {
+                error!("Cannot reopen file after remount: {e}");
+                self.fail();
+            }
+        };
+        if direct {
+            self.direct_file =
+                Some(SharedFile(open_direct(&self.fname).unwrap()));
+        }
+    }
+
+    /// Take a snapshot, and remember what it should contain
+    fn snapshot(&mut self) {
+        let snapshots = self.snapshots.as_ref().unwrap();
+        let n = snapshots.taken.len() + 1;
+        let cmd = self.expand(&snapshots.command, n);
+        info!("{:width$} snapshot {n}", self.steps, width = self.stepwidth);
+        // Not every file system includes dirty data in its snapshots
+        if let Err(e) = eintr(|| self.file.sync_all()) {
+            error!("fsync failed with {e}");
+            self.fail();
+        }
+        self.run_hook("snapshot_command", &cmd);
+        let len = self.file_size as usize;
+        let crcs = (0..len.div_ceil(CHECKSUM_BLOCK))
+            .map(|b| crc32(&self.good_buf.to_vec(Checksums::block(b, len))))
+            .collect();
+        self.snapshots
+            .as_mut()
+            .unwrap()
+            .taken
+            .push((self.file_size, crcs));
+        if n > 1 {
+            self.check_snapshot(n - 1);
+        }
+    }
+
+    /// Verify that a snapshot still contains what it did when it was taken
+    fn check_snapshot(&self, n: usize) {
+        let snapshots = self.snapshots.as_ref().unwrap();
+        let path = self.expand_path(&snapshots.path, n);
+        let (size, crcs) = &snapshots.taken[n - 1];
+        let buf = match fs::read(&path) {
+            Ok(buf) => buf,
+            Err(e) => {
+                error!("reading snapshot {n} at {path}: {e}");
+                self.fail();
+            }
+        };
+        if buf.len() as u64 != *size {
+            error!(
+                "snapshot {n} size error: expected {:#x} but found {:#x}",
+                size,
+                buf.len()
+            );
+            self.fail();
+        }
+        for (b, &expected) in crcs.iter().enumerate() {
+            let range = Checksums::block(b, buf.len());
+            let actual = crc32(&buf[range.clone()]);
+            if actual != expected {
+                error!(
+                    "snapshot {n} miscompare: offset= {:#x}, size = {:#x}",
+                    range.start,
+                    range.len()
+                );
+                error!(
+                    "expected CRC-32 {:#010x}, found {:#010x}",
+                    expected, actual
+                );
+                self.fail();
+            }
+        }
+    }
+
+    /// Write a summary of this run, if requested
+    fn save_report(&self, failure: Option<&str>) {
+        let Some(path) = &*self.report else { return };
+        Report::new(vec![RunSummary {
+            seed:      self.seed,
+            ops:       self.steps,
+            skipped:   self.skips.get(),
+            completed: failure.is_none(),
+            failure:   failure.map(str::to_owned),
+            artifacts: failure.and(self.artifacts_dir.clone()),
+            verified:  self.verified.clone(),
+        }])
+        .save(path, self.report_format);
+    }
+
+    fn save_goodfile(&self) {
+        let fsxgoodfname = artifact_path(
+            self.artifacts_dir.as_deref(),
+            &self.fname,
+            &self.artifact_name,
+            ".fsxgood",
+        );
+        if let Err(e) = save_sparse(&self.good_buf, &fsxgoodfname) {
+            warn!("writing {}: {}", fsxgoodfname.display(), e);
+        }
+    }
+
+    /// The length of the file's actual contents
+    fn actual_len(&self) -> Option<u64> {
+        if self.blockmode {
+            return Some(self.flen);
+        }
+        match self.file.metadata() {
+            Ok(md) => Some(md.len().saturating_sub(self.base)),
+            Err(e) => {
+                warn!("Cannot stat {}: {}", self.fname.display(), e);
+                None
+            }
+        }
+    }
+
+    /// Read the first `len` bytes of the file's actual contents in chunks,
+    /// passing each to `f` along with its offset, until `f` returns false.
+    fn read_chunks(&self, len: u64, mut f: impl FnMut(u64, &[u8]) -> bool) {
+        const CHUNK: u64 = 1 << 20;
+
+        let mut buf = vec![0u8; CHUNK.min(len) as usize];
+        let mut offset = 0;
+        while offset < len {
+            let n = CHUNK.min(len - offset) as usize;
+            if let Err(e) =
+                self.file.read_exact_at(&mut buf[..n], self.base + offset)
+            {
+                warn!("reading {}: {}", self.fname.display(), e);
+                break;
+            }
+            if !f(offset, &buf[..n]) {
+                break;
+            }
+            offset += n as u64;
+        }
+    }
+
+    /// Save the file's actual contents, for comparison with the .fsxgood file.
+    /// Files larger than `FSXBAD_MAX` only get the chunks that differ, with
+    /// holes elsewhere.
+    fn save_badfile(&self) {
+        let path = artifact_path(
+            self.artifacts_dir.as_deref(),
+            &self.fname,
+            &self.artifact_name,
+            ".fsxbad",
+        );
+        let Some(len) = self.actual_len() else {
+            return;
+        };
+        let bad = match File::create(&path) {
+            Ok(f) => f,
+            Err(e) => {
+                warn!("Cannot create {}: {}", path.display(), e);
+                return;
+            }
+        };
+        let sparse = len > FSXBAD_MAX;
+        let mut ok = true;
+        self.read_chunks(len, |offset, buf| {
+            let range = offset as usize..offset as usize + buf.len();
+            if !sparse
+                || range.end > self.file_size as usize
+                || *self.good_buf.slice(range) != *buf
+            {
+                if let Err(e) = bad.write_all_at(buf, offset) {
+                    warn!("writing {}: {}", path.display(), e);
+                    ok = false;
                 }
-                self.file_size = ooffset + size as u64;
             }
-            let i = ioffset as usize;
-            let j = ooffset as usize;
-            self.good_buf[..].copy_within(i..i + size, j);
+            ok
+        });
+        if !ok {
+            return;
+        }
+        if let Err(e) = bad.set_len(len) {
+            warn!("writing {}: {}", path.display(), e);
+        }
+        if sparse {
+            warn!(
+                "{} only contains the chunks that differ from the expected \
+                 contents",
+                path.display()
+            );
+        }
+    }
+
+    /// With `ShadowMode::Checksum`, save the file's actual contents of only
+    /// the bad block, since the expected contents are unknown
+    fn save_bad_block(&self) {
+        warn!(
+            "shadow = \"checksum\" keeps no expected contents, so there is no \
+             .fsxgood file"
+        );
+        let Some((first, last)) = self.badrange.get() else {
+            return;
+        };
+        let Some(len) = self.actual_len() else {
+            return;
+        };
+        let path = artifact_path(
+            self.artifacts_dir.as_deref(),
+            &self.fname,
+            &self.artifact_name,
+            ".fsxbad",
+        );
+        let mut buf =
+            vec![0u8; (last + 1).min(len).saturating_sub(first) as usize];
+        if let Err(e) = self.file.read_exact_at(&mut buf, self.base + first) {
+            warn!("reading {}: {}", self.fname.display(), e);
+            return;
+        }
+        let r = File::create(&path).and_then(|bad| {
+            bad.write_all_at(&buf, first)?;
+            bad.set_len(len)
+        });
+        if let Err(e) = r {
+            warn!("writing {}: {}", path.display(), e);
+            return;
+        }
+        warn!(
+            "{} only contains the bad range {:#x} .. {:#x}",
+            path.display(),
+            first,
+            last
+        );
+    }
+
+    /// Save only the regions where the file's actual contents differ from the
+    /// expected, up to `FSXDIFF_MAX` bytes of them
+    fn save_difffile(&self) {
+        let path = artifact_path(
+            self.artifacts_dir.as_deref(),
+            &self.fname,
+            &self.artifact_name,
+            ".fsxdiff",
+        );
+        let Some(len) = self.actual_len() else {
+            return;
+        };
+        let good = &self.good_buf;
+        let mut diff = DiffArtifact {
+            expected_size: self.file_size,
+            actual_size: len,
+            ..Default::default()
+        };
+        let mut saved = 0;
+        // Offset and actual contents of the region being built
+        let mut cur: Option<(usize, Vec<u8>)> = None;
+        let mut push =
+            |diff: &mut DiffArtifact, (start, actual): (usize, Vec<u8>)| {
+                if saved + actual.len() > FSXDIFF_MAX {
+                    diff.truncated = true;
+                    return false;
+                }
+                saved += actual.len();
+                diff.region.push(DiffRegion {
+                    offset:   start as u64,
+                    expected: hex(&good.slice(start..start + actual.len())),
+                    actual:   hex(&actual),
+                });
+                true
+            };
+        // Bytes past the expected EoF are only reported by actual_size
+        let len = len.min(self.file_size);
+        self.read_chunks(len, |offset, buf| {
+            for (i, &b) in buf.iter().enumerate() {
+                let o = offset as usize + i;
+                if good[o] != b {
+                    let (start, actual) = cur.get_or_insert((o, Vec::new()));
+                    // Equal bytes within the gap are part of the region
+                    actual.extend_from_slice(
+                        &good.slice(*start + actual.len()..o),
+                    );
+                    actual.push(b);
+                } else if let Some((start, actual)) = &cur {
+                    if o - (start + actual.len()) >= FSXDIFF_GAP
+                        && !push(&mut diff, cur.take().unwrap())
+                    {
+                        return false;
+                    }
+                }
+            }
+            true
+        });
+        if let Some(region) = cur {
+            push(&mut diff, region);
+        }
+        if diff.truncated {
+            warn!(
+                "{} only contains the first {} bytes of differences",
+                path.display(),
+                saved
+            );
+        }
+        if let Err(e) = fs::write(&path, toml::to_string(&diff).unwrap()) {
+            warn!("writing {}: {}", path.display(), e);
+        }
+    }
+
+    /// Save the last lines of the kernel log, if requested
+    fn save_dmesg(&self) {
+        let Some((lines, cmd)) = &self.dmesg else {
+            return;
+        };
+        let output =
+            match process::Command::new("sh").arg("-c").arg(cmd).output() {
+                Ok(output) => output,
+                Err(e) => {
+                    warn!("Cannot run dmesg_command: {e}");
+                    return;
+                }
+            };
+        if !output.status.success() {
+            warn!("dmesg_command failed with {}", output.status);
+        }
+        let log = String::from_utf8_lossy(&output.stdout);
+        let all = log.lines().collect::<Vec<_>>();
+        let tail = &all[all.len().saturating_sub(lines.get())..];
+        let path = artifact_path(
+            self.artifacts_dir.as_deref(),
+            &self.fname,
+            &self.artifact_name,
+            ".fsxdmesg",
+        );
+        let mut contents = tail.join("\n");
+        contents.push('\n');
+        if let Err(e) = fs::write(&path, contents) {
+            warn!("writing {}: {}", path.display(), e);
+        }
+    }
+
+    /// Save the resolved config, and print a command that reproduces the run
+    fn save_repro(&self) {
+        let Some(repro) = &self.repro else {
+            return;
+        };
+        let path = artifact_path(
+            self.artifacts_dir.as_deref(),
+            &self.fname,
+            &self.artifact_name,
+            ".fsxconfig",
+        );
+        if let Err(e) = fs::write(&path, &repro.config) {
+            warn!("writing {}: {}", path.display(), e);
+            return;
+        }
+        error!("Reproduce with: {}", repro.command(self.steps, &path));
+    }
+
+    /// Save the run's state as of the start of the failed step, so
+    /// --rng-state can resume from there
+    fn save_state(&self) {
+        let Some(ss) = &*self.step_start else {
+            return;
+        };
+        let Some(rng) = RngState::save(&ss.rng) else {
+            return;
+        };
+        let state = RunState {
+            seed: self.seed,
+            step: self.steps.saturating_sub(1),
+            file_size: ss.file_size,
+            cursor: ss.cursor,
+            counts: ss.counts.to_vec(),
+            contents: None,
+            rng,
+        };
+        // The failed step may already have changed good_buf
+        if let Some(path) = self.write_state(state, &self.good_buf.rewound()) {
+            error!(
+                "Resume from step {} with: --rng-state {}",
+                self.steps.max(1),
+                path.display()
+            );
+        }
+    }
+
+    /// Save the run's state after this step, so --rng-state can resume from
+    /// here if the run is interrupted
+    fn checkpoint(&self) {
+        let Some(rng) = RngState::save(&self.rng) else {
+            return;
+        };
+        let state = RunState {
+            seed: self.seed,
+            step: self.steps,
+            file_size: self.file_size,
+            cursor: self.cursor,
+            counts: self.counts.to_vec(),
+            contents: None,
+            rng,
+        };
+        if let Some(path) = self.write_state(state, &self.good_buf) {
+            debug!(
+                "{:width$} saved state to {}",
+                self.steps,
+                path.display(),
+                width = self.stepwidth
+            );
+        }
+    }
+
+    /// Write `state` to the .fsxstate file, with `contents` beside it in the
+    /// .fsxstart file.  Each is written to a temporary file first, so an
+    /// interrupted checkpoint never leaves either one half-written.
+    fn write_state(
+        &self,
+        mut state: RunState,
+        contents: &SparseBuf,
+    ) -> Option<PathBuf> {
+        let path = |suffix| {
+            artifact_path(
+                self.artifacts_dir.as_deref(),
+                &self.fname,
+                &self.artifact_name,
+                suffix,
+            )
+        };
+        let (start, start_tmp) = (path(".fsxstart"), path(".fsxstart.tmp"));
+        let (state_path, state_tmp) =
+            (path(".fsxstate"), path(".fsxstate.tmp"));
+        state.contents = Some(PathBuf::from(start.file_name().unwrap()));
+        let r = save_sparse(contents, &start_tmp)
+            .and_then(|()| {
+                fs::write(&state_tmp, toml::to_string(&state).unwrap())
+            })
+            .and_then(|()| fs::rename(&start_tmp, &start))
+            .and_then(|()| fs::rename(&state_tmp, &state_path));
+        if let Err(e) = r {
+            warn!("writing {}: {}", state_path.display(), e);
+            return None;
+        }
+        Some(state_path)
+    }
+
+    /// Resume the run from a saved state
+    fn restore(&mut self, state: RunState) {
+        if state.rng.kind() != self.rng.kind() {
+            eprintln!(
+                "error: the state was saved with the {} RNG, not {}",
+                state.rng.kind(),
+                self.rng.kind()
+            );
+            process::exit(2);
+        }
+        if state.file_size > self.flen {
+            eprintln!(
+                "error: the saved file size {:#x} exceeds flen {:#x}",
+                state.file_size, self.flen
+            );
+            process::exit(2);
+        }
+        if let Some(path) = &state.contents {
+            let image = fs::read(path).unwrap_or_else(|e| {
+                eprintln!("Cannot read {}: {e}", path.display());
+                process::exit(1);
+            });
+            let n = image.len().min(self.good_buf.len());
+            self.good_buf.write(0, &image[..n]);
+        }
+        let len = state.file_size as usize;
+        self.good_buf.fill(len..self.good_buf.len(), 0);
+        self.rng = state.rng.restore();
+        self.steps = state.step;
+        self.file_size = state.file_size;
+        self.cursor = state.cursor;
+        for (count, saved) in self.counts.iter_mut().zip(state.counts) {
+            *count = saved;
+        }
+        if let Err(e) = self.good_buf.write_to(&self.file, 0..len, self.base) {
+            eprintln!("writing {}: {e}", self.fname.display());
+            process::exit(1);
+        }
+        if !self.blockmode {
+            self.file.set_len(self.file_size).unwrap();
+        }
+        self.mirror(|o| {
+            self.good_buf.write_to(o, 0..len, 0)?;
+            o.set_len(self.file_size)
+        });
+        self.apply_max_count();
+        info!("Resuming after step {}", self.steps);
+    }
+
+    /// Should this step be skipped as not part of the test plan?
+    fn skip(&self) -> bool {
+        self.steps <= self.simulatedopcount || self.injected()
+    }
+
+    /// Should this step's I/O be skipped, to introduce a discrepancy?
+    fn injected(&self) -> bool {
+        (Some(self.steps) == self.inject && self.inject_errno.is_none())
+            || self
+                .skip_ops
+                .iter()
+                .any(|&(a, b)| (a..=b).contains(&self.steps))
+    }
+
+    /// Wrapper around write-like operations.
+    fn write_like<F>(&mut self, op: Op, offset: u64, size: usize, f: F)
+    where
+        F: Fn(&mut Exerciser, u64, usize, u64) -> usize,
+    {
+        if size == 0 {
+            self.oplog.push(LogEntry::Skip(op));
+            debug!(
+                "{:width$} skipping zero size write",
+                self.steps,
+                width = self.stepwidth
+            );
+            return;
+        }
+
+        self.ck_load(offset..offset + size as u64, true);
+        let saved = self.may_skip().then(|| {
+            self.good_buf
+                .to_vec(offset as usize..offset as usize + size)
+        });
+        self.gendata(offset, size);
+
+        let cur_file_size = self.file_size;
+        if self.file_size < offset + size as u64 {
+            if self.file_size < offset {
+                self.good_buf
+                    .fill(self.file_size as usize..offset as usize, 0);
+            }
+            self.file_size = offset + size as u64;
+        }
+        assert!(!self.blockmode || self.file_size == cur_file_size);
+
+        if op == Op::Write {
+            self.oplog
+                .push(LogEntry::Write(cur_file_size, offset, size));
+        } else {
+            self.oplog
+                .push(LogEntry::MapWrite(cur_file_size, offset, size));
+        }
+
+        if self.skip() {
+            if self.injected() {
+                // Like a lost write, which verify_after_write should catch
+                self.verify_written(op, offset, size);
+            }
+            return;
+        }
+
+        let loglevel = self.loglevel(offset, None, size);
+        log!(
+            loglevel,
+            "{:stepwidth$} {:8} {:#fwidth$x} .. {:#fwidth$x} ({:#swidth$x} \
+             bytes)",
+            self.steps,
+            op,
+            offset,
+            offset + size as u64 - 1,
+            size,
+            stepwidth = self.stepwidth,
+            fwidth = self.fwidth,
+            swidth = self.swidth
+        );
+
+        let written = f(self, cur_file_size, size, offset);
+        self.bytes_written += written as u64;
+        let range = offset as usize..offset as usize + written;
+        self.mirror(|o| o.write_all_at(&self.good_buf.slice(range), offset));
+        if written < size {
+            self.rollback(offset, written, saved.unwrap(), cur_file_size);
+            return;
+        }
+        self.verify_written(op, offset, size);
+    }
+
+    /// Read back what was just written, if verify_after_write is enabled
+    fn verify_written(&mut self, op: Op, offset: u64, size: usize) {
+        if map_faulted() {
+            // step will report the fault
+            return;
+        }
+        let mapped = match self.verify_after_write {
+            VerifyAfterWrite::Off => return,
+            VerifyAfterWrite::Same => op == Op::MapWrite,
+            VerifyAfterWrite::Opposite => op == Op::Write,
+        };
+        let mut temp_buf = self.take_read_buf(size);
+        let read = if mapped {
+            self.domapread(&mut temp_buf[..size], offset, size)
+        } else {
+            self.doread(&mut temp_buf[..size], offset, size)
+        };
+        if read {
+            self.check_buffers(&temp_buf[..size], offset);
+        }
+        self.read_buf = temp_buf;
+    }
+
+    fn exercise(&mut self) {
+        loop {
+            if let Some(n) = self.numops {
+                if n <= self.steps {
+                    break;
+                }
+            }
+            if let Some(max) = self.max_write_bytes {
+                if self.bytes_written >= max.get() {
+                    info!(
+                        "Stopping after writing {:#x} bytes",
+                        self.bytes_written
+                    );
+                    break;
+                }
+            }
+            self.pause();
+            self.step();
+            self.maybe_emit_stats();
+            if let (Some(meter), Some(numops)) = (&mut *self.meter, self.numops)
+            {
+                meter.draw(self.steps, numops);
+            }
+            self.check_watchdog();
+            if self.thread.is_none()
+                && DUMP_LATENCIES.swap(false, Ordering::Relaxed)
+            {
+                if let Some(latencies) = &self.latencies {
+                    print_latencies(latencies);
+                }
+            }
+        }
+        if self.meter.is_some() {
+            Meter::clear();
+        }
+
+        self.stop_verifiers();
+        self.check_reflink();
+        if !self.files.is_empty() {
+            // Each file may have gone unverified since long before the end
+            for i in 0..self.files.len() {
+                self.switch_to(i);
+                self.check_file();
+            }
+        } else if self.oracle.is_some() {
+            self.check_file();
+        }
+        if let Some(snapshots) = &*self.snapshots {
+            for n in 1..=snapshots.taken.len() {
+                self.check_snapshot(n);
+            }
+        }
+        if self.check_hook.is_some() {
+            self.check_hook();
+        }
 
-            self.oplog.push(LogEntry::CopyFileRange(
-                cur_file_size,
-                ioffset,
-                ooffset,
-                size,
-            ));
-            let loglevel = self.loglevel(ioffset, Some(ooffset), size);
-            log!(
-                loglevel,
-                "{:stepwidth$} copy_file_range [{:#fwidth$x}:{:#fwidth$x}] => \
-                 [{:#fwidth$x}:{:#fwidth$x}] ({:#swidth$x} bytes)",
-                self.steps,
-                ioffset,
-                ioffset + size as u64 - 1,
-                ooffset,
-                ooffset + size as u64 - 1,
-                size,
-                stepwidth = self.stepwidth,
-                fwidth = self.fwidth,
-                swidth = self.swidth
-            );
-            self.do_copy_file_range(ioffset, ooffset, size)
+        if self.tmpfile_link {
+            // fsx conventionally replaces whatever was at fname.
+            match fs::remove_file(&self.fname) {
+                Ok(()) => (),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => (),
+                Err(e) => {
+                    error!("Cannot remove {}: {}", self.fname.display(), e);
+                    self.fail();
+                }
+            }
+            if let Err(e) = link_tmpfile(&self.file, &self.fname) {
+                error!("linkat failed with {e}");
+                self.fail();
+            }
+        }
+        if let Some(trace) = self.trace.take() {
+            trace.flush();
+            if trace.mode == TraceMode::Failure {
+                let _ = fs::remove_file(&trace.path);
+            }
+        }
+        if let Some(csv) = self.csv.take() {
+            csv.flush();
+        }
+        #[cfg(feature = "strace")]
+        if let Some(strace) = self.strace.take() {
+            strace.flush();
+        }
+        if let Some(oplog_file) = self.oplog_file.take() {
+            oplog_file.flush();
         }
     }
 
-    /// Actually perform the copy_file_range, including retrying short writes
-    #[cfg(any(target_os = "freebsd", target_os = "linux"))]
-    fn do_copy_file_range(&mut self, inoff: u64, outoff: u64, mut len: usize) {
-        let mut inoff: i64 = inoff.try_into().unwrap();
-        let mut outoff: i64 = outoff.try_into().unwrap();
-        while len > 0 {
-            let r = nix::fcntl::copy_file_range(
-                self.file.as_fd(),
-                Some(&mut inoff),
-                self.file.as_fd(),
-                Some(&mut outoff),
-                len,
-            )
-            .unwrap();
-            assert!(r > 0, "0-length copy_file_range");
-            len -= r;
+    /// Divide the file into `n` disjoint regions, returning a new exerciser
+    /// for each region but the first, which this exerciser keeps.  They all
+    /// share one open file.
+    fn split(&mut self, n: usize) -> Vec<Exerciser> {
+        if n == 1 {
+            return Vec::new();
+        }
+        let mut chunk = self.flen / n as u64;
+        chunk -= chunk % self.align_offset as u64;
+        if chunk == 0 {
+            error!("ERROR: file is too small to divide among {n} threads");
+            process::exit(2);
         }
+        let region = |i: usize| {
+            let start = i as u64 * chunk;
+            let end = if i == n - 1 { self.flen } else { start + chunk };
+            (start, end)
+        };
+        let workers = (1..n)
+            .map(|i| {
+                let mut worker = self.clone();
+                // Give each thread its own operation sequence
+                worker.seed = self.seed.wrapping_add(i as u64);
+                worker.rng = FsxRng::new(self.rng.kind(), worker.seed);
+                worker.thread = Some(i);
+                worker.region = region(i);
+                worker.cursor = region(i).0;
+                // Only the main thread runs hooks and whole-file checks
+                worker.check_hook = None;
+                worker.remount_hook = None;
+                worker.drop_caches = None;
+                worker.stblocks_check = None;
+                worker.timecheck = None;
+                worker.tmpfile_link = false;
+                worker.interactive = false;
+                worker.break_at = None;
+                worker
+            })
+            .collect();
+        self.thread = Some(0);
+        self.region = region(0);
+        workers
     }
 
-    #[cfg(not(any(target_os = "freebsd", target_os = "linux")))]
-    fn do_copy_file_range(&mut self, _inoff: u64, _outoff: u64, _len: usize) {
-        eprintln!("copy_file_range is not supported on this platform.");
-        process::exit(1);
-    }
+    fn fsync(&mut self) {
+        self.oplog.push(LogEntry::Fsync);
+        self.crash_sync();
 
-    fn doread(&mut self, buf: &mut [u8], offset: u64, size: usize) {
-        let read = self.file.read_at(buf, offset).unwrap();
-        if read < size {
-            error!("short read: {:#x} bytes instead of {:#x}", read, size);
-            self.fail();
+        if self.skip() {
+            return;
+        }
+        info!("{:width$} fsync", self.steps, width = self.stepwidth);
+        if let Err(e) = self.retry(|| {
+            sys!(self, "fsync"(self.file.as_raw_fd()), self.file.sync_all())
+        }) {
+            if !self.skip_error(Op::Fsync, &e) {
+                error!("fsync failed with {e}");
+                self.fail();
+            }
         }
     }
 
-    fn domapread(&mut self, buf: &mut [u8], offset: u64, size: usize) {
-        let page_mask = Self::getpagesize() as usize - 1;
-        let pg_offset = offset as usize & page_mask;
-        let map_size = pg_offset + size;
-        unsafe {
-            let p = mmap(
-                None,
-                map_size.try_into().unwrap(),
-                ProtFlags::PROT_READ | ProtFlags::PROT_WRITE,
-                MapFlags::MAP_FILE | MapFlags::MAP_SHARED,
-                self.file.as_fd(),
-                offset as i64 - pg_offset as i64,
+    fn fdatasync(&mut self) {
+        self.oplog.push(LogEntry::Fdatasync);
+        self.crash_sync();
+
+        if self.skip() {
+            return;
+        }
+        info!("{:width$} fdatasync", self.steps, width = self.stepwidth);
+        if let Err(e) = self.retry(|| {
+            sys!(
+                self,
+                "fdatasync"(self.file.as_raw_fd()),
+                self.file.sync_data()
             )
-            .unwrap();
-            p.as_ptr()
-                .cast::<u8>()
-                .add(pg_offset)
-                .copy_to(buf.as_mut_ptr(), size);
-            self.check_eofpage(offset, p.as_ptr(), size);
+        }) {
+            if !self.skip_error(Op::Fdatasync, &e) {
+                error!("fdatasync failed with {e}");
+                self.fail();
+            }
         }
     }
 
-    fn domapwrite(&mut self, cur_file_size: u64, size: usize, offset: u64) {
-        if self.file_size > cur_file_size {
-            self.file.set_len(self.file_size).unwrap();
+    /// While replaying a crash, record that the file was synced.
+    fn crash_sync(&mut self) {
+        if let Some(crash) = &mut *self.crash {
+            // An fsync in progress during the crash may not have completed
+            if self.steps < crash.step {
+                crash.sync(&self.good_buf, self.file_size);
+            }
         }
-        let buf = &self.good_buf[offset as usize..offset as usize + size];
-        let page_mask = Self::getpagesize() as usize - 1;
-        let pg_offset = offset as usize & page_mask;
-        let map_size = pg_offset + size;
-        // Safety: good luck proving it's safe.
-        unsafe {
-            let p = mmap(
+    }
+
+    /// Run the test in a child process, kill it at a random step, then verify
+    /// the file by replaying the same operations.
+    fn crash_test(mut self, hook: Option<String>) -> ! {
+        let numops = self.numops.unwrap();
+        let target = XorShiftRng::seed_from_u64(self.seed.rotate_left(32))
+            .gen_range(1..=numops);
+        // Safe because nothing else uses this mapping
+        let progress: &'static AtomicU64 = unsafe {
+            let p = mmap_anonymous(
                 None,
-                map_size.try_into().unwrap(),
+                NonZeroUsize::new(mem::size_of::<AtomicU64>()).unwrap(),
                 ProtFlags::PROT_READ | ProtFlags::PROT_WRITE,
-                MapFlags::MAP_FILE | MapFlags::MAP_SHARED,
-                self.file.as_fd(),
-                offset as i64 - pg_offset as i64,
+                MapFlags::MAP_SHARED | MapFlags::MAP_ANONYMOUS,
             )
             .unwrap();
-            p.as_ptr()
-                .cast::<u8>()
-                .add(pg_offset)
-                .copy_from(buf.as_ptr(), size);
-            if !self.nomsyncafterwrite {
-                msync(p, map_size, MsFlags::MS_SYNC).unwrap();
+            &*p.as_ptr().cast()
+        };
+        // Safe because no other threads have been started yet
+        let child = match unsafe { fork() }.expect("fork failed") {
+            ForkResult::Child => {
+                *self.progress = Some(progress);
+                self.exercise();
+                process::exit(0);
+            }
+            ForkResult::Parent { child } => child,
+        };
+        info!("Crashing at step {target}");
+        let exited = loop {
+            match waitpid(child, Some(WaitPidFlag::WNOHANG)).unwrap() {
+                WaitStatus::StillAlive => (),
+                WaitStatus::Exited(_, 0) => break true,
+                WaitStatus::Exited(_, code) => process::exit(code),
+                status => {
+                    error!("child process died unexpectedly: {status:?}");
+                    process::exit(1);
+                }
+            }
+            if progress.load(Ordering::SeqCst) >= target {
+                kill(child, Signal::SIGKILL).unwrap();
+                waitpid(child, None).unwrap();
+                break false;
+            }
+            thread::sleep(Duration::from_micros(100));
+        };
+        let step = progress.load(Ordering::SeqCst);
+        if exited {
+            info!("Child completed all {step} steps before the crash");
+        } else {
+            info!("Killed child during step {step}");
+        }
+
+        // Close the file, in case the hook needs to unmount its file system
+        self.direct_file = None;
+        self.file = SharedFile(File::open("/dev/null").unwrap());
+        if let Some(hook) = hook {
+            let cmd = self.expand(&hook, step as usize);
+            self.run_hook("crash_hook", &cmd);
+        }
+        let actual = match fs::read(&self.fname) {
+            Ok(buf) => buf,
+            Err(e) => {
+                error!("Cannot read file after crash: {e}");
+                process::exit(1);
             }
-            self.check_eofpage(offset, p.as_ptr(), size);
-            munmap(p, map_size).unwrap();
+        };
+
+        // Replay everything up to the crash, without doing any I/O
+        self.simulatedopcount = u64::MAX;
+        *self.crash = Some(CrashCheck::new(step, actual));
+        self.crash_sync();
+        while self.steps < step {
+            self.step();
         }
+        self.check_crash();
+        println!("Crash at step {step} verified A-OK!");
+        process::exit(0);
     }
 
-    fn dowrite(&mut self, _cur_file_size: u64, size: usize, offset: u64) {
-        let buf = &self.good_buf[offset as usize..offset as usize + size];
-        let written = self.file.write_at(buf, offset).unwrap();
-        if written != size {
-            error!("short write: {:#x} bytes instead of {:#x}", written, size);
+    /// After replaying a crash, verify that every byte of the file is legit.
+    fn check_crash(&self) {
+        if let Err(e) = self.crash.as_ref().unwrap().verify() {
+            error!("{e} after crash");
             self.fail();
         }
     }
 
-    /// Dump the contents of the oplog
-    fn dump_logfile(&self) {
-        let mut i = self.steps + 1 - self.oplog.len() as u64;
-        error!("Using seed {}", self.seed);
-        error!("LOG DUMP");
-        for le in self.oplog.iter() {
-            match le {
-                LogEntry::Skip(op) => error!(
-                    "{:stepwidth$} SKIPPED  ({})",
-                    i,
-                    op,
-                    stepwidth = self.stepwidth
-                ),
-                LogEntry::CloseOpen => error!(
-                    "{:stepwidth$} CLOSE/OPEN",
-                    i,
-                    stepwidth = self.stepwidth
-                ),
-                LogEntry::Read(offset, size) => error!(
-                    "{:stepwidth$} READ     {:#fwidth$x} => {:#fwidth$x} \
-                     ({:#swidth$x} bytes)",
-                    i,
-                    offset,
-                    offset + *size as u64,
-                    size,
-                    stepwidth = self.stepwidth,
-                    fwidth = self.fwidth,
-                    swidth = self.swidth
-                ),
-                LogEntry::MapRead(offset, size) => error!(
-                    "{:stepwidth$} MAPREAD  {:#fwidth$x} => {:#fwidth$x} \
-                     ({:#swidth$x} bytes)",
-                    i,
-                    offset,
-                    offset + *size as u64,
-                    size,
-                    stepwidth = self.stepwidth,
-                    fwidth = self.fwidth,
-                    swidth = self.swidth
-                ),
-                LogEntry::Write(old_len, offset, size) => {
-                    let sym = if offset > old_len {
-                        " HOLE"
-                    } else if offset + *size as u64 > *old_len {
-                        " EXTEND"
-                    } else {
-                        ""
-                    };
-                    error!(
-                        "{:stepwidth$} WRITE    {:#fwidth$x} => {:#fwidth$x} \
-                         ({:#swidth$x} bytes){}",
-                        i,
-                        offset,
-                        offset + *size as u64,
-                        size,
-                        sym,
-                        stepwidth = self.stepwidth,
-                        fwidth = self.fwidth,
-                        swidth = self.swidth
-                    )
+    fn gendata(&mut self, offset: u64, size: usize) {
+        let start = usize::try_from(offset).unwrap();
+        if self.pattern == Pattern::Zeros {
+            // Zeroing, rather than writing, keeps the shadow sparse
+            self.good_buf.fill(start..start + size, 0);
+            return;
+        }
+        let (seed, step) = (self.seed, self.steps);
+        let orig = (self.pattern == Pattern::Stamp).then(|| {
+            match &mut self.original_buf {
+                Some(o) => o.slice(start..start + size),
+                None => {
+                    // Op stream v1's original data is cheap to recompute.
+                    let mut v = vec![0; size];
+                    fill_words(&mut v, start, |i| {
+                        v1_word(seed, 0, i as u64).to_le_bytes()
+                    });
+                    Cow::Owned(v)
                 }
-                LogEntry::MapWrite(old_len, offset, size) => {
-                    let sym = if offset > old_len {
-                        " HOLE"
-                    } else if offset + *size as u64 > *old_len {
-                        " EXTEND"
-                    } else {
-                        ""
-                    };
-                    error!(
-                        "{:stepwidth$} MAPWRITE {:#fwidth$x} => {:#fwidth$x} \
-                         ({:#swidth$x} bytes){}",
-                        i,
-                        offset,
-                        offset + *size as u64,
-                        size,
-                        sym,
-                        stepwidth = self.stepwidth,
-                        fwidth = self.fwidth,
-                        swidth = self.swidth
+            }
+        });
+        let pattern = &self.pattern;
+        self.good_buf.segments_mut(start..start + size, |pos, buf| {
+            let orig = orig.as_ref().map(|o| &o[pos - start..]);
+            gendata(pattern, seed, step, pos, buf, orig)
+        });
+    }
+
+    /// Report a fault caught while the last operation accessed a mapped file
+    fn check_map_fault(&self) {
+        let signo = MAP_FAULT_SIGNAL.load(Ordering::Relaxed);
+        if signo == 0 {
+            return;
+        }
+        let sig = Signal::try_from(signo).unwrap();
+        let op = Op::ALL[MAP_FAULT_OP.load(Ordering::Relaxed)];
+        error!(
+            "{sig} during {op} at file offset {:#x}; expected file size {:#x}",
+            MAP_FAULT_OFFSET.load(Ordering::Relaxed),
+            self.file_size
+        );
+        self.fail();
+    }
+
+    /// Describe a mapping of the file, for the fault handler.  `offset` may
+    /// be anywhere within the mapping's first page.
+    fn map_fault(
+        &self,
+        op: Op,
+        p: *const c_void,
+        offset: u64,
+        len: usize,
+    ) -> MapFault {
+        let page_size = Self::getpagesize() as usize;
+        MapFault {
+            op,
+            base: p as usize,
+            offset: offset & !(page_size as u64 - 1),
+            len,
+            page_size,
+        }
+    }
+
+    fn getpagesize() -> i32 {
+        // This function is inherently safe
+        sysconf(SysconfVar::PAGE_SIZE).unwrap().unwrap() as i32
+    }
+
+    fn invalidate(&mut self) {
+        self.oplog.push(LogEntry::Invalidate);
+
+        if self.skip() {
+            return;
+        }
+        let len = self.file_size as usize;
+        if len == 0 {
+            debug!(
+                "{:width$} skipping invalidate of zero-length file",
+                self.steps,
+                width = self.stepwidth
+            );
+            return;
+        }
+        info!(
+            "{:width$} msync(MS_INVALIDATE)",
+            self.steps,
+            width = self.stepwidth
+        );
+        unsafe {
+            let Some(p) = self.map_or_skip(Op::Invalidate, 0, len) else {
+                return;
+            };
+            self.map_fault(Op::Invalidate, p.as_ptr(), 0, len)
+                .guard(|| {
+                    sys!(
+                        self,
+                        "msync"(format!("{:p}", p), 0, "MS_INVALIDATE"),
+                        msync(p, 0, MsFlags::MS_INVALIDATE)
                     )
-                }
-                LogEntry::Truncate(old_len, new_len) => {
-                    let dir = if new_len > old_len { "UP" } else { "DOWN" };
-                    error!(
-                        "{:stepwidth$} TRUNCATE  {:4} from {:#fwidth$x} to \
-                         {:#fwidth$x}",
-                        i,
-                        dir,
-                        old_len,
-                        new_len,
-                        stepwidth = self.stepwidth,
-                        fwidth = self.fwidth
-                    );
-                }
-                LogEntry::Invalidate => error!(
-                    "{:stepwidth$} INVALIDATE",
-                    i,
-                    stepwidth = self.stepwidth
-                ),
-                LogEntry::Fsync => {
-                    error!("{:stepwidth$} FSYNC", i, stepwidth = self.stepwidth)
-                }
-                LogEntry::Fdatasync => error!(
-                    "{:stepwidth$} FDATASYNC",
-                    i,
-                    stepwidth = self.stepwidth
-                ),
-                LogEntry::PosixFallocate(offset, len) => {
-                    error!(
-                        "{:stepwidth$} POSIX_FALLOCATE {:#fwidth$x} => \
-                         {:#fwidth$x} ({:#swidth$x} bytes)",
-                        i,
-                        offset,
-                        offset + len - 1,
-                        len,
-                        stepwidth = self.stepwidth,
-                        swidth = self.swidth,
-                        fwidth = self.fwidth
-                    );
-                }
-                LogEntry::PunchHole(offset, len) => {
-                    error!(
-                        "{:stepwidth$} PUNCH_HOLE {:#fwidth$x} => \
-                         {:#fwidth$x} ({:#swidth$x} bytes)",
-                        i,
-                        offset,
-                        offset + len - 1,
-                        len,
-                        stepwidth = self.stepwidth,
-                        swidth = self.swidth,
-                        fwidth = self.fwidth
-                    );
-                }
-                LogEntry::Sendfile(offset, size) => error!(
-                    "{:stepwidth$} SENDFILE {:#fwidth$x} => {:#fwidth$x} \
-                     ({:#swidth$x} bytes)",
-                    i,
-                    offset,
-                    offset + *size as u64,
-                    size,
-                    stepwidth = self.stepwidth,
-                    fwidth = self.fwidth,
-                    swidth = self.swidth
-                ),
-                #[cfg(any(
-                    target_os = "linux",
-                    target_os = "android",
-                    target_os = "freebsd"
-                ))]
-                LogEntry::PosixFadvise(advice, offset, len) => error!(
-                    "{:stepwidth$} POSIX_FADVISE({:10}) {:#fwidth$x} => \
-                     {:#fwidth$x} ({:#swidth$x} bytes)",
-                    i,
-                    advice,
-                    offset,
-                    offset + len - 1,
+                    .unwrap();
+                });
+            self.unmap(Op::Invalidate, p, len);
+        }
+    }
+
+    /// Map `len` bytes of the file from the page-aligned `pg_start`.
+    unsafe fn map(
+        &mut self,
+        pg_start: u64,
+        len: usize,
+    ) -> nix::Result<ptr::NonNull<c_void>> {
+        if self.map_mode == MapMode::Op {
+            return self.mmap(pg_start, len);
+        }
+        if let Some(p) = self
+            .map_window
+            .as_ref()
+            .and_then(|w| w.get(pg_start, len, self.file_size))
+        {
+            return Ok(p);
+        }
+        let page_size = Self::getpagesize() as u64;
+        let w = if self.map_mode == MapMode::File {
+            // Map past EoF, so the mapping stays valid as the file grows.
+            let wlen = self.flen.next_multiple_of(page_size) as usize;
+            MapWindow {
+                p:         self.mmap(0, wlen)?,
+                offset:    0,
+                len:       wlen,
+                file_size: None,
+            }
+        } else {
+            // Unmap the old window first, then map a new one covering the
+            // surrounding MAP_WINDOW-aligned range, within the file's last
+            // page.
+            self.drop_map_window();
+            let eof = self.file_size.next_multiple_of(page_size);
+            let start = pg_start - pg_start % MAP_WINDOW;
+            let end = (pg_start + len as u64)
+                .next_multiple_of(MAP_WINDOW)
+                .min(eof)
+                .max(pg_start + len as u64);
+            let wlen = (end - start) as usize;
+            MapWindow {
+                p:         self.mmap(start, wlen)?,
+                offset:    start,
+                len:       wlen,
+                file_size: Some(self.file_size),
+            }
+        };
+        let p = w.get(pg_start, len, self.file_size).unwrap();
+        *self.map_window = Some(w);
+        Ok(p)
+    }
+
+    /// Map part of the file for `op`, applying the error policy if that
+    /// fails.  Returns `None` if the operation should be skipped.
+    unsafe fn map_or_skip(
+        &mut self,
+        op: Op,
+        pg_start: u64,
+        len: usize,
+    ) -> Option<ptr::NonNull<c_void>> {
+        match self.map(pg_start, len) {
+            Ok(p) => Some(p),
+            Err(e) if self.skip_error(op, &e) => None,
+            Err(e) => {
+                error!("mmap failed with {e}");
+                self.fail();
+            }
+        }
+    }
+
+    unsafe fn mmap(
+        &self,
+        pg_start: u64,
+        len: usize,
+    ) -> nix::Result<ptr::NonNull<c_void>> {
+        let off = (self.base + pg_start) as i64;
+        self.retry(|| {
+            sys!(
+                self,
+                "mmap"(
+                    "NULL",
                     len,
-                    stepwidth = self.stepwidth,
-                    swidth = self.swidth,
-                    fwidth = self.fwidth
+                    "PROT_READ|PROT_WRITE",
+                    "MAP_SHARED",
+                    self.file.as_raw_fd(),
+                    off
                 ),
-                LogEntry::CopyFileRange(old_len, ioffset, ooffset, size) => {
-                    let sym = if ooffset > old_len {
-                        " HOLE"
-                    } else if ooffset + *size as u64 > *old_len {
-                        " EXTEND"
-                    } else {
-                        ""
-                    };
-                    error!(
-                        "{:stepwidth$} COPY_FILE_RANGE \
-                         [{:#fwidth$x},{:#fwidth$x}] => \
-                         [{:#fwidth$x},{:#fwidth$x}] ({:#swidth$x} bytes){}",
-                        i,
-                        ioffset,
-                        ioffset + *size as u64,
-                        ooffset,
-                        ooffset + *size as u64,
-                        size,
-                        sym,
-                        stepwidth = self.stepwidth,
-                        fwidth = self.fwidth,
-                        swidth = self.swidth
-                    )
+                mmap(
+                    None,
+                    len.try_into().unwrap(),
+                    ProtFlags::PROT_READ | ProtFlags::PROT_WRITE,
+                    MapFlags::MAP_FILE | MapFlags::MAP_SHARED,
+                    self.file.as_fd(),
+                    off,
+                )
+            )
+        })
+    }
+
+    /// Unmap the mapping kept between operations, if any.
+    fn drop_map_window(&mut self) {
+        if let Some(w) = self.map_window.take() {
+            let w = mem::ManuallyDrop::new(w);
+            // Safe because nothing borrows from the mapping between
+            // operations
+            let r = self.retry(|| {
+                sys!(self, "munmap"(format!("{:p}", w.p), w.len), unsafe {
+                    munmap(w.p, w.len)
+                })
+            });
+            if let Err(e) = r {
+                error!("munmap failed with {e}");
+                self.fail();
+            }
+        }
+    }
+
+    /// Release a mapping returned by `map` for `op`.
+    unsafe fn unmap(&self, op: Op, p: ptr::NonNull<c_void>, len: usize) {
+        if self.map_mode == MapMode::Op {
+            let r = self.retry(|| {
+                sys!(self, "munmap"(format!("{:p}", p), len), munmap(p, len))
+            });
+            match r {
+                Ok(()) => (),
+                // The operation is already done, so there's nothing to skip.
+                Err(e) if self.skip_error(op, &e) => (),
+                Err(e) => {
+                    error!("munmap failed with {e}");
+                    self.fail();
                 }
             }
-            i += 1;
         }
     }
 
-    /// Report a failure and exit.
-    fn fail(&self) -> ! {
-        self.dump_logfile();
-        self.save_goodfile();
-        process::exit(1);
+    /// Log level to use for I/O operations.
+    fn loglevel(
+        &self,
+        offset: u64,
+        offset2: Option<u64>,
+        size: usize,
+    ) -> Level {
+        let mut loglevel = Level::Info;
+        for &(start, end) in &self.monitor {
+            if start < offset + size as u64 && offset <= end {
+                loglevel = Level::Warn;
+            }
+            if let Some(offset2) = offset2 {
+                if start < offset2 + size as u64 && offset2 <= end {
+                    loglevel = Level::Warn;
+                }
+            }
+        }
+        loglevel
     }
 
-    /// Wrapper around read-like operations
-    fn read_like<F>(&mut self, op: Op, offset: u64, size: usize, f: F)
-    where
-        F: Fn(&mut Exerciser, &mut [u8], u64, usize),
-    {
-        if size == 0 {
-            self.oplog.push(LogEntry::Skip(op));
-            debug!(
-                "{:width$} skipping zero size read",
-                self.steps,
-                width = self.stepwidth
-            );
+    fn mapread(&mut self, offset: u64, size: usize) {
+        self.read_like(Op::MapRead, offset, size, Self::domapread)
+    }
+
+    fn mapwrite(&mut self, offset: u64, size: usize) {
+        self.write_like(Op::MapWrite, offset, size, Self::domapwrite)
+    }
+
+    fn read(&mut self, offset: u64, size: usize) {
+        self.read_like(Op::Read, offset, size, Self::doread)
+    }
+
+    fn sendfile(&mut self, offset: u64, size: usize) {
+        self.read_like(Op::Sendfile, offset, size, Self::dosendfile)
+    }
+
+    /// Create the additional test files.  `base` is the name that they're
+    /// numbered after.
+    fn add_files(&mut self, base: &Path, n: usize) {
+        if n == 1 {
             return;
         }
-        if size as u64 + offset > self.file_size {
-            self.oplog.push(LogEntry::Skip(op));
-            debug!(
-                "{:width$} skipping seek/read past EoF",
-                self.steps,
-                width = self.stepwidth
-            );
+        self.files.push(None);
+        for i in 1..n {
+            let fname = numbered(base, i);
+            self.locks.push(lock_file(&fname, true));
+            let file = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(true)
+                .open(&fname)
+                .expect("Cannot create file");
+            let direct_file = self
+                .direct_file
+                .as_ref()
+                .map(|_| open_direct(&fname).expect("Cannot open O_DIRECT"));
+            // Each file begins with the same contents as the first
+            self.good_buf
+                .write_to(&file, 0..self.file_size as usize, 0)
+                .unwrap();
+            self.files.push(Some(Target {
+                fname,
+                file: SharedFile(file),
+                direct_file: direct_file.map(SharedFile),
+                good_buf: self.good_buf.clone(),
+                file_size: self.file_size,
+            }));
+        }
+    }
+
+    /// Make the given test file the current one.
+    fn switch_to(&mut self, i: usize) {
+        if i == self.cur {
             return;
         }
-        match op {
-            Op::Read => self.oplog.push(LogEntry::Read(offset, size)),
-            Op::MapRead => self.oplog.push(LogEntry::MapRead(offset, size)),
-            Op::Sendfile => self.oplog.push(LogEntry::Sendfile(offset, size)),
-            _ => unimplemented!(),
+        let t = self.files[i].take().unwrap();
+        self.drop_map_window();
+        let old = Target {
+            fname:       mem::replace(&mut self.fname, t.fname),
+            file:        mem::replace(&mut self.file, t.file),
+            direct_file: mem::replace(&mut self.direct_file, t.direct_file),
+            good_buf:    mem::replace(&mut self.good_buf, t.good_buf),
+            file_size:   mem::replace(&mut self.file_size, t.file_size),
+        };
+        self.files[self.cur] = Some(old);
+        self.cur = i;
+    }
+
+    /// The name of the given test file
+    fn target_name(&self, i: usize) -> &Path {
+        match &self.files[i] {
+            Some(t) => &t.fname,
+            None => &self.fname,
         }
-        if self.skip() {
+    }
+
+    /// Start background verifier threads.
+    fn start_verifiers(&mut self, n: usize) {
+        if n == 0 {
             return;
         }
-        let loglevel = self.loglevel(offset, None, size);
-        log!(
-            loglevel,
-            "{:stepwidth$} {:8} {:#fwidth$x} .. {:#fwidth$x} ({:#swidth$x} \
-             bytes)",
-            self.steps,
-            op,
-            offset,
-            offset + size as u64 - 1,
-            size,
-            stepwidth = self.stepwidth,
-            fwidth = self.fwidth,
-            swidth = self.swidth
-        );
-        let mut temp_buf = vec![0u8; size];
-        f(self, &mut temp_buf[..], offset, size);
-        self.check_buffers(&temp_buf, offset)
+        let shadow = Arc::new(Mutex::new(Shadow {
+            buf: self.good_buf.clone(),
+            ..Default::default()
+        }));
+        let threads = (0..n)
+            .map(|_| {
+                let file = self.file.try_clone().unwrap();
+                let shadow = shadow.clone();
+                let max_size = self.opsize.max;
+                thread::spawn(move || verifier(file, shadow, max_size))
+            })
+            .collect();
+        *self.verifiers = Some(Verifiers { shadow, threads });
     }
 
-    fn save_goodfile(&self) {
-        let mut final_component =
-            self.fname.as_path().file_name().unwrap().to_owned();
-        final_component.push(".fsxgood");
-        let mut fsxgoodfname = if let Some(d) = &self.artifacts_dir {
-            d.clone()
+    /// Stop the verifiers from examining this range until the next publish.
+    fn mark_busy(&self, start: u64, end: u64) {
+        if let Some(v) = &*self.verifiers {
+            v.shadow.lock().unwrap().busy = Some((start, end));
+        }
+    }
+
+    /// Share the results of the latest operation with the verifiers, and
+    /// report any failure they found.
+    fn publish(&self, old_file_size: u64) {
+        let Some(v) = &*self.verifiers else { return };
+        if self.skip() && !self.injected() {
+            // The file hasn't been written yet.
+            return;
+        }
+        let mut sh = v.shadow.lock().unwrap();
+        let (start, end) = if self.steps == self.simulatedopcount + 1 {
+            // writefileimage just wrote everything
+            (0, self.file_size)
+        } else if let Some((start, end)) = sh.busy.take() {
+            // Extending the file also zero-fills the shadow up to start
+            (start.min(old_file_size), end.min(self.file_size))
         } else {
-            let mut fname = self.fname.clone();
-            fname.pop();
-            fname
+            (0, 0)
         };
-        fsxgoodfname.push(final_component);
-        let mut fsxgoodfile = OpenOptions::new()
-            .write(true)
-            .create(true)
-            .truncate(true)
-            .open(&fsxgoodfname)
-            .expect("Cannot create fsxgood file");
-        if let Err(e) = fsxgoodfile.write_all(&self.good_buf) {
-            warn!("writing {}: {}", fsxgoodfname.display(), e);
+        if start < end {
+            let range = start as usize..end as usize;
+            sh.buf.write(range.start, &self.good_buf.slice(range));
+        }
+        sh.busy = None;
+        sh.file_size = self.file_size;
+        if let Some(failure) = sh.failure.take() {
+            drop(sh);
+            error!("{failure}");
+            self.fail();
         }
     }
 
-    /// Should this step be skipped as not part of the test plan?
-    fn skip(&self) -> bool {
-        self.steps <= self.simulatedopcount || Some(self.steps) == self.inject
+    /// Stop the verifiers, and report any failure they found.
+    fn stop_verifiers(&mut self) {
+        let Some(v) = self.verifiers.take() else {
+            return;
+        };
+        v.shadow.lock().unwrap().done = true;
+        for t in v.threads {
+            t.join().unwrap();
+        }
+        let failure = v.shadow.lock().unwrap().failure.take();
+        if let Some(failure) = failure {
+            error!("{failure}");
+            self.fail();
+        }
     }
 
-    /// Wrapper around write-like operations.
-    fn write_like<F>(&mut self, op: Op, offset: u64, size: usize, f: F)
-    where
-        F: Fn(&mut Exerciser, u64, usize, u64),
-    {
-        if size == 0 {
-            self.oplog.push(LogEntry::Skip(op));
-            debug!(
-                "{:width$} skipping zero size write",
-                self.steps,
+    /// Choose an aligned operation location within this exerciser's region,
+    /// ending no later than `end`.
+    fn place(&mut self, offset: u64, size: usize, end: u64) -> (u64, usize) {
+        let (start, end) = (self.region.0, self.region.1.min(end));
+        // Choose the offset from within a monitored range, if possible,
+        // taking turns among those that fit
+        let fitting = self
+            .monitor
+            .iter()
+            .filter(|_| self.monitor_only)
+            .map(|&(from, to)| (start.max(from), end.min(to.saturating_add(1))))
+            .filter(|(lo, hi)| lo < hi)
+            .collect::<Vec<_>>();
+        let (start, hi) = if fitting.is_empty() {
+            (start, end)
+        } else {
+            fitting[(self.steps % fitting.len() as u64) as usize]
+        };
+        let uniform = |offset: u64| start + offset % (hi - start);
+        let mut offset = if hi <= start {
+            start
+        } else {
+            match &self.offsets {
+                OffsetMode::Uniform => uniform(offset),
+                OffsetMode::Sequential => {
+                    if (start..hi).contains(&self.cursor) {
+                        self.cursor
+                    } else {
+                        start
+                    }
+                }
+                OffsetMode::Append => {
+                    let eof = self.file_size.clamp(start, hi);
+                    if eof < hi {
+                        eof
+                    } else {
+                        hi.saturating_sub(size as u64).max(start)
+                    }
+                }
+                OffsetMode::Hotspot { ranges, bias } => {
+                    // Split the drawn offset's range in two: the first part
+                    // selects a position within the hot ranges, the rest is
+                    // uniform.
+                    let x = offset as f64 / (1u64 << 32) as f64;
+                    if x < *bias {
+                        let y = x / bias * ranges.len() as f64;
+                        let (s, e) = ranges[(y as usize).min(ranges.len() - 1)];
+                        let (s, e) = (s.max(start), e.min(hi));
+                        if s < e {
+                            s + (y.fract() * (e - s) as f64) as u64
+                        } else {
+                            uniform(offset)
+                        }
+                    } else {
+                        uniform(offset)
+                    }
+                }
+            }
+        };
+        offset -= offset % self.align_offset as u64;
+        let mut size = size.min(usize::try_from(end - offset).unwrap());
+        size -= size % self.align_size;
+        self.cursor = offset + size as u64;
+        (offset, size)
+    }
+
+    /// Switch to the phase that the next step belongs to, if it changed
+    fn enter_phase(&mut self) {
+        if self.phases.is_empty() {
+            return;
+        }
+        let total: u64 = self.phases.iter().map(|p| p.ops).sum();
+        let mut pos = self.steps;
+        if self.phase_cycle {
+            pos %= total;
+        }
+        let mut i = 0;
+        while i < self.phases.len() - 1 && pos >= self.phases[i].ops {
+            pos -= self.phases[i].ops;
+            i += 1;
+        }
+        if i != self.phase {
+            let phase = &self.phases[i];
+            self.phase = i;
+            self.wi = phase.wi.clone();
+            self.weights = phase.weights;
+            self.opsize = phase.opsize.clone();
+            self.align_offset = phase.opsize.offset_align();
+            self.align_size = phase.opsize.size_align();
+            info!(
+                "{:width$} beginning phase {}",
+                self.steps + 1,
+                i,
                 width = self.stepwidth
             );
-            return;
+            self.apply_max_count();
         }
+    }
 
-        self.gendata(offset, size);
-
-        let cur_file_size = self.file_size;
-        if self.file_size < offset + size as u64 {
-            if self.file_size < offset {
-                self.good_buf[self.file_size as usize..offset as usize].fill(0);
+    /// Stop choosing operations that have reached their max_count
+    fn apply_max_count(&mut self) {
+        let mut changed = false;
+        for (i, max) in self.max_count.iter().enumerate() {
+            if max.is_some_and(|m| self.counts[i] >= m.get())
+                && self.weights[i] > 0.0
+            {
+                self.weights[i] = 0.0;
+                changed = true;
             }
-            self.file_size = offset + size as u64;
         }
-        assert!(!self.blockmode || self.file_size == cur_file_size);
-
-        if op == Op::Write {
-            self.oplog
-                .push(LogEntry::Write(cur_file_size, offset, size));
+        if !changed {
+            return;
+        }
+        if self.weights.iter().all(|&w| w <= 0.0) {
+            info!(
+                "{:width$} every operation has reached its max_count",
+                self.steps,
+                width = self.stepwidth
+            );
+            self.numops = Some(self.steps);
         } else {
-            self.oplog
-                .push(LogEntry::MapWrite(cur_file_size, offset, size));
+            self.wi = Op::make_weighted_index(self.weights.into_iter());
         }
+    }
 
-        if self.skip() {
-            return;
+    /// Choose the next operation, and draw all of its random values
+    fn draw(&mut self) -> OpRecord {
+        if let Some(rec) = self.script.next() {
+            return rec;
         }
-
-        let loglevel = self.loglevel(offset, None, size);
-        log!(
-            loglevel,
-            "{:stepwidth$} {:8} {:#fwidth$x} .. {:#fwidth$x} ({:#swidth$x} \
-             bytes)",
-            self.steps,
+        if self.op_stream == OpStream::V1 && self.replay.is_none() {
+            return self.draw_v1();
+        }
+        if let Some(replay) = &mut *self.replay {
+            let rec = replay.next().unwrap();
+            if rec.target as usize >= self.files.len().max(1) {
+                error!(
+                    "Trace step {} uses file {}, but there are only {}",
+                    self.steps + 1,
+                    rec.target,
+                    self.files.len().max(1)
+                );
+                self.fail();
+            }
+            return rec;
+        }
+        if self.rng.kind() == RngKind::Legacy {
+            return self.draw_legacy();
+        }
+        let op: Op = self.wi.sample(&mut self.rng);
+        let target = if self.files.is_empty() {
+            0
+        } else {
+            self.rng.gen_range(0..self.files.len()) as u32
+        };
+        let size = self.opsize.sample(&mut self.rng) as u64;
+        let offset: u32 = self.rng.gen();
+        let (extra, flag) = match op {
+            Op::Truncate
+            | Op::CopyFileRange
+            | Op::ScratchCopyIn
+            | Op::ScratchCopyOut
+            | Op::ScratchSendfile
+            | Op::ScratchClone => (self.rng.gen(), false),
+            Op::PosixFadvise => {
+                let advice: PosixFadviseAdvice = self.rng.gen();
+                (advice.index(), false)
+            }
+            Op::FsizeLimit => (0, self.rng.gen()),
+            Op::CopyFileRangeOverlap => {
+                let shift: u32 = self.rng.gen();
+                (shift, self.rng.gen())
+            }
+            _ => (0, false),
+        };
+        OpRecord {
             op,
-            offset,
-            offset + size as u64 - 1,
+            target,
             size,
-            stepwidth = self.stepwidth,
-            fwidth = self.fwidth,
-            swidth = self.swidth
-        );
+            offset,
+            extra,
+            flag,
+        }
+    }
 
-        f(self, cur_file_size, size, offset)
+    /// Like `draw`, but mapping random(3)'s output to operations following the
+    /// C-based fsx's test loop.  Only the read, write, mapread, mapwrite, and
+    /// truncate weights matter, and only whether each is zero.
+    fn draw_legacy(&mut self) -> OpRecord {
+        let truncate = self.weights[Op::Truncate as usize] > 0.0;
+        let mapwrite = self.weights[Op::MapWrite as usize] > 0.0;
+        let mapread = self.weights[Op::MapRead as usize] > 0.0;
+        let rv = self.rng.next_u32();
+        let nops = 3 + u32::from(truncate) + u32::from(mapwrite);
+        let op = match rv % nops {
+            0 => Op::Read,
+            1 => Op::Write,
+            2 if mapread => Op::MapRead,
+            2 => Op::Read,
+            3 if truncate => Op::Truncate,
+            _ => Op::MapWrite,
+        };
+        let (mut size, mut offset, mut extra) = (self.opsize.max as u64, 0, 0);
+        if op == Op::Truncate {
+            extra = self.rng.next_u32();
+        } else {
+            if self.opsize.min != self.opsize.max {
+                size = u64::from(self.rng.next_u32()) % (size + 1);
+            }
+            offset = self.rng.next_u32();
+        }
+        OpRecord {
+            op,
+            target: 0,
+            size,
+            offset,
+            extra,
+            flag: false,
+        }
     }
 
-    fn exercise(&mut self) {
-        loop {
-            if let Some(n) = self.numops {
-                if n <= self.steps {
-                    break;
+    /// Like `draw`, but using op stream v1.  Never change this!
+    fn draw_v1(&self) -> OpRecord {
+        let step = self.steps + 1;
+        let word = |field| v1_word(self.seed, step, field);
+        let total: f64 = self.weights.iter().sum();
+        let mut x = (word(0) >> 11) as f64 / (1u64 << 53) as f64 * total;
+        let i = (0..self.weights.len())
+            .find(|&i| {
+                let w = self.weights[i];
+                if w > 0.0 && x < w {
+                    true
+                } else {
+                    x -= w;
+                    false
                 }
-            }
-            self.step();
+            })
+            .or_else(|| self.weights.iter().rposition(|&w| w > 0.0))
+            .unwrap();
+        let op = Op::ALL[i];
+        let target = if self.files.is_empty() {
+            0
+        } else {
+            (word(1) % self.files.len() as u64) as u32
+        };
+        let size = if self.opsize.distribution == SizeDistribution::Uniform {
+            let (min, max) = (self.opsize.min as u64, self.opsize.max as u64);
+            min + word(2) % (max - min + 1)
+        } else {
+            let x = (word(2) >> 11) as f64 / (1u64 << 53) as f64;
+            self.opsize.quantile(x) as u64
+        };
+        let offset = (word(3) >> 32) as u32;
+        let extra = (word(4) >> 32) as u32;
+        let flag = word(5) & 1 == 1;
+        let (extra, flag) = match op {
+            Op::Truncate
+            | Op::CopyFileRange
+            | Op::ScratchCopyIn
+            | Op::ScratchCopyOut
+            | Op::ScratchSendfile
+            | Op::ScratchClone => (extra, false),
+            Op::PosixFadvise => (extra % 6, false),
+            Op::FsizeLimit => (0, flag),
+            Op::CopyFileRangeOverlap => (extra, flag),
+            _ => (0, false),
+        };
+        OpRecord {
+            op,
+            target,
+            size,
+            offset,
+            extra,
+            flag,
         }
-
-        println!("All operations completed A-OK!");
     }
 
-    fn fsync(&mut self) {
-        self.oplog.push(LogEntry::Fsync);
-
-        if self.skip() {
+    /// Append the latest oplog entry to the --oplog-file, and to the
+    /// watchdog's copy of the log
+    fn append_log_line(&self) {
+        let mut guard =
+            self.thread.is_none().then(|| WATCHDOG_LOG.lock().unwrap());
+        let watchdog_log = guard.as_deref_mut().and_then(Option::as_mut);
+        if self.oplog_file.is_none() && watchdog_log.is_none() {
             return;
         }
-        info!("{:width$} fsync", self.steps, width = self.stepwidth);
-        self.file.sync_all().unwrap();
-    }
-
-    fn fdatasync(&mut self) {
-        self.oplog.push(LogEntry::Fdatasync);
-
-        if self.skip() {
+        let Some(le) = self.oplog.back() else {
+            return;
+        };
+        let timing = self.timings.as_ref().and_then(|t| t.back());
+        let mut line = with_timing(self.describe(self.steps, le), timing);
+        if self.reopens.back() == Some(&self.steps) {
+            line += &format!(
+                "\n{:stepwidth$} CLOSE/OPEN",
+                self.steps,
+                stepwidth = self.stepwidth
+            );
+        }
+        if let Some(log) = watchdog_log {
+            log.push(line.clone());
+        }
+        // Release the watchdog's lock before failing, which takes FAILURE
+        drop(guard);
+        let Some(oplog_file) = &*self.oplog_file else {
             return;
+        };
+        if let Err(e) = oplog_file.append(&line) {
+            error!("writing {}: {}", oplog_file.path.display(), e);
+            self.fail();
         }
-        info!("{:width$} fdatasync", self.steps, width = self.stepwidth);
-        self.file.sync_data().unwrap();
     }
 
-    fn gendata(&mut self, offset: u64, mut size: usize) {
-        let mut uoff = usize::try_from(offset).unwrap();
-        loop {
-            size -= 1;
-            self.good_buf[uoff] = (self.steps % 256) as u8;
-            if uoff % 2 > 0 {
-                self.good_buf[uoff] =
-                    self.good_buf[uoff].wrapping_add(self.original_buf[uoff]);
-            }
-            uoff += 1;
-            if size == 0 {
-                break;
+    /// Emit a progress snapshot, if one is due
+    fn maybe_emit_stats(&mut self) {
+        let Some(stats) = &mut *self.stats else {
+            return;
+        };
+        let now = Instant::now();
+        let since = now.duration_since(stats.last);
+        if since < stats.interval {
+            return;
+        }
+        let line = format!(
+            "{:.1}s: {} steps, {:.1} ops/s, {:#x} bytes read, {:#x} bytes \
+             written, {:.2}% skipped",
+            now.duration_since(stats.start).as_secs_f64(),
+            self.steps,
+            (self.steps - stats.last_steps) as f64 / since.as_secs_f64(),
+            self.bytes_read,
+            self.bytes_written,
+            100.0 * self.skips.get() as f64 / self.steps.max(1) as f64
+        );
+        stats.last = now;
+        stats.last_steps = self.steps;
+        match &mut stats.file {
+            Some((path, f)) => {
+                if let Err(e) = writeln!(f, "{line}") {
+                    warn!("writing {}: {}", path.display(), e);
+                }
             }
+            None => println!("{line}"),
         }
     }
 
-    fn getpagesize() -> i32 {
-        // This function is inherently safe
-        sysconf(SysconfVar::PAGE_SIZE).unwrap().unwrap() as i32
-    }
-
-    fn invalidate(&mut self) {
-        self.oplog.push(LogEntry::Invalidate);
-
-        if self.skip() {
+    /// If the run is paused, sync the file and wait until it resumes
+    fn pause(&self) {
+        if !PAUSED.load(Ordering::Relaxed) {
             return;
         }
-        let len = self.file_size as usize;
-        if len == 0 {
-            debug!(
-                "{:width$} skipping invalidate of zero-length file",
-                self.steps,
-                width = self.stepwidth
-            );
-            return;
+        if let Err(e) = eintr(|| self.file.sync_all()) {
+            error!("fsync failed with {e}");
+            self.fail();
         }
-        info!(
-            "{:width$} msync(MS_INVALIDATE)",
+        warn!(
+            "{:width$} paused: file size {:#x}, {:#x} bytes written.  Send \
+             SIGUSR2 to resume.",
             self.steps,
+            self.file_size,
+            self.bytes_written,
             width = self.stepwidth
         );
-        unsafe {
-            let p = mmap(
-                None,
-                len.try_into().unwrap(),
-                ProtFlags::PROT_READ | ProtFlags::PROT_WRITE,
-                MapFlags::MAP_FILE | MapFlags::MAP_SHARED,
-                self.file.as_fd(),
-                0,
-            )
-            .unwrap();
-            msync(p, 0, MsFlags::MS_INVALIDATE).unwrap();
-            munmap(p, len).unwrap();
+        while PAUSED.load(Ordering::Relaxed) {
+            thread::sleep(Duration::from_millis(100));
         }
+        warn!("{:width$} resumed", self.steps, width = self.stepwidth);
     }
 
-    /// Log level to use for I/O operations.
-    fn loglevel(
-        &self,
-        offset: u64,
-        offset2: Option<u64>,
-        size: usize,
-    ) -> Level {
-        let mut loglevel = Level::Info;
-        if let Some((start, end)) = self.monitor {
-            if start < offset + size as u64 && offset <= end {
-                loglevel = Level::Warn;
+    /// Print the next operation, and wait for the user's command.  Return
+    /// whether to execute it.
+    fn prompt(&mut self, rec: &OpRecord) -> bool {
+        let stdin = io::stdin();
+        loop {
+            eprint!(
+                "{:width$} next: {} file {} offset {:#x} size {:#x} extra \
+                 {:#x} flag {} [next/skip/dump/continue/quit] ",
+                self.steps + 1,
+                rec.op,
+                rec.target,
+                rec.offset,
+                rec.size,
+                rec.extra,
+                rec.flag,
+                width = self.stepwidth
+            );
+            let mut line = String::new();
+            match stdin.read_line(&mut line) {
+                Ok(0) | Err(_) => {
+                    // Nobody is listening anymore
+                    self.interactive = false;
+                    return true;
+                }
+                Ok(_) => (),
             }
-            if let Some(offset2) = offset2 {
-                if start < offset2 + size as u64 && offset2 <= end {
-                    loglevel = Level::Warn;
+            match line.trim() {
+                "" | "n" | "next" => return true,
+                "s" | "skip" => return false,
+                "d" | "dump" => self.dump_logfile(),
+                "c" | "continue" => {
+                    self.interactive = false;
+                    return true;
                 }
+                "q" | "quit" => {
+                    // Stop here, but still do the end-of-run checks
+                    self.numops = Some(self.steps);
+                    return false;
+                }
+                cmd => eprintln!("unknown command {cmd:?}"),
             }
         }
-        loglevel
-    }
-
-    fn mapread(&mut self, offset: u64, size: usize) {
-        self.read_like(Op::MapRead, offset, size, Self::domapread)
-    }
-
-    fn mapwrite(&mut self, offset: u64, size: usize) {
-        self.write_like(Op::MapWrite, offset, size, Self::domapwrite)
-    }
-
-    fn read(&mut self, offset: u64, size: usize) {
-        self.read_like(Op::Read, offset, size, Self::doread)
-    }
-
-    fn sendfile(&mut self, offset: u64, size: usize) {
-        self.read_like(Op::Sendfile, offset, size, Self::dosendfile)
     }
 
     fn step(&mut self) {
-        let op: Op = self.wi.sample(&mut self.rng);
+        if let Some(ss) = &mut *self.step_start {
+            ss.rng.clone_from(&self.rng);
+            ss.file_size = self.file_size;
+            ss.cursor = self.cursor;
+            ss.counts = self.counts;
+            self.good_buf.mark();
+        }
+        self.enter_phase();
+        let rec = self.draw();
+        let op = rec.op;
+        let execute = !self.interactive || self.prompt(&rec);
+        if !execute && self.numops == Some(self.steps) {
+            return;
+        }
 
         if self.simulatedopcount > 0 && self.steps == self.simulatedopcount {
-            self.writefileimage();
+            for i in 0..self.files.len().max(1) {
+                self.switch_to(i);
+                self.writefileimage();
+            }
+        }
+        if !self.files.is_empty() {
+            let i = rec.target as usize;
+            self.targets.push(i);
+            self.switch_to(i);
+        }
+        if !execute {
+            self.steps += 1;
+            self.oplog.push(LogEntry::Skip(op));
+            if let Some(timings) = &mut self.timings {
+                timings.push((SystemTime::now(), Duration::ZERO));
+            }
+            self.append_log_line();
+            return;
+        }
+        if let Some(trace) = &*self.trace {
+            if let Err(e) = trace.record(&rec) {
+                error!("writing {}: {}", trace.path.display(), e);
+                self.fail();
+            }
         }
         self.steps += 1;
+        if self.break_at.is_some_and(|n| n.get() == self.steps) {
+            warn!(
+                "{:width$} stopping before {}.  Send SIGCONT to pid {} to \
+                 continue.",
+                self.steps,
+                op,
+                process::id(),
+                width = self.stepwidth
+            );
+            raise(Signal::SIGSTOP).unwrap();
+        }
+        self.counts[op as usize] += 1;
+        if self.max_count[op as usize].is_some() {
+            self.apply_max_count();
+        }
+        CURRENT_OP.store(op as usize, Ordering::Relaxed);
+        PROGRESS.store(self.steps, Ordering::Relaxed);
+        if let Some(progress) = *self.progress {
+            progress.store(self.steps, Ordering::SeqCst);
+        }
+
+        let mut size = rec.size as usize;
+        let mut offset = u64::from(rec.offset);
 
-        let mut size = self.rng.gen_range(self.opsize.min..=self.opsize.max);
-        let mut offset: u64 = self.rng.gen::<u32>() as u64;
+        let times_before = self
+            .timecheck
+            .map(|_| (SystemTime::now(), self.timestamps()));
 
+        let old_file_size = self.file_size;
+        let skips_before = self.skips.get();
+        #[cfg(feature = "metrics")]
+        let bytes_before = (self.bytes_read, self.bytes_written);
+        let started = Instant::now();
+        let wall_started = SystemTime::now();
+        if matches!(
+            op,
+            Op::PosixFallocate
+                | Op::FsizeLimit
+                | Op::CopyFileRange
+                | Op::CopyFileRangeOverlap
+                | Op::ScratchCopyIn
+                | Op::ScratchCopyOut
+                | Op::ScratchSendfile
+                | Op::ScratchClone
+        ) {
+            self.mark_busy(0, u64::MAX);
+        }
         match op {
             Op::CloseOpen => self.closeopen(),
             Op::Write | Op::MapWrite => {
-                offset %= self.flen;
-                offset -= offset % self.align as u64;
-                if offset + size as u64 > self.flen {
-                    size = usize::try_from(self.flen - offset).unwrap();
-                }
-                size -= size % self.align;
+                (offset, size) = self.place(offset, size, self.flen);
+                self.mark_busy(offset, offset + size as u64);
                 if op == Op::MapWrite {
                     self.mapwrite(offset, size);
                 } else {
@@ -1524,32 +10657,44 @@ impl Exerciser {
                 }
             }
             Op::Truncate => {
-                let fsize = u64::from(self.rng.gen::<u32>()) % self.flen;
+                let fsize = u64::from(rec.extra) % self.flen;
+                self.mark_busy(fsize.min(self.file_size), u64::MAX);
                 self.truncate(fsize)
             }
             Op::Invalidate => self.invalidate(),
             Op::Read | Op::MapRead | Op::Sendfile | Op::PosixFadvise => {
-                offset = if self.file_size > 0 {
-                    offset % self.file_size
-                } else {
-                    0
-                };
-                offset -= offset % self.align as u64;
-                if offset + size as u64 > self.file_size {
-                    size = usize::try_from(self.file_size - offset).unwrap();
-                }
-                size -= size % self.align;
+                (offset, size) = self.place(offset, size, self.file_size);
                 match op {
                     Op::MapRead => self.mapread(offset, size),
                     Op::Read => self.read(offset, size),
                     Op::Sendfile => self.sendfile(offset, size),
                     Op::PosixFadvise => {
-                        let advice: PosixFadviseAdvice = self.rng.gen();
+                        let advice = PosixFadviseAdvice::from_index(rec.extra);
                         self.posix_fadvise(advice, offset, size as u64)
                     }
                     _ => unreachable!(),
                 }
             }
+            Op::Reflink => self.reflink(),
+            Op::Stat => self.stat(),
+            Op::ReadEof => {
+                // Start no more than size bytes before EOF, so the read
+                // always reaches it.
+                let back = offset % (size as u64 + 1);
+                offset = self.file_size.saturating_sub(back);
+                offset -= offset % self.align_offset as u64;
+                size -= size % self.align_size;
+                self.read_eof(offset, size)
+            }
+            Op::FsizeLimit => {
+                offset %= self.flen;
+                offset -= offset % self.align_offset as u64;
+                if offset + size as u64 > self.flen {
+                    size = usize::try_from(self.flen - offset).unwrap();
+                }
+                size -= size % self.align_size;
+                self.fsize_limit(offset, size, rec.flag)
+            }
             Op::Fsync => self.fsync(),
             Op::Fdatasync => self.fdatasync(),
             Op::PosixFallocate => {
@@ -1557,80 +10702,432 @@ impl Exerciser {
                 if offset + size as u64 > self.flen {
                     size = usize::try_from(self.flen - offset).unwrap();
                 }
-                size -= size % self.align;
+                size -= size % self.align_size;
                 self.posix_fallocate(offset, size as u64)
             }
             Op::PunchHole => {
-                offset = if self.file_size > 0 {
-                    offset % self.file_size
-                } else {
-                    0
-                };
-                offset -= offset % self.align as u64;
-                if offset + size as u64 > self.file_size {
-                    size = usize::try_from(self.file_size - offset).unwrap();
-                }
-                size -= size % self.align;
+                (offset, size) = self.place(offset, size, self.file_size);
+                self.mark_busy(offset, offset + size as u64);
                 self.punch_hole(offset, size as u64)
             }
             Op::CopyFileRange => {
-                let ooffset: u64 = self.rng.gen::<u32>() as u64;
+                let ooffset = u64::from(rec.extra);
                 self.copy_file_range(op, offset, ooffset, size);
             }
+            Op::ScratchCopyIn
+            | Op::ScratchCopyOut
+            | Op::ScratchSendfile
+            | Op::ScratchClone => {
+                let ooffset = u64::from(rec.extra);
+                self.scratch_op(op, offset, ooffset, size);
+            }
+            Op::CopyFileRangeOverlap => {
+                let shift = u64::from(rec.extra);
+                self.copy_file_range_overlap(offset, shift, rec.flag, size);
+            }
+        }
+        CURRENT_OP.store(usize::MAX, Ordering::Relaxed);
+        let elapsed = started.elapsed();
+        self.check_map_fault();
+        self.ck_store(old_file_size);
+        if self.thread.is_none() {
+            let c = &STATUS_COUNTERS;
+            c.file_size.store(self.file_size, Ordering::Relaxed);
+            c.bytes_read.store(self.bytes_read, Ordering::Relaxed);
+            c.bytes_written.store(self.bytes_written, Ordering::Relaxed);
+            c.skips.store(self.skips.get(), Ordering::Relaxed);
+        }
+        let ran = !self.skip()
+            && !matches!(self.oplog.back(), Some(LogEntry::Skip(_)));
+        if let Some(latencies) = &mut self.latencies {
+            if ran {
+                latencies[op as usize].record(elapsed);
+            }
+        }
+        #[cfg(feature = "metrics")]
+        if let Some(metrics) = &self.metrics {
+            metrics.record(
+                op,
+                ran.then_some(elapsed),
+                self.bytes_read - bytes_before.0,
+                self.bytes_written - bytes_before.1,
+                self.skips.get() - skips_before,
+            );
+        }
+        if let Some(timings) = &mut self.timings {
+            timings.push((wall_started, elapsed));
+            if ran {
+                let loglevel = self
+                    .oplog
+                    .back()
+                    .and_then(LogEntry::range)
+                    .map(|(offset, len)| {
+                        self.loglevel(offset, None, len as usize)
+                    })
+                    .unwrap_or(Level::Info);
+                log!(
+                    loglevel,
+                    "{:width$} {:8} took {}us",
+                    self.steps,
+                    op,
+                    elapsed.as_micros(),
+                    width = self.stepwidth
+                );
+            }
+        }
+        if let Some(csv) = &*self.csv {
+            let entry = self.oplog.back();
+            let result = if matches!(entry, Some(LogEntry::Skip(_))) {
+                "skipped"
+            } else if self.steps <= self.simulatedopcount {
+                "simulated"
+            } else if self.injected() {
+                "injected"
+            } else if self.skips.get() > skips_before {
+                "error"
+            } else {
+                "ok"
+            };
+            let range = entry.and_then(LogEntry::range);
+            if let Err(e) = csv.record(self.steps, op, range, elapsed, result) {
+                error!("writing {}: {}", csv.path.display(), e);
+                self.fail();
+            }
+        }
+        if let Some((start, before)) = times_before {
+            self.check_timestamps(op, start, before);
+        }
+        self.publish(old_file_size);
+        if let Some(crash) = &mut *self.crash {
+            crash.observe(&self.good_buf, self.file_size);
         }
         if self.steps > self.simulatedopcount {
             self.check_size();
         }
+        self.maybe_closeopen();
+        self.append_log_line();
+        if self.steps > self.simulatedopcount {
+            if let Some(interval) = self.verify_interval {
+                if self.steps % interval.get() == 0 {
+                    debug!(
+                        "{:width$} verifying entire file",
+                        self.steps,
+                        width = self.stepwidth
+                    );
+                    self.check_file();
+                    if self.report.is_some() {
+                        self.verified.push(self.steps);
+                    }
+                }
+            }
+            if let Some(interval) = self.checkpoint_interval {
+                if self.steps % interval.get() == 0 {
+                    self.checkpoint();
+                }
+            }
+            if let Some(snapshots) = &*self.snapshots {
+                if self.steps % snapshots.interval.get() == 0 {
+                    self.snapshot();
+                }
+            }
+            if let Some((_, interval)) = &self.check_hook {
+                if self.steps % interval.get() == 0 {
+                    self.check_hook();
+                }
+            }
+            if let Some((_, interval)) = &self.remount_hook {
+                if self.steps % interval.get() == 0 {
+                    self.remount();
+                }
+            }
+            if let Some((_, interval)) = &self.drop_caches {
+                if self.steps % interval.get() == 0 {
+                    self.drop_caches();
+                }
+            }
+            self.fault_step();
+        }
+    }
+
+    /// Begin or end a fault window, if it's time
+    fn fault_step(&mut self) {
+        let Some(fault) = &mut *self.fault else {
+            return;
+        };
+        if let Some(window) = &mut fault.window {
+            let file_size = self.file_size;
+            window.states.push((self.good_buf.clone(), file_size));
+            if self.steps >= window.start + fault.conf.duration.get() {
+                self.end_fault_window();
+            }
+        } else if self.steps % fault.conf.interval.get() == 0 {
+            self.begin_fault_window();
+        }
+    }
+
+    /// Sync the file, then make the device faulty
+    fn begin_fault_window(&mut self) {
+        info!("{:width$} fault begins", self.steps, width = self.stepwidth);
+        if let Err(e) = eintr(|| self.file.sync_all()) {
+            error!("fsync failed with {e}");
+            self.fail();
+        }
+        let fault = self.fault.as_ref().unwrap();
+        let on = fault.on.clone();
+        let mode = fault.conf.mode;
+        self.run_hook("fault injection", &on);
+        let eio = if mode == FaultMode::Error {
+            self.errors.insert(libc::EIO, ErrorPolicy::Skip)
+        } else {
+            self.errors.get(&libc::EIO).copied()
+        };
+        self.fault.as_mut().unwrap().window = Some(FaultWindow {
+            start: self.steps,
+            synced: self.good_buf.clone(),
+            synced_size: self.file_size,
+            states: Vec::new(),
+            eio,
+        });
+    }
+
+    /// Make the device healthy again, and verify that the file holds either
+    /// the data it had when the window began, or data written since.  Then
+    /// update the model to match.
+    fn end_fault_window(&mut self) {
+        info!("{:width$} fault ends", self.steps, width = self.stepwidth);
+        let fault = self.fault.as_mut().unwrap();
+        let window = fault.window.take().unwrap();
+        let off = fault.off.clone();
+        self.run_hook("fault injection", &off);
+        match window.eio {
+            Some(policy) => self.errors.insert(libc::EIO, policy),
+            None => self.errors.remove(&libc::EIO),
+        };
+        if let Some((cmd, _)) = &self.remount_hook {
+            // Remount without syncing, so dropped writes are really lost
+            let cmd = self.expand(cmd, self.steps as usize);
+            self.cycle_mount(&cmd);
+        }
+        let len = self.file.metadata().unwrap().len();
+        let mut actual = vec![0u8; len as usize];
+        if let Err(e) = self.file.read_exact_at(&mut actual, 0) {
+            error!("reading back file: {e}");
+            self.fail();
+        }
+        let mut check = CrashCheck::new(u64::MAX, actual);
+        check.sync(&window.synced, window.synced_size);
+        for (buf, size) in window.states.iter() {
+            check.observe(buf, *size);
+        }
+        if let Err(e) = check.verify() {
+            error!("{e} after fault window");
+            self.fail();
+        }
+        // The file is legit.  Believe it.
+        let len = check.actual.len();
+        self.good_buf.write(0, &check.actual);
+        if (len as u64) < self.file_size {
+            self.good_buf.fill(len..self.file_size as usize, 0);
+        }
+        self.file_size = len as u64;
     }
 
     fn posix_fallocate(&mut self, offset: u64, len: u64) {
+        let cur_file_size = self.file_size;
         let new_size = self.file_size.max(offset + len);
         if new_size > self.file_size {
-            self.good_buf[self.file_size as usize..new_size as usize].fill(0);
+            self.good_buf
+                .fill(self.file_size as usize..new_size as usize, 0);
+        }
+        self.file_size = new_size;
+        self.oplog.push(LogEntry::PosixFallocate(offset, len));
+
+        if self.skip() {
+            return;
+        }
+
+        // XXX Should not log at WARN if size and self.file_size are both
+        // below a monitored range's start.  But the C-based implementation
+        // does.
+        let mut loglevel = Level::Info;
+        if self.monitor.iter().any(|&(_, end)| len <= end) {
+            loglevel = Level::Warn;
+        }
+        log!(
+            loglevel,
+            "{:stepwidth$} posix_fallocate {:#fwidth$x} .. {:#fwidth$x} \
+             ({:#swidth$x} bytes)",
+            self.steps,
+            offset,
+            offset + len - 1,
+            len,
+            stepwidth = self.stepwidth,
+            fwidth = self.fwidth,
+            swidth = self.swidth
+        );
+        let before = self.allocated();
+        let r = self.retry(|| {
+            sys!(
+                self,
+                "posix_fallocate"(self.file.as_raw_fd(), offset, len),
+                posix_fallocate(
+                    self.file.as_raw_fd(),
+                    offset as i64,
+                    len as i64
+                )
+            )
+        });
+        match r {
+            Ok(()) => (),
+            Err(e) if self.skip_error(Op::PosixFallocate, &e) => {
+                // The file may have been partially extended.  The extension
+                // is zero-filled either way.
+                let len = self.file.metadata().unwrap().len();
+                self.file_size = len.clamp(cur_file_size, new_size);
+                self.mirror(|o| o.set_len(self.file_size));
+                return;
+            }
+            Err(nix::Error::EINVAL) => {
+                error!("Test file system does not support posix_fallocate.");
+                self.fail();
+            }
+            Err(e) => {
+                error!("posix_fallocate unexpectedly failed with {e}");
+                self.fail();
+            }
+        }
+        self.mirror(|o| o.set_len(new_size));
+        if let Some(tolerance) = self.stblocks_check {
+            // Allocation should never shrink, and every block in the range
+            // must now be allocated.
+            let after = self.allocated();
+            let bs = self.blksize;
+            let needed =
+                ((offset + len) / bs).saturating_sub(offset.div_ceil(bs)) * bs;
+            if after + tolerance < before || after + tolerance < needed {
+                error!(
+                    "st_blocks error after posix_fallocate: {:#x} bytes \
+                     allocated before, {:#x} after, but at least {:#x} \
+                     expected",
+                    before, after, needed
+                );
+                self.fail();
+            }
+        }
+    }
+
+    /// Fetch the file's metadata by path, by file descriptor, and with statx
+    /// where available, and verify that they all agree.
+    fn stat(&mut self) {
+        self.oplog.push(LogEntry::Stat);
+
+        if self.skip() {
+            return;
+        }
+        info!("{:width$} stat", self.steps, width = self.stepwidth);
+
+        let path = if self.tmpfile {
+            PathBuf::from(format!("/proc/self/fd/{}", self.file.as_raw_fd()))
+        } else {
+            self.fname.clone()
+        };
+        let by_path = match fs::metadata(&path) {
+            Ok(md) => FileAttrs::from(&md),
+            Err(e) => {
+                error!("stat failed with {e}");
+                self.fail();
+            }
+        };
+        let by_fd = match sys!(
+            self,
+            "fstat"(self.file.as_raw_fd()),
+            self.file.metadata()
+        ) {
+            Ok(md) => FileAttrs::from(&md),
+            Err(e) => {
+                error!("fstat failed with {e}");
+                self.fail();
+            }
+        };
+        let mut results = vec![("stat", by_path), ("fstat", by_fd)];
+        match statx_attrs(&self.file) {
+            Ok(Some(attrs)) => results.push(("statx", attrs)),
+            Ok(None) => (),
+            Err(e) => {
+                error!("statx failed with {e}");
+                self.fail();
+            }
+        }
+        for (name, attrs) in &results[1..] {
+            if *attrs != results[0].1 {
+                error!(
+                    "Metadata mismatch: {} returned {:?} but {} returned {:?}",
+                    results[0].0, results[0].1, name, attrs
+                );
+                self.fail();
+            }
         }
-        self.file_size = new_size;
-        self.oplog.push(LogEntry::PosixFallocate(offset, len));
+    }
 
-        if self.skip() {
-            return;
-        }
+    /// The file's mtime and ctime
+    fn timestamps(&self) -> (SystemTime, SystemTime) {
+        let md = self.file.metadata().unwrap();
+        let ctime = UNIX_EPOCH
+            + Duration::new(md.ctime() as u64, md.ctime_nsec() as u32);
+        (md.modified().unwrap(), ctime)
+    }
 
-        // XXX Should not log at WARN if size < self.monitor.0 and
-        // self.file_size < self.monitor.0.  But the C-based implementation
-        // does.
-        let mut loglevel = Level::Info;
-        if let Some((_, end)) = self.monitor {
-            if len <= end {
-                loglevel = Level::Warn;
+    /// Verify that the most recent operation advanced the file's mtime and
+    /// ctime, if it should have.
+    fn check_timestamps(
+        &self,
+        op: Op,
+        start: SystemTime,
+        before: (SystemTime, SystemTime),
+    ) {
+        let granularity = self.timecheck.unwrap();
+        let modified = match self.oplog.back() {
+            // An injected step is like a lost write, which should be noticed
+            _ if self.skip() && !self.injected() => false,
+            // Overlapping copies may legitimately fail with EINVAL
+            _ if op == Op::CopyFileRangeOverlap => false,
+            // Mapped writes need not update timestamps until msync
+            Some(LogEntry::MapWrite(..)) => !self.nomsyncafterwrite,
+            Some(LogEntry::Truncate(old_len, new_len)) => old_len != new_len,
+            Some(LogEntry::Write(..))
+            | Some(LogEntry::PunchHole(..))
+            | Some(LogEntry::CopyFileRange(..)) => true,
+            Some(LogEntry::Scratch(op, ..)) => {
+                matches!(op, Op::ScratchCopyIn | Op::ScratchClone)
             }
+            _ => false,
+        };
+        if !modified {
+            return;
         }
-        log!(
-            loglevel,
-            "{:stepwidth$} posix_fallocate {:#fwidth$x} .. {:#fwidth$x} \
-             ({:#swidth$x} bytes)",
-            self.steps,
-            offset,
-            offset + len - 1,
-            len,
-            stepwidth = self.stepwidth,
-            fwidth = self.fwidth,
-            swidth = self.swidth
-        );
-        let r =
-            posix_fallocate(self.file.as_raw_fd(), offset as i64, len as i64);
-        match r {
-            Ok(()) => (),
-            Err(nix::Error::EINVAL) => {
-                eprintln!("Test file system does not support posix_fallocate.");
-                self.fail();
-            }
-            Err(e) => {
-                eprintln!("posix_fallocate unexpectedly failed with {e}");
+        let after = self.timestamps();
+        let earliest = start - granularity;
+        for (name, b, a) in
+            [("mtime", before.0, after.0), ("ctime", before.1, after.1)]
+        {
+            if a < b || a < earliest {
+                error!(
+                    "{name} did not advance: {:?} before {op} and {:?} after \
+                     it, which began at {:?}",
+                    b.duration_since(UNIX_EPOCH).unwrap(),
+                    a.duration_since(UNIX_EPOCH).unwrap(),
+                    start.duration_since(UNIX_EPOCH).unwrap()
+                );
                 self.fail();
             }
         }
     }
 
+    /// Number of bytes allocated to the file, according to st_blocks
+    fn allocated(&self) -> u64 {
+        self.file.metadata().unwrap().blocks() * 512
+    }
+
     fn punch_hole(&mut self, offset: u64, len: u64) {
         assert!(offset + len <= self.file_size);
 
@@ -1644,7 +11141,11 @@ impl Exerciser {
             return;
         }
 
-        self.good_buf[offset as usize..(offset + len) as usize].fill(0);
+        self.ck_load(offset..offset + len, true);
+        let range = offset as usize..(offset + len) as usize;
+        let saved =
+            self.may_skip().then(|| self.good_buf.to_vec(range.clone()));
+        self.good_buf.fill(range.clone(), 0);
         self.oplog.push(LogEntry::PunchHole(offset, len));
 
         if self.skip() {
@@ -1664,38 +11165,88 @@ impl Exerciser {
             fwidth = self.fwidth,
             swidth = self.swidth
         );
-        cfg_if! {
-            if #[cfg(have_fspacectl)] {
-                nix::fcntl::fspacectl_all(
+        let before = self.allocated();
+        let r = self.retry(|| {
+            sys!(
+                self,
+                "fallocate"(
                     self.file.as_raw_fd(),
-                    offset as i64,
-                    len as i64
-                ).unwrap();
-            } else if #[cfg(any(
-                    target_os = "android",
-                    target_os = "emscripten",
-                    target_os = "fuchsia",
-                    target_os = "linux",
-                ))] {
-                use nix::fcntl::FallocateFlags;
-
-                nix::fcntl::fallocate(
-                    self.file.as_raw_fd(),
-                    FallocateFlags::FALLOC_FL_PUNCH_HOLE |
-                        FallocateFlags::FALLOC_FL_KEEP_SIZE,
-                    offset as i64,
-                    len as i64
-                ).unwrap();
-            } else {
-                eprintln!("hole punching is not supported on this platform.");
-                process::exit(1);
+                    "FALLOC_FL_PUNCH_HOLE|FALLOC_FL_KEEP_SIZE",
+                    self.base + offset,
+                    len
+                ),
+                punch_hole(&self.file, self.base + offset, len)
+            )
+        });
+        if let Err(e) = r {
+            if self.skip_error(Op::PunchHole, &e) {
+                self.good_buf.write(range.start, &saved.unwrap());
+                return;
+            }
+            error!("punch_hole failed with {e}");
+            self.fail();
+        }
+        self.mirror(|o| o.write_all_at(&vec![0; len as usize], offset));
+        if let Some(tolerance) = self.stblocks_check {
+            // Allocation should never grow, and no block entirely within the
+            // hole may remain allocated.
+            let after = self.allocated();
+            let bs = self.blksize;
+            let freed =
+                ((offset + len) / bs).saturating_sub(offset.div_ceil(bs)) * bs;
+            let limit = self.file_size.div_ceil(bs) * bs - freed;
+            if after > before + tolerance || after > limit + tolerance {
+                error!(
+                    "st_blocks error after punch_hole: {:#x} bytes allocated \
+                     before, {:#x} after, but at most {:#x} expected",
+                    before, after, limit
+                );
+                self.fail();
+            }
+        }
+        if self.holecheck {
+            self.check_hole(offset, len);
+        }
+    }
+
+    /// Verify that every whole block within the given range is a hole
+    fn check_hole(&self, offset: u64, len: u64) {
+        let bs = self.blksize;
+        let start = offset.div_ceil(bs) * bs;
+        let end = (offset + len) / bs * bs;
+        if start >= end {
+            return;
+        }
+        let (hole, data) = match seek_hole_data(&self.file, start) {
+            Ok(r) => r,
+            // EINVAL means the file system doesn't support SEEK_HOLE
+            Err(e) if self.skip_error(Op::PunchHole, &e) => return,
+            Err(e) => {
+                error!("lseek failed with {e}");
+                self.fail();
             }
+        };
+        if hole != start || data.is_some_and(|d| d < end) {
+            error!(
+                "Punched range {:#x} .. {:#x} is not a hole.  SEEK_HOLE \
+                 returned {:#x} and SEEK_DATA returned {:#x?}",
+                start,
+                end - 1,
+                hole,
+                data
+            );
+            self.fail();
         }
     }
 
     fn truncate(&mut self, size: u64) {
+        if size < self.file_size {
+            // The rest of the block at the new EoF will read as zeros
+            self.ck_load(size..size + 1, true);
+        }
         if size > self.file_size {
-            self.good_buf[self.file_size as usize..size as usize].fill(0);
+            self.good_buf
+                .fill(self.file_size as usize..size as usize, 0);
         }
         let cur_file_size = self.file_size;
         self.file_size = size;
@@ -1704,17 +11255,19 @@ impl Exerciser {
             .push(LogEntry::Truncate(cur_file_size, self.file_size));
 
         if self.skip() {
+            if self.injected() && size < cur_file_size {
+                // Like a truncate that left stale data in the EoF page
+                self.check_eofpage_after_truncate();
+            }
             return;
         }
 
-        // XXX Should not log at WARN if size < self.monitor.0 and
-        // self.file_size < self.monitor.0.  But the C-based implementation
+        // XXX Should not log at WARN if size and self.file_size are both
+        // below a monitored range's start.  But the C-based implementation
         // does.
         let mut loglevel = Level::Info;
-        if let Some((_, end)) = self.monitor {
-            if size <= end {
-                loglevel = Level::Warn;
-            }
+        if self.monitor.iter().any(|&(_, end)| size <= end) {
+            loglevel = Level::Warn;
         }
         log!(
             loglevel,
@@ -1725,7 +11278,52 @@ impl Exerciser {
             stepwidth = self.stepwidth,
             fwidth = self.fwidth
         );
-        self.file.set_len(size).unwrap();
+        match self.retry(|| {
+            sys!(
+                self,
+                "ftruncate"(self.file.as_raw_fd(), size),
+                self.file.set_len(size)
+            )
+        }) {
+            Ok(()) => self.mirror(|o| o.set_len(size)),
+            Err(e) if self.skip_error(Op::Truncate, &e) => {
+                self.file_size = cur_file_size;
+                return;
+            }
+            Err(e) => {
+                error!("truncate failed with {e}");
+                self.fail();
+            }
+        }
+        if size < cur_file_size {
+            self.check_eofpage_after_truncate();
+        }
+    }
+
+    /// Verify through a fresh mapping that the remainder of the file's last
+    /// page reads as zeros.
+    fn check_eofpage_after_truncate(&self) {
+        let page_mask = Self::getpagesize() as u64 - 1;
+        let pg_offset = self.file_size & page_mask;
+        if pg_offset == 0 {
+            // The file ends on a page boundary.  Nothing to check.
+            return;
+        }
+        let page_start = self.file_size - pg_offset;
+        let map_size = NonZeroUsize::new(pg_offset as usize).unwrap();
+        unsafe {
+            let p = mmap(
+                None,
+                map_size,
+                ProtFlags::PROT_READ,
+                MapFlags::MAP_FILE | MapFlags::MAP_SHARED,
+                self.file.as_fd(),
+                page_start as i64,
+            )
+            .unwrap();
+            self.check_eofpage(page_start, p.as_ptr(), pg_offset as usize);
+            munmap(p, map_size.get()).unwrap();
+        }
     }
 
     fn write(&mut self, offset: u64, size: usize) {
@@ -1733,48 +11331,91 @@ impl Exerciser {
     }
 
     fn writefileimage(&mut self) {
-        let written = self
-            .file
-            .write_at(&self.good_buf[..self.file_size as usize], 0)
-            .unwrap();
-        if written as u64 != self.file_size {
-            error!(
-                "short write: {:#x} bytes instead of {:#x}",
-                written, self.file_size
-            );
+        let (start, end) = (self.region.0, self.region.1.min(self.file_size));
+        let range = start as usize..end as usize;
+        if let Err(e) =
+            self.good_buf.write_to(&self.file, range, self.base + start)
+        {
+            error!("writing file image: {e}");
             self.fail();
         }
-        if !self.blockmode {
+        if !self.blockmode && self.thread.is_none() {
             self.file.set_len(self.file_size).unwrap();
         }
+        if let Some(scratch) = &*self.scratch {
+            let size = scratch.size as usize;
+            scratch
+                .file
+                .write_all_at(&scratch.good_buf[..size], 0)
+                .unwrap();
+            scratch.file.set_len(scratch.size).unwrap();
+        }
+        self.mirror(|o| {
+            self.good_buf.write_to(o, 0..self.file_size as usize, 0)?;
+            o.set_len(self.file_size)
+        });
     }
 
     // Clippy false positive:
     // https://github.com/rust-lang/rust-clippy/issues/11300
     #[allow(clippy::useless_conversion)]
     fn new(cli: Cli, conf: Config) -> Self {
-        let seed = cli.seed.unwrap_or_else(|| {
-            let mut seeder = thread_rng();
-            seeder.gen::<u64>()
-        });
+        let seed = cli.seed.as_ref().map_or_else(
+            || {
+                let mut seeder = thread_rng();
+                seeder.gen::<u64>()
+            },
+            |s| s.0,
+        );
         debug!("Using seed {}", seed);
-        let mut oo = OpenOptions::new();
-        oo.read(true).write(true);
-        if !conf.blockmode {
-            oo.create(true).truncate(true);
+        // An anonymous file can't be shared, so it needs no lock.
+        let locks = if conf.tmpfile {
+            Vec::new()
+        } else {
+            vec![lock_file(&cli.fname, !conf.blockmode)]
+        };
+        let file = if conf.tmpfile {
+            open_tmpfile(&cli.fname).expect("Cannot create O_TMPFILE file")
+        } else {
+            let mut oo = OpenOptions::new();
+            oo.read(true).write(true);
+            if !conf.blockmode {
+                oo.create(true).truncate(!conf.keep_contents);
+            }
+            oo.open(&cli.fname).expect("Cannot create file")
+        };
+        let base = conf.blockmode_offset;
+        if base % Self::getpagesize() as u64 != 0 {
+            error!(
+                "ERROR: blockmode_offset must be a multiple of the page size"
+            );
+            process::exit(2);
         }
-        let mut file = oo.open(&cli.fname).expect("Cannot create file");
         let flen = conf.flen.map(u64::from).unwrap_or_else(|| {
-            if conf.blockmode {
+            if let Some(len) = conf.blockmode_length {
+                len.get()
+            } else if conf.blockmode {
                 let md = file.metadata().unwrap();
                 let ft = md.file_type();
                 if ft.is_file() {
-                    md.len()
+                    md.len().saturating_sub(base)
                 } else if ft.is_char_device() || ft.is_block_device() {
-                    mediasize(file.as_raw_fd()).unwrap()
+                    match mediasize(file.as_raw_fd()) {
+                        Ok(len) => len.saturating_sub(base),
+                        Err(e) => {
+                            error!(
+                                "ERROR: cannot get the size of {}: {e}.  Set \
+                                 flen or blockmode_length.",
+                                cli.fname.display()
+                            );
+                            process::exit(2);
+                        }
+                    }
                 } else {
                     unimplemented!()
                 }
+            } else if conf.keep_contents {
+                default_flen().max(file.metadata().unwrap().len())
             } else {
                 default_flen()
             }
@@ -1783,6 +11424,19 @@ impl Exerciser {
             error!("ERROR: file length must be greater than zero");
             process::exit(2);
         }
+        for (name, used) in [
+            ("crash", conf.crash),
+            ("fault", conf.fault.is_some()),
+            ("scratch_file", conf.scratch_file.is_some()),
+        ] {
+            if used && flen > WHOLE_COPY_MAX {
+                error!(
+                    "ERROR: {name} keeps whole copies of the file in RAM, so \
+                     flen must not exceed {WHOLE_COPY_MAX:#x}"
+                );
+                process::exit(2);
+            }
+        }
         let nosizechecks = if !conf.blockmode {
             conf.nosizechecks
         } else {
@@ -1790,77 +11444,900 @@ impl Exerciser {
             // it any way.
             true
         };
-        let file_size = if conf.blockmode { flen } else { 0 };
-        let mut original_buf = vec![0u8; flen as usize];
-        let good_buf = vec![0u8; flen as usize];
+        // With multiple threads, the file's size stays fixed so the threads
+        // won't interfere with each other.
+        let fixed_size = conf.blockmode || conf.threads.get() > 1;
+        let mut file_size = if fixed_size { flen } else { 0 };
+        let mut good_buf = SparseBuf::new(flen as usize);
+        if let Some(path) = &conf.initial_contents {
+            let image = fs::read(path).unwrap_or_else(|e| {
+                error!("reading {}: {}", path.display(), e);
+                process::exit(1);
+            });
+            let n = image.len().min(flen as usize);
+            if n < image.len() {
+                warn!(
+                    "Using only the first {:#x} bytes of {}",
+                    n,
+                    path.display()
+                );
+            }
+            good_buf.write(0, &image[..n]);
+            file_size = file_size.max(n as u64);
+        }
+        if conf.keep_contents {
+            let len = file.metadata().unwrap().len();
+            if len > flen {
+                error!(
+                    "ERROR: file is larger than flen ({len:#x} > {flen:#x})"
+                );
+                process::exit(2);
+            }
+            good_buf.segments_mut(0..len as usize, |pos, buf| {
+                file.read_exact_at(buf, pos as u64).unwrap()
+            });
+            file_size = file_size.max(len);
+        }
         if conf.blockmode {
-            // Zero existing file
-            file.write_all(&good_buf).unwrap();
+            // Overwrite existing file with zeros or the initial contents
+            good_buf.write_to(&file, 0..flen as usize, base).unwrap();
+        } else {
+            if fixed_size {
+                file.set_len(flen).unwrap();
+            }
+            if conf.initial_contents.is_some() {
+                good_buf.write_to(&file, 0..file_size as usize, 0).unwrap();
+            }
+        }
+        let mut rng = FsxRng::new(conf.rng, seed);
+        let original_buf = match conf.op_stream {
+            OpStream::Legacy if conf.pattern == Pattern::Stamp => {
+                Some(OriginalBuf::new(&mut rng, flen as usize))
+            }
+            OpStream::Legacy => {
+                // Nothing needs the original data, but the RNG must still
+                // advance past it.
+                let mut buf = vec![0u8; SPARSE_CHUNK.min(flen as usize)];
+                let mut left = flen as usize;
+                while left > 0 {
+                    let n = left.min(buf.len());
+                    rng.fill_bytes(&mut buf[..n]);
+                    left -= n;
+                }
+                None
+            }
+            OpStream::V1 => None,
+        };
+        let blksize = file.metadata().unwrap().blksize();
+        let mut errors: HashMap<i32, ErrorPolicy> = conf
+            .errors
+            .iter()
+            .map(|(name, policy)| {
+                let errno = ERRNOS.iter().find(|(n, _)| n == name).unwrap().1;
+                (errno, *policy)
+            })
+            .collect();
+        if conf.enospc == Enospc::Skip {
+            errors.entry(libc::ENOSPC).or_insert(ErrorPolicy::Skip);
+        }
+        if conf.max_weights().fsize_limit > 0.0 {
+            // Exceeding RLIMIT_FSIZE should produce EFBIG, not kill us.
+            unsafe { signal(Signal::SIGXFSZ, SigHandler::SigIgn) }.unwrap();
+            let (_, hard) = getrlimit(Resource::RLIMIT_FSIZE).unwrap();
+            if hard < flen {
+                warn!(
+                    "The hard RLIMIT_FSIZE ({hard:#x}) is less than flen; \
+                     fsize_limit will not set a limit above it"
+                );
+            }
         }
-        let mut rng = XorShiftRng::seed_from_u64(seed);
-        rng.fill_bytes(&mut original_buf[..]);
+        let direct_file = if conf.direct_read_verify {
+            let path = if conf.tmpfile {
+                PathBuf::from(format!("/proc/self/fd/{}", file.as_raw_fd()))
+            } else {
+                cli.fname.clone()
+            };
+            match open_direct(&path) {
+                Ok(f) => Some(f),
+                Err(e) => {
+                    eprintln!(
+                        "Test file system does not support O_DIRECT: {e}"
+                    );
+                    process::exit(1);
+                }
+            }
+        } else {
+            None
+        };
+        let scratch = conf.scratch_file.as_ref().map(|p| {
+            let path = match cli.fname.parent() {
+                Some(d) => d.join(p),
+                None => p.clone(),
+            };
+            let file = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(true)
+                .open(&path)
+                .expect("Cannot create scratch file");
+            // Use a separate RNG so the scratch file's contents don't perturb
+            // the operation sequence.
+            let mut good_buf = vec![0u8; flen as usize];
+            match conf.op_stream {
+                OpStream::Legacy => {
+                    FsxRng::new(conf.rng, !seed).fill_bytes(&mut good_buf[..])
+                }
+                OpStream::V1 => v1_fill(!seed, &mut good_buf[..]),
+            }
+            file.write_all_at(&good_buf, 0).unwrap();
+            Scratch {
+                file,
+                path,
+                size: flen,
+                good_buf,
+            }
+        });
+        let oracle = cli.oracle.as_ref().map(|p| {
+            let f = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(true)
+                .open(p)
+                .expect("Cannot create oracle file");
+            good_buf.write_to(&f, 0..file_size as usize, 0).unwrap();
+            f.set_len(file_size).unwrap();
+            f
+        });
+        let checksums = (conf.shadow == ShadowMode::Checksum).then(|| {
+            let checksums = Checksums::new(&good_buf, file_size);
+            // From now on the shadow only holds the blocks in use
+            good_buf.fill(0..flen as usize, 0);
+            checksums
+        });
+        let fault = conf.fault.map(|conf| match fault_commands(&conf) {
+            Ok((on, off)) => Fault {
+                conf,
+                on,
+                off,
+                window: None,
+            },
+            Err(e) => {
+                eprintln!("{e}");
+                process::exit(1);
+            }
+        });
+        let snapshots = conf.snapshot_interval.map(|interval| Snapshots {
+            interval,
+            command: conf.snapshot_command.unwrap(),
+            path: conf.snapshot_path.unwrap(),
+            taken: Vec::new(),
+        });
+        let trace = (conf.trace != TraceMode::Off).then(|| {
+            let path = artifact_path(
+                cli.artifacts_dir.as_deref(),
+                &cli.fname,
+                &cli.artifact_name,
+                ".fsxops",
+            );
+            match Trace::create(conf.trace, path.clone(), seed) {
+                Ok(t) => t,
+                Err(e) => {
+                    eprintln!("Cannot create {}: {e}", path.display());
+                    process::exit(1);
+                }
+            }
+        });
+        let csv = cli.csv.map(|path| match CsvTrace::create(path.clone()) {
+            Ok(c) => c,
+            Err(e) => {
+                eprintln!("Cannot create {}: {e}", path.display());
+                process::exit(1);
+            }
+        });
+        #[cfg(feature = "strace")]
+        let strace =
+            cli.strace.map(|path| match Strace::create(path.clone()) {
+                Ok(s) => s,
+                Err(e) => {
+                    eprintln!("Cannot create {}: {e}", path.display());
+                    process::exit(1);
+                }
+            });
+        // Only when the op log isn't printed, lest they interfere
+        let meter = (cli.numops.is_some()
+            && io::stderr().is_terminal()
+            && log::max_level() < LevelFilter::Info)
+            .then(|| {
+                let now = Instant::now();
+                Meter {
+                    start: now,
+                    last:  now,
+                }
+            });
+        #[cfg(feature = "metrics")]
+        let metrics =
+            (cli.prometheus.is_some() || cli.statsd.is_some()).then(|| {
+                let metrics = Arc::new(Metrics::new());
+                if let Some(addr) = &cli.prometheus {
+                    start_prometheus(addr, metrics.clone());
+                }
+                if let Some(addr) = &cli.statsd {
+                    start_statsd(addr, metrics.clone());
+                }
+                metrics
+            });
+        let oplog_size = conf.oplog_size.get();
+        let oplog_file =
+            cli.oplog_file
+                .map(|path| match OplogFile::create(path.clone()) {
+                    Ok(f) => f,
+                    Err(e) => {
+                        eprintln!("Cannot create {}: {e}", path.display());
+                        process::exit(1);
+                    }
+                });
+        let stats = cli.stats_interval.map(|interval| {
+            let file = cli.stats_file.map(|path| {
+                match OpenOptions::new().create(true).append(true).open(&path) {
+                    Ok(f) => (path, f),
+                    Err(e) => {
+                        eprintln!("Cannot open {}: {e}", path.display());
+                        process::exit(1);
+                    }
+                }
+            });
+            let now = Instant::now();
+            Stats {
+                interval,
+                file,
+                start: now,
+                last: now,
+                last_steps: 0,
+            }
+        });
         let fwidth = field_width(flen as usize, true);
-        let swidth = field_width(conf.opsize.max, true);
+        let phases: Vec<Phase> = conf
+            .phases
+            .iter()
+            .map(|p| {
+                let weights = p.weights.to_array();
+                Phase {
+                    ops: p.ops.get(),
+                    wi: Op::make_weighted_index(weights.into_iter()),
+                    weights,
+                    opsize: p.opsize.clone().unwrap_or(conf.opsize.clone()),
+                }
+            })
+            .collect();
+        let max_opsize = phases
+            .iter()
+            .map(|p| p.opsize.max)
+            .fold(conf.opsize.max, usize::max);
+        let swidth = field_width(max_opsize, true);
         let stepwidth = field_width(
             cli.numops.map(|x| x as usize).unwrap_or(999999),
             false,
         );
-        let wi = Op::make_weighted_index(
-            [
-                conf.weights.close_open,
-                conf.weights.read,
-                conf.weights.write,
-                conf.weights.mapread,
-                conf.weights.truncate,
-                conf.weights.invalidate,
-                conf.weights.mapwrite,
-                conf.weights.fsync,
-                conf.weights.fdatasync,
-                conf.weights.posix_fallocate,
-                conf.weights.punch_hole,
-                conf.weights.sendfile,
-                conf.weights.posix_fadvise,
-                conf.weights.copy_file_range,
-            ]
-            .into_iter(),
-        );
+        let weights = conf.weights.to_array();
+        let wi = Op::make_weighted_index(weights.into_iter());
         Exerciser {
-            align: conf.opsize.align.map(usize::from).unwrap_or(1),
+            align_offset: conf.opsize.offset_align(),
+            align_size: conf.opsize.size_align(),
             artifacts_dir: cli.artifacts_dir,
+            artifact_name: cli.artifact_name,
+            artifact_mode: conf.artifact_mode,
             blockmode: conf.blockmode,
-            file,
+            base: conf.blockmode_offset,
+            blksize,
+            file: SharedFile(file),
             file_size,
             flen,
             fwidth,
             fname: cli.fname,
             good_buf,
+            checksums: Unshared(checksums),
+            read_buf: vec![0; conf.opsize.max],
             inject: cli.inject,
-            monitor: cli.monitor,
+            inject_errno: cli.inject_errno,
+            errno_injected: Cell::new(false),
+            skip_ops: cli.skip_ops,
+            monitor: conf.monitor.iter().chain(&cli.monitor).copied().collect(),
+            monitor_only: cli.monitor_only,
+            map_mode: conf.map_mode,
+            map_window: Unshared(None),
             nomsyncafterwrite: conf.nomsyncafterwrite,
             nosizechecks,
             numops: cli.numops,
             opsize: conf.opsize,
-            oplog: AllocRingBuffer::with_capacity(1024),
+            offsets: conf.offsets.mode,
+            pattern: conf.pattern,
+            cursor: 0,
+            reopen: conf.reopen,
+            closeprob: conf.closeprob,
+            reopens: AllocRingBuffer::with_capacity(oplog_size),
+            reflink: Unshared(None),
+            scratch: Unshared(scratch),
+            oracle: Unshared(oracle),
+            snapshots: Unshared(snapshots),
+            check_hook: conf.check_hook.map(|c| (c, conf.check_hook_interval)),
+            remount_hook: conf.remount_hook.map(|c| (c, conf.remount_interval)),
+            drop_caches: conf.drop_caches.map(|n| (conf.drop_caches_hook, n)),
+            dmesg: conf.dmesg_lines.map(|n| {
+                (n, conf.dmesg_command.unwrap_or_else(|| "dmesg".to_owned()))
+            }),
+            locks: Unshared(locks),
+            oplog: AllocRingBuffer::with_capacity(oplog_size),
             seed,
             simulatedopcount: <NonZeroU64 as Into<u64>>::into(cli.opnum) - 1,
             swidth,
             stepwidth,
+            tmpfile: conf.tmpfile,
+            tmpfile_link: conf.tmpfile_link,
+            verify_interval: conf.verify_interval,
+            checkpoint_interval: conf.checkpoint_interval,
+            verify_after_write: conf.verify_after_write,
+            direct_file: direct_file.map(SharedFile),
+            stblocks_check: conf
+                .stblocks_check
+                .then_some(conf.stblocks_tolerance),
+            holecheck: conf.holecheck,
+            timecheck: conf
+                .timecheck
+                .then(|| Duration::from_secs_f64(conf.timecheck_granularity)),
+            errors,
+            signal_storm: conf.signal_storm.is_some(),
+            thread: None,
+            region: (0, flen),
+            verifiers: Unshared(None),
+            files: Unshared(Vec::new()),
+            cur: 0,
+            targets: AllocRingBuffer::with_capacity(oplog_size),
+            timings: cli
+                .timestamps
+                .then(|| AllocRingBuffer::with_capacity(oplog_size)),
+            progress: Unshared(None),
+            crash: Unshared(None),
+            fault: Unshared(fault),
+            trace: Unshared(trace),
+            csv: Unshared(csv),
+            #[cfg(feature = "strace")]
+            strace: Unshared(strace),
+            oplog_file: Unshared(oplog_file),
+            replay: Unshared(None),
+            script: Unshared(
+                conf.script
+                    .iter()
+                    .filter_map(ScriptOp::to_record)
+                    .collect::<Vec<_>>()
+                    .into_iter(),
+            ),
+            report: Unshared(cli.report),
+            report_format: cli.report_format,
+            verified: Vec::new(),
+            repro: None,
+            skips: Cell::new(0),
+            bytes_written: 0,
+            bytes_read: 0,
+            stats: Unshared(stats),
+            meter: Unshared(meter),
+            badrange: Cell::new(None),
+            #[cfg(feature = "metrics")]
+            metrics,
+            latencies: cli
+                .latency
+                .then(|| vec![Histogram::default(); Op::ALL.len()]),
+            max_write_bytes: conf.max_write_bytes,
             original_buf,
+            // Checksum mode has no expected contents to resume from, and
+            // neither pcg64 nor legacy can be restored.
+            step_start: Unshared(
+                RngState::save(&rng)
+                    .filter(|_| conf.shadow != ShadowMode::Checksum)
+                    .map(|_| StepStart {
+                        rng:       rng.clone(),
+                        file_size: 0,
+                        cursor:    0,
+                        counts:    [0; Op::ALL.len()],
+                    }),
+            ),
             rng,
             steps: 0,
             wi,
+            weights,
+            max_count: Op::ALL
+                .iter()
+                .map(|op| conf.max_count.get(&op.to_string()).copied())
+                .collect(),
+            counts: [0; Op::ALL.len()],
+            op_stream: conf.op_stream,
+            phases,
+            phase: usize::MAX,
+            phase_cycle: conf.phase_mode == PhaseMode::Cycle,
+            interactive: cli.step,
+            break_at: cli.break_at,
         }
     }
 }
 
-fn main() {
-    let cli = Cli::parse();
+/// Compare a test file to its expected contents, and exit
+fn verify(args: VerifyArgs) -> ! {
+    let good_path = args.good.unwrap_or_else(|| {
+        artifact_path(
+            args.artifacts_dir.as_deref(),
+            &args.fname,
+            "{name}",
+            ".fsxgood",
+        )
+    });
+    let read = |path: &Path| {
+        fs::read(path).unwrap_or_else(|e| {
+            eprintln!("Cannot read {}: {e}", path.display());
+            process::exit(1);
+        })
+    };
+    let actual = read(&args.fname);
+    let good = read(&good_path);
+    // The .fsxgood file is always flen bytes long, regardless of the test
+    // file's size.
+    if actual.len() > good.len() {
+        eprintln!(
+            "error: {} is {:#x} bytes long, but {} is only {:#x}",
+            args.fname.display(),
+            actual.len(),
+            good_path.display(),
+            good.len()
+        );
+        process::exit(1);
+    }
+    let mut bad = (0..actual.len()).filter(|&i| actual[i] != good[i]);
+    if let Some(first) = bad.next() {
+        eprintln!(
+            "error: miscompare at offset {:#x}: expected {:#04x}, found \
+             {:#04x}.  {:#x} bad bytes",
+            first,
+            good[first],
+            actual[first],
+            1 + bad.count()
+        );
+        process::exit(1);
+    }
+    println!("{} matches {}", args.fname.display(), good_path.display());
+    process::exit(0);
+}
+
+/// Print the effective configuration, and exit
+fn show_config(args: ConfigArgs) -> ! {
     env_logger::builder()
-        .filter_level(cli.verbose.log_level_filter())
+        .filter_level(LevelFilter::Warn)
         .format_timestamp(None)
         .init();
-    let config = cli.config.as_ref().map(Config::load).unwrap_or_default();
+    let config = args
+        .config
+        .as_deref()
+        .map(|path| Config::load(path, args.profile.as_deref()))
+        .unwrap_or_default();
+    print!("{}", toml::to_string(&config).unwrap());
+    process::exit(0);
+}
+
+/// The example configuration file, which documents every option
+const EXAMPLE_CONFIG: &str = include_str!("../doc/fsx.toml");
+
+/// Write the example configuration file, with every option set to its
+/// default and unsupported operations annotated, and exit
+fn generate_config(args: GenerateConfigArgs) -> ! {
+    let defaults = toml::Table::try_from(Config::default()).unwrap();
+    let empty = toml::Table::new();
+    // Weights are named like operations, but with no slashes.
+    let unsupported = Op::ALL
+        .iter()
+        .filter(|op| !op.supported())
+        .map(|op| op.to_string().replace('/', "_"))
+        .collect::<Vec<_>>();
+    let mut section = "";
+    let mut table = &defaults;
+    let mut out = String::new();
+    for line in EXAMPLE_CONFIG.lines() {
+        if let Some(name) =
+            line.strip_prefix('[').and_then(|l| l.strip_suffix(']'))
+        {
+            section = name;
+            table = defaults
+                .get(name)
+                .and_then(toml::Value::as_table)
+                .unwrap_or(&empty);
+        } else if let Some((key, _)) =
+            line.split_once(" = ").filter(|(k, _)| {
+                k.bytes().all(|b| b.is_ascii_lowercase() || b == b'_')
+            })
+        {
+            if section == "weights" && unsupported.iter().any(|u| u == key) {
+                out.push_str("# Not supported on this platform\n");
+            }
+            match table.get(key) {
+                Some(v) => out.push_str(&format!("{key} = {v}\n")),
+                None => out.push_str(&format!("# {line}\n")),
+            }
+            continue;
+        }
+        out.push_str(line);
+        out.push('\n');
+    }
+    let r = match &args.path {
+        Some(path) => fs::write(path, out),
+        None => io::stdout().write_all(out.as_bytes()),
+    };
+    if let Err(e) = r {
+        eprintln!("Cannot write config file: {e}");
+        process::exit(1);
+    }
+    process::exit(0);
+}
+
+/// Find the smallest alignment that O_DIRECT reads accept
+fn probe_direct(path: &Path) -> Result<String, String> {
+    const MAX: usize = 65536;
+    let f = open_direct(path).map_err(|e| e.to_string())?;
+    let mut storage = vec![0u8; 2 * MAX];
+    let skip = storage.as_ptr().align_offset(MAX);
+    let buf = &mut storage[skip..skip + MAX];
+    let mut align = 512;
+    loop {
+        match f.read_at(&mut buf[..align], align as u64) {
+            Ok(_) => return Ok(format!("alignment {align}")),
+            Err(e) if align >= MAX => return Err(e.to_string()),
+            Err(_) => align *= 2,
+        }
+    }
+}
+
+/// Try every optional operation on a pair of scratch files, print the
+/// results, and exit
+fn doctor(args: DoctorArgs) -> ! {
+    let dir = if args.path.is_dir() {
+        args.path.clone()
+    } else {
+        args.path.parent().unwrap_or(Path::new(".")).to_owned()
+    };
+    let path = dir.join(format!(".fsx-doctor.{}", process::id()));
+    let path2 = numbered(&path, 2);
+    let open = |p: &Path| {
+        let f = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(p)?;
+        f.write_all_at(&[0xa5u8; 65536], 0)?;
+        f.sync_all()?;
+        Ok::<File, io::Error>(f)
+    };
+    let (f, mut f2) = match (open(&path), open(&path2)) {
+        (Ok(f), Ok(f2)) => (f, f2),
+        (Err(e), _) | (_, Err(e)) => {
+            eprintln!("Cannot create scratch files in {}: {e}", dir.display());
+            let _ = fs::remove_file(&path);
+            let _ = fs::remove_file(&path2);
+            process::exit(1);
+        }
+    };
+    let unsupported = || Err("not supported on this platform".to_owned());
+    let probe = |op: Op, f: &dyn Fn() -> nix::Result<()>| {
+        if op.supported() {
+            f().map(|_| String::new()).map_err(|e| e.to_string())
+        } else {
+            unsupported()
+        }
+    };
+    // Supported on this platform, but not tried on this file system
+    const UNTESTED: &str = "untested";
+    // Capability, weights that depend on it, and result
+    let results: Vec<(&str, &[&str], Result<String, String>)> = vec![
+        (
+            "posix_fallocate",
+            &["posix_fallocate"],
+            probe(Op::PosixFallocate, &|| {
+                posix_fallocate(f.as_raw_fd(), 65536, 65536)
+            }),
+        ),
+        (
+            "punch_hole",
+            &["punch_hole"],
+            probe(Op::PunchHole, &|| punch_hole(&f, 4096, 4096)),
+        ),
+        (
+            "fspacectl",
+            &[],
+            if cfg!(have_fspacectl) {
+                // punch_hole uses fspacectl, where available
+                punch_hole(&f, 12288, 4096)
+                    .map(|_| String::new())
+                    .map_err(|e| e.to_string())
+            } else {
+                unsupported()
+            },
+        ),
+        (
+            "copy_file_range",
+            &["copy_file_range", "copy_file_range_overlap"],
+            probe(Op::CopyFileRange, &|| {
+                copy_file_range_all(&f, 0, &f2, 8192, 4096)
+            }),
+        ),
+        (
+            "sendfile",
+            &["sendfile"],
+            if cfg!(any(target_os = "android", target_os = "linux")) {
+                sendfile_all(&f, 0, &mut f2, 8192, 4096)
+                    .map(|_| String::new())
+                    .map_err(|e| e.to_string())
+            } else if Op::Sendfile.supported() {
+                Ok(UNTESTED.to_owned())
+            } else {
+                unsupported()
+            },
+        ),
+        (
+            "posix_fadvise",
+            &["posix_fadvise"],
+            if Op::PosixFadvise.supported() {
+                Ok(UNTESTED.to_owned())
+            } else {
+                unsupported()
+            },
+        ),
+        (
+            "FICLONE",
+            &["reflink"],
+            probe(Op::Reflink, &|| clone_file(&f, &f2)),
+        ),
+        (
+            "FICLONERANGE",
+            &[],
+            probe(Op::ScratchClone, &|| clone_range(&f, 0, &f2, 0, 65536)),
+        ),
+        (
+            "FIEMAP",
+            &[],
+            if cfg!(any(target_os = "android", target_os = "linux")) {
+                fiemap_extents(&f, 65536)
+                    .map(|n| format!("{n} extents"))
+                    .map_err(|e| e.to_string())
+            } else {
+                unsupported()
+            },
+        ),
+        (
+            "SEEK_HOLE",
+            &[],
+            if cfg!(any(
+                target_os = "android",
+                target_os = "freebsd",
+                target_os = "linux"
+            )) {
+                seek_hole_data(&f, 0)
+                    .map(|_| String::new())
+                    .map_err(|e| e.to_string())
+            } else {
+                unsupported()
+            },
+        ),
+        (
+            "O_DIRECT",
+            &[],
+            if cfg!(any(
+                target_os = "android",
+                target_os = "dragonfly",
+                target_os = "freebsd",
+                target_os = "linux",
+                target_os = "netbsd"
+            )) {
+                probe_direct(&path)
+            } else {
+                unsupported()
+            },
+        ),
+    ];
+    let _ = fs::remove_file(&path);
+    let _ = fs::remove_file(&path2);
+
+    println!("Capabilities of {}:", dir.display());
+    for (name, _, result) in results.iter() {
+        let status = match result {
+            Ok(d) if d.is_empty() => "yes".to_owned(),
+            Ok(d) => format!("yes ({d})"),
+            Err(e) => format!("no ({e})"),
+        };
+        println!("  {name:16} {status}");
+    }
+    println!();
+    println!("# Suggested weights");
+    println!("[weights]");
+    for (_, weights, result) in results.iter() {
+        let untested = matches!(result, Ok(d) if d == UNTESTED);
+        for w in weights.iter() {
+            if untested {
+                println!("{w} = 0  # untested; doctor could not try it here");
+            } else {
+                println!("{w} = {}", u8::from(result.is_ok()));
+            }
+        }
+    }
+    process::exit(0);
+}
+
+/// Parse the command line.  For compatibility, the run subcommand is
+/// optional.
+fn parse_cli() -> Cli {
+    let mut args = std::env::args_os().collect::<Vec<_>>();
+    if args.get(1).is_some_and(|a| a == "--legacy-cli") {
+        args.remove(1);
+        return LegacyCli::parse_from(args).into_cli();
+    }
+    let legacy = args.get(1).and_then(|a| a.to_str()).is_some_and(|a| {
+        Fsx::command().find_subcommand(a).is_none()
+            && !["-h", "--help", "-V", "--version"].contains(&a)
+    });
+    if legacy {
+        return Cli::parse_from(args);
+    }
+    match Fsx::parse_from(args).command {
+        Cmd::Run(cli) => cli,
+        Cmd::Verify(args) => verify(args),
+        Cmd::Replay(args) => Cli {
+            replay: Some(args.trace),
+            ..args.cli
+        },
+        Cmd::Config(args) => show_config(args),
+        Cmd::GenerateConfig(args) => generate_config(args),
+        Cmd::Doctor(args) => doctor(args),
+    }
+}
+
+fn main() {
+    let mut cli = parse_cli();
+    // Let log::set_max_level alone choose the verbosity, so the config file
+    // can change it later.
+    env_logger::builder()
+        .filter_level(LevelFilter::Trace)
+        .format_timestamp(
+            cli.timestamps
+                .then_some(env_logger::TimestampPrecision::Micros),
+        )
+        .init();
+    log::set_max_level(cli.verbose.log_level_filter());
+    for flag in &cli.legacy_ignored {
+        warn!("ignoring unsupported option {flag}");
+    }
+    let mut config = cli.legacy_config.take().unwrap_or_else(|| {
+        cli.config
+            .as_deref()
+            .map(|path| Config::load(path, cli.profile.as_deref()))
+            .unwrap_or_default()
+    });
+    if let Some(level) = config.log_level {
+        if cli.verbose.log_level_filter() == LevelFilter::Warn {
+            // Neither -v nor -q was given, since clap rejects both together
+            log::set_max_level(level);
+        }
+    }
+    config.detect_device(&cli.fname);
+    config.align_to_device(&cli.fname);
     config.validate(&cli);
+    let replay = cli.replay.as_ref().map(|path| match read_trace(path) {
+        Ok(trace) => trace,
+        Err(e) => {
+            eprintln!("Cannot read {}: {e}", path.display());
+            process::exit(1);
+        }
+    });
+    let rng_state = cli.rng_state.as_deref().map(RunState::load);
+    if let Some(state) = &rng_state {
+        // The seed still determines the file's original contents.
+        cli.seed = Some((state.seed, None));
+    }
+    if let Some((seed, records)) = &replay {
+        // The seed still determines the file's original contents.
+        cli.seed = Some((*seed, None));
+        let n = records.len() as u64;
+        cli.numops = Some(cli.numops.map_or(n, |numops| numops.min(n)));
+    }
+    if let Some((seed, Some(name))) = &cli.seed {
+        info!("Seed {:?} hashes to {}", name, seed);
+    }
+    if config.script_mode == ScriptMode::Instead {
+        let n = config.script.len() as u64;
+        cli.numops = Some(cli.numops.map_or(n, |numops| numops.min(n)));
+    }
+    let mut cli = match cli.jobs {
+        Some(jobs) => fork_jobs(cli, jobs),
+        None if cli.soak => soak(cli),
+        None => cli,
+    };
+    let seed = cli.seed.get_or_insert_with(|| (thread_rng().gen(), None)).0;
+    cli.artifact_name = resolve_artifact_name(&cli.artifact_name, seed);
+    if let Some(meta) = cli.fuzz_config {
+        // Fuzz after forking, so that without an explicit meta-seed every job
+        // and soak run gets its own config.  With one, they all share it.
+        let meta = meta.unwrap_or(seed);
+        config.fuzz(meta);
+        config.validate(&cli);
+        let path = artifact_path(
+            cli.artifacts_dir.as_deref(),
+            &cli.fname,
+            &cli.artifact_name,
+            ".fsxconfig",
+        );
+        if let Err(e) = fs::write(&path, toml::to_string(&config).unwrap()) {
+            eprintln!("Cannot write {}: {e}", path.display());
+            process::exit(1);
+        }
+        info!("Using fuzzed config {} (meta-seed {meta})", path.display());
+    }
+    let base = cli.fname.clone();
+    if config.files.get() > 1 {
+        cli.fname = numbered(&base, 0);
+    }
+    let files = config.files;
+    let crash = config.crash.then(|| config.crash_hook.clone());
+    let signal_storm = config.signal_storm;
+    let threads = config.threads;
+    let verify_threads = config.verify_threads;
+    let watchdog = config.watchdog.map(|limit| OpTimeout {
+        limit,
+        action: config.watchdog_action,
+    });
+    let timeouts = Op::ALL
+        .iter()
+        .map(|op| config.timeouts.get(&op.to_string()).copied())
+        .collect::<Vec<_>>();
+    let watchdog_kstack = config.watchdog_kstack;
+    let oplog_size = config.oplog_size.get();
+    let repro = Repro::new(&cli, &base, &config);
     let mut exerciser = Exerciser::new(cli, config);
-    exerciser.exercise()
+    exerciser.repro = Some(repro);
+    *exerciser.replay = replay.map(|(_, records)| records.into_iter());
+    if let Some(state) = rng_state {
+        exerciser.restore(state);
+    }
+    if let Some(hook) = crash {
+        exerciser.crash_test(hook);
+    }
+    catch_map_faults();
+    catch_pause_signal();
+    start_status_thread(exerciser.seed);
+    if watchdog.is_some() || timeouts.iter().any(Option::is_some) {
+        start_watchdog(timeouts, watchdog, watchdog_kstack, oplog_size);
+    }
+    if let Some(interval) = signal_storm {
+        start_signal_storm(interval);
+    }
+    exerciser.add_files(&base, files.get());
+    exerciser.start_verifiers(verify_threads);
+    let workers = exerciser
+        .split(threads.get())
+        .into_iter()
+        .map(|mut w| {
+            thread::spawn(move || {
+                w.exercise();
+                w.latencies
+            })
+        })
+        .collect::<Vec<_>>();
+    exerciser.exercise();
+    for w in workers {
+        let theirs = w.join().unwrap();
+        if let (Some(mine), Some(theirs)) = (&mut exerciser.latencies, theirs) {
+            for (a, b) in mine.iter_mut().zip(theirs.iter()) {
+                a.merge(b);
+            }
+        }
+    }
+    exerciser.save_report(None);
+    if let Some(latencies) = &exerciser.latencies {
+        print_latencies(latencies);
+    }
+    println!("All operations completed A-OK!");
 }
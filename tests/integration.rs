@@ -275,6 +275,76 @@ use tempfile::{NamedTempFile, TempDir};
 [INFO  fsx] 10 read      0x58941 ..  0x5b149 ( 0x2809 bytes)
 "
 )]
+// Op stream v1 must never change, regardless of the rand crate.
+#[case::v1(
+    "op_stream = \"v1\"",
+    "-N10 -S 2",
+    "[DEBUG fsx] Using seed 2
+[DEBUG fsx]  1 skipping zero size read
+[INFO  fsx]  2 mapwrite 0x32dec .. 0x383df ( 0x55f4 bytes)
+[INFO  fsx]  3 mapread   0x3213 ..  0xd335 ( 0xa123 bytes)
+[INFO  fsx]  4 write    0x24c03 .. 0x2fda7 ( 0xb1a5 bytes)
+[INFO  fsx]  5 mapread  0x2fb9f .. 0x383df ( 0x8841 bytes)
+[INFO  fsx]  6 write    0x34cdf .. 0x3ffff ( 0xb321 bytes)
+[INFO  fsx]  7 truncate 0x40000 => 0x312d7
+[INFO  fsx]  8 truncate 0x312d7 =>  0xc18b
+[INFO  fsx]  9 truncate  0xc18b => 0x106d9
+[INFO  fsx] 10 truncate 0x106d9 => 0x32831
+"
+)]
+#[case::v1_mixed(
+    "op_stream = \"v1\"
+     [weights]
+     fsync = 1
+     punch_hole = 1
+     posix_fallocate = 1
+     copy_file_range = 1",
+    "-N10 -S 2",
+    "[DEBUG fsx] Using seed 2
+[INFO  fsx]  1 truncate     0x0 =>  0xf0f1
+[INFO  fsx]  2 punch_hole  0x9745 ..  0xed38 ( 0x55f4 bytes)
+[INFO  fsx]  3 mapread   0xa9fa ..  0xf0f0 ( 0x46f7 bytes)
+[INFO  fsx]  4 write    0x24c03 .. 0x2fda7 ( 0xb1a5 bytes)
+[INFO  fsx]  5 mapread  0x1e9ff .. 0x2abc3 ( 0xc1c5 bytes)
+[INFO  fsx]  6 write    0x34cdf .. 0x3ffff ( 0xb321 bytes)
+[INFO  fsx]  7 truncate 0x40000 => 0x312d7
+[INFO  fsx]  8 truncate 0x312d7 =>  0xc18b
+[INFO  fsx]  9 mapwrite  0x4aa3 .. 0x13a49 ( 0xefa7 bytes)
+[INFO  fsx] 10 truncate 0x13a4a => 0x32831
+"
+)]
+#[case::chacha8(
+    "rng = \"chacha8\"",
+    "-N10 -S 2",
+    "[DEBUG fsx] Using seed 2
+[DEBUG fsx]  1 skipping zero size read
+[DEBUG fsx]  2 skipping zero size read
+[INFO  fsx]  3 write    0x375eb .. 0x3ffff ( 0x8a15 bytes)
+[INFO  fsx]  4 mapwrite 0x2904e .. 0x2fd90 ( 0x6d43 bytes)
+[INFO  fsx]  5 read     0x15068 .. 0x1f47c ( 0xa415 bytes)
+[INFO  fsx]  6 write    0x33a09 .. 0x3ae5c ( 0x7454 bytes)
+[INFO  fsx]  7 mapread  0x3a549 .. 0x3ffff ( 0x5ab7 bytes)
+[INFO  fsx]  8 truncate 0x40000 => 0x201ac
+[INFO  fsx]  9 mapwrite 0x3a3d1 .. 0x3ffff ( 0x5c2f bytes)
+[INFO  fsx] 10 truncate 0x40000 => 0x300c4
+"
+)]
+#[case::pcg64(
+    "rng = \"pcg64\"",
+    "-N10 -S 2",
+    "[DEBUG fsx] Using seed 2
+[INFO  fsx]  1 mapwrite 0x18c10 .. 0x261ec ( 0xd5dd bytes)
+[INFO  fsx]  2 mapread  0x17f68 .. 0x248f6 ( 0xc98f bytes)
+[INFO  fsx]  3 mapread  0x170a6 .. 0x2515d ( 0xe0b8 bytes)
+[INFO  fsx]  4 mapwrite 0x35600 .. 0x367ea ( 0x11eb bytes)
+[INFO  fsx]  5 write    0x2fb35 .. 0x312e6 ( 0x17b2 bytes)
+[INFO  fsx]  6 truncate 0x367eb => 0x1fe74
+[INFO  fsx]  7 read     0x18bd9 .. 0x1930d (  0x735 bytes)
+[INFO  fsx]  8 mapread  0x19052 .. 0x1fe73 ( 0x6e22 bytes)
+[INFO  fsx]  9 mapread   0x1bd9 .. 0x11107 ( 0xf52f bytes)
+[INFO  fsx] 10 write    0x3e271 .. 0x3ffff ( 0x1d8f bytes)
+"
+)]
 fn stability(#[case] conf: &str, #[case] args: &str, #[case] stderr: &str) {
     let mut cf = NamedTempFile::new().unwrap();
     cf.write_all(conf.as_bytes()).unwrap();
@@ -303,6 +373,61 @@ fn stability(#[case] conf: &str, #[case] args: &str, #[case] stderr: &str) {
     assert_eq!(stderr, actual_stderr);
 }
 
+/// rng = "legacy" should draw operations from a copy of glibc's random(3), the
+/// way the C-based fsx's test loop maps them.  The expected output was computed
+/// from that loop, using glibc's random(3).
+#[cfg(target_os = "linux")]
+#[test]
+fn legacy_rng() {
+    let mut cf = NamedTempFile::new().unwrap();
+    cf.write_all(b"rng = \"legacy\"").unwrap();
+
+    let tf = NamedTempFile::new().unwrap();
+
+    Command::cargo_bin("fsx")
+        .unwrap()
+        .args(["-vv", "-N12", "-S2", "-f"])
+        .arg(cf.path())
+        .arg(tf.path())
+        .assert()
+        .success()
+        .stderr(
+            "[DEBUG fsx] Using seed 2
+[INFO  fsx]  1 truncate     0x0 => 0x25022
+[INFO  fsx]  2 mapwrite 0x16f16 .. 0x23c86 ( 0xcd71 bytes)
+[INFO  fsx]  3 mapwrite 0x133f2 .. 0x16618 ( 0x3227 bytes)
+[INFO  fsx]  4 mapwrite  0xe8cc .. 0x1b615 ( 0xcd4a bytes)
+[INFO  fsx]  5 truncate 0x25022 =>  0xaaa2
+[INFO  fsx]  6 write     0x5540 ..  0xf726 ( 0xa1e7 bytes)
+[INFO  fsx]  7 truncate  0xf727 => 0x14519
+[INFO  fsx]  8 read      0x8721 .. 0x10966 ( 0x8246 bytes)
+[INFO  fsx]  9 mapwrite 0x173eb .. 0x1dc8b ( 0x68a1 bytes)
+[INFO  fsx] 10 mapwrite 0x2d742 .. 0x2da63 (  0x322 bytes)
+[INFO  fsx] 11 read     0x12869 .. 0x1e9fb ( 0xc193 bytes)
+[INFO  fsx] 12 mapwrite 0x23b64 .. 0x2dbaa ( 0xa047 bytes)
+",
+        );
+}
+
+/// rng = "legacy" can't choose operations that the C-based fsx lacks
+#[test]
+fn legacy_rng_unsupported_op() {
+    let mut cf = NamedTempFile::new().unwrap();
+    cf.write_all(b"rng = \"legacy\"\n[weights]\nfsync = 1.0")
+        .unwrap();
+
+    let tf = NamedTempFile::new().unwrap();
+
+    Command::cargo_bin("fsx")
+        .unwrap()
+        .args(["-N1", "-f"])
+        .arg(cf.path())
+        .arg(tf.path())
+        .assert()
+        .failure()
+        .stderr("error: cannot use fsync with rng = \"legacy\"\n");
+}
+
 #[cfg_attr(not(target_os = "freebsd"), allow(unused))]
 #[rstest]
 fn miscompare() {
@@ -320,8 +445,8 @@ fn miscompare() {
             .unwrap()
             .into_string()
             .unwrap();
-        assert_eq!(
-            "[DEBUG fsx] Using seed 10
+        // The rest of the report is checked piece by piece below
+        let expected = "[DEBUG fsx] Using seed 10
 [DEBUG fsx]  1 skipping zero size read
 [INFO  fsx]  2 truncate     0x0 => 0x19efd
 [INFO  fsx]  4 truncate 0x19efd => 0x1cb67
@@ -330,15 +455,47 @@ fn miscompare() {
 [ERROR fsx] OFFSET  GOOD  BAD  RANGE  
 [ERROR fsx]  0xe279 0xd1 0x00  0x26a9
 [ERROR fsx] Step# for the bad data is unknown; check HOLE and EXTEND ops
-[ERROR fsx] Using seed 10
+[ERROR fsx] Using seed 10 and RNG xorshift
 [ERROR fsx] LOG DUMP
 [ERROR fsx]  1 SKIPPED  (read)
-[ERROR fsx]  2 TRUNCATE  UP   from     0x0 to 0x19efd
-[ERROR fsx]  3 WRITE     0xda28 => 0x14205 ( 0x67dd bytes)
+[ERROR fsx]  2 TRUNCATE  UP   from     0x0 to 0x19efd\t******WWWW
+[ERROR fsx]  3 WRITE     0xda28 => 0x14205 ( 0x67dd bytes)\t******WWWW
 [ERROR fsx]  4 TRUNCATE  UP   from 0x19efd to 0x1cb67
-[ERROR fsx]  5 MAPREAD   0xe279 => 0x10932 ( 0x26b9 bytes)
-",
-            actual_stderr
+[ERROR fsx]  5 MAPREAD   0xe279 => 0x10932 ( 0x26b9 bytes)\t***RRRR***
+[ERROR fsx] Steps that overlap the bad range 0xe279 .. 0x10931: 2, 3, 5
+";
+        assert_eq!(
+            expected,
+            &actual_stderr[..expected.len().min(actual_stderr.len())]
+        );
+        // How the file system lays out the file varies
+        assert!(
+            actual_stderr.contains("\n[ERROR fsx] FILE LAYOUT\n"),
+            "{actual_stderr}"
+        );
+        assert!(actual_stderr.contains("\t***BAD***\n"), "{actual_stderr}");
+        // So do the kernel, mount, and statvfs details
+        assert!(
+            actual_stderr.contains("\n[ERROR fsx] FILE SYSTEM\n"),
+            "{actual_stderr}"
+        );
+        assert!(
+            actual_stderr.contains("\n[ERROR fsx] kernel: "),
+            "{actual_stderr}"
+        );
+        let resume = format!(
+            "\n[ERROR fsx] Resume from step 5 with: --rng-state {}.fsxstate\n",
+            tf.path().display()
+        );
+        assert!(actual_stderr.contains(&resume), "{actual_stderr}");
+        let repro = format!(
+            " -S 10 --inject 3 -N 5 -f {0}.fsxconfig {0}\n",
+            tf.path().display()
+        );
+        assert!(
+            actual_stderr.ends_with(&repro)
+                && actual_stderr.contains("\n[ERROR fsx] Reproduce with: "),
+            "{actual_stderr}"
         );
     }
     // There should be a .fsxgood artifact
@@ -352,596 +509,4348 @@ fn miscompare() {
     fs::remove_file(&fsxgoodfname).unwrap();
 }
 
+/// With verify_interval, corruption is detected by the next full verification
+/// rather than whenever a read happens to touch it.
 #[test]
-fn artifacts_dir() {
+fn verify_interval() {
+    let mut cf = NamedTempFile::new().unwrap();
+    cf.write_all(b"verify_interval = 1").unwrap();
+
     let tf = NamedTempFile::new().unwrap();
-    let artifacts_dir = TempDir::new().unwrap();
 
-    Command::cargo_bin("fsx")
+    let cmd = Command::cargo_bin("fsx")
         .unwrap()
-        .args(["-vv", "-N2", "-S11", "--inject", "1", "-P"])
-        .arg(artifacts_dir.path())
+        .args(["-vv", "-N10", "-S10", "--inject", "3", "-f"])
+        .arg(cf.path())
         .arg(tf.path())
         .assert()
         .failure();
-    // Don't bother checking stderr; we cover that in the miscompare test.  Here
-    // we're just checking the location of the artifacts.
+    let actual_stderr = CString::new(cmd.get_output().stderr.clone())
+        .unwrap()
+        .into_string()
+        .unwrap();
+    assert!(actual_stderr.contains(
+        "[DEBUG fsx]  3 verifying entire file
+[ERROR fsx] miscompare: offset= 0x0, size = 0x19efd
+"
+    ));
+    assert!(!actual_stderr.contains("[INFO  fsx]  4"));
 
-    // Check the location of the .fsxgood artifact
-    let mut fsxgoodfname = artifacts_dir.path().to_owned();
-    let mut final_component = tf.path().file_name().unwrap().to_owned();
+    let mut fsxgoodfname = tf.path().to_owned();
+    let mut final_component = fsxgoodfname.file_name().unwrap().to_owned();
     final_component.push(".fsxgood");
-    fsxgoodfname.push(final_component);
-    assert_eq!(fs::metadata(&fsxgoodfname).unwrap().len(), 262144);
-
-    // finally, clean it up.
+    fsxgoodfname.set_file_name(final_component);
     fs::remove_file(&fsxgoodfname).unwrap();
 }
 
-// https://github.com/asomers/fsx-rs/issues/20
-#[test]
-fn blockmode_zero() {
+/// With verify_after_write, data is read back as soon as it's written
+#[rstest]
+#[case::same("same")]
+#[case::opposite("opposite")]
+fn verify_after_write(#[case] mode: &str) {
     let mut cf = NamedTempFile::new().unwrap();
-    cf.write_all(
-        b"blockmode = true
-[weights]
-truncate = 0",
-    )
-    .unwrap();
+    let conf = format!("verify_after_write = \"{mode}\"");
+    cf.write_all(conf.as_bytes()).unwrap();
 
     let tf = NamedTempFile::new().unwrap();
-    let artifacts_dir = TempDir::new().unwrap();
 
-    let cmd = Command::cargo_bin("fsx")
+    Command::cargo_bin("fsx")
         .unwrap()
-        .args(["-N2", "-S72", "-P"])
-        .arg(artifacts_dir.path())
+        .args(["-N100", "-S20", "-f"])
+        .arg(cf.path())
         .arg(tf.path())
+        .assert()
+        .success();
+
+    // Losing step 4's write should be caught right away, not at step 7's read
+    let dir = TempDir::new().unwrap();
+    let cmd = Command::cargo_bin("fsx")
+        .unwrap()
+        .args(["-vv", "-N10", "-S20", "--inject", "4", "-P"])
+        .arg(dir.path())
         .arg("-f")
         .arg(cf.path())
+        .arg(tf.path())
         .assert()
         .failure();
-
     let actual_stderr = CString::new(cmd.get_output().stderr.clone())
         .unwrap()
         .into_string()
         .unwrap();
-    assert_eq!(
-        actual_stderr,
-        "[ERROR fsx] ERROR: file length must be greater than zero
-"
-    );
+    assert!(actual_stderr
+        .contains("[ERROR fsx] miscompare: offset= 0x6798, size = 0x63aa\n"));
+    assert!(!actual_stderr.contains("[INFO  fsx]  5"));
 }
 
-/// flen is optional with blockmode, but can be used to limit RAM consumption
+/// With direct_read_verify, every read is repeated with O_DIRECT
 #[test]
-fn blockmode_flen() {
+#[cfg_attr(
+    not(any(
+        target_os = "android",
+        target_os = "dragonfly",
+        target_os = "freebsd",
+        target_os = "linux",
+        target_os = "netbsd"
+    )),
+    ignore
+)]
+fn direct_read_verify() {
     let mut cf = NamedTempFile::new().unwrap();
-    cf.write_all(
-        b"blockmode = true
-flen = 131072
-[weights]
-truncate = 0",
-    )
-    .unwrap();
+    cf.write_all(b"direct_read_verify = true").unwrap();
 
-    let mut tf = NamedTempFile::new().unwrap();
-    tf.as_file_mut().set_len(1 << 40).unwrap(); // 1 TiB
-    let artifacts_dir = TempDir::new().unwrap();
+    let tf = NamedTempFile::new().unwrap();
+
+    let mut cmd = Command::cargo_bin("fsx").unwrap();
+    cmd.args(["-N100", "-S20", "-f"])
+        .arg(cf.path())
+        .arg(tf.path());
+    if let Err(e) = cmd.ok() {
+        let actual_stderr = CString::new(e.as_output().unwrap().stderr.clone())
+            .unwrap()
+            .into_string()
+            .unwrap();
+        if actual_stderr.contains("Test file system does not support O_DIRECT")
+        {
+            eprintln!("Skipped(O_DIRECT unsupported)");
+        } else {
+            panic!("{e}");
+        }
+    }
+}
+
+/// With timecheck, every modification must advance mtime and ctime
+#[test]
+fn timecheck() {
+    let mut cf = NamedTempFile::new().unwrap();
+    cf.write_all(b"timecheck = true").unwrap();
+
+    let tf = NamedTempFile::new().unwrap();
 
     Command::cargo_bin("fsx")
         .unwrap()
-        .args(["-N1", "-S72", "-P"])
-        .arg(artifacts_dir.path())
-        .arg(tf.path())
-        .arg("-f")
+        .args(["-N100", "-S20", "-f"])
         .arg(cf.path())
+        .arg(tf.path())
         .assert()
         .success();
-    // Don't bother checking stderr.  If the flen option isn't handled
-    // correctly, fsx will either report failure or else consume 1 TiB of RAM.
 }
 
-/// Checks that the weights are assigned in the correct order, for operations
-/// that must read.
-#[rstest]
-#[case::read(
-    "[weights]\nread = 1000000",
-    "[DEBUG fsx] Using seed 200
-[INFO  fsx] 1 read        0x0 .. 0x1fff ( 0x2000 bytes)
-"
-)]
-#[case::mapread(
-    "[weights]\nmapread = 1000000",
-    "[DEBUG fsx] Using seed 200
-[INFO  fsx] 1 mapread     0x0 .. 0x1fff ( 0x2000 bytes)
-"
-)]
-#[case::invalidate(
-    "[weights]\ninvalidate = 1000000",
-    "[DEBUG fsx] Using seed 200
-[INFO  fsx] 1 msync(MS_INVALIDATE)
-"
-)]
-#[cfg_attr(
-    not(any(
-        target_os = "android",
-        target_os = "dragonfly",
-        target_os = "freebsd",
-        target_os = "ios",
-        target_os = "linux",
-        target_os = "macos",
-    )),
-    ignore
-)]
-#[case::sendfile(
-    "[weights]\nsendfile = 1000000",
-    "[DEBUG fsx] Using seed 200
-[INFO  fsx] 1 sendfile    0x0 .. 0x1fff ( 0x2000 bytes)
-"
-)]
-#[cfg_attr(
-    not(any(
-        target_os = "linux",
-        target_os = "android",
-        target_os = "freebsd"
-    )),
-    ignore
-)]
-#[case::posix_fadvise(
-    "[weights]\nposix_fadvise = 1000000",
-    "[DEBUG fsx] Using seed 200
-[INFO  fsx] 1 posix_fadvise(WillNeed  )    0x0 .. 0x1fff ( 0x2000 bytes)
-"
-)]
-#[cfg_attr(not(any(target_os = "linux", target_os = "freebsd")), ignore)]
-#[case::copy_file_range(
-    "[weights]\ncopy_file_range = 1000000",
-    "[DEBUG fsx] Using seed 200
-[INFO  fsx] 1 copy_file_range [   0x0: 0xfff] => [0x1000:0x1fff] ( 0x1000 \
-     bytes)
-"
-)]
-fn read_weights(#[case] wconf: &str, #[case] stderr: &str) {
+/// timecheck should notice a write that didn't update the timestamps
+#[test]
+fn timecheck_lost_write() {
+    // Age the file after every step, so a missing update can't hide within
+    // the timestamp granularity.
     let mut cf = NamedTempFile::new().unwrap();
-    let conf = format!(
-        "blockmode=true\n[opsize]\nalign=4096\nmin=4096\n{wconf}\ntruncate = \
-         0.0"
-    );
-    cf.write_all(conf.as_bytes()).unwrap();
+    cf.write_all(
+        b"timecheck = true
+check_hook = \"touch -d 2000-01-01 {path}\"
+check_hook_interval = 1",
+    )
+    .unwrap();
 
-    let mut tf = NamedTempFile::new().unwrap();
-    tf.as_file_mut().set_len(8192).unwrap();
+    let dir = TempDir::new().unwrap();
+    let fname = dir.path().join("fsx.bin");
+
+    Command::cargo_bin("fsx")
+        .unwrap()
+        .args(["-N10", "-S20", "-f"])
+        .arg(cf.path())
+        .arg(&fname)
+        .assert()
+        .success();
 
+    // Seed 20's step 4 is a write
     let cmd = Command::cargo_bin("fsx")
         .unwrap()
-        .args(["-vv", "-S", "200", "-N", "1", "-P", "/tmp"])
+        .args(["-N10", "-S20", "--inject", "4", "-P"])
+        .arg(dir.path())
         .arg("-f")
         .arg(cf.path())
-        .arg(tf.path())
+        .arg(&fname)
         .assert()
-        .success();
+        .failure();
     let actual_stderr = CString::new(cmd.get_output().stderr.clone())
         .unwrap()
         .into_string()
         .unwrap();
-    assert_eq!(stderr, actual_stderr);
+    assert!(actual_stderr.contains("[ERROR fsx] mtime did not advance"));
 }
 
-/// Checks that the weights are assigned in the correct order
-#[rstest]
-#[case::close_open(
-    "[weights]\nclose_open = 1000000",
-    "[DEBUG fsx] Using seed 200
-[INFO  fsx] 1 close/open
-"
-)]
-#[case::write(
-    "[weights]\nwrite = 1000000",
-    "[DEBUG fsx] Using seed 200
-[INFO  fsx] 1 write    0x18004 .. 0x1a03a ( 0x2037 bytes)
-"
-)]
-#[case::mapwrite(
-    "[weights]\nmapwrite = 1000000",
-    "[DEBUG fsx] Using seed 200
-[INFO  fsx] 1 mapwrite 0x18004 .. 0x1a03a ( 0x2037 bytes)
-"
-)]
-#[case::truncate(
-    "[weights]\ntruncate = 1000000",
-    "[DEBUG fsx] Using seed 200
-[INFO  fsx] 1 truncate     0x0 => 0x11184
-"
-)]
-#[case::fsync(
-    "[weights]\nfsync = 1000000",
-    "[DEBUG fsx] Using seed 200
-[INFO  fsx] 1 fsync
-"
-)]
-#[case::fdatasync(
-    "[weights]\nfdatasync = 1000000",
-    "[DEBUG fsx] Using seed 200
-[INFO  fsx] 1 fdatasync
-"
-)]
-fn weights(#[case] wconf: &str, #[case] stderr: &str) {
-    let mut cf = NamedTempFile::new().unwrap();
-    cf.write_all(wconf.as_bytes()).unwrap();
-
-    let tf = NamedTempFile::new().unwrap();
+/// After truncating down, the rest of the EoF page must read as zeros.  A lost
+/// truncate leaves the old data there.
+#[test]
+fn truncate_eofpage() {
+    let dir = TempDir::new().unwrap();
+    let fname = dir.path().join("fsx.bin");
 
+    // Seed 20's step 25 truncates from 0x248ea to 0x1e64f
     let cmd = Command::cargo_bin("fsx")
         .unwrap()
-        .args(["-vv", "-S", "200", "-N", "1"])
-        .arg("-f")
-        .arg(cf.path())
-        .arg(tf.path())
+        .args(["-N30", "-S20", "--inject", "25", "-P"])
+        .arg(dir.path())
+        .arg(&fname)
         .assert()
-        .success();
+        .failure();
     let actual_stderr = CString::new(cmd.get_output().stderr.clone())
         .unwrap()
         .into_string()
         .unwrap();
-    assert_eq!(stderr, actual_stderr);
+    assert!(actual_stderr.starts_with(
+        "[ERROR fsx] Mapped non-zero data past EoF (0x1e64e) page offset \
+         0x64f is 0x17\n"
+    ));
 }
 
+/// Reads straddling EOF must return exactly the bytes before EOF
 #[test]
-#[cfg_attr(
-    not(any(
-        target_os = "android",
-        target_os = "dragonfly",
-        target_os = "emscripten",
-        target_os = "freebsd",
-        target_os = "fuchsia",
-        target_os = "linux"
-    )),
-    ignore
-)]
-fn posix_fallocate() {
+fn read_eof() {
     let mut cf = NamedTempFile::new().unwrap();
-    cf.write_all(b"[weights]\nposix_fallocate=1000000").unwrap();
+    cf.write_all(b"[weights]\nread_eof = 10").unwrap();
 
     let tf = NamedTempFile::new().unwrap();
 
-    let mut cmd = Command::cargo_bin("fsx").unwrap();
-    cmd.args(["-vv", "-S", "200", "-N", "1"])
-        .arg("-f")
+    Command::cargo_bin("fsx")
+        .unwrap()
+        .args(["-N1000", "-S3", "-f"])
         .arg(cf.path())
-        .arg(tf.path());
-    let result = cmd.ok();
-    match result {
-        Ok(r) => {
-            // fsx passed.  Now check its output
-            let actual_stderr =
-                CString::new(r.stderr).unwrap().into_string().unwrap();
-            let expected = "[DEBUG fsx] Using seed 200
-[INFO  fsx] 1 posix_fallocate 0x18004 .. 0x1a03a ( 0x2037 bytes)
-";
-            assert_eq!(expected, actual_stderr);
-        }
-        Err(e) => {
-            let actual_stderr =
-                CString::new(e.as_output().unwrap().stderr.clone())
-                    .unwrap()
-                    .into_string()
-                    .unwrap();
-            if actual_stderr
-                .contains("Test file system does not support posix_fallocate.")
-            {
-                // XXX It would be nice if we could report a "skipped" status
-                eprintln!("Skipped(posix_fallocate unsupported)");
-            } else {
-                panic!("{e}");
-            }
-        }
-    }
-}
+        .arg(tf.path())
+        .assert()
+        .success();
 
-/// Exercise all advice types.
-#[test]
-#[cfg_attr(
-    not(any(
-        target_os = "linux",
-        target_os = "android",
-        target_os = "freebsd"
-    )),
-    ignore
-)]
-fn posix_fadvise() {
+    // Without size checks, only read_eof will notice that the file is
+    // shorter than expected, because step 6's extending mapwrite was lost.
     let mut cf = NamedTempFile::new().unwrap();
-    cf.write_all(b"[weights]\nposix_fadvise=1000000").unwrap();
-
-    let tf = NamedTempFile::new().unwrap();
-
-    let mut cmd = Command::cargo_bin("fsx").unwrap();
-    cmd.args(["-vv", "-N", "6", "-S", "12318153001044186923"])
+    cf.write_all(b"nosizechecks = true\n[weights]\nread_eof = 10")
+        .unwrap();
+    let dir = TempDir::new().unwrap();
+    let cmd = Command::cargo_bin("fsx")
+        .unwrap()
+        .args(["-vv", "-N12", "-S3", "--inject", "6", "-P"])
+        .arg(dir.path())
         .arg("-f")
         .arg(cf.path())
-        .arg(tf.path());
-    let r = cmd.ok().unwrap();
-    let actual_stderr = CString::new(r.stderr).unwrap().into_string().unwrap();
-    let expected = "[DEBUG fsx] Using seed 12318153001044186923
-[INFO  fsx] 1 posix_fadvise(Sequential)     0x0 ..     0x0 (    0x0 bytes)
-[INFO  fsx] 2 posix_fadvise(NoReuse   )     0x0 ..     0x0 (    0x0 bytes)
-[INFO  fsx] 3 posix_fadvise(Random    )     0x0 ..     0x0 (    0x0 bytes)
-[INFO  fsx] 4 posix_fadvise(WillNeed  )     0x0 ..     0x0 (    0x0 bytes)
-[INFO  fsx] 5 posix_fadvise(DontNeed  )     0x0 ..     0x0 (    0x0 bytes)
-[INFO  fsx] 6 posix_fadvise(Normal    )     0x0 ..     0x0 (    0x0 bytes)
-";
-    assert_eq!(expected, actual_stderr);
+        .arg(tf.path())
+        .assert()
+        .failure();
+    let actual_stderr = CString::new(cmd.get_output().stderr.clone())
+        .unwrap()
+        .into_string()
+        .unwrap();
+    assert!(actual_stderr.contains(
+        "[INFO  fsx]  2 read_eof 0x1befd .. 0x25469 ( 0x956d bytes) EOF at \
+         0x1d431\n"
+    ));
+    assert!(actual_stderr.contains(
+        "[INFO  fsx]  7 read_eof 0x27fbc .. 0x33dec ( 0xbe31 bytes) EOF at \
+         0x2d3de
+[ERROR fsx] read at EOF returned 0x0 bytes instead of 0x5422\n"
+    ));
 }
 
-#[cfg_attr(
-    not(any(
-        have_fspacectl,
-        target_os = "android",
-        target_os = "emscripten",
-        target_os = "fuchsia",
-        target_os = "linux"
-    )),
-    ignore
-)]
+/// Writes and truncates beyond RLIMIT_FSIZE must fail with EFBIG
 #[test]
-fn punch_hole() {
+fn fsize_limit() {
     let mut cf = NamedTempFile::new().unwrap();
-    cf.write_all(
-        b"[weights]\npunch_hole=10\nmapread=0\nmapwrite=0\ntruncate=0",
-    )
-    .unwrap();
+    cf.write_all(b"[weights]\nfsize_limit = 10").unwrap();
 
     let tf = NamedTempFile::new().unwrap();
 
-    let cmd = Command::cargo_bin("fsx")
+    Command::cargo_bin("fsx")
         .unwrap()
-        .args(["-vv", "-S", "301", "-N", "10", "-P", "/tmp", "-f"])
+        .args(["-N1000", "-S3", "-f"])
         .arg(cf.path())
         .arg(tf.path())
         .assert()
         .success();
-    let actual_stderr = CString::new(cmd.get_output().stderr.clone())
-        .unwrap()
-        .into_string()
-        .unwrap();
-    let expected: &str = "[DEBUG fsx] Using seed 301
-[INFO  fsx]  1 write    0x31a71 .. 0x32208 (  0x798 bytes)
-[INFO  fsx]  2 write    0x1b01b .. 0x2a456 ( 0xf43c bytes)
-[INFO  fsx]  3 read     0x2a547 .. 0x32208 ( 0x7cc2 bytes)
-[INFO  fsx]  4 punch_hole  0xe1a3 .. 0x1a7bb ( 0xc619 bytes)
-[INFO  fsx]  5 read      0x2df9 .. 0x11e7c ( 0xf084 bytes)
-[INFO  fsx]  6 punch_hole 0x2794a .. 0x32208 ( 0xa8bf bytes)
-[INFO  fsx]  7 write    0x2a1af .. 0x2a72c (  0x57e bytes)
-[INFO  fsx]  8 read     0x1eba8 .. 0x21a49 ( 0x2ea2 bytes)
-[INFO  fsx]  9 read     0x2298f .. 0x2bf0a ( 0x957c bytes)
-[INFO  fsx] 10 punch_hole  0x6e88 ..  0xac44 ( 0x3dbd bytes)
-";
-    assert_eq!(expected, actual_stderr);
 }
 
-/// During punch hole, monitor affected byte ranges
-#[cfg_attr(
-    not(any(
-        have_fspacectl,
-        target_os = "android",
-        target_os = "emscripten",
-        target_os = "fuchsia",
-        target_os = "linux"
-    )),
-    ignore
-)]
+/// fsize_limit must not try to raise RLIMIT_FSIZE above the hard limit
 #[test]
-fn punch_hole_monitor() {
+fn fsize_limit_hard() {
+    use std::os::unix::process::CommandExt;
+
+    use nix::sys::resource::{setrlimit, Resource};
+
     let mut cf = NamedTempFile::new().unwrap();
     cf.write_all(
-        b"[weights]\npunch_hole=3\nread=0\nwrite=0\nmapread=0\nmapwrite=0\ntruncate=1",
+        b"[weights]
+read = 0
+write = 0
+mapread = 0
+mapwrite = 0
+truncate = 0
+fsize_limit = 10",
     )
     .unwrap();
 
     let tf = NamedTempFile::new().unwrap();
 
-    let cmd = Command::cargo_bin("fsx")
-        .unwrap()
-        .args([
-            "-vv",
-            "-S",
-            "7482128686191834117",
-            "-N",
-            "4",
-            "-m",
-            "94428:94429",
-            "-P",
-            "/tmp",
-            "-f",
-        ])
+    let mut cmd = Command::cargo_bin("fsx").unwrap();
+    cmd.args(["-N1000", "-S3", "-f"])
         .arg(cf.path())
-        .arg(tf.path())
-        .assert()
-        .success();
-    let actual_stderr = CString::new(cmd.get_output().stderr.clone())
+        .arg(tf.path());
+    unsafe {
+        cmd.pre_exec(|| {
+            setrlimit(Resource::RLIMIT_FSIZE, 0x20000, 0x20000)?;
+            Ok(())
+        });
+    }
+    cmd.assert().success().stderr(
+        "[WARN  fsx] The hard RLIMIT_FSIZE (0x20000) is less than flen; \
+         fsize_limit will not set a limit above it\n",
+    );
+    assert!(tf.as_file().metadata().unwrap().len() <= 0x20000);
+}
+
+/// With enospc = "skip", fsx can run on a file system that's too small for
+/// the file.  mmap operations are disabled because tmpfs would raise SIGBUS.
+#[cfg(target_os = "linux")]
+#[rstest]
+#[case::enospc("enospc = \"skip\"")]
+#[case::errors("errors = { ENOSPC = \"skip\" }")]
+fn enospc_skip(#[case] policy: &str) {
+    let mut cf = NamedTempFile::new().unwrap();
+    let conf = format!(
+        "flen = 1048576
+{policy}
+[weights]
+mapread = 0
+mapwrite = 0
+truncate = 0
+posix_fallocate = 1
+copy_file_range = 1"
+    );
+    cf.write_all(conf.as_bytes()).unwrap();
+
+    let dir = TempDir::new().unwrap();
+    let output = Command::new("mount")
+        .args(["-t", "tmpfs", "-o", "size=128k", "tmpfs"])
+        .arg(dir.path())
+        .output()
+        .unwrap();
+    if !output.status.success() {
+        eprintln!(
+            "Skipping test: {}",
+            String::from_utf8_lossy(&output.stderr).trim_end()
+        );
+        return;
+    }
+
+    let output = Command::cargo_bin("fsx")
         .unwrap()
-        .into_string()
+        .args(["-N1000", "-S3", "-f"])
+        .arg(cf.path())
+        .arg(dir.path().join("fsx.bin"))
+        .output()
         .unwrap();
-    let expected: &str = "[DEBUG fsx] Using seed 7482128686191834117
-[INFO  fsx] 1 truncate     0x0 => 0x170de
-[WARN  fsx] 2 punch_hole 0x13847 .. 0x170dd ( 0x3897 bytes)
-[INFO  fsx] 3 punch_hole  0x9876 .. 0x16b4e ( 0xd2d9 bytes)
-[INFO  fsx] 4 punch_hole  0x99ea .. 0x15902 ( 0xbf19 bytes)
-";
-    assert_eq!(expected, actual_stderr);
+    Command::new("umount").arg(dir.path()).output().unwrap();
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(output.status.success(), "{stderr}");
+    assert!(stderr.contains("No space left on device"), "{stderr}");
 }
 
-/// Skip zero-length hole punches
-#[cfg_attr(
-    not(any(
-        have_fspacectl,
-        target_os = "android",
-        target_os = "emscripten",
-        target_os = "fuchsia",
-        target_os = "linux"
-    )),
-    ignore
-)]
+/// With signal_storm, every interrupted system call must be retried
 #[test]
-fn punch_hole_zero() {
+fn signal_storm() {
     let mut cf = NamedTempFile::new().unwrap();
-    cf.write_all(b"[weights]\npunch_hole=1000").unwrap();
+    cf.write_all(b"signal_storm = 100").unwrap();
 
     let tf = NamedTempFile::new().unwrap();
 
-    let cmd = Command::cargo_bin("fsx")
+    Command::cargo_bin("fsx")
         .unwrap()
-        .args(["-vv", "-S", "301", "-N", "1", "-f"])
+        .args(["-N2000", "-S3", "-f"])
         .arg(cf.path())
         .arg(tf.path())
         .assert()
         .success();
-    let actual_stderr = CString::new(cmd.get_output().stderr.clone())
-        .unwrap()
-        .into_string()
-        .unwrap();
-    let expected: &str = "[DEBUG fsx] Using seed 301
-[DEBUG fsx] 1 skipping zero size hole punch
-";
-    assert_eq!(expected, actual_stderr);
 }
 
-/// Tests that work on real device files
-mod blockdev {
-    use cfg_if::cfg_if;
-    use rstest::fixture;
+/// The oracle file should track every modification.  And when fsx's model is
+/// wrong, the oracle should say so.
+#[rstest]
+#[case::ok(None)]
+#[case::inject(Some("20"))]
+fn oracle(#[case] inject: Option<&str>) {
+    let mut cf = NamedTempFile::new().unwrap();
+    cf.write_all(b"verify_interval = 10\nnosizechecks = true")
+        .unwrap();
 
-    use super::*;
+    let tf = NamedTempFile::new().unwrap();
+    let oracle = NamedTempFile::new().unwrap();
+    let artifacts_dir = TempDir::new().unwrap();
 
-    cfg_if! {
-        if #[cfg(target_os = "freebsd")] {
-            use mdconfig::Md;
+    let mut cmd = Command::cargo_bin("fsx").unwrap();
+    cmd.args(["-N100", "-S1", "-f"])
+        .arg(cf.path())
+        .arg("--oracle")
+        .arg(oracle.path())
+        .arg("-P")
+        .arg(artifacts_dir.path())
+        .arg(tf.path());
+    if let Some(step) = inject {
+        let output = cmd.args(["--inject", step]).output().unwrap();
+        assert!(!output.status.success());
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert!(
+            stderr.contains(
+                "file agrees with the oracle file, but not with fsx's own \
+                 model"
+            ),
+            "{stderr}"
+        );
+    } else {
+        cmd.assert().success();
+    }
+}
 
-            #[fixture]
-            fn md() -> Option<Md> {
-                mdconfig::Builder::swap(1 << 20).create().ok()
-            }
-        } else if #[cfg(target_os = "netbsd")] {
-            use std::{ffi::OsStr, os::unix::ffi::OsStrExt, path::{Path, PathBuf}};
+/// Several threads exercise disjoint regions of one file.  An error in any
+/// one of them should be detected.
+#[rstest]
+#[case::ok(None)]
+#[case::inject(Some("100"))]
+fn threads(#[case] inject: Option<&str>) {
+    let mut cf = NamedTempFile::new().unwrap();
+    cf.write_all(
+        b"threads = 4
+verify_interval = 25
+[weights]
+truncate = 0
+punch_hole = 1
+fsync = 1",
+    )
+    .unwrap();
 
-            struct Md(PathBuf);
+    let tf = NamedTempFile::new().unwrap();
+    let artifacts_dir = TempDir::new().unwrap();
 
-            impl Md {
-                fn path(&self) -> &Path {
-                    self.0.as_path()
-                }
-            }
-            impl Drop for Md {
-                fn drop(&mut self) {
-                    Command::new("mdconfig")
-                        .args(["-d", "-u"])
-                        .arg(&self.0)
-                        .output()
-                        .expect("failed to deallocate md(4) device");
-                }
-            }
+    let mut cmd = Command::cargo_bin("fsx").unwrap();
+    cmd.args(["-N500", "-S1", "-f"])
+        .arg(cf.path())
+        .arg("-P")
+        .arg(artifacts_dir.path())
+        .arg(tf.path());
+    if let Some(step) = inject {
+        let output = cmd.args(["--inject", step]).output().unwrap();
+        assert!(!output.status.success());
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert!(stderr.contains("miscompare"), "{stderr}");
+        assert_eq!(stderr.matches("LOG DUMP").count(), 1, "{stderr}");
+    } else {
+        cmd.assert().success();
+    }
+}
 
-            #[fixture]
-            fn md() -> Option<Md> {
-                let output = Command::new("mdconfig")
-                    .args(["-a", "-t", "swap", "-s", "1m"])
-                    .output()
-                    .expect("failed to allocate md(4) device");
-                if output.status.success() {
-                    // Strip the trailing "\n"
-                    let l = output.stdout.len().saturating_sub(1);
-                    let mddev = OsStr::from_bytes(&output.stdout[0..l]);
-                    Some(Md(Path::new("/dev").join(mddev)))
-                } else {
-                    let l = output.stderr.len().saturating_sub(1);
-                    eprintln!("Skipping test: {}",
-                              OsStr::from_bytes(&output.stderr[0..l])
-                              .to_string_lossy());
-                    None
-                }
-            }
-        } else if #[cfg(target_os = "linux")] {
-            use std::{ffi::OsStr, os::unix::ffi::OsStrExt, path::{Path, PathBuf}};
-            struct Md(PathBuf);
+/// Operations that change the file's size can't be used with threads
+#[test]
+fn threads_truncate() {
+    let mut cf = NamedTempFile::new().unwrap();
+    cf.write_all(b"threads = 2").unwrap();
 
-            impl Md {
-                fn path(&self) -> &Path {
-                    self.0.as_path()
-                }
-            }
-            impl Drop for Md {
-                fn drop(&mut self) {
-                    Command::new("losetup")
-                        .args(["-d"])
-                        .arg(&self.0)
-                        .output()
-                        .expect("failed to deallocate loop device");
-                }
-            }
+    let tf = NamedTempFile::new().unwrap();
 
-            #[fixture]
-            fn md() -> Option<Md> {
-                let tf = NamedTempFile::new().unwrap();
-                tf.as_file().set_len(1048576).unwrap();
-                let output = Command::new("/sbin/losetup")
-                    .args(["-f", "--show"])
-                    .arg(tf.path())
-                    .output()
-                    .expect("failed to allocate loop device");
-                if output.status.success() {
-                    // Strip the trailing "\n"
-                    let l = output.stdout.len() - 1;
-                    let mddev = OsStr::from_bytes(&output.stdout[0..l]);
-                    Some(Md(mddev.into()))
-                } else {
-                    let l = output.stderr.len().saturating_sub(1);
-                    eprintln!("Skipping test: {}",
-                              OsStr::from_bytes(&output.stderr[0..l])
-                              .to_string_lossy());
-                    None
-                }
-            }
-        } else {
-            struct Md(PathBuf);
+    Command::cargo_bin("fsx")
+        .unwrap()
+        .args(["-N1", "-f"])
+        .arg(cf.path())
+        .arg(tf.path())
+        .assert()
+        .failure()
+        .stderr("error: cannot use truncate with threads\n");
+}
 
-            #[fixture]
-            fn md() -> Option<Md> {
-                unimplemented!()
-            }
+/// Verifier threads should detect corruption, even if the main thread never
+/// reads it back.
+#[rstest]
+#[case::ok(None)]
+#[case::inject(Some("85"))]
+fn verify_threads(#[case] inject: Option<&str>) {
+    let mut cf = NamedTempFile::new().unwrap();
+    cf.write_all(
+        b"verify_threads = 2
+[weights]
+read = 0
+mapread = 0
+mapwrite = 0
+truncate = 0
+write = 1
+fsync = 50",
+    )
+    .unwrap();
+
+    let tf = NamedTempFile::new().unwrap();
+    let artifacts_dir = TempDir::new().unwrap();
+
+    let mut cmd = Command::cargo_bin("fsx").unwrap();
+    // With this seed, step 85 overwrites part of step 5's write.
+    cmd.args(["-N1000", "-S6", "-f"])
+        .arg(cf.path())
+        .arg("-P")
+        .arg(artifacts_dir.path())
+        .arg(tf.path());
+    if let Some(step) = inject {
+        let output = cmd.args(["--inject", step]).output().unwrap();
+        assert!(!output.status.success());
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert!(stderr.contains("verifier: miscompare"), "{stderr}");
+    } else {
+        cmd.assert().success();
+    }
+}
+
+/// Run several jobs, each on its own file with its own artifacts directory
+#[rstest]
+#[case::ok(None)]
+#[case::inject(Some("30"))]
+fn jobs(#[case] inject: Option<&str>) {
+    let dir = TempDir::new().unwrap();
+    let fname = dir.path().join("f");
+
+    let mut cmd = Command::cargo_bin("fsx").unwrap();
+    cmd.args(["-N100", "-S7", "-j3", "-P"])
+        .arg(dir.path())
+        .arg(&fname);
+    if let Some(step) = inject {
+        let output = cmd.args(["--inject", step]).output().unwrap();
+        assert!(!output.status.success());
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert!(stderr.contains("LOG DUMP"), "{stderr}");
+        assert!(stderr.contains("exited with status 1"), "{stderr}");
+    } else {
+        let output = cmd.output().unwrap();
+        assert!(output.status.success());
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        assert!(stdout.contains("All 3 jobs completed A-OK!"), "{stdout}");
+        for job in 0..3 {
+            assert!(dir.path().join(format!("f.{job}")).exists());
+            let log = dir.path().join(format!("job{job}/fsx.log"));
+            let log = fs::read_to_string(log).unwrap();
+            assert!(log.contains("All operations completed A-OK!"));
         }
     }
+}
 
-    /// When operating on a block device, fsx will automatically determine the
-    /// file size.
-    #[rstest]
-    fn flen_zero(md: Option<Md>) {
-        if md.is_none() {
-            return;
+/// Exercise several files at once, and verify every one at the end.  Step
+/// 300 is a write that no later step reads back.
+#[rstest]
+#[case::ok(None)]
+#[case::lost_write(Some("300"))]
+fn files(#[case] inject: Option<&str>) {
+    let mut cf = NamedTempFile::new().unwrap();
+    cf.write_all(b"files = 3").unwrap();
+    let dir = TempDir::new().unwrap();
+    let fname = dir.path().join("f");
+
+    let mut cmd = Command::cargo_bin("fsx").unwrap();
+    cmd.args(["-N300", "-S2", "-P"])
+        .arg(dir.path())
+        .arg("-f")
+        .arg(cf.path())
+        .arg(&fname);
+    if let Some(step) = inject {
+        cmd.args(["--inject", step]);
+        let output = cmd.output().unwrap();
+        assert!(!output.status.success());
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert!(stderr
+            .contains("Steps that overlap the bad range 0x1cfb1 .. 0x228e8: "));
+    } else {
+        cmd.assert().success();
+        for i in 0..3 {
+            let md = fs::metadata(dir.path().join(format!("f.{i}"))).unwrap();
+            assert!(md.len() > 0);
         }
-        let md = md.unwrap();
+    }
+}
 
-        let mut cf = NamedTempFile::new().unwrap();
-        cf.write_all(
-            b"blockmode = true
-[opsize]
-align = 4096
+/// Kill fsx partway through, and verify what survived.  Here, overwriting the
+/// beginning of the file stands in for a file system that loses synced data.
+#[rstest]
+#[case::ok("sync", None)]
+#[case::corrupt(
+    "printf garbage | dd of={path} conv=notrunc 2>/dev/null",
+    Some("Consistency error at offset 0x0")
+)]
+fn crash(#[case] hook: &str, #[case] err: Option<&str>) {
+    let mut cf = NamedTempFile::new().unwrap();
+    let conf =
+        format!("crash = true\ncrash_hook = \"{hook}\"\n[weights]\nfsync = 5");
+    cf.write_all(conf.as_bytes()).unwrap();
+
+    let tf = NamedTempFile::new().unwrap();
+    let artifacts_dir = TempDir::new().unwrap();
+
+    let output = Command::cargo_bin("fsx")
+        .unwrap()
+        .args(["-N1000", "-S1", "-f"])
+        .arg(cf.path())
+        .arg("-P")
+        .arg(artifacts_dir.path())
+        .arg(tf.path())
+        .output()
+        .unwrap();
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    if let Some(err) = err {
+        assert!(!output.status.success());
+        assert!(stderr.contains(err), "{stderr}");
+    } else {
+        assert!(output.status.success(), "{stderr}");
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        assert!(stdout.contains("verified A-OK!"), "{stdout}");
+    }
+}
+
+/// Periodically inject faults with dm-flakey.  A stub dmsetup stands in for
+/// the real thing, and corrupting the file during the remount stands in for a
+/// file system that loses synced data.
+#[cfg_attr(not(target_os = "linux"), ignore)]
+#[rstest]
+#[case::ok("sync", None)]
+#[case::corrupt(
+    "printf garbage | dd of={path} conv=notrunc 2>/dev/null",
+    Some("Consistency error at offset 0x0")
+)]
+fn fault(#[case] hook: &str, #[case] err: Option<&str>) {
+    let bindir = TempDir::new().unwrap();
+    let dmsetup = bindir.path().join("dmsetup");
+    fs::write(
+        &dmsetup,
+        "#!/bin/sh\n[ \"$1\" = table ] && echo 0 2048 flakey 7:0 0 180 \
+         0\nexit 0\n",
+    )
+    .unwrap();
+    Command::new("chmod")
+        .arg("+x")
+        .arg(&dmsetup)
+        .status()
+        .unwrap();
+    let path = format!(
+        "{}:{}",
+        bindir.path().display(),
+        std::env::var("PATH").unwrap()
+    );
+
+    let mut cf = NamedTempFile::new().unwrap();
+    let conf = format!(
+        "remount_hook = \"{hook}\"
+[fault]
+device = \"flakey0\"
+interval = 100
+duration = 20
 [weights]
-mapread = 0
-mapwrite = 0
-truncate = 0",
-        )
+mapwrite = 0"
+    );
+    cf.write_all(conf.as_bytes()).unwrap();
+
+    let tf = NamedTempFile::new().unwrap();
+    let artifacts_dir = TempDir::new().unwrap();
+
+    let output = Command::cargo_bin("fsx")
+        .unwrap()
+        .env("PATH", path)
+        .args(["-N500", "-S1", "-f"])
+        .arg(cf.path())
+        .arg("-P")
+        .arg(artifacts_dir.path())
+        .arg(tf.path())
+        .output()
         .unwrap();
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    if let Some(err) = err {
+        assert!(!output.status.success());
+        assert!(stderr.contains(err), "{stderr}");
+    } else {
+        assert!(output.status.success(), "{stderr}");
+    }
+}
 
-        let artifacts_dir = TempDir::new().unwrap();
+/// Verify periodic snapshots.  Here, a copy stands in for a snapshot.
+#[rstest]
+#[case::ok("", None)]
+#[case::truncated(
+    "; echo garbage >> {path}.snap1",
+    Some("snapshot 1 size error")
+)]
+#[case::corrupt(
+    "; printf garbage | dd of={path}.snap1 bs=1 seek=100 conv=notrunc",
+    Some("snapshot 1 miscompare: offset= 0x0, size = 0xde9d")
+)]
+fn snapshot(#[case] extra: &str, #[case] err: Option<&str>) {
+    let mut cf = NamedTempFile::new().unwrap();
+    let conf = format!(
+        "snapshot_interval = 20
+snapshot_command = \"cp {{path}} {{path}}.snap{{n}}{extra}\"
+snapshot_path = \"{{path}}.snap{{n}}\""
+    );
+    cf.write_all(conf.as_bytes()).unwrap();
 
-        Command::cargo_bin("fsx")
-            .unwrap()
-            .args(["-N10", "-P"])
-            .arg(artifacts_dir.path())
-            .arg("-f")
-            .arg(cf.path())
-            .arg(md.path())
-            .assert()
-            .success();
+    let dir = TempDir::new().unwrap();
+
+    let output = Command::cargo_bin("fsx")
+        .unwrap()
+        .args(["-N100", "-S1", "-f"])
+        .arg(cf.path())
+        // The path needs quoting in snapshot_command
+        .arg(dir.path().join("fsx test.bin"))
+        .output()
+        .unwrap();
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    if let Some(err) = err {
+        assert!(!output.status.success());
+        assert!(stderr.contains(err), "{stderr}");
+    } else {
+        assert!(output.status.success(), "{stderr}");
+    }
+}
+
+/// A failing check_hook fails the run
+#[rstest]
+#[case::ok("test -s {path}", None)]
+#[case::fail("exit 3", Some("check_hook failed with exit status: 3"))]
+fn check_hook(#[case] hook: &str, #[case] err: Option<&str>) {
+    let mut cf = NamedTempFile::new().unwrap();
+    let conf = format!("check_hook = \"{hook}\"\ncheck_hook_interval = 40");
+    cf.write_all(conf.as_bytes()).unwrap();
+
+    let tf = NamedTempFile::new().unwrap();
+    let artifacts_dir = TempDir::new().unwrap();
+
+    let output = Command::cargo_bin("fsx")
+        .unwrap()
+        .args(["-N100", "-S1", "-f"])
+        .arg(cf.path())
+        .arg("-P")
+        .arg(artifacts_dir.path())
+        .arg(tf.path())
+        .output()
+        .unwrap();
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    if let Some(err) = err {
+        assert!(!output.status.success());
+        assert!(stderr.contains(err), "{stderr}");
+    } else {
+        assert!(output.status.success(), "{stderr}");
     }
 }
+
+/// A SIGBUS while accessing a mapped file should produce the usual failure
+/// report, not a crash.
+#[test]
+fn map_fault() {
+    let mut cf = NamedTempFile::new().unwrap();
+    // Truncate the file behind fsx's back, so the next mapread faults.
+    cf.write_all(
+        b"check_hook = \"truncate -s 0 {path}\"
+check_hook_interval = 1
+[weights]
+close_open = 0
+read = 0
+write = 1
+mapread = 1
+mapwrite = 0
+truncate = 0
+invalidate = 0
+fsync = 0
+fdatasync = 0
+punch_hole = 0
+posix_fallocate = 0
+copy_file_range = 0
+sendfile = 0
+posix_fadvise = 0",
+    )
+    .unwrap();
+
+    let tf = NamedTempFile::new().unwrap();
+    let artifacts_dir = TempDir::new().unwrap();
+
+    let output = Command::cargo_bin("fsx")
+        .unwrap()
+        .args(["-N100", "-S1", "-f"])
+        .arg(cf.path())
+        .arg("-P")
+        .arg(artifacts_dir.path())
+        .arg(tf.path())
+        .output()
+        .unwrap();
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert_eq!(output.status.code(), Some(1), "{stderr}");
+    assert!(stderr.contains("SIGBUS during mapread"), "{stderr}");
+    assert!(stderr.contains("LOG DUMP"), "{stderr}");
+    let mut final_component = tf.path().file_name().unwrap().to_owned();
+    final_component.push(".fsxgood");
+    assert!(artifacts_dir.path().join(final_component).exists());
+}
+
+/// The watchdog should act on a run that gets stuck.  Here, check_hook blocks
+/// until the watchdog has fired.
+#[rstest]
+#[case::warn("warn")]
+#[case::dump("dump")]
+#[case::abort("abort")]
+fn watchdog(#[case] action: &str) {
+    use std::io::{BufRead, BufReader};
+
+    let dir = TempDir::new().unwrap();
+    let go = dir.path().join("go");
+    let mut cf = NamedTempFile::new().unwrap();
+    let conf = format!(
+        "check_hook = \"while [ ! -e {} ]; do sleep 0.01; done\"
+check_hook_interval = 5
+watchdog = 0.1
+watchdog_action = \"{action}\"",
+        go.display()
+    );
+    cf.write_all(conf.as_bytes()).unwrap();
+
+    let mut child = Command::cargo_bin("fsx")
+        .unwrap()
+        .args(["-N10", "-S1", "-f"])
+        .arg(cf.path())
+        .arg("-P")
+        .arg(dir.path())
+        .arg(dir.path().join("fsx.bin"))
+        .stdout(std::process::Stdio::piped())
+        .stderr(std::process::Stdio::piped())
+        .spawn()
+        .unwrap();
+    let mut stderr = String::new();
+    let mut lines = BufReader::new(child.stderr.take().unwrap()).lines();
+    for line in lines.by_ref() {
+        let line = line.unwrap();
+        stderr.push_str(&line);
+        stderr.push('\n');
+        if line.contains("watchdog: step 5 has not completed") {
+            break;
+        }
+    }
+    fs::File::create(&go).unwrap();
+    for line in lines {
+        stderr.push_str(&line.unwrap());
+        stderr.push('\n');
+    }
+    let output = child.wait_with_output().unwrap();
+
+    assert!(
+        stderr.contains("watchdog: step 5 has not completed"),
+        "{stderr}"
+    );
+    assert_eq!(output.status.success(), action != "abort", "{stderr}");
+    assert_eq!(stderr.contains("LOG DUMP"), action != "warn", "{stderr}");
+    assert_eq!(
+        dir.path().join("fsx.bin.fsxgood").exists(),
+        action == "abort"
+    );
+}
+
+/// If the main thread never responds, the watchdog should dump its own copy of
+/// the log before aborting.  Here, a pause stands in for a hung operation.
+#[test]
+fn watchdog_unresponsive() {
+    let dir = TempDir::new().unwrap();
+    let mut cf = NamedTempFile::new().unwrap();
+    cf.write_all(b"watchdog = 0.2\nwatchdog_action = \"abort\"\n")
+        .unwrap();
+
+    let child = Command::cargo_bin("fsx")
+        .unwrap()
+        .args(["-S1", "-f"])
+        .arg(cf.path())
+        .arg("-P")
+        .arg(dir.path())
+        .arg(dir.path().join("fsx.bin"))
+        .stdout(std::process::Stdio::piped())
+        .stderr(std::process::Stdio::piped())
+        .spawn()
+        .unwrap();
+    // Give fsx time to install its signal handler and log some operations
+    std::thread::sleep(std::time::Duration::from_millis(500));
+    Command::new("kill")
+        .args(["-USR2", &child.id().to_string()])
+        .assert()
+        .success();
+    let output = child.wait_with_output().unwrap();
+    let stderr = String::from_utf8_lossy(&output.stderr);
+
+    assert!(!output.status.success(), "{stderr}");
+    let (_, dump) = stderr
+        .split_once("no response from the main thread; aborting\n")
+        .expect(&stderr);
+    assert!(dump.starts_with("[ERROR fsx] LOG DUMP\n"), "{stderr}");
+    assert!(dump.lines().count() > 1, "{stderr}");
+}
+
+/// After remount_hook runs, the file must be intact.  Here, `sync` stands in
+/// for a real remount, and truncate for a file system that loses data.
+#[rstest]
+#[case::ok("sync", None)]
+#[case::lost("truncate -s 100 {path}", Some("Size error"))]
+fn remount_hook(#[case] hook: &str, #[case] err: Option<&str>) {
+    let mut cf = NamedTempFile::new().unwrap();
+    let conf = format!("remount_hook = \"{hook}\"\nremount_interval = 40");
+    cf.write_all(conf.as_bytes()).unwrap();
+
+    let tf = NamedTempFile::new().unwrap();
+    let artifacts_dir = TempDir::new().unwrap();
+
+    let output = Command::cargo_bin("fsx")
+        .unwrap()
+        .args(["-N100", "-S1", "-f"])
+        .arg(cf.path())
+        .arg("-P")
+        .arg(artifacts_dir.path())
+        .arg(tf.path())
+        .output()
+        .unwrap();
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    if let Some(err) = err {
+        assert!(!output.status.success());
+        assert!(stderr.contains(err), "{stderr}");
+    } else {
+        assert!(output.status.success(), "{stderr}");
+    }
+}
+
+#[test]
+fn artifacts_dir() {
+    let tf = NamedTempFile::new().unwrap();
+    let artifacts_dir = TempDir::new().unwrap();
+
+    Command::cargo_bin("fsx")
+        .unwrap()
+        .args(["-vv", "-N2", "-S11", "--inject", "1", "-P"])
+        .arg(artifacts_dir.path())
+        .arg(tf.path())
+        .assert()
+        .failure();
+    // Don't bother checking stderr; we cover that in the miscompare test.  Here
+    // we're just checking the location of the artifacts.
+
+    // Check the location of the .fsxgood artifact
+    let mut fsxgoodfname = artifacts_dir.path().to_owned();
+    let mut final_component = tf.path().file_name().unwrap().to_owned();
+    final_component.push(".fsxgood");
+    fsxgoodfname.push(final_component);
+    assert_eq!(fs::metadata(&fsxgoodfname).unwrap().len(), 262144);
+
+    // finally, clean it up.
+    fs::remove_file(&fsxgoodfname).unwrap();
+}
+
+/// A failed run should print a command that reproduces it, using a saved copy
+/// of the resolved config
+#[test]
+fn repro_command() {
+    let tf = NamedTempFile::new().unwrap();
+    let artifacts_dir = TempDir::new().unwrap();
+    let run = |args: &[&str]| {
+        let output = Command::cargo_bin("fsx")
+            .unwrap()
+            .args(args)
+            .output()
+            .unwrap();
+        assert!(!output.status.success());
+        String::from_utf8(output.stderr).unwrap()
+    };
+    let stderr = run(&[
+        "-N100",
+        "-S",
+        "repro",
+        "--inject",
+        "5",
+        "-P",
+        artifacts_dir.path().to_str().unwrap(),
+        tf.path().to_str().unwrap(),
+    ]);
+    let last = stderr.lines().last().unwrap();
+    let cmd = last.strip_prefix("[ERROR fsx] Reproduce with: ").unwrap();
+    let words = cmd.split(' ').collect::<Vec<_>>();
+    assert!(words.windows(2).any(|w| w == ["-S", "3443223652705517254"]));
+    assert!(words.windows(2).any(|w| w == ["--inject", "5"]));
+    let mut fsxconfig = artifacts_dir.path().to_owned();
+    let mut final_component = tf.path().file_name().unwrap().to_owned();
+    final_component.push(".fsxconfig");
+    fsxconfig.push(final_component);
+    assert!(fsxconfig.exists());
+
+    // Rerunning the printed command should fail the same way
+    let rerun = run(&words[1..]);
+    assert_eq!(rerun.lines().last().unwrap(), last);
+}
+
+// https://github.com/asomers/fsx-rs/issues/20
+#[test]
+fn blockmode_zero() {
+    let mut cf = NamedTempFile::new().unwrap();
+    cf.write_all(
+        b"blockmode = true
+[weights]
+truncate = 0",
+    )
+    .unwrap();
+
+    let tf = NamedTempFile::new().unwrap();
+    let artifacts_dir = TempDir::new().unwrap();
+
+    let cmd = Command::cargo_bin("fsx")
+        .unwrap()
+        .args(["-N2", "-S72", "-P"])
+        .arg(artifacts_dir.path())
+        .arg(tf.path())
+        .arg("-f")
+        .arg(cf.path())
+        .assert()
+        .failure();
+
+    let actual_stderr = CString::new(cmd.get_output().stderr.clone())
+        .unwrap()
+        .into_string()
+        .unwrap();
+    assert_eq!(
+        actual_stderr,
+        "[ERROR fsx] ERROR: file length must be greater than zero
+"
+    );
+}
+
+/// Features that keep whole copies of the file in RAM should refuse a huge flen
+#[rstest]
+#[case::crash("crash = true")]
+#[case::scratch_file("scratch_file = \"fsx.scratch\"")]
+fn whole_copy_flen(#[case] feature: &str) {
+    let dir = TempDir::new().unwrap();
+    let mut cf = NamedTempFile::new().unwrap();
+    cf.write_all(format!("flen = 2147483648\n{feature}\n").as_bytes())
+        .unwrap();
+    let name = feature.split_once(' ').unwrap().0;
+
+    Command::cargo_bin("fsx")
+        .unwrap()
+        .args(["-N2", "-f"])
+        .arg(cf.path())
+        .arg(dir.path().join("fsx.bin"))
+        .assert()
+        .code(2)
+        .stderr(format!(
+            "[ERROR fsx] ERROR: {name} keeps whole copies of the file in RAM, \
+             so flen must not exceed 0x40000000\n"
+        ));
+}
+
+/// flen is optional with blockmode, but can be used to limit RAM consumption
+#[test]
+fn blockmode_flen() {
+    let mut cf = NamedTempFile::new().unwrap();
+    cf.write_all(
+        b"blockmode = true
+flen = 131072
+[weights]
+truncate = 0",
+    )
+    .unwrap();
+
+    let mut tf = NamedTempFile::new().unwrap();
+    tf.as_file_mut().set_len(1 << 40).unwrap(); // 1 TiB
+    let artifacts_dir = TempDir::new().unwrap();
+
+    Command::cargo_bin("fsx")
+        .unwrap()
+        .args(["-N1", "-S72", "-P"])
+        .arg(artifacts_dir.path())
+        .arg(tf.path())
+        .arg("-f")
+        .arg(cf.path())
+        .assert()
+        .success();
+    // Don't bother checking stderr.  If the flen option isn't handled
+    // correctly, fsx will either report failure or else consume 1 TiB of RAM.
+}
+
+/// blockmode_offset and blockmode_length should confine all I/O to a window
+/// of the file
+#[test]
+fn blockmode_window() {
+    let mut cf = NamedTempFile::new().unwrap();
+    cf.write_all(
+        b"blockmode = true
+blockmode_offset = 65536
+blockmode_length = 131072
+[weights]
+truncate = 0",
+    )
+    .unwrap();
+
+    let mut tf = NamedTempFile::new().unwrap();
+    tf.write_all(&[0xa5u8; 1 << 20]).unwrap();
+    let artifacts_dir = TempDir::new().unwrap();
+
+    Command::cargo_bin("fsx")
+        .unwrap()
+        .args(["-N1000", "-S72", "-P"])
+        .arg(artifacts_dir.path())
+        .arg(tf.path())
+        .arg("-f")
+        .arg(cf.path())
+        .assert()
+        .success();
+    let data = fs::read(tf.path()).unwrap();
+    assert_eq!(data.len(), 1 << 20);
+    assert!(data[..65536].iter().all(|&b| b == 0xa5));
+    assert!(data[196608..].iter().all(|&b| b == 0xa5));
+    assert!(data[65536..196608].iter().any(|&b| b != 0xa5));
+}
+
+/// A non-numeric seed should be hashed to a numeric one, which is logged
+#[test]
+fn string_seed() {
+    let tf = NamedTempFile::new().unwrap();
+    let run = |seed: &str| {
+        let output = Command::cargo_bin("fsx")
+            .unwrap()
+            .args(["-v", "-N10", "-S", seed])
+            .arg(tf.path())
+            .output()
+            .unwrap();
+        assert!(output.status.success());
+        String::from_utf8(output.stderr).unwrap()
+    };
+    let named = run("nightly-zfs-2024-06-01");
+    let (first, ops) = named.split_once('\n').unwrap();
+    assert_eq!(
+        first,
+        "[INFO  fsx] Seed \"nightly-zfs-2024-06-01\" hashes to \
+         13664671075413004526"
+    );
+    assert_eq!(run("13664671075413004526"), ops);
+}
+
+/// Checks that the weights are assigned in the correct order, for operations
+/// that must read.
+#[rstest]
+#[case::read(
+    "[weights]\nread = 1000000",
+    "[DEBUG fsx] Using seed 200
+[INFO  fsx] 1 read        0x0 .. 0x1fff ( 0x2000 bytes)
+"
+)]
+#[case::mapread(
+    "[weights]\nmapread = 1000000",
+    "[DEBUG fsx] Using seed 200
+[INFO  fsx] 1 mapread     0x0 .. 0x1fff ( 0x2000 bytes)
+"
+)]
+#[case::invalidate(
+    "[weights]\ninvalidate = 1000000",
+    "[DEBUG fsx] Using seed 200
+[INFO  fsx] 1 msync(MS_INVALIDATE)
+"
+)]
+#[cfg_attr(
+    not(any(
+        target_os = "android",
+        target_os = "dragonfly",
+        target_os = "freebsd",
+        target_os = "ios",
+        target_os = "linux",
+        target_os = "macos",
+    )),
+    ignore
+)]
+#[case::sendfile(
+    "[weights]\nsendfile = 1000000",
+    "[DEBUG fsx] Using seed 200
+[INFO  fsx] 1 sendfile    0x0 .. 0x1fff ( 0x2000 bytes)
+"
+)]
+#[cfg_attr(
+    not(any(
+        target_os = "linux",
+        target_os = "android",
+        target_os = "freebsd"
+    )),
+    ignore
+)]
+#[case::posix_fadvise(
+    "[weights]\nposix_fadvise = 1000000",
+    "[DEBUG fsx] Using seed 200
+[INFO  fsx] 1 posix_fadvise(WillNeed  )    0x0 .. 0x1fff ( 0x2000 bytes)
+"
+)]
+#[cfg_attr(not(any(target_os = "linux", target_os = "freebsd")), ignore)]
+#[case::copy_file_range(
+    "[weights]\ncopy_file_range = 1000000",
+    "[DEBUG fsx] Using seed 200
+[INFO  fsx] 1 copy_file_range [   0x0: 0xfff] => [0x1000:0x1fff] ( 0x1000 \
+     bytes)
+"
+)]
+#[cfg_attr(not(target_os = "freebsd"), ignore)]
+#[case::copy_file_range_overlap(
+    "[weights]\ncopy_file_range_overlap = 1000000",
+    "[DEBUG fsx] Using seed 200
+[DEBUG fsx] 1 skipping too small copy_file_range_overlap
+"
+)]
+fn read_weights(#[case] wconf: &str, #[case] stderr: &str) {
+    let mut cf = NamedTempFile::new().unwrap();
+    let conf = format!(
+        "blockmode=true\n[opsize]\nalign=4096\nmin=4096\n{wconf}\ntruncate = \
+         0.0"
+    );
+    cf.write_all(conf.as_bytes()).unwrap();
+
+    let mut tf = NamedTempFile::new().unwrap();
+    tf.as_file_mut().set_len(8192).unwrap();
+
+    let cmd = Command::cargo_bin("fsx")
+        .unwrap()
+        .args(["-vv", "-S", "200", "-N", "1", "-P", "/tmp"])
+        .arg("-f")
+        .arg(cf.path())
+        .arg(tf.path())
+        .assert()
+        .success();
+    let actual_stderr = CString::new(cmd.get_output().stderr.clone())
+        .unwrap()
+        .into_string()
+        .unwrap();
+    assert_eq!(stderr, actual_stderr);
+}
+
+/// Checks that the weights are assigned in the correct order
+#[rstest]
+#[case::close_open(
+    "[weights]\nclose_open = 1000000",
+    "[DEBUG fsx] Using seed 200
+[INFO  fsx] 1 close/open
+"
+)]
+#[case::write(
+    "[weights]\nwrite = 1000000",
+    "[DEBUG fsx] Using seed 200
+[INFO  fsx] 1 write    0x18004 .. 0x1a03a ( 0x2037 bytes)
+"
+)]
+#[case::mapwrite(
+    "[weights]\nmapwrite = 1000000",
+    "[DEBUG fsx] Using seed 200
+[INFO  fsx] 1 mapwrite 0x18004 .. 0x1a03a ( 0x2037 bytes)
+"
+)]
+#[case::truncate(
+    "[weights]\ntruncate = 1000000",
+    "[DEBUG fsx] Using seed 200
+[INFO  fsx] 1 truncate     0x0 => 0x11184
+"
+)]
+#[case::fsync(
+    "[weights]\nfsync = 1000000",
+    "[DEBUG fsx] Using seed 200
+[INFO  fsx] 1 fsync
+"
+)]
+#[case::fdatasync(
+    "[weights]\nfdatasync = 1000000",
+    "[DEBUG fsx] Using seed 200
+[INFO  fsx] 1 fdatasync
+"
+)]
+#[case::stat(
+    "[weights]\nstat = 1000000",
+    "[DEBUG fsx] Using seed 200
+[INFO  fsx] 1 stat
+"
+)]
+fn weights(#[case] wconf: &str, #[case] stderr: &str) {
+    let mut cf = NamedTempFile::new().unwrap();
+    cf.write_all(wconf.as_bytes()).unwrap();
+
+    let tf = NamedTempFile::new().unwrap();
+
+    let cmd = Command::cargo_bin("fsx")
+        .unwrap()
+        .args(["-vv", "-S", "200", "-N", "1"])
+        .arg("-f")
+        .arg(cf.path())
+        .arg(tf.path())
+        .assert()
+        .success();
+    let actual_stderr = CString::new(cmd.get_output().stderr.clone())
+        .unwrap()
+        .into_string()
+        .unwrap();
+    assert_eq!(stderr, actual_stderr);
+}
+
+#[test]
+#[cfg_attr(
+    not(any(
+        target_os = "android",
+        target_os = "dragonfly",
+        target_os = "emscripten",
+        target_os = "freebsd",
+        target_os = "fuchsia",
+        target_os = "linux"
+    )),
+    ignore
+)]
+fn posix_fallocate() {
+    let mut cf = NamedTempFile::new().unwrap();
+    cf.write_all(b"[weights]\nposix_fallocate=1000000").unwrap();
+
+    let tf = NamedTempFile::new().unwrap();
+
+    let mut cmd = Command::cargo_bin("fsx").unwrap();
+    cmd.args(["-vv", "-S", "200", "-N", "1"])
+        .arg("-f")
+        .arg(cf.path())
+        .arg(tf.path());
+    let result = cmd.ok();
+    match result {
+        Ok(r) => {
+            // fsx passed.  Now check its output
+            let actual_stderr =
+                CString::new(r.stderr).unwrap().into_string().unwrap();
+            let expected = "[DEBUG fsx] Using seed 200
+[INFO  fsx] 1 posix_fallocate 0x18004 .. 0x1a03a ( 0x2037 bytes)
+";
+            assert_eq!(expected, actual_stderr);
+        }
+        Err(e) => {
+            let actual_stderr =
+                CString::new(e.as_output().unwrap().stderr.clone())
+                    .unwrap()
+                    .into_string()
+                    .unwrap();
+            if actual_stderr
+                .contains("Test file system does not support posix_fallocate.")
+            {
+                // XXX It would be nice if we could report a "skipped" status
+                eprintln!("Skipped(posix_fallocate unsupported)");
+            } else {
+                panic!("{e}");
+            }
+        }
+    }
+}
+
+/// Clone the whole file, and verify the clone at the end of the run
+#[test]
+#[cfg_attr(not(any(target_os = "android", target_os = "linux")), ignore)]
+fn reflink() {
+    let mut cf = NamedTempFile::new().unwrap();
+    cf.write_all(b"[weights]\nreflink=5").unwrap();
+
+    let dir = TempDir::new().unwrap();
+    let fname = dir.path().join("fsx.bin");
+
+    let mut cmd = Command::cargo_bin("fsx").unwrap();
+    cmd.args(["-v", "-S", "200", "-N", "100"])
+        .arg("-f")
+        .arg(cf.path())
+        .arg(&fname);
+    match cmd.ok() {
+        Ok(r) => {
+            let actual_stderr =
+                CString::new(r.stderr).unwrap().into_string().unwrap();
+            assert!(actual_stderr.contains("reflink"));
+            assert!(dir.path().join("fsx.bin.fsxclone").exists());
+        }
+        Err(e) => {
+            let actual_stderr =
+                CString::new(e.as_output().unwrap().stderr.clone())
+                    .unwrap()
+                    .into_string()
+                    .unwrap();
+            if actual_stderr
+                .contains("Test file system does not support FICLONE.")
+            {
+                eprintln!("Skipped(FICLONE unsupported)");
+            } else {
+                panic!("{e}");
+            }
+        }
+    }
+}
+
+/// Exercise all advice types.
+#[test]
+#[cfg_attr(
+    not(any(
+        target_os = "linux",
+        target_os = "android",
+        target_os = "freebsd"
+    )),
+    ignore
+)]
+fn posix_fadvise() {
+    let mut cf = NamedTempFile::new().unwrap();
+    cf.write_all(b"[weights]\nposix_fadvise=1000000").unwrap();
+
+    let tf = NamedTempFile::new().unwrap();
+
+    let mut cmd = Command::cargo_bin("fsx").unwrap();
+    cmd.args(["-vv", "-N", "6", "-S", "12318153001044186923"])
+        .arg("-f")
+        .arg(cf.path())
+        .arg(tf.path());
+    let r = cmd.ok().unwrap();
+    let actual_stderr = CString::new(r.stderr).unwrap().into_string().unwrap();
+    let expected = "[DEBUG fsx] Using seed 12318153001044186923
+[INFO  fsx] 1 posix_fadvise(Sequential)     0x0 ..     0x0 (    0x0 bytes)
+[INFO  fsx] 2 posix_fadvise(NoReuse   )     0x0 ..     0x0 (    0x0 bytes)
+[INFO  fsx] 3 posix_fadvise(Random    )     0x0 ..     0x0 (    0x0 bytes)
+[INFO  fsx] 4 posix_fadvise(WillNeed  )     0x0 ..     0x0 (    0x0 bytes)
+[INFO  fsx] 5 posix_fadvise(DontNeed  )     0x0 ..     0x0 (    0x0 bytes)
+[INFO  fsx] 6 posix_fadvise(Normal    )     0x0 ..     0x0 (    0x0 bytes)
+";
+    assert_eq!(expected, actual_stderr);
+}
+
+#[cfg_attr(
+    not(any(
+        have_fspacectl,
+        target_os = "android",
+        target_os = "emscripten",
+        target_os = "fuchsia",
+        target_os = "linux"
+    )),
+    ignore
+)]
+#[test]
+fn punch_hole() {
+    let mut cf = NamedTempFile::new().unwrap();
+    cf.write_all(
+        b"[weights]\npunch_hole=10\nmapread=0\nmapwrite=0\ntruncate=0",
+    )
+    .unwrap();
+
+    let tf = NamedTempFile::new().unwrap();
+
+    let cmd = Command::cargo_bin("fsx")
+        .unwrap()
+        .args(["-vv", "-S", "301", "-N", "10", "-P", "/tmp", "-f"])
+        .arg(cf.path())
+        .arg(tf.path())
+        .assert()
+        .success();
+    let actual_stderr = CString::new(cmd.get_output().stderr.clone())
+        .unwrap()
+        .into_string()
+        .unwrap();
+    let expected: &str = "[DEBUG fsx] Using seed 301
+[INFO  fsx]  1 write    0x31a71 .. 0x32208 (  0x798 bytes)
+[INFO  fsx]  2 write    0x1b01b .. 0x2a456 ( 0xf43c bytes)
+[INFO  fsx]  3 read     0x2a547 .. 0x32208 ( 0x7cc2 bytes)
+[INFO  fsx]  4 punch_hole  0xe1a3 .. 0x1a7bb ( 0xc619 bytes)
+[INFO  fsx]  5 read      0x2df9 .. 0x11e7c ( 0xf084 bytes)
+[INFO  fsx]  6 punch_hole 0x2794a .. 0x32208 ( 0xa8bf bytes)
+[INFO  fsx]  7 write    0x2a1af .. 0x2a72c (  0x57e bytes)
+[INFO  fsx]  8 read     0x1eba8 .. 0x21a49 ( 0x2ea2 bytes)
+[INFO  fsx]  9 read     0x2298f .. 0x2bf0a ( 0x957c bytes)
+[INFO  fsx] 10 punch_hole  0x6e88 ..  0xac44 ( 0x3dbd bytes)
+";
+    assert_eq!(expected, actual_stderr);
+}
+
+/// During punch hole, monitor affected byte ranges
+#[cfg_attr(
+    not(any(
+        have_fspacectl,
+        target_os = "android",
+        target_os = "emscripten",
+        target_os = "fuchsia",
+        target_os = "linux"
+    )),
+    ignore
+)]
+#[test]
+fn punch_hole_monitor() {
+    let mut cf = NamedTempFile::new().unwrap();
+    cf.write_all(
+        b"[weights]\npunch_hole=3\nread=0\nwrite=0\nmapread=0\nmapwrite=0\ntruncate=1",
+    )
+    .unwrap();
+
+    let tf = NamedTempFile::new().unwrap();
+
+    let cmd = Command::cargo_bin("fsx")
+        .unwrap()
+        .args([
+            "-vv",
+            "-S",
+            "7482128686191834117",
+            "-N",
+            "4",
+            "-m",
+            "94428:94429",
+            "-P",
+            "/tmp",
+            "-f",
+        ])
+        .arg(cf.path())
+        .arg(tf.path())
+        .assert()
+        .success();
+    let actual_stderr = CString::new(cmd.get_output().stderr.clone())
+        .unwrap()
+        .into_string()
+        .unwrap();
+    let expected: &str = "[DEBUG fsx] Using seed 7482128686191834117
+[INFO  fsx] 1 truncate     0x0 => 0x170de
+[WARN  fsx] 2 punch_hole 0x13847 .. 0x170dd ( 0x3897 bytes)
+[INFO  fsx] 3 punch_hole  0x9876 .. 0x16b4e ( 0xd2d9 bytes)
+[INFO  fsx] 4 punch_hole  0x99ea .. 0x15902 ( 0xbf19 bytes)
+";
+    assert_eq!(expected, actual_stderr);
+}
+
+/// With --monitor-only, reads and writes should begin within the monitored
+/// range, whether it comes from -m or the config file
+#[rstest]
+#[case::cli("", &["-m", "65536:131071"])]
+#[case::config("monitor = [[65536, 131071]]", &[])]
+fn monitor_only(#[case] conf: &str, #[case] args: &[&str]) {
+    let mut cf = NamedTempFile::new().unwrap();
+    cf.write_all(conf.as_bytes()).unwrap();
+    let tf = NamedTempFile::new().unwrap();
+
+    let output = Command::cargo_bin("fsx")
+        .unwrap()
+        .args(["-v", "-N200", "-S1", "--monitor-only", "-f"])
+        .arg(cf.path())
+        .args(args)
+        .arg(tf.path())
+        .output()
+        .unwrap();
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(output.status.success(), "{stderr}");
+    let offsets = stderr
+        .lines()
+        .filter_map(|l| {
+            let fields = l.split_whitespace().collect::<Vec<_>>();
+            if !["read", "write", "mapread", "mapwrite"].contains(&fields[3]) {
+                return None;
+            }
+            let h = fields[4].trim_start_matches("0x");
+            Some(u64::from_str_radix(h, 16).unwrap())
+        })
+        .collect::<Vec<_>>();
+    assert!(offsets.len() > 100, "{}", offsets.len());
+    // Reads may begin before the range while the file is still short
+    let inside = offsets
+        .iter()
+        .filter(|o| (65536..=131071).contains(*o))
+        .count();
+    assert!(inside > offsets.len() * 9 / 10, "{inside}");
+}
+
+/// --monitor-only needs a range, and config ranges must not be backwards
+#[rstest]
+#[case::no_range(
+    "",
+    "error: --monitor-only requires a range from -m or the monitor config \
+     option\n"
+)]
+#[case::backwards(
+    "monitor = [[200, 100]]",
+    "error: monitor ranges must not end before they start\n"
+)]
+fn bad_monitor(#[case] conf: &str, #[case] stderr: &str) {
+    let mut cf = NamedTempFile::new().unwrap();
+    cf.write_all(conf.as_bytes()).unwrap();
+    let tf = NamedTempFile::new().unwrap();
+    Command::cargo_bin("fsx")
+        .unwrap()
+        .args(["-N1", "--monitor-only", "-f"])
+        .arg(cf.path())
+        .arg(tf.path())
+        .assert()
+        .failure()
+        .stderr(stderr.to_owned());
+}
+
+/// Ranges from -m and from the config file should each be monitored
+#[test]
+fn monitor_multiple() {
+    let mut cf = NamedTempFile::new().unwrap();
+    cf.write_all(b"monitor = [[80000, 80001]]").unwrap();
+    let tf = NamedTempFile::new().unwrap();
+
+    let output = Command::cargo_bin("fsx")
+        .unwrap()
+        .args(["-N10", "-S68", "-m", "32768:65536", "-f"])
+        .arg(cf.path())
+        .arg(tf.path())
+        .output()
+        .unwrap();
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(output.status.success(), "{stderr}");
+    let steps = stderr
+        .lines()
+        .filter_map(|l| l.strip_prefix("[WARN  fsx] "))
+        .map(|l| l.split_whitespace().next().unwrap())
+        .collect::<Vec<_>>();
+    assert_eq!(steps, ["5", "8", "10"]);
+}
+
+/// Verify st_blocks after hole punching and posix_fallocate
+#[cfg_attr(
+    not(any(
+        have_fspacectl,
+        target_os = "android",
+        target_os = "emscripten",
+        target_os = "fuchsia",
+        target_os = "linux"
+    )),
+    ignore
+)]
+#[test]
+fn stblocks_check() {
+    let mut cf = NamedTempFile::new().unwrap();
+    cf.write_all(
+        b"stblocks_check = true\n[weights]\npunch_hole=10\nposix_fallocate=10",
+    )
+    .unwrap();
+
+    let tf = NamedTempFile::new().unwrap();
+
+    let mut cmd = Command::cargo_bin("fsx").unwrap();
+    cmd.args(["-S", "301", "-N", "200", "-P", "/tmp", "-f"])
+        .arg(cf.path())
+        .arg(tf.path());
+    if let Err(e) = cmd.ok() {
+        let actual_stderr = CString::new(e.as_output().unwrap().stderr.clone())
+            .unwrap()
+            .into_string()
+            .unwrap();
+        if actual_stderr
+            .contains("Test file system does not support posix_fallocate.")
+        {
+            eprintln!("Skipped(posix_fallocate unsupported)");
+        } else {
+            panic!("{e}");
+        }
+    }
+}
+
+/// Verify with SEEK_HOLE that punched ranges became holes
+#[cfg_attr(
+    not(any(
+        have_fspacectl,
+        target_os = "android",
+        target_os = "emscripten",
+        target_os = "fuchsia",
+        target_os = "linux"
+    )),
+    ignore
+)]
+#[test]
+fn holecheck() {
+    let mut cf = NamedTempFile::new().unwrap();
+    cf.write_all(b"holecheck = true\n[weights]\npunch_hole=10")
+        .unwrap();
+
+    let tf = NamedTempFile::new().unwrap();
+
+    Command::cargo_bin("fsx")
+        .unwrap()
+        .args(["-S", "301", "-N", "200", "-P", "/tmp", "-f"])
+        .arg(cf.path())
+        .arg(tf.path())
+        .assert()
+        .success();
+}
+
+/// Skip zero-length hole punches
+#[cfg_attr(
+    not(any(
+        have_fspacectl,
+        target_os = "android",
+        target_os = "emscripten",
+        target_os = "fuchsia",
+        target_os = "linux"
+    )),
+    ignore
+)]
+#[test]
+fn punch_hole_zero() {
+    let mut cf = NamedTempFile::new().unwrap();
+    cf.write_all(b"[weights]\npunch_hole=1000").unwrap();
+
+    let tf = NamedTempFile::new().unwrap();
+
+    let cmd = Command::cargo_bin("fsx")
+        .unwrap()
+        .args(["-vv", "-S", "301", "-N", "1", "-f"])
+        .arg(cf.path())
+        .arg(tf.path())
+        .assert()
+        .success();
+    let actual_stderr = CString::new(cmd.get_output().stderr.clone())
+        .unwrap()
+        .into_string()
+        .unwrap();
+    let expected: &str = "[DEBUG fsx] Using seed 301
+[DEBUG fsx] 1 skipping zero size hole punch
+";
+    assert_eq!(expected, actual_stderr);
+}
+
+/// Operate on an anonymous O_TMPFILE inode, optionally linking it in at the end
+#[cfg_attr(not(any(target_os = "android", target_os = "linux")), ignore)]
+#[rstest]
+#[case::anon(false)]
+#[case::link(true)]
+fn tmpfile(#[case] link: bool) {
+    let mut cf = NamedTempFile::new().unwrap();
+    let conf = format!("tmpfile = true\ntmpfile_link = {link}");
+    cf.write_all(conf.as_bytes()).unwrap();
+
+    let dir = TempDir::new().unwrap();
+    let fname = dir.path().join("fsx.bin");
+
+    Command::cargo_bin("fsx")
+        .unwrap()
+        .args(["-N100", "-S7", "-f"])
+        .arg(cf.path())
+        .arg(&fname)
+        .assert()
+        .success();
+    if !link {
+        assert!(!fname.exists());
+        return;
+    }
+
+    // The linked file should hold exactly what an ordinary run writes
+    let regular = dir.path().join("regular.bin");
+    Command::cargo_bin("fsx")
+        .unwrap()
+        .args(["-N100", "-S7"])
+        .arg(&regular)
+        .assert()
+        .success();
+    let expected = fs::read(&regular).unwrap();
+    assert!(!expected.is_empty());
+    assert_eq!(expected, fs::read(&fname).unwrap());
+}
+
+/// Overlapping copy_file_range must either copy like memmove or fail with
+/// EINVAL and leave the file unchanged.
+#[cfg_attr(not(target_os = "freebsd"), ignore)]
+#[test]
+fn copy_file_range_overlap() {
+    let mut cf = NamedTempFile::new().unwrap();
+    cf.write_all(b"[weights]\ncopy_file_range_overlap = 10")
+        .unwrap();
+
+    let tf = NamedTempFile::new().unwrap();
+
+    let cmd = Command::cargo_bin("fsx")
+        .unwrap()
+        .args(["-v", "-N100", "-S5", "-f"])
+        .arg(cf.path())
+        .arg(tf.path())
+        .assert()
+        .success();
+    let actual_stderr = CString::new(cmd.get_output().stderr.clone())
+        .unwrap()
+        .into_string()
+        .unwrap();
+    assert!(actual_stderr.contains("overlapping"));
+}
+
+/// Linux rejects every overlapping copy, so the operation isn't available
+#[cfg(target_os = "linux")]
+#[test]
+fn copy_file_range_overlap_linux() {
+    let mut cf = NamedTempFile::new().unwrap();
+    cf.write_all(
+        b"[weights]
+read = 0
+write = 0
+mapread = 0
+mapwrite = 0
+truncate = 0
+copy_file_range_overlap = 10",
+    )
+    .unwrap();
+
+    let tf = NamedTempFile::new().unwrap();
+
+    Command::cargo_bin("fsx")
+        .unwrap()
+        .args(["-N1", "-f"])
+        .arg(cf.path())
+        .arg(tf.path())
+        .assert()
+        .failure()
+        .stderr(
+            "error: [weights] only has nonzero weights for operations that \
+             this platform does not support: copy_file_range_overlap\n",
+        );
+}
+
+/// Move data between the test file and a scratch file
+#[rstest]
+#[cfg_attr(not(any(target_os = "linux", target_os = "freebsd")), ignore)]
+#[case::copy_in("scratch_copy_in")]
+#[cfg_attr(not(any(target_os = "linux", target_os = "freebsd")), ignore)]
+#[case::copy_out("scratch_copy_out")]
+#[cfg_attr(not(any(target_os = "android", target_os = "linux")), ignore)]
+#[case::sendfile("scratch_sendfile")]
+fn scratch(#[case] op: &str) {
+    let mut cf = NamedTempFile::new().unwrap();
+    let conf = format!("scratch_file = \"fsx.scratch\"\n[weights]\n{op} = 10");
+    cf.write_all(conf.as_bytes()).unwrap();
+
+    let dir = TempDir::new().unwrap();
+
+    let cmd = Command::cargo_bin("fsx")
+        .unwrap()
+        .args(["-v", "-N100", "-S5", "-f"])
+        .arg(cf.path())
+        .arg(dir.path().join("fsx.bin"))
+        .assert()
+        .success();
+    let actual_stderr = CString::new(cmd.get_output().stderr.clone())
+        .unwrap()
+        .into_string()
+        .unwrap();
+    assert!(actual_stderr.contains(op));
+    assert!(dir.path().join("fsx.scratch").exists());
+}
+
+/// Scratch operations require a scratch file
+#[test]
+fn scratch_missing() {
+    let mut cf = NamedTempFile::new().unwrap();
+    cf.write_all(b"[weights]\nscratch_copy_in = 10").unwrap();
+
+    let tf = NamedTempFile::new().unwrap();
+
+    Command::cargo_bin("fsx")
+        .unwrap()
+        .args(["-N1", "-f"])
+        .arg(cf.path())
+        .arg(tf.path())
+        .assert()
+        .failure()
+        .stderr("error: scratch operations require scratch_file\n");
+}
+
+/// Only known errnos may be used in the errors table
+#[test]
+fn errors_unknown() {
+    let mut cf = NamedTempFile::new().unwrap();
+    cf.write_all(b"[errors]\nEFOO = \"skip\"").unwrap();
+
+    let tf = NamedTempFile::new().unwrap();
+
+    Command::cargo_bin("fsx")
+        .unwrap()
+        .args(["-N1", "-f"])
+        .arg(cf.path())
+        .arg(tf.path())
+        .assert()
+        .failure()
+        .stderr("error: unsupported errno EFOO in errors\n");
+}
+
+/// A read that fails with EIO should fail the test, unless the error policy
+/// says to skip it
+#[rstest]
+#[case::read("", 7, "read failed with Input/output error", " 7 read failed")]
+#[case::mapread("", 8, "mmap failed with EIO", " 8 mapread failed")]
+#[case::sendfile(
+    "[weights]\nsendfile = 10",
+    12,
+    "sendfile failed with EIO",
+    "12 sendfile failed"
+)]
+#[case::read_eof(
+    "[weights]\nread_eof = 10",
+    12,
+    "read failed with Input/output error",
+    "12 read_eof failed"
+)]
+fn read_eio(
+    #[case] conf: &str,
+    #[case] step: u64,
+    #[case] error: &str,
+    #[case] warning: &str,
+) {
+    let tf = NamedTempFile::new().unwrap();
+    let run = |conf: String| {
+        let mut cf = NamedTempFile::new().unwrap();
+        cf.write_all(conf.as_bytes()).unwrap();
+        Command::cargo_bin("fsx")
+            .unwrap()
+            .args(["-N12", "-S20", "--inject-errno", "EIO", "--inject"])
+            .arg(step.to_string())
+            .arg("-f")
+            .arg(cf.path())
+            .arg(tf.path())
+            .assert()
+    };
+
+    let cmd = run(conf.to_owned()).failure();
+    let stderr = CString::new(cmd.get_output().stderr.clone())
+        .unwrap()
+        .into_string()
+        .unwrap();
+    assert!(stderr.contains(error), "{stderr}");
+
+    let cmd = run(format!("[errors]\nEIO = \"skip\"\n{conf}")).success();
+    let stderr = CString::new(cmd.get_output().stderr.clone())
+        .unwrap()
+        .into_string()
+        .unwrap();
+    assert!(stderr.contains(&format!("{warning} with ")), "{stderr}");
+}
+
+/// Reopen the file by means other than its path, verifying its contents
+#[cfg_attr(not(any(target_os = "android", target_os = "linux")), ignore)]
+#[rstest]
+#[case::procfd("reopen = \"procfd\"")]
+#[case::openat2("reopen = \"openat2\"")]
+#[case::tmpfile("reopen = \"procfd\"\ntmpfile = true")]
+fn reopen(#[case] conf: &str) {
+    let mut cf = NamedTempFile::new().unwrap();
+    let conf = format!("{conf}\n[weights]\nclose_open = 10");
+    cf.write_all(conf.as_bytes()).unwrap();
+
+    let tf = NamedTempFile::new().unwrap();
+
+    let cmd = Command::cargo_bin("fsx")
+        .unwrap()
+        .args(["-v", "-N100", "-S8", "-f"])
+        .arg(cf.path())
+        .arg(tf.path())
+        .assert()
+        .success();
+    let actual_stderr = CString::new(cmd.get_output().stderr.clone())
+        .unwrap()
+        .into_string()
+        .unwrap();
+    assert!(actual_stderr.contains("close/open"));
+}
+
+/// Tests that work on real device files
+mod blockdev {
+    use cfg_if::cfg_if;
+    use rstest::fixture;
+
+    use super::*;
+
+    cfg_if! {
+        if #[cfg(target_os = "freebsd")] {
+            use mdconfig::Md;
+
+            #[fixture]
+            fn md() -> Option<Md> {
+                mdconfig::Builder::swap(1 << 20).create().ok()
+            }
+        } else if #[cfg(target_os = "netbsd")] {
+            use std::{ffi::OsStr, os::unix::ffi::OsStrExt, path::{Path, PathBuf}};
+
+            struct Md(PathBuf);
+
+            impl Md {
+                fn path(&self) -> &Path {
+                    self.0.as_path()
+                }
+            }
+            impl Drop for Md {
+                fn drop(&mut self) {
+                    Command::new("mdconfig")
+                        .args(["-d", "-u"])
+                        .arg(&self.0)
+                        .output()
+                        .expect("failed to deallocate md(4) device");
+                }
+            }
+
+            #[fixture]
+            fn md() -> Option<Md> {
+                let output = Command::new("mdconfig")
+                    .args(["-a", "-t", "swap", "-s", "1m"])
+                    .output()
+                    .expect("failed to allocate md(4) device");
+                if output.status.success() {
+                    // Strip the trailing "\n"
+                    let l = output.stdout.len().saturating_sub(1);
+                    let mddev = OsStr::from_bytes(&output.stdout[0..l]);
+                    Some(Md(Path::new("/dev").join(mddev)))
+                } else {
+                    let l = output.stderr.len().saturating_sub(1);
+                    eprintln!("Skipping test: {}",
+                              OsStr::from_bytes(&output.stderr[0..l])
+                              .to_string_lossy());
+                    None
+                }
+            }
+        } else if #[cfg(target_os = "linux")] {
+            use std::{ffi::OsStr, os::unix::ffi::OsStrExt, path::{Path, PathBuf}};
+            struct Md(PathBuf);
+
+            impl Md {
+                fn path(&self) -> &Path {
+                    self.0.as_path()
+                }
+            }
+            impl Drop for Md {
+                fn drop(&mut self) {
+                    Command::new("losetup")
+                        .args(["-d"])
+                        .arg(&self.0)
+                        .output()
+                        .expect("failed to deallocate loop device");
+                }
+            }
+
+            #[fixture]
+            fn md() -> Option<Md> {
+                let tf = NamedTempFile::new().unwrap();
+                tf.as_file().set_len(1048576).unwrap();
+                let output = Command::new("/sbin/losetup")
+                    .args(["-f", "--show"])
+                    .arg(tf.path())
+                    .output()
+                    .expect("failed to allocate loop device");
+                if output.status.success() {
+                    // Strip the trailing "\n"
+                    let l = output.stdout.len() - 1;
+                    let mddev = OsStr::from_bytes(&output.stdout[0..l]);
+                    Some(Md(mddev.into()))
+                } else {
+                    let l = output.stderr.len().saturating_sub(1);
+                    eprintln!("Skipping test: {}",
+                              OsStr::from_bytes(&output.stderr[0..l])
+                              .to_string_lossy());
+                    None
+                }
+            }
+        } else {
+            struct Md(PathBuf);
+
+            #[fixture]
+            fn md() -> Option<Md> {
+                unimplemented!()
+            }
+        }
+    }
+
+    /// When operating on a block device, fsx will automatically determine the
+    /// file size.
+    #[rstest]
+    fn flen_zero(md: Option<Md>) {
+        if md.is_none() {
+            return;
+        }
+        let md = md.unwrap();
+
+        let mut cf = NamedTempFile::new().unwrap();
+        cf.write_all(
+            b"blockmode = true
+[opsize]
+align = 4096
+[weights]
+mapread = 0
+mapwrite = 0
+truncate = 0",
+        )
+        .unwrap();
+
+        let artifacts_dir = TempDir::new().unwrap();
+
+        Command::cargo_bin("fsx")
+            .unwrap()
+            .args(["-N10", "-P"])
+            .arg(artifacts_dir.path())
+            .arg("-f")
+            .arg(cf.path())
+            .arg(md.path())
+            .assert()
+            .success();
+    }
+
+    /// fsx should detect a disk device and switch to blockmode, disabling
+    /// the operations that can't change its size
+    #[rstest]
+    fn detect(md: Option<Md>) {
+        if md.is_none() {
+            return;
+        }
+        let md = md.unwrap();
+
+        let mut cf = NamedTempFile::new().unwrap();
+        cf.write_all(
+            b"[opsize]
+align = 4096
+[weights]
+mapread = 0
+mapwrite = 0",
+        )
+        .unwrap();
+
+        let artifacts_dir = TempDir::new().unwrap();
+
+        let output = Command::cargo_bin("fsx")
+            .unwrap()
+            .args(["-v", "-N10", "-P"])
+            .arg(artifacts_dir.path())
+            .arg("-f")
+            .arg(cf.path())
+            .arg(md.path())
+            .output()
+            .unwrap();
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert!(output.status.success(), "{stderr}");
+        assert!(stderr.contains("is a device; using blockmode"), "{stderr}");
+        assert!(
+            stderr.contains(
+                "[WARN  fsx] disabling truncate, which block devices do not \
+                 support"
+            ),
+            "{stderr}"
+        );
+    }
+
+    /// In blockmode on a disk, fsx should align operations to the device's
+    /// minimum I/O size by default, and warn about unusable alignments
+    #[rstest]
+    #[case::default("", "minimum I/O size")]
+    #[case::unaligned(
+        "[opsize]\nalign = 100",
+        "[WARN  fsx] opsize alignment is not a multiple of the 512-byte \
+         sector size"
+    )]
+    fn align(md: Option<Md>, #[case] conf: &str, #[case] msg: &str) {
+        if md.is_none() {
+            return;
+        }
+        let md = md.unwrap();
+
+        let mut cf = NamedTempFile::new().unwrap();
+        writeln!(cf, "blockmode = true\n{conf}").unwrap();
+
+        let output = Command::cargo_bin("fsx")
+            .unwrap()
+            .args(["-v", "-N0", "-f"])
+            .arg(cf.path())
+            .arg(md.path())
+            .output()
+            .unwrap();
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert!(stderr.contains(msg), "{stderr}");
+    }
+}
+
+/// The binary trace should hold a header and one record per operation
+#[rstest]
+#[case::always("always", None, true)]
+#[case::failure_ok("failure", None, false)]
+#[case::failure_inject("failure", Some("30"), true)]
+fn trace(
+    #[case] mode: &str,
+    #[case] inject: Option<&str>,
+    #[case] saved: bool,
+) {
+    let mut cf = NamedTempFile::new().unwrap();
+    cf.write_all(format!("trace = \"{mode}\"").as_bytes())
+        .unwrap();
+
+    let tf = NamedTempFile::new().unwrap();
+    let artifacts_dir = TempDir::new().unwrap();
+
+    let mut cmd = Command::cargo_bin("fsx").unwrap();
+    cmd.args(["-N100", "-S7", "-f"])
+        .arg(cf.path())
+        .arg("-P")
+        .arg(artifacts_dir.path());
+    if let Some(n) = inject {
+        cmd.args(["--inject", n]);
+    }
+    let output = cmd.arg(tf.path()).output().unwrap();
+    assert_eq!(output.status.success(), inject.is_none());
+
+    let mut final_component = tf.path().file_name().unwrap().to_owned();
+    final_component.push(".fsxops");
+    let path = artifacts_dir.path().join(final_component);
+    if !saved {
+        assert!(!path.exists());
+        return;
+    }
+    let trace = fs::read(&path).unwrap();
+    assert_eq!(&trace[..6], b"FSXOPS");
+    assert_eq!(&trace[8..16], &7u64.to_le_bytes());
+    assert_eq!((trace.len() - 16) % 22, 0);
+    if inject.is_none() {
+        assert_eq!(trace.len(), 16 + 22 * 100);
+    } else {
+        assert!(trace.len() >= 16 + 22 * 30);
+    }
+}
+
+/// Replaying a trace should repeat the recorded run exactly, regardless of
+/// the seed, with either the --replay flag or the replay subcommand
+#[rstest]
+#[case::flag(false)]
+#[case::subcommand(true)]
+fn replay(#[case] subcommand: bool) {
+    let mut cf = NamedTempFile::new().unwrap();
+    cf.write_all(
+        b"trace = \"always\"\n[weights]\ntruncate = 1\npunch_hole = 1",
+    )
+    .unwrap();
+
+    let tf = NamedTempFile::new().unwrap();
+    let artifacts_dir = TempDir::new().unwrap();
+    Command::cargo_bin("fsx")
+        .unwrap()
+        .args(["-N200", "-S7", "-f"])
+        .arg(cf.path())
+        .arg("-P")
+        .arg(artifacts_dir.path())
+        .arg(tf.path())
+        .assert()
+        .success();
+    let mut final_component = tf.path().file_name().unwrap().to_owned();
+    final_component.push(".fsxops");
+    let trace = artifacts_dir.path().join(final_component);
+
+    let tf2 = NamedTempFile::new().unwrap();
+    let mut cmd = Command::cargo_bin("fsx").unwrap();
+    if subcommand {
+        cmd.args(["replay", "-S1", "-f"]).arg(cf.path()).arg(&trace);
+    } else {
+        cmd.args(["-S1", "-f"])
+            .arg(cf.path())
+            .arg("--replay")
+            .arg(&trace);
+    }
+    let output = cmd
+        .arg("-P")
+        .arg(artifacts_dir.path())
+        .arg(tf2.path())
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    assert_eq!(fs::read(tf.path()).unwrap(), fs::read(tf2.path()).unwrap());
+}
+
+/// Soak mode should keep going after failures, saving each one's artifacts,
+/// with a fresh seed for every run
+#[test]
+fn soak() {
+    use std::io::{BufRead, BufReader};
+
+    let tf = NamedTempFile::new().unwrap();
+    let artifacts_dir = TempDir::new().unwrap();
+
+    let mut child = Command::cargo_bin("fsx")
+        .unwrap()
+        .args(["--soak", "-N50", "--inject", "30", "-S7", "-P"])
+        .arg(artifacts_dir.path())
+        .arg(tf.path())
+        .stdout(std::process::Stdio::piped())
+        .stderr(std::process::Stdio::null())
+        .spawn()
+        .unwrap();
+    // The parent prints a tally after each run
+    let stdout = BufReader::new(child.stdout.take().unwrap());
+    let tallies = stdout
+        .lines()
+        .map(Result::unwrap)
+        .take(3)
+        .collect::<Vec<_>>();
+    child.kill().unwrap();
+    child.wait().unwrap();
+    // No later step reads what seed 9 lost at step 30, so that run passes.
+    assert_eq!(
+        tallies,
+        [
+            "1 runs, 1 failures",
+            "2 runs, 2 failures",
+            "3 runs, 2 failures"
+        ]
+    );
+    for (run, seed) in [(0, 7), (1, 8)] {
+        let dir = artifacts_dir.path().join(format!("run{run}-seed{seed}"));
+        let log = fs::read_to_string(dir.join("fsx.log")).unwrap();
+        assert!(log.contains(&format!("Using seed {seed} ")), "{log}");
+    }
+    assert!(!artifacts_dir.path().join("run2-seed9").exists());
+}
+
+/// --report should summarize every job
+#[test]
+fn report() {
+    let dir = TempDir::new().unwrap();
+    let report = dir.path().join("report.toml");
+
+    Command::cargo_bin("fsx")
+        .unwrap()
+        .args(["-j2", "-N100", "-S7", "-P"])
+        .arg(dir.path())
+        .arg("--report")
+        .arg(&report)
+        .arg(dir.path().join("fsx.bin"))
+        .assert()
+        .success();
+    let report = fs::read_to_string(&report).unwrap();
+    assert!(report.contains("runs = 2\nops = 200\n"), "{report}");
+    assert!(report.contains("failures = 0\n"), "{report}");
+    assert!(report.contains("seed = 8\n"), "{report}");
+}
+
+/// --report-format should write the report as TAP or JUnit, with a test for
+/// each periodic verification
+#[rstest]
+#[case::tap(
+    "tap",
+    "TAP version 14
+1..1
+# Subtest: seed 1
+    1..3
+    ok 1 - verified at step 40
+    ok 2 - verified at step 80
+    ok 3 - 100 operations
+ok 1 - seed 1
+"
+)]
+#[case::junit(
+    "junit",
+    r#"<?xml version="1.0" encoding="UTF-8"?>
+<testsuites name="fsx" tests="3" failures="0">
+  <testsuite name="seed 1" tests="3" failures="0">
+    <testcase classname="seed 1" name="verified at step 40"/>
+    <testcase classname="seed 1" name="verified at step 80"/>
+    <testcase classname="seed 1" name="100 operations"/>
+  </testsuite>
+</testsuites>
+"#
+)]
+fn report_format(#[case] format: &str, #[case] expected: &str) {
+    let dir = TempDir::new().unwrap();
+    let report = dir.path().join("report");
+    let mut cf = NamedTempFile::new().unwrap();
+    cf.write_all(b"verify_interval = 40").unwrap();
+
+    Command::cargo_bin("fsx")
+        .unwrap()
+        .args(["-N100", "-S1", "--report-format", format, "-f"])
+        .arg(cf.path())
+        .arg("--report")
+        .arg(&report)
+        .arg(dir.path().join("fsx.bin"))
+        .assert()
+        .success();
+    assert_eq!(fs::read_to_string(&report).unwrap(), expected);
+}
+
+/// fsx verify should compare a file to its .fsxgood artifact
+#[rstest]
+#[case::ok(false)]
+#[case::corrupt(true)]
+fn verify(#[case] corrupt: bool) {
+    let dir = TempDir::new().unwrap();
+    let fname = dir.path().join("fsx.bin");
+    let good = dir.path().join("fsx.bin.fsxgood");
+    let mut contents = vec![0xa5u8; 4096];
+    fs::write(&good, &contents).unwrap();
+    if corrupt {
+        contents[100] = 0;
+    }
+    fs::write(&fname, &contents[..2048]).unwrap();
+
+    let output = Command::cargo_bin("fsx")
+        .unwrap()
+        .arg("verify")
+        .arg(&fname)
+        .output()
+        .unwrap();
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert_eq!(output.status.success(), !corrupt, "{stderr}");
+    if corrupt {
+        assert!(
+            stderr.contains("miscompare at offset 0x64: expected 0xa5, found"),
+            "{stderr}"
+        );
+    }
+}
+
+/// A test file named like a subcommand must follow a separator or an explicit
+/// run
+#[rstest]
+#[case::separator(&["-N10", "--", "verify"])]
+#[case::subcommand(&["run", "-N10", "verify"])]
+fn subcommand_named_file(#[case] args: &[&str]) {
+    let dir = TempDir::new().unwrap();
+
+    Command::cargo_bin("fsx")
+        .unwrap()
+        .current_dir(dir.path())
+        .args(args)
+        .assert()
+        .success();
+    assert!(dir.path().join("verify").metadata().unwrap().len() > 0);
+}
+
+/// fsx config should print the effective configuration
+#[test]
+fn config() {
+    let mut cf = NamedTempFile::new().unwrap();
+    cf.write_all(b"[weights]\nfsync = 5").unwrap();
+
+    let output = Command::cargo_bin("fsx")
+        .unwrap()
+        .args(["config", "-f"])
+        .arg(cf.path())
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("\nfsync = 5.0\n"), "{stdout}");
+    assert!(stdout.contains("\nwrite = 10.0\n"), "{stdout}");
+}
+
+/// The generated config file should hold exactly the defaults
+#[test]
+fn generate_config() {
+    let dir = TempDir::new().unwrap();
+    let path = dir.path().join("fsx.toml");
+    Command::cargo_bin("fsx")
+        .unwrap()
+        .arg("generate-config")
+        .arg(&path)
+        .assert()
+        .success();
+    let generated = Command::cargo_bin("fsx")
+        .unwrap()
+        .args(["config", "-f"])
+        .arg(&path)
+        .output()
+        .unwrap();
+    assert!(generated.status.success());
+    let defaults = Command::cargo_bin("fsx")
+        .unwrap()
+        .arg("config")
+        .output()
+        .unwrap();
+    assert_eq!(
+        String::from_utf8_lossy(&generated.stdout),
+        String::from_utf8_lossy(&defaults.stdout)
+    );
+}
+
+/// fsx doctor should report on every capability, and clean up after itself
+#[test]
+fn doctor() {
+    let dir = TempDir::new().unwrap();
+    let output = Command::cargo_bin("fsx")
+        .unwrap()
+        .arg("doctor")
+        .arg(dir.path())
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("  posix_fallocate  "), "{stdout}");
+    assert!(stdout.contains("  fspacectl        "), "{stdout}");
+    assert!(stdout.contains("  FIEMAP           "), "{stdout}");
+    assert!(stdout.contains("\n[weights]\n"), "{stdout}");
+    // Capabilities that doctor can't try aren't suggested
+    if cfg!(target_os = "linux") {
+        assert!(stdout.contains("\nposix_fadvise = 0  #"), "{stdout}");
+    }
+    assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 0);
+}
+
+/// max_write_bytes should end an otherwise unbounded run successfully
+#[test]
+fn max_write_bytes() {
+    let mut cf = NamedTempFile::new().unwrap();
+    cf.write_all(b"max_write_bytes = 1048576").unwrap();
+    let tf = NamedTempFile::new().unwrap();
+
+    let output = Command::cargo_bin("fsx")
+        .unwrap()
+        .args(["-v", "-S1", "-f"])
+        .arg(cf.path())
+        .arg(tf.path())
+        .output()
+        .unwrap();
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(output.status.success(), "{stderr}");
+    assert!(stderr.contains("Stopping after writing 0x10"), "{stderr}");
+}
+
+/// Each phase should use its own weights, for its own number of operations
+#[rstest]
+#[case::cycle("cycle", "7 write")]
+#[case::progress("progress", "7 truncate")]
+fn phases(#[case] mode: &str, #[case] expected: &str) {
+    let mut cf = NamedTempFile::new().unwrap();
+    write!(
+        cf,
+        "phase_mode = \"{mode}\"
+
+        [[phases]]
+        ops = 3
+        [phases.weights]
+        mapread = 0.0
+        mapwrite = 0.0
+        read = 0.0
+        truncate = 0.0
+
+        [[phases]]
+        ops = 3
+        [phases.weights]
+        mapread = 0.0
+        mapwrite = 0.0
+        read = 0.0
+        write = 0.0
+        truncate = 1.0"
+    )
+    .unwrap();
+    let tf = NamedTempFile::new().unwrap();
+
+    let output = Command::cargo_bin("fsx")
+        .unwrap()
+        .args(["-v", "-N10", "-S1", "-f"])
+        .arg(cf.path())
+        .arg(tf.path())
+        .output()
+        .unwrap();
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(output.status.success(), "{stderr}");
+    assert!(stderr.contains("1 beginning phase 0"), "{stderr}");
+    assert!(stderr.contains("3 write"), "{stderr}");
+    assert!(stderr.contains("4 beginning phase 1"), "{stderr}");
+    assert!(stderr.contains("4 truncate"), "{stderr}");
+    assert!(stderr.contains(expected), "{stderr}");
+}
+
+/// Scripted operations should run exactly as written
+#[rstest]
+#[case::before("before", true)]
+#[case::instead("instead", false)]
+fn script(#[case] mode: &str, #[case] random: bool) {
+    let mut cf = NamedTempFile::new().unwrap();
+    write!(
+        cf,
+        "script_mode = \"{mode}\"
+
+        [[script]]
+        op = \"write\"
+        offset = 0x1000
+        size = 0x100
+
+        [[script]]
+        op = \"truncate\"
+        extra = 0x800"
+    )
+    .unwrap();
+    let tf = NamedTempFile::new().unwrap();
+
+    let output = Command::cargo_bin("fsx")
+        .unwrap()
+        .args(["-v", "-N5", "-S1", "-f"])
+        .arg(cf.path())
+        .arg(tf.path())
+        .output()
+        .unwrap();
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(output.status.success(), "{stderr}");
+    assert!(stderr.contains("1 write     0x1000 ..  0x10ff"), "{stderr}");
+    assert!(stderr.contains("2 truncate  0x1100 =>   0x800"), "{stderr}");
+    assert_eq!(stderr.contains("3 "), random, "{stderr}");
+}
+
+/// --fuzz-config should save a config that depends only on the meta-seed
+#[test]
+fn fuzz_config() {
+    let dir = TempDir::new().unwrap();
+    let configs = ["a", "b"].map(|name| {
+        let output = Command::cargo_bin("fsx")
+            .unwrap()
+            .args(["-N100", "--fuzz-config=42", "-P"])
+            .arg(dir.path())
+            .arg(dir.path().join(name))
+            .output()
+            .unwrap();
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert!(output.status.success(), "{stderr}");
+        fs::read_to_string(dir.path().join(format!("{name}.fsxconfig")))
+            .unwrap()
+    });
+    assert_eq!(configs[0], configs[1]);
+    assert!(!configs[0].contains("\nwrite = 1.0\n"), "{}", configs[0]);
+}
+
+/// --step should obey commands from stdin
+#[test]
+fn step() {
+    let tf = NamedTempFile::new().unwrap();
+
+    let mut child = Command::cargo_bin("fsx")
+        .unwrap()
+        .args(["--step", "-S1"])
+        .arg(tf.path())
+        .stdin(std::process::Stdio::piped())
+        .stdout(std::process::Stdio::piped())
+        .stderr(std::process::Stdio::piped())
+        .spawn()
+        .unwrap();
+    let mut stdin = child.stdin.take().unwrap();
+    stdin.write_all(b"\nskip\ndump\nquit\n").unwrap();
+    drop(stdin);
+    let output = child.wait_with_output().unwrap();
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(output.status.success(), "{stderr}");
+    assert!(stderr.contains("2 SKIPPED  (mapread)"), "{stderr}");
+    assert!(stderr.contains("3 next: mapread"), "{stderr}");
+    assert!(!stderr.contains("4 next"), "{stderr}");
+}
+
+/// SIGUSR2 should pause the run, and a second one should resume it
+#[test]
+fn pause() {
+    let dir = TempDir::new().unwrap();
+    let log = dir.path().join("fsx.log");
+
+    let mut child = Command::cargo_bin("fsx")
+        .unwrap()
+        .arg(dir.path().join("fsx.bin"))
+        .stderr(fs::File::create(&log).unwrap())
+        .spawn()
+        .unwrap();
+    let signal = || {
+        Command::new("kill")
+            .args(["-USR2", &child.id().to_string()])
+            .assert()
+            .success();
+    };
+    let wait_for = |msg: &str| {
+        for _ in 0..100 {
+            if fs::read_to_string(&log).unwrap().contains(msg) {
+                return true;
+            }
+            std::thread::sleep(std::time::Duration::from_millis(100));
+        }
+        false
+    };
+    // Give fsx time to install its signal handler
+    std::thread::sleep(std::time::Duration::from_millis(500));
+    signal();
+    let paused = wait_for("paused");
+    signal();
+    let resumed = wait_for("resumed");
+    child.kill().unwrap();
+    child.wait().unwrap();
+    let log = fs::read_to_string(&log).unwrap();
+    assert!(paused, "{log}");
+    assert!(resumed, "{log}");
+}
+
+/// --break-at should stop the process before the given operation
+#[test]
+fn break_at() {
+    let dir = TempDir::new().unwrap();
+    let log = dir.path().join("fsx.log");
+
+    let child = Command::cargo_bin("fsx")
+        .unwrap()
+        .args(["-v", "-N10", "-S1", "--break-at", "5"])
+        .arg(dir.path().join("fsx.bin"))
+        .stdout(std::process::Stdio::piped())
+        .stderr(fs::File::create(&log).unwrap())
+        .spawn()
+        .unwrap();
+    let pid = child.id().to_string();
+    let stopped = (0..100).any(|_| {
+        std::thread::sleep(std::time::Duration::from_millis(100));
+        let ps = Command::new("ps")
+            .args(["-o", "stat=", "-p", &pid])
+            .output()
+            .unwrap();
+        String::from_utf8_lossy(&ps.stdout).starts_with('T')
+    });
+    let before = fs::read_to_string(&log).unwrap();
+    Command::new("kill")
+        .args(["-CONT", &pid])
+        .assert()
+        .success();
+    let status = child.wait_with_output().unwrap().status;
+    let after = fs::read_to_string(&log).unwrap();
+    assert!(stopped, "{after}");
+    assert!(before.contains(" 5 stopping before"), "{before}");
+    assert!(!before.contains(" 5 truncate"), "{before}");
+    assert!(after.contains(" 5 truncate"), "{after}");
+    assert!(status.success(), "{after}");
+}
+
+/// --skip-ops should skip the I/O of exactly the given operations
+#[rstest]
+#[case::harmless("1:1", true)]
+#[case::write("3:4", false)]
+#[case::multiple("1:1,3:3", false)]
+fn skip_ops(#[case] ranges: &str, #[case] pass: bool) {
+    let tf = NamedTempFile::new().unwrap();
+
+    let output = Command::cargo_bin("fsx")
+        .unwrap()
+        .args(["-N10", "-S10", "--skip-ops", ranges])
+        .arg(tf.path())
+        .output()
+        .unwrap();
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert_eq!(output.status.success(), pass, "{stderr}");
+    if !pass {
+        assert!(stderr.contains("LOG DUMP"), "{stderr}");
+    }
+}
+
+/// drop_caches should evict the file's data periodically, by posix_fadvise
+/// or by the hook
+#[rstest]
+#[case::fadvise(None)]
+#[case::hook(Some("test -s {path}"))]
+#[case::fail(Some("exit 3"))]
+fn drop_caches(#[case] hook: Option<&str>) {
+    let mut cf = NamedTempFile::new().unwrap();
+    writeln!(cf, "drop_caches = 10").unwrap();
+    if let Some(hook) = hook {
+        writeln!(cf, "drop_caches_hook = \"{hook}\"").unwrap();
+    }
+    let tf = NamedTempFile::new().unwrap();
+
+    let output = Command::cargo_bin("fsx")
+        .unwrap()
+        .args(["-v", "-N100", "-S1", "-f"])
+        .arg(cf.path())
+        .arg(tf.path())
+        .output()
+        .unwrap();
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains(" 10 drop_caches"), "{stderr}");
+    if hook == Some("exit 3") {
+        assert!(!output.status.success());
+        assert!(stderr.contains("drop_caches_hook failed"), "{stderr}");
+    } else {
+        assert!(output.status.success(), "{stderr}");
+        assert!(stderr.contains("100 drop_caches"), "{stderr}");
+    }
+}
+
+/// A second fsx process should refuse to use a file that's already in use
+#[test]
+fn lock() {
+    let tf = NamedTempFile::new().unwrap();
+
+    let mut first = Command::cargo_bin("fsx")
+        .unwrap()
+        .arg(tf.path())
+        .stderr(std::process::Stdio::null())
+        .spawn()
+        .unwrap();
+    // Give the first process time to take its lock
+    std::thread::sleep(std::time::Duration::from_millis(500));
+    let output = Command::cargo_bin("fsx")
+        .unwrap()
+        .args(["-N10"])
+        .arg(tf.path())
+        .output()
+        .unwrap();
+    first.kill().unwrap();
+    first.wait().unwrap();
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(!output.status.success());
+    assert!(stderr.contains("already in use by another fsx"), "{stderr}");
+}
+
+/// Included config files should be overridden by the including file
+#[test]
+fn include() {
+    let dir = TempDir::new().unwrap();
+    fs::write(
+        dir.path().join("base.toml"),
+        "flen = 65536\n[weights]\nfsync = 5\nwrite = 3",
+    )
+    .unwrap();
+    let child = dir.path().join("child.toml");
+    fs::write(&child, "include = [\"base.toml\"]\n[weights]\nwrite = 7")
+        .unwrap();
+
+    let output = Command::cargo_bin("fsx")
+        .unwrap()
+        .args(["config", "-f"])
+        .arg(&child)
+        .output()
+        .unwrap();
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(output.status.success());
+    assert!(stdout.contains("flen = 65536\n"), "{stdout}");
+    assert!(stdout.contains("\nfsync = 5.0\n"), "{stdout}");
+    assert!(stdout.contains("\nwrite = 7.0\n"), "{stdout}");
+}
+
+/// Config strings should expand environment variables
+#[test]
+fn interpolate() {
+    let mut cf = NamedTempFile::new().unwrap();
+    cf.write_all(
+        b"check_hook = \"${FSX_TEST_DIR}/check {path} $${HOME} $1\"
+scratch_file = \"${FSX_TEST_UNSET:-/tmp}/scratch\"",
+    )
+    .unwrap();
+
+    let output = Command::cargo_bin("fsx")
+        .unwrap()
+        .env("FSX_TEST_DIR", "/mnt/test")
+        .env_remove("FSX_TEST_UNSET")
+        .args(["config", "-f"])
+        .arg(cf.path())
+        .output()
+        .unwrap();
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(output.status.success());
+    assert!(
+        stdout.contains("check_hook = \"/mnt/test/check {path} ${HOME} $1\""),
+        "{stdout}"
+    );
+    assert!(
+        stdout.contains("scratch_file = \"/tmp/scratch\""),
+        "{stdout}"
+    );
+
+    let output = Command::cargo_bin("fsx")
+        .unwrap()
+        .env_remove("FSX_TEST_DIR")
+        .args(["config", "-f"])
+        .arg(cf.path())
+        .output()
+        .unwrap();
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(!output.status.success());
+    assert!(stderr.contains("FSX_TEST_DIR is not set"), "{stderr}");
+}
+
+/// --profile should apply the named profile's settings over the others
+#[rstest]
+#[case::none(None, "flen = 65536\n", "\nfsync = 1.0\n")]
+#[case::smoke(Some("smoke"), "flen = 4096\n", "\nfsync = 1.0\n")]
+#[case::dio(Some("dio"), "flen = 65536\n", "\nfsync = 9.0\n")]
+fn profile(
+    #[case] name: Option<&str>,
+    #[case] flen: &str,
+    #[case] fsync: &str,
+) {
+    let mut cf = NamedTempFile::new().unwrap();
+    cf.write_all(
+        b"flen = 65536
+[weights]
+fsync = 1
+[profile.smoke]
+flen = 4096
+[profile.dio.weights]
+fsync = 9",
+    )
+    .unwrap();
+
+    let mut cmd = Command::cargo_bin("fsx").unwrap();
+    cmd.args(["config", "-f"]).arg(cf.path());
+    if let Some(name) = name {
+        cmd.args(["--profile", name]);
+    }
+    let output = cmd.output().unwrap();
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(output.status.success());
+    assert!(stdout.contains(flen), "{stdout}");
+    assert!(stdout.contains(fsync), "{stdout}");
+    assert!(!stdout.contains("profile"), "{stdout}");
+}
+
+/// A preset should disable unsupported operations, and change defaults
+#[test]
+fn preset() {
+    let mut cf = NamedTempFile::new().unwrap();
+    cf.write_all(b"preset = \"msdosfs\"\n[weights]\npunch_hole = 5\nfsync = 5")
+        .unwrap();
+
+    let output = Command::cargo_bin("fsx")
+        .unwrap()
+        .args(["config", "-f"])
+        .arg(cf.path())
+        .output()
+        .unwrap();
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(output.status.success());
+    assert!(
+        stderr.contains("[WARN  fsx] disabling punch_hole, which Msdosfs"),
+        "{stderr}"
+    );
+    assert!(stdout.contains("\npunch_hole = 0.0\n"), "{stdout}");
+    assert!(stdout.contains("\nalign = 512\n"), "{stdout}");
+    assert!(stdout.contains("\nfsync = 5.0\n"), "{stdout}");
+    assert!(
+        stdout.contains("\ntimecheck_granularity = 2.0\n"),
+        "{stdout}"
+    );
+}
+
+/// Unknown config keys should be errors, with suggestions for typos
+#[rstest]
+#[case::weight(
+    "[weights]\nmapwrte = 3",
+    "unknown key `mapwrte`, did you mean `mapwrite`?"
+)]
+#[case::top("nomsyncafterwrit = true", "did you mean `nomsyncafterwrite`?")]
+#[case::unknown("zzzzzzzz = true", "unknown field `zzzzzzzz`")]
+fn unknown_key(#[case] conf: &str, #[case] err: &str) {
+    let mut cf = NamedTempFile::new().unwrap();
+    cf.write_all(conf.as_bytes()).unwrap();
+
+    let output = Command::cargo_bin("fsx")
+        .unwrap()
+        .args(["config", "-f"])
+        .arg(cf.path())
+        .output()
+        .unwrap();
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(!output.status.success());
+    assert!(stderr.contains(err), "{stderr}");
+}
+
+/// Weight groups should be divided among members not set individually
+#[test]
+fn weight_groups() {
+    let mut cf = NamedTempFile::new().unwrap();
+    cf.write_all(
+        b"[weights]
+mapwrite = 1
+[weights.groups]
+writes = 50
+syncs = 5",
+    )
+    .unwrap();
+
+    let output = Command::cargo_bin("fsx")
+        .unwrap()
+        .args(["config", "-f"])
+        .arg(cf.path())
+        .output()
+        .unwrap();
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(output.status.success());
+    assert!(stdout.contains("\nmapwrite = 1.0\n"), "{stdout}");
+    assert!(stdout.contains("\nwrite = 25.0\n"), "{stdout}");
+    assert!(stdout.contains("\nfsync = 2.5\n"), "{stdout}");
+    assert!(stdout.contains("\nfdatasync = 2.5\n"), "{stdout}");
+    assert!(stdout.contains("\nread = 10.0\n"), "{stdout}");
+}
+
+/// Operation sizes should follow the configured distribution
+#[rstest]
+#[case::fixed("{ fixed = [4096, 8192] }")]
+#[case::geometric("\"geometric\"")]
+#[case::pareto("\"pareto\"")]
+fn size_distribution(#[case] distribution: &str) {
+    let mut cf = NamedTempFile::new().unwrap();
+    write!(
+        cf,
+        "flen = 1048576
+        [opsize]
+        distribution = {distribution}
+        [weights]
+        mapread = 0
+        mapwrite = 0
+        read = 0
+        truncate = 0"
+    )
+    .unwrap();
+    let tf = NamedTempFile::new().unwrap();
+
+    let output = Command::cargo_bin("fsx")
+        .unwrap()
+        .args(["-v", "-N1000", "-S1", "-f"])
+        .arg(cf.path())
+        .arg(tf.path())
+        .output()
+        .unwrap();
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(output.status.success(), "{stderr}");
+    let sizes = stderr
+        .lines()
+        .filter_map(|l| l.strip_suffix(" bytes)"))
+        .map(|l| {
+            let hex = l.rsplit('(').next().unwrap().trim();
+            let hex = hex.trim_start_matches("0x");
+            usize::from_str_radix(hex, 16).unwrap()
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(sizes.len(), 1000);
+    let small = sizes.iter().filter(|&&s| s < 4096).count();
+    if distribution.contains("fixed") {
+        // Only writes that would pass flen are clipped
+        assert!(sizes.iter().all(|&s| s <= 8192));
+        let exact = sizes.iter().filter(|s| [4096, 8192].contains(s)).count();
+        assert!(exact > 950, "{exact}");
+        assert!(sizes.contains(&4096) && sizes.contains(&8192));
+    } else {
+        // Uniform sizes would be under 4096 only about 6% of the time
+        assert!(small > 300, "{small}");
+        assert!(sizes.iter().any(|&s| s > 32768));
+    }
+}
+
+/// The test file should begin with the contents of initial_contents
+#[test]
+fn initial_contents() {
+    let mut image = NamedTempFile::new().unwrap();
+    let data = (0..0x18000u32)
+        .map(|i| (i * 7 + 3) as u8)
+        .collect::<Vec<_>>();
+    image.write_all(&data).unwrap();
+    let mut cf = NamedTempFile::new().unwrap();
+    write!(
+        cf,
+        "initial_contents = \"{}\"
+        [weights]
+        write = 0
+        mapwrite = 0
+        truncate = 0",
+        image.path().display()
+    )
+    .unwrap();
+    let tf = NamedTempFile::new().unwrap();
+
+    // Every read verifies the initial contents
+    Command::cargo_bin("fsx")
+        .unwrap()
+        .args(["-N100", "-S1", "-f"])
+        .arg(cf.path())
+        .arg(tf.path())
+        .assert()
+        .success();
+    assert_eq!(fs::read(tf.path()).unwrap(), data);
+}
+
+/// With keep_contents, fsx should exercise an existing file in place
+#[test]
+fn keep_contents() {
+    let mut tf = NamedTempFile::new().unwrap();
+    let data = (0..0x18000u32)
+        .map(|i| (i * 7 + 3) as u8)
+        .collect::<Vec<_>>();
+    tf.write_all(&data).unwrap();
+    let mut cf = NamedTempFile::new().unwrap();
+    cf.write_all(
+        b"keep_contents = true
+[weights]
+write = 0
+mapwrite = 0
+truncate = 0",
+    )
+    .unwrap();
+
+    // Every read verifies the existing contents
+    Command::cargo_bin("fsx")
+        .unwrap()
+        .args(["-N100", "-S1", "-f"])
+        .arg(cf.path())
+        .arg(tf.path())
+        .assert()
+        .success();
+    assert_eq!(fs::read(tf.path()).unwrap(), data);
+
+    // Writes modify it in place
+    let mut cf = NamedTempFile::new().unwrap();
+    cf.write_all(b"keep_contents = true").unwrap();
+    Command::cargo_bin("fsx")
+        .unwrap()
+        .args(["-N100", "-S1", "-f"])
+        .arg(cf.path())
+        .arg(tf.path())
+        .assert()
+        .success();
+    assert_ne!(fs::read(tf.path()).unwrap(), data);
+}
+
+/// Operations should stop once they reach their max_count
+#[test]
+fn max_count() {
+    let mut cf = NamedTempFile::new().unwrap();
+    cf.write_all(
+        b"flen = 1048576
+[weights]
+close_open = 10
+[max_count]
+\"close/open\" = 3
+write = 5",
+    )
+    .unwrap();
+    let tf = NamedTempFile::new().unwrap();
+
+    let output = Command::cargo_bin("fsx")
+        .unwrap()
+        .args(["-v", "-N1000", "-S1", "-f"])
+        .arg(cf.path())
+        .arg(tf.path())
+        .output()
+        .unwrap();
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(output.status.success(), "{stderr}");
+    let count = |op| {
+        stderr
+            .lines()
+            .filter(|l| l.split_whitespace().nth(3) == Some(op))
+            .count()
+    };
+    assert_eq!(count("close/open"), 3);
+    assert_eq!(count("write"), 5);
+    assert!(count("read") > 100);
+}
+
+/// Offsets and sizes may be aligned independently
+#[rstest]
+#[case::offset("align_offset = 4096", 4096, 1)]
+#[case::size("align_size = 4096", 1, 4096)]
+fn align_offset_size(
+    #[case] setting: &str,
+    #[case] oalign: u64,
+    #[case] salign: u64,
+) {
+    let mut cf = NamedTempFile::new().unwrap();
+    write!(
+        cf,
+        "flen = 1048576
+        [opsize]
+        {setting}
+        [weights]
+        mapread = 0
+        mapwrite = 0
+        read = 0
+        truncate = 0"
+    )
+    .unwrap();
+    let tf = NamedTempFile::new().unwrap();
+
+    let output = Command::cargo_bin("fsx")
+        .unwrap()
+        .args(["-v", "-N200", "-S1", "-f"])
+        .arg(cf.path())
+        .arg(tf.path())
+        .output()
+        .unwrap();
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(output.status.success(), "{stderr}");
+    let ranges = stderr
+        .lines()
+        .filter(|l| l.contains(" write "))
+        .map(|l| {
+            let fields = l.split_whitespace().collect::<Vec<_>>();
+            let hex = |i: usize| {
+                let h = fields[i].trim_start_matches("0x");
+                u64::from_str_radix(h, 16).unwrap()
+            };
+            (hex(4), hex(6) + 1 - hex(4))
+        })
+        .collect::<Vec<_>>();
+    // Writes that round down to zero bytes are skipped
+    assert!(ranges.len() > 150, "{}", ranges.len());
+    assert!(ranges.iter().all(|(o, _)| o % oalign == 0));
+    assert!(ranges.iter().all(|(_, s)| s % salign == 0));
+    // The other dimension remains unaligned
+    assert!(ranges
+        .iter()
+        .any(|(o, s)| o % 4096 != 0 && oalign == 1
+            || s % 4096 != 0 && salign == 1));
+}
+
+/// Operation offsets should follow the configured mode
+#[rstest]
+#[case::append("\"append\"")]
+#[case::hotspot("{ hotspot = { ranges = [[0x10000, 0x20000]], bias = 0.9 } }")]
+#[case::sequential("\"sequential\"")]
+fn offsets(#[case] mode: &str) {
+    let mut cf = NamedTempFile::new().unwrap();
+    write!(
+        cf,
+        "flen = 1048576
+        [offsets]
+        mode = {mode}
+        [weights]
+        mapread = 0
+        mapwrite = 0
+        read = 0
+        truncate = 0"
+    )
+    .unwrap();
+    let tf = NamedTempFile::new().unwrap();
+
+    let output = Command::cargo_bin("fsx")
+        .unwrap()
+        .args(["-v", "-N1000", "-S1", "-f"])
+        .arg(cf.path())
+        .arg(tf.path())
+        .output()
+        .unwrap();
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(output.status.success(), "{stderr}");
+    let ranges = stderr
+        .lines()
+        .filter(|l| l.contains(" write "))
+        .map(|l| {
+            let fields = l.split_whitespace().collect::<Vec<_>>();
+            let hex = |i: usize| {
+                let h = fields[i].trim_start_matches("0x");
+                u64::from_str_radix(h, 16).unwrap()
+            };
+            (hex(4), hex(6))
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(ranges.len(), 1000);
+    if mode.contains("append") {
+        // Each write extends the file, until it reaches flen
+        let mut eof = 0;
+        for &(start, end) in ranges.iter() {
+            assert!(start == eof || end == 1048575, "{start:#x} {eof:#x}");
+            eof = eof.max(end + 1);
+        }
+    } else if mode.contains("hotspot") {
+        let hot = ranges
+            .iter()
+            .filter(|(start, _)| (0x10000..0x20000).contains(start))
+            .count();
+        assert!(hot > 850, "{hot}");
+    } else {
+        // Each write begins where the last one ended
+        let mut cursor = 0;
+        for &(start, end) in ranges.iter() {
+            assert!(start == cursor || start == 0, "{start:#x} {cursor:#x}");
+            cursor = end + 1;
+        }
+    }
+}
+
+/// The C-based fsx's options should translate to the equivalent config
+#[test]
+fn legacy_cli() {
+    let mut cf = NamedTempFile::new().unwrap();
+    cf.write_all(
+        b"flen = 131072
+        nosizechecks = true
+        [opsize]
+        min = 4096
+        max = 4096
+        align = 512
+        [weights]
+        mapread = 0
+        mapwrite = 0",
+    )
+    .unwrap();
+    let tf = NamedTempFile::new().unwrap();
+    let run = |args: &[&str]| {
+        let output = Command::cargo_bin("fsx")
+            .unwrap()
+            .args(args)
+            .arg(tf.path())
+            .output()
+            .unwrap();
+        assert!(output.status.success());
+        String::from_utf8(output.stderr).unwrap()
+    };
+    let legacy = run(&[
+        "--legacy-cli",
+        "-d",
+        "-N100",
+        "-S7",
+        "-l131072",
+        "-n",
+        "-o4096",
+        "-O",
+        "-r512",
+        "-w512",
+        "-R",
+        "-W",
+    ]);
+    let modern =
+        run(&["-vv", "-N100", "-S7", "-f", cf.path().to_str().unwrap()]);
+    assert_eq!(legacy, modern);
+}
+
+/// The C-based fsx's separate read, write, and truncate alignments should
+/// combine into their least common multiple, and its unsupported options
+/// should draw a warning
+#[test]
+fn legacy_cli_align() {
+    let mut cf = NamedTempFile::new().unwrap();
+    cf.write_all(b"[opsize]\nalign = 3072").unwrap();
+    let tf = NamedTempFile::new().unwrap();
+    let run = |args: &[&str]| {
+        let output = Command::cargo_bin("fsx")
+            .unwrap()
+            .args(args)
+            .arg(tf.path())
+            .output()
+            .unwrap();
+        let stderr = String::from_utf8(output.stderr).unwrap();
+        assert!(output.status.success(), "{stderr}");
+        stderr
+    };
+    let legacy = run(&[
+        "--legacy-cli",
+        "-d",
+        "-f",
+        "-N100",
+        "-S7",
+        "-r1024",
+        "-w1536",
+    ]);
+    let modern =
+        run(&["-vv", "-N100", "-S7", "-f", cf.path().to_str().unwrap()]);
+    let (warning, legacy) = legacy.split_once('\n').unwrap();
+    assert_eq!(warning, "[WARN  fsx] ignoring unsupported option -f");
+    assert_eq!(legacy, modern);
+}
+
+/// closeprob should reopen the file after about 1 in N operations, in addition
+/// to the operation itself
+#[test]
+fn closeprob() {
+    let mut cf = NamedTempFile::new().unwrap();
+    cf.write_all(b"closeprob = 10").unwrap();
+    let tf = NamedTempFile::new().unwrap();
+    let output = Command::cargo_bin("fsx")
+        .unwrap()
+        .args(["-v", "-N1000", "-S5", "-f"])
+        .arg(cf.path())
+        .arg(tf.path())
+        .output()
+        .unwrap();
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(output.status.success(), "{stderr}");
+    let step = |line: &str| line.split_whitespace().nth(2).unwrap().to_owned();
+    let lines = stderr.lines().collect::<Vec<_>>();
+    let mut reopens = 0;
+    for (i, line) in lines.iter().enumerate() {
+        if line.ends_with(" close/open") {
+            reopens += 1;
+            // Each reopen follows the operation of the same step
+            assert_eq!(step(lines[i - 1]), step(line));
+        }
+    }
+    assert!((50..150).contains(&reopens), "{reopens}");
+}
+
+/// The config file's log_level should apply unless -v or -q is given
+#[rstest]
+#[case::config(&[], true)]
+#[case::quiet(&["-q"], false)]
+#[case::verbose(&["-v"], false)]
+fn log_level(#[case] args: &[&str], #[case] debug: bool) {
+    let mut cf = NamedTempFile::new().unwrap();
+    cf.write_all(b"log_level = \"debug\"").unwrap();
+    let tf = NamedTempFile::new().unwrap();
+    let output = Command::cargo_bin("fsx")
+        .unwrap()
+        .args(["-N10", "-S2"])
+        .args(args)
+        .arg("-f")
+        .arg(cf.path())
+        .arg(tf.path())
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert_eq!(stderr.starts_with("[DEBUG fsx] Using seed 2\n"), debug);
+}
+
+/// -v and -q together are an error, rather than cancelling out
+#[rstest]
+#[case::verbose_first(&["-v", "-q"])]
+#[case::quiet_first(&["-q", "-v"])]
+fn verbose_quiet(#[case] args: &[&str]) {
+    let tf = NamedTempFile::new().unwrap();
+    let output = Command::cargo_bin("fsx")
+        .unwrap()
+        .arg("-N10")
+        .args(args)
+        .arg(tf.path())
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(2));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("cannot be used with"), "{stderr}");
+}
+
+/// Every data pattern should verify, and the zeros pattern should write
+/// nothing but zeros
+#[rstest]
+#[case::random("pattern = \"random\"")]
+#[case::zeros("pattern = \"zeros\"")]
+#[case::compressible("pattern = \"compressible\"")]
+#[case::dedupe("pattern = { dedupe = { block = 512 } }")]
+fn pattern(#[case] conf: &str) {
+    let mut cf = NamedTempFile::new().unwrap();
+    cf.write_all(conf.as_bytes()).unwrap();
+    let tf = NamedTempFile::new().unwrap();
+    let output = Command::cargo_bin("fsx")
+        .unwrap()
+        .args(["-N1000", "-S8", "-f"])
+        .arg(cf.path())
+        .arg(tf.path())
+        .output()
+        .unwrap();
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(output.status.success(), "{stderr}");
+    let contents = fs::read(tf.path()).unwrap();
+    if conf.contains("zeros") {
+        assert!(contents.iter().all(|&b| b == 0));
+    }
+    if conf.contains("dedupe") {
+        // Every byte is either a hole's zero or the one block's data
+        let mut block = [0u8; 512];
+        for (i, &b) in contents.iter().enumerate() {
+            let expected = &mut block[i % 512];
+            if *expected == 0 {
+                *expected = b;
+            }
+            assert!(b == 0 || b == *expected, "offset {i:#x}");
+        }
+    }
+}
+
+/// Every data pattern should generate the same contents as it always has, for
+/// the same seed
+#[rstest]
+#[case::stamp("pattern = \"stamp\"", 0xa4932ec6658428e2)]
+#[case::stamp64("pattern = \"stamp64\"", 0x885170569e1baad1)]
+#[case::random("pattern = \"random\"", 0xe0fcb81750476cc6)]
+#[case::compressible("pattern = \"compressible\"", 0x02867a0fc7ef9635)]
+#[case::dedupe("pattern = { dedupe = { block = 1000 } }", 0xcbf3c6e3c5ff8fb1)]
+fn pattern_contents(#[case] conf: &str, #[case] fnv1a: u64) {
+    let mut cf = NamedTempFile::new().unwrap();
+    cf.write_all(conf.as_bytes()).unwrap();
+    let tf = NamedTempFile::new().unwrap();
+    Command::cargo_bin("fsx")
+        .unwrap()
+        .args(["-N50", "-S8", "-f"])
+        .arg(cf.path())
+        .arg(tf.path())
+        .assert()
+        .success();
+    let hash = fs::read(tf.path())
+        .unwrap()
+        .iter()
+        .fold(0xcbf29ce484222325u64, |h, &b| {
+            (h ^ u64::from(b)).wrapping_mul(0x100000001b3)
+        });
+    assert_eq!(hash, fnv1a, "{hash:#x}");
+}
+
+/// With the stamp64 pattern, a miscompare should report exactly which step
+/// wrote the bad data
+#[test]
+fn stamp64() {
+    let mut cf = NamedTempFile::new().unwrap();
+    cf.write_all(b"pattern = \"stamp64\"").unwrap();
+    let tf = NamedTempFile::new().unwrap();
+    let artifacts_dir = TempDir::new().unwrap();
+    let output = Command::cargo_bin("fsx")
+        .unwrap()
+        .args(["-N200", "-S2", "--inject", "80", "-P"])
+        .arg(artifacts_dir.path())
+        .arg("-f")
+        .arg(cf.path())
+        .arg(tf.path())
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(
+        stderr.contains(
+            "[ERROR fsx] Step# for the bad data is 65, which wrote it for \
+             offset 0x16f1c\n"
+        ),
+        "{stderr}"
+    );
+}
+
+/// Percentage weights should be converted to ordinary weights, with the rest
+/// shared by the other operations
+#[test]
+fn percent_weights() {
+    let mut cf = NamedTempFile::new().unwrap();
+    cf.write_all(
+        b"[weights]
+        write = \"50%\"
+        read = 30
+        mapread = 10
+        mapwrite = 0
+        truncate = 0
+        groups = { syncs = \"10%\" }",
+    )
+    .unwrap();
+    let output = Command::cargo_bin("fsx")
+        .unwrap()
+        .args(["config", "-f"])
+        .arg(cf.path())
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let config: toml::Table =
+        String::from_utf8(output.stdout).unwrap().parse().unwrap();
+    let weights = config["weights"].as_table().unwrap();
+    let weight = |name: &str| weights[name].as_float().unwrap();
+    assert_eq!(weight("write"), 50.0);
+    assert_eq!(weight("fsync"), 5.0);
+    assert_eq!(weight("fdatasync"), 5.0);
+    assert_eq!(weight("read"), 30.0);
+    assert_eq!(weight("mapread"), 10.0);
+}
+
+/// Unusable weight tables should be rejected, instead of panicking
+#[rstest]
+#[case::zero(
+    "read = 0\nwrite = 0\nmapread = 0\nmapwrite = 0\ntruncate = 0",
+    "error: [weights] has no nonzero weights\n"
+)]
+#[case::negative(
+    "read = -1",
+    "error: [weights] has an invalid weight -1 for read\n"
+)]
+#[case::over_100(
+    "read = \"60%\"\nwrite = \"60%\"",
+    "Error reading config file: percentage weights total 120%, over 100%\n"
+)]
+fn bad_weights(#[case] weights: &str, #[case] stderr: &str) {
+    let mut cf = NamedTempFile::new().unwrap();
+    cf.write_all(format!("[weights]\n{weights}").as_bytes())
+        .unwrap();
+    let tf = NamedTempFile::new().unwrap();
+    Command::cargo_bin("fsx")
+        .unwrap()
+        .args(["-N1", "-f"])
+        .arg(cf.path())
+        .arg(tf.path())
+        .assert()
+        .failure()
+        .stderr(stderr.to_owned());
+}
+
+/// --csv should write one row per operation
+#[test]
+fn csv() {
+    let dir = TempDir::new().unwrap();
+    let csv = dir.path().join("ops.csv");
+
+    Command::cargo_bin("fsx")
+        .unwrap()
+        .args(["-N6", "-S3", "-b3", "--csv"])
+        .arg(&csv)
+        .arg(dir.path().join("fsx.bin"))
+        .assert()
+        .success();
+    let contents = fs::read_to_string(&csv).unwrap();
+    let rows = contents
+        .lines()
+        .map(|l| {
+            // The duration is not deterministic
+            let mut fields = l.split(',').collect::<Vec<_>>();
+            assert_eq!(fields.len(), 6, "{l}");
+            fields.remove(4);
+            fields.join(",")
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(
+        rows,
+        [
+            "step,op,offset,len,result",
+            "1,truncate,0,242049,simulated",
+            "2,mapread,180570,61479,simulated",
+            "3,write,199859,34418,ok",
+            "4,read,154689,63341,ok",
+            "5,mapwrite,29946,52122,ok",
+            "6,truncate,80695,161354,ok",
+        ]
+    );
+}
+
+/// --timestamps should timestamp each log line, and log each op's duration
+#[test]
+fn timestamps() {
+    let dir = TempDir::new().unwrap();
+
+    let output = Command::cargo_bin("fsx")
+        .unwrap()
+        .args(["-v", "-N4", "-S3", "--timestamps"])
+        .arg(dir.path().join("fsx.bin"))
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    let lines = stderr.lines().collect::<Vec<_>>();
+    assert_eq!(lines.len(), 8, "{stderr}");
+    for (i, line) in lines.iter().enumerate() {
+        // Like "[2024-01-01T00:00:00.000000Z INFO  fsx] 1 truncate ..."
+        assert_eq!(&line[27..38], "Z INFO  fsx", "{line}");
+        if i % 2 == 1 {
+            assert!(line.ends_with("us"), "{line}");
+            assert!(line.contains(" took "), "{line}");
+        }
+    }
+}
+
+/// --stats-interval should periodically append a progress snapshot
+#[test]
+fn stats_interval() {
+    let dir = TempDir::new().unwrap();
+    let stats = dir.path().join("stats.log");
+
+    Command::cargo_bin("fsx")
+        .unwrap()
+        .args(["-N1000", "-S1", "--stats-interval", "1ms", "--stats-file"])
+        .arg(&stats)
+        .arg(dir.path().join("fsx.bin"))
+        .assert()
+        .success();
+    let contents = fs::read_to_string(&stats).unwrap();
+    assert!(contents.lines().count() > 0);
+    for line in contents.lines() {
+        assert!(line.contains(" steps, "), "{line}");
+        assert!(line.contains(" ops/s, "), "{line}");
+        assert!(line.ends_with("% skipped"), "{line}");
+    }
+}
+
+/// --latency should print a histogram summary for each operation at exit,
+/// even after a failure
+#[rstest]
+#[case::ok(None)]
+#[case::failure(Some("70"))]
+fn latency(#[case] inject: Option<&str>) {
+    let dir = TempDir::new().unwrap();
+
+    let mut cmd = Command::cargo_bin("fsx").unwrap();
+    cmd.args(["-N200", "-S1", "--latency", "-P"])
+        .arg(dir.path());
+    if let Some(step) = inject {
+        cmd.args(["--inject", step]);
+    }
+    let output = cmd.arg(dir.path().join("fsx.bin")).output().unwrap();
+    assert_eq!(output.status.success(), inject.is_none());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let mut lines = stdout.lines();
+    let header = lines.next().unwrap().split_whitespace().collect::<Vec<_>>();
+    assert_eq!(
+        header,
+        ["latency", "(us)", "count", "p50", "p90", "p99", "p99.9", "max"]
+    );
+    let mut total = 0;
+    for line in lines.by_ref().take_while(|l| !l.starts_with("All")) {
+        let fields = line.split_whitespace().collect::<Vec<_>>();
+        assert_eq!(fields.len(), 7, "{line}");
+        total += fields[1].parse::<u64>().unwrap();
+        let pct = fields[2..]
+            .iter()
+            .map(|f| f.parse::<f64>().unwrap())
+            .collect::<Vec<_>>();
+        assert!(pct.windows(2).all(|w| w[0] <= w[1]), "{line}");
+    }
+    // Skipped operations, like zero-length reads, aren't timed
+    assert!(total > 0 && total <= 200, "{stdout}");
+}
+
+/// SIGUSR1 should print the run's status without stopping it
+#[test]
+fn status_signal() {
+    let dir = TempDir::new().unwrap();
+    let log = dir.path().join("fsx.out");
+
+    let mut child = Command::cargo_bin("fsx")
+        .unwrap()
+        .args(["-S", "42"])
+        .arg(dir.path().join("fsx.bin"))
+        .stdout(fs::File::create(&log).unwrap())
+        .spawn()
+        .unwrap();
+    // Give fsx time to install its signal handler
+    std::thread::sleep(std::time::Duration::from_millis(500));
+    Command::new("kill")
+        .args(["-USR1", &child.id().to_string()])
+        .assert()
+        .success();
+    let mut status = None;
+    for _ in 0..100 {
+        let contents = fs::read_to_string(&log).unwrap();
+        if let Some(line) = contents.lines().find(|l| l.starts_with("status:"))
+        {
+            status = Some(line.to_owned());
+            break;
+        }
+        std::thread::sleep(std::time::Duration::from_millis(100));
+    }
+    let running = child.try_wait().unwrap().is_none();
+    child.kill().unwrap();
+    child.wait().unwrap();
+    let status = status.expect("no status line");
+    assert!(running);
+    assert!(status.contains(", seed 42, "), "{status}");
+    assert!(status.contains("s elapsed, file size "), "{status}");
+}
+
+/// With -N, fsx should draw a progress line if stderr is a terminal
+#[cfg(target_os = "linux")]
+#[test]
+fn progress_meter() {
+    let dir = TempDir::new().unwrap();
+    let fsx = assert_cmd::cargo::cargo_bin("fsx");
+    let cmd = format!(
+        "{} -N3000 -S1 {}",
+        fsx.display(),
+        dir.path().join("fsx.bin").display()
+    );
+
+    // script(1) runs the command with a pseudo-terminal
+    let output = Command::new("script")
+        .args(["-qec", &cmd, "/dev/null"])
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("\r\x1b[Kstep "), "{stdout:?}");
+    assert!(stdout.contains("/3000 ("), "{stdout:?}");
+    // The line should be erased before the final message
+    assert!(
+        stdout.contains("\r\x1b[KAll operations completed A-OK!"),
+        "{stdout:?}"
+    );
+}
+
+/// --strace should record each operation's system calls
+#[cfg(feature = "strace")]
+#[test]
+fn strace() {
+    let dir = TempDir::new().unwrap();
+    let path = dir.path().join("fsx.strace");
+
+    Command::cargo_bin("fsx")
+        .unwrap()
+        .args(["-N20", "-S3", "--strace"])
+        .arg(&path)
+        .arg(dir.path().join("fsx.bin"))
+        .assert()
+        .success();
+    let strace = fs::read_to_string(&path).unwrap();
+    let lines = strace.lines().collect::<Vec<_>>();
+    assert!(lines[0].starts_with("1 ftruncate("), "{strace}");
+    assert!(
+        lines
+            .iter()
+            .any(|l| l.starts_with("3 pwrite(")
+                && l.ends_with(", 199859) = 34418")),
+        "{strace}"
+    );
+    // Step 15 is a mapwrite that extends the file to 0x2e175 bytes
+    assert!(
+        lines
+            .iter()
+            .any(|l| l.starts_with("15 ftruncate(")
+                && l.ends_with(", 188789) = 0")),
+        "{strace}"
+    );
+    assert!(lines.iter().all(|l| l.contains(") = ")), "{strace}");
+}
+
+/// --prometheus should serve the op counters over HTTP, even after a client
+/// that never sends its request
+#[cfg(feature = "metrics")]
+#[test]
+fn prometheus() {
+    use std::{
+        io::Read,
+        net::{TcpListener, TcpStream},
+    };
+
+    let dir = TempDir::new().unwrap();
+    // Find a free port
+    let addr = TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .to_string();
+
+    let mut child = Command::cargo_bin("fsx")
+        .unwrap()
+        .args(["--prometheus", &addr])
+        .arg(dir.path().join("fsx.bin"))
+        .spawn()
+        .unwrap();
+    let mut idle = None;
+    for _ in 0..100 {
+        if let Ok(s) = TcpStream::connect(&addr) {
+            idle = Some(s);
+            break;
+        }
+        std::thread::sleep(std::time::Duration::from_millis(100));
+    }
+    let stream = idle.as_ref().and_then(|_| TcpStream::connect(&addr).ok());
+    let mut response = String::new();
+    if let Some(mut stream) = stream {
+        stream.write_all(b"GET /metrics HTTP/1.0\r\n\r\n").unwrap();
+        stream.read_to_string(&mut response).unwrap();
+    }
+    child.kill().unwrap();
+    child.wait().unwrap();
+    assert!(response.starts_with("HTTP/1.0 200 OK\r\n"), "{response}");
+    assert!(
+        response.contains("# TYPE fsx_ops_total counter\n"),
+        "{response}"
+    );
+    assert!(
+        response.contains("fsx_ops_total{op=\"read\"} "),
+        "{response}"
+    );
+    assert!(
+        response.contains("fsx_op_latency_seconds_count{op=\"write\"} "),
+        "{response}"
+    );
+}
+
+/// After a miscompare, the log dump should mark the ops that overlap it
+#[test]
+fn miscompare_overlap() {
+    let dir = TempDir::new().unwrap();
+
+    let output = Command::cargo_bin("fsx")
+        .unwrap()
+        .args(["-N6", "-S3", "--inject", "3", "-P"])
+        .arg(dir.path())
+        .arg(dir.path().join("fsx.bin"))
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains(
+            "3 WRITE    0x30cb3 => 0x39325 ( 0x8672 bytes)\t******WWWW\n"
+        ),
+        "{stderr}"
+    );
+    assert!(
+        stderr.contains(
+            "4 READ     0x25c41 => 0x353ae ( 0xf76d bytes)\t***RRRR***\n"
+        ),
+        "{stderr}"
+    );
+    assert!(
+        stderr.contains(
+            "Steps that overlap the bad range 0x30cb3 .. 0x353ad: 1, 2, 3, 4\n"
+        ),
+        "{stderr}"
+    );
+}
+
+/// --oplog-file should record every operation, however many
+#[test]
+fn oplog_file() {
+    let dir = TempDir::new().unwrap();
+    let oplog = dir.path().join("fsx.oplog");
+    let mut cf = NamedTempFile::new().unwrap();
+    cf.write_all(b"oplog_size = 4").unwrap();
+
+    Command::cargo_bin("fsx")
+        .unwrap()
+        .args(["-N10", "-S3", "--oplog-file"])
+        .arg(&oplog)
+        .arg("-f")
+        .arg(cf.path())
+        .arg(dir.path().join("fsx.bin"))
+        .assert()
+        .success();
+    assert_eq!(
+        fs::read_to_string(&oplog).unwrap(),
+        " 1 TRUNCATE  UP   from     0x0 to 0x3b181
+ 2 MAPREAD  0x2c15a => 0x3b181 ( 0xf027 bytes)
+ 3 WRITE    0x30cb3 => 0x39325 ( 0x8672 bytes)
+ 4 READ     0x25c41 => 0x353ae ( 0xf76d bytes)
+ 5 MAPWRITE  0x74fa => 0x14094 ( 0xcb9a bytes)
+ 6 TRUNCATE  DOWN from 0x3b181 to 0x13b37
+ 7 READ      0x64fc =>  0x9b43 ( 0x3647 bytes)
+ 8 MAPWRITE   0xde9 =>  0xf612 ( 0xe829 bytes)
+ 9 READ      0xf84b => 0x13b37 ( 0x42ec bytes)
+10 READ      0x2628 =>  0xca5f ( 0xa437 bytes)
+"
+    );
+}
+
+/// oplog_size should limit the log dump
+#[test]
+fn oplog_size() {
+    let dir = TempDir::new().unwrap();
+    let mut cf = NamedTempFile::new().unwrap();
+    cf.write_all(b"oplog_size = 4").unwrap();
+
+    let output = Command::cargo_bin("fsx")
+        .unwrap()
+        .args(["-N10", "-S3", "--inject", "8", "-f"])
+        .arg(cf.path())
+        .arg("-P")
+        .arg(dir.path())
+        .arg(dir.path().join("fsx.bin"))
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    let dump = stderr
+        .lines()
+        .skip_while(|l| !l.ends_with("LOG DUMP"))
+        .skip(1)
+        .take_while(|l| !l.contains("Steps that overlap"))
+        .collect::<Vec<_>>();
+    assert_eq!(dump.len(), 4, "{stderr}");
+    assert!(dump[0].contains(" 7 READ "), "{stderr}");
+}
+
+/// On failure, fsx should save the file's actual contents to .fsxbad
+#[test]
+fn fsxbad() {
+    let dir = TempDir::new().unwrap();
+    let fname = dir.path().join("fsx.bin");
+
+    Command::cargo_bin("fsx")
+        .unwrap()
+        .args(["-N10", "-S3", "--inject", "8", "-P"])
+        .arg(dir.path())
+        .arg(&fname)
+        .assert()
+        .failure();
+    let actual = fs::read(&fname).unwrap();
+    let bad = fs::read(dir.path().join("fsx.bin.fsxbad")).unwrap();
+    let good = fs::read(dir.path().join("fsx.bin.fsxgood")).unwrap();
+    assert_eq!(actual, bad);
+    assert_ne!(&good[..bad.len()], &bad[..]);
+}
+
+/// On failure, fsx should log the file's layout of data and holes
+#[test]
+#[cfg_attr(not(any(target_os = "linux", target_os = "freebsd")), ignore)]
+fn file_layout() {
+    let dir = TempDir::new().unwrap();
+
+    let output = Command::cargo_bin("fsx")
+        .unwrap()
+        .args(["-N10", "-S3", "--inject", "8", "-P"])
+        .arg(dir.path())
+        .arg(dir.path().join("fsx.bin"))
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    let layout = stderr.split("FILE LAYOUT\n").nth(1).unwrap();
+    assert!(layout.starts_with("[ERROR fsx] 0x0 .. "), "{stderr}");
+    assert!(layout.contains("\t***BAD***\n"), "{stderr}");
+}
+
+/// On failure, fsx should log information about the file system
+#[test]
+fn fs_info() {
+    let dir = TempDir::new().unwrap();
+
+    let output = Command::cargo_bin("fsx")
+        .unwrap()
+        .args(["-N10", "-S3", "--inject", "8", "-P"])
+        .arg(dir.path())
+        .arg(dir.path().join("fsx.bin"))
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    let info = stderr.split("FILE SYSTEM\n").nth(1).unwrap();
+    assert!(info.starts_with("[ERROR fsx] kernel: "), "{stderr}");
+    assert!(info.contains("[ERROR fsx] statvfs: bsize "), "{stderr}");
+    if cfg!(target_os = "linux") {
+        assert!(info.contains("[ERROR fsx] mount: "), "{stderr}");
+    }
+}
+
+/// dmesg_lines should save the end of the kernel log on failure
+#[test]
+fn dmesg() {
+    let dir = TempDir::new().unwrap();
+    let mut config = NamedTempFile::new().unwrap();
+    config
+        .write_all(b"dmesg_lines = 3\ndmesg_command = \"seq 10\"\n")
+        .unwrap();
+
+    Command::cargo_bin("fsx")
+        .unwrap()
+        .args(["-N10", "-S3", "--inject", "8", "-P"])
+        .arg(dir.path())
+        .arg("-f")
+        .arg(config.path())
+        .arg(dir.path().join("fsx.bin"))
+        .assert()
+        .failure();
+    let dmesg =
+        fs::read_to_string(dir.path().join("fsx.bin.fsxdmesg")).unwrap();
+    assert_eq!(dmesg, "8\n9\n10\n");
+}
+
+/// dmesg_command is useless without dmesg_lines
+#[test]
+fn dmesg_command_without_lines() {
+    let mut cf = NamedTempFile::new().unwrap();
+    cf.write_all(b"dmesg_command = \"dmesg\"").unwrap();
+
+    let tf = NamedTempFile::new().unwrap();
+
+    Command::cargo_bin("fsx")
+        .unwrap()
+        .args(["-N1", "-f"])
+        .arg(cf.path())
+        .arg(tf.path())
+        .assert()
+        .failure()
+        .stderr("error: dmesg_command requires dmesg_lines\n");
+}
+
+/// --rng-state should resume a failed run at the failed step, and finish just
+/// like an uninterrupted run
+#[rstest]
+// Step 10 is a read, that finds step 8's lost write
+#[case::read("", 8, 9)]
+// Step 5 is a mapwrite, that fails only after updating the expected contents
+#[case::mapwrite("verify_after_write = \"same\"", 5, 4)]
+fn rng_state(#[case] conf: &str, #[case] inject: u64, #[case] saved: u64) {
+    let dir = TempDir::new().unwrap();
+    let failed = dir.path().join("failed");
+    let resumed = dir.path().join("resumed");
+    let clean = dir.path().join("clean");
+    let before = dir.path().join("before");
+    fs::create_dir(&failed).unwrap();
+    let mut cf = NamedTempFile::new().unwrap();
+    cf.write_all(conf.as_bytes()).unwrap();
+
+    Command::cargo_bin("fsx")
+        .unwrap()
+        .args(["-N10", "-S3", "--inject"])
+        .arg(inject.to_string())
+        .arg("-f")
+        .arg(cf.path())
+        .arg("-P")
+        .arg(&failed)
+        .arg(failed.join("fsx.bin"))
+        .assert()
+        .failure();
+    // The saved contents are from before the failed step
+    Command::cargo_bin("fsx")
+        .unwrap()
+        .arg(format!("-N{saved}"))
+        .args(["-S3", "-f"])
+        .arg(cf.path())
+        .arg(&before)
+        .assert()
+        .success();
+    let before = fs::read(&before).unwrap();
+    let start = fs::read(failed.join("fsx.bin.fsxstart")).unwrap();
+    assert_eq!(&start[..before.len()], &before[..]);
+
+    let state = failed.join("fsx.bin.fsxstate");
+    let output = Command::cargo_bin("fsx")
+        .unwrap()
+        .args(["-v", "-N10", "-f"])
+        .arg(cf.path())
+        .arg("--rng-state")
+        .arg(&state)
+        .arg(&resumed)
+        .output()
+        .unwrap();
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(output.status.success(), "{stderr}");
+    assert!(
+        stderr.contains(&format!("Resuming after step {saved}\n")),
+        "{stderr}"
+    );
+    assert!(!stderr.contains(&format!(" {saved} ")), "{stderr}");
+    Command::cargo_bin("fsx")
+        .unwrap()
+        .args(["-N10", "-S3", "-f"])
+        .arg(cf.path())
+        .arg(&clean)
+        .assert()
+        .success();
+    assert_eq!(fs::read(&resumed).unwrap(), fs::read(&clean).unwrap());
+}
+
+/// checkpoint_interval should periodically save a state that --rng-state can
+/// resume from
+#[test]
+fn checkpoint_interval() {
+    let dir = TempDir::new().unwrap();
+    let interrupted = dir.path().join("interrupted");
+    let resumed = dir.path().join("resumed");
+    let clean = dir.path().join("clean");
+    let mut cf = NamedTempFile::new().unwrap();
+    cf.write_all(b"checkpoint_interval = 4").unwrap();
+
+    // Stand in for an interrupted run by stopping early
+    Command::cargo_bin("fsx")
+        .unwrap()
+        .args(["-N10", "-S3", "-f"])
+        .arg(cf.path())
+        .arg(&interrupted)
+        .assert()
+        .success();
+    let output = Command::cargo_bin("fsx")
+        .unwrap()
+        .args(["-v", "-N20", "--rng-state"])
+        .arg(dir.path().join("interrupted.fsxstate"))
+        .arg(&resumed)
+        .output()
+        .unwrap();
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(output.status.success(), "{stderr}");
+    assert!(stderr.contains("Resuming after step 8\n"), "{stderr}");
+    Command::cargo_bin("fsx")
+        .unwrap()
+        .args(["-N20", "-S3"])
+        .arg(&clean)
+        .assert()
+        .success();
+    assert_eq!(fs::read(&resumed).unwrap(), fs::read(&clean).unwrap());
+}
+
+/// checkpoint_interval is pointless when the run can't be resumed
+#[test]
+fn checkpoint_interval_pcg64() {
+    let mut cf = NamedTempFile::new().unwrap();
+    cf.write_all(b"checkpoint_interval = 4\nrng = \"pcg64\"")
+        .unwrap();
+    let tf = NamedTempFile::new().unwrap();
+    Command::cargo_bin("fsx")
+        .unwrap()
+        .args(["-N1", "-f"])
+        .arg(cf.path())
+        .arg(tf.path())
+        .assert()
+        .failure()
+        .stderr("error: cannot use checkpoint_interval with the pcg64 RNG\n");
+}
+
+/// artifact_mode = "diff" should save just the differing regions
+#[test]
+fn artifact_mode_diff() {
+    let dir = TempDir::new().unwrap();
+    let fname = dir.path().join("fsx.bin");
+    let mut config = NamedTempFile::new().unwrap();
+    config.write_all(b"artifact_mode = \"diff\"\n").unwrap();
+
+    Command::cargo_bin("fsx")
+        .unwrap()
+        .args(["-N10", "-S3", "--inject", "8", "-P"])
+        .arg(dir.path())
+        .arg("-f")
+        .arg(config.path())
+        .arg(&fname)
+        .assert()
+        .failure();
+    assert!(!dir.path().join("fsx.bin.fsxgood").exists());
+    assert!(!dir.path().join("fsx.bin.fsxbad").exists());
+    let diff = fs::read_to_string(dir.path().join("fsx.bin.fsxdiff")).unwrap();
+    let actual_size = fs::metadata(&fname).unwrap().len();
+    assert!(
+        diff.contains(&format!("actual_size = {actual_size}\n")),
+        "{diff}"
+    );
+    assert!(diff.contains("truncated = false\n"), "{diff}");
+    assert!(diff.contains("[[region]]\noffset = "), "{diff}");
+}
+
+/// map_mode = "window" should reuse mappings and still verify the data
+#[test]
+fn map_mode_window() {
+    let dir = TempDir::new().unwrap();
+    let fname = dir.path().join("fsx.bin");
+    let mut config = NamedTempFile::new().unwrap();
+    config
+        .write_all(
+            b"map_mode = \"window\"\n\
+              flen = 4194304\n\
+              [weights]\n\
+              mapread = 10\n\
+              mapwrite = 10\n\
+              invalidate = 1\n",
+        )
+        .unwrap();
+
+    Command::cargo_bin("fsx")
+        .unwrap()
+        .args(["-N500", "-S7", "-f"])
+        .arg(config.path())
+        .arg(&fname)
+        .assert()
+        .success();
+}
+
+/// map_mode = "file" should map the file once as it grows and shrinks
+#[test]
+fn map_mode_file() {
+    let dir = TempDir::new().unwrap();
+    let fname = dir.path().join("fsx.bin");
+    let mut config = NamedTempFile::new().unwrap();
+    config
+        .write_all(
+            b"map_mode = \"file\"\n\
+              [weights]\n\
+              mapread = 10\n\
+              mapwrite = 10\n\
+              truncate = 2\n\
+              invalidate = 1\n",
+        )
+        .unwrap();
+
+    Command::cargo_bin("fsx")
+        .unwrap()
+        .args(["-N500", "-S7", "-f"])
+        .arg(config.path())
+        .arg(&fname)
+        .assert()
+        .success();
+}
+
+/// A huge flen shouldn't need a similar amount of RAM
+#[rstest]
+#[case::v1("flen = 4000000000\nop_stream = \"v1\"\n")]
+// The legacy op stream must still draw all of the original data from the RNG,
+// which is slow in a debug build.  So use a smaller flen.
+#[case::legacy("flen = 536870912\n")]
+fn huge_flen(#[case] conf: &str) {
+    use std::os::unix::process::CommandExt;
+
+    use nix::sys::resource::{setrlimit, Resource};
+
+    let dir = TempDir::new().unwrap();
+    let mut config = NamedTempFile::new().unwrap();
+    config.write_all(conf.as_bytes()).unwrap();
+
+    let mut cmd = Command::cargo_bin("fsx").unwrap();
+    cmd.args(["-N100", "-S4", "-f"])
+        .arg(config.path())
+        .arg(dir.path().join("fsx.bin"));
+    unsafe {
+        cmd.pre_exec(|| {
+            setrlimit(Resource::RLIMIT_AS, 256 << 20, 256 << 20)?;
+            Ok(())
+        });
+    }
+    cmd.assert().success();
+}
+
+/// shadow = "checksum" should exercise a file without keeping its contents
+#[rstest]
+#[case::v1("flen = 4000000000\nop_stream = \"v1\"\n")]
+// The legacy op stream draws all of the original data from the RNG, which is
+// slow in a debug build.  So use a smaller flen.
+#[case::legacy("flen = 268435456\n")]
+fn shadow_checksum(#[case] conf: &str) {
+    use std::os::unix::process::CommandExt;
+
+    use nix::sys::resource::{setrlimit, Resource};
+
+    let dir = TempDir::new().unwrap();
+    let mut config = NamedTempFile::new().unwrap();
+    config
+        .write_all(
+            format!(
+                "shadow = \"checksum\"\n{conf}[weights]\npunch_hole = \
+                 1\nposix_fallocate = 1\n"
+            )
+            .as_bytes(),
+        )
+        .unwrap();
+
+    let mut cmd = Command::cargo_bin("fsx").unwrap();
+    cmd.args(["-N200", "-S4", "-f"])
+        .arg(config.path())
+        .arg(dir.path().join("fsx.bin"));
+    unsafe {
+        cmd.pre_exec(|| {
+            setrlimit(Resource::RLIMIT_AS, 256 << 20, 256 << 20)?;
+            Ok(())
+        });
+    }
+    cmd.assert().success();
+}
+
+/// With shadow = "checksum", a failure should be reported by block, without
+/// a .fsxgood file
+#[test]
+fn shadow_checksum_failure() {
+    let dir = TempDir::new().unwrap();
+    let mut config = NamedTempFile::new().unwrap();
+    config.write_all(b"shadow = \"checksum\"\n").unwrap();
+
+    let cmd = Command::cargo_bin("fsx")
+        .unwrap()
+        .args(["-N10", "-S3", "--inject", "8", "-P"])
+        .arg(dir.path())
+        .arg("-f")
+        .arg(config.path())
+        .arg(dir.path().join("fsx.bin"))
+        .assert()
+        .failure();
+    let actual_stderr = CString::new(cmd.get_output().stderr.clone())
+        .unwrap()
+        .into_string()
+        .unwrap();
+    assert!(actual_stderr
+        .contains("checksum mismatch: offset= 0x0, size = 0x10000"));
+    assert!(actual_stderr.contains("Step 8 last changed the block\n"));
+    assert!(actual_stderr.contains("can't tell which bytes of the block"));
+    assert!(actual_stderr.contains("rerun with shadow = \"full\""));
+    assert!(!dir.path().join("fsx.bin.fsxgood").exists());
+    assert!(!dir.path().join("fsx.bin.fsxstate").exists());
+    assert!(dir.path().join("fsx.bin.fsxbad").exists());
+}
+
+/// --artifact-name should name the artifacts after its template
+#[test]
+fn artifact_name() {
+    let dir = TempDir::new().unwrap();
+
+    Command::cargo_bin("fsx")
+        .unwrap()
+        .args(["-N10", "-S3", "--inject", "8"])
+        .args(["--artifact-name", "{name}.{seed}.{pid}", "-P"])
+        .arg(dir.path())
+        .arg(dir.path().join("fsx.bin"))
+        .assert()
+        .failure();
+    let mut names = fs::read_dir(dir.path())
+        .unwrap()
+        .map(|e| e.unwrap().file_name().into_string().unwrap())
+        .filter(|n| n != "fsx.bin")
+        .map(|n| {
+            // Strip the pid, which varies
+            let (head, tail) = n.rsplit_once('.').unwrap();
+            let (head, _pid) = head.rsplit_once('.').unwrap();
+            format!("{head}.{tail}")
+        })
+        .collect::<Vec<_>>();
+    names.sort();
+    assert_eq!(
+        names,
+        [
+            "fsx.bin.3.fsxbad",
+            "fsx.bin.3.fsxconfig",
+            "fsx.bin.3.fsxgood",
+            "fsx.bin.3.fsxstart",
+            "fsx.bin.3.fsxstate"
+        ]
+    );
+}